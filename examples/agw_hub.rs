@@ -0,0 +1,61 @@
+//! Share one AGW connection between many AGW clients.
+use anyhow::Result;
+use clap::Parser;
+
+use std::sync::Arc;
+
+use agw::r#async::hub::Hub;
+use agw::ratelimit::{Limits, Overflow, RateLimiter};
+
+#[derive(Parser, Debug)]
+struct Opt {
+    #[clap(short, default_value = "0")]
+    verbose: usize,
+
+    /// Address to accept AGW clients on.
+    #[clap(short, long, default_value = "127.0.0.1:9010")]
+    listen: String,
+
+    /// Upstream AGW endpoint.
+    #[clap(short = 'c', default_value = "127.0.0.1:8010")]
+    agw_addr: String,
+
+    /// Max frames per minute each client may transmit.
+    #[clap(long)]
+    client_frames_per_minute: Option<u32>,
+
+    /// Max share of time each port may spend transmitting, 0.0 to 1.0.
+    #[clap(long)]
+    port_duty_cycle: Option<f64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opt = Opt::parse();
+    stderrlog::new()
+        .module(module_path!())
+        .module("agw")
+        .quiet(false)
+        .verbosity(opt.verbose)
+        .timestamp(stderrlog::Timestamp::Second)
+        .init()
+        .unwrap();
+
+    let hub = Hub::new(&opt.agw_addr).await?;
+    if opt.client_frames_per_minute.is_some() || opt.port_duty_cycle.is_some() {
+        hub.set_rate_limiter(Some(Arc::new(RateLimiter::new(
+            Limits {
+                frames_per_minute: opt.client_frames_per_minute,
+                ..Limits::default()
+            },
+            Limits {
+                duty_cycle: opt.port_duty_cycle,
+                ..Limits::default()
+            },
+            Overflow::Queue,
+        ))));
+    }
+    let listener = tokio::net::TcpListener::bind(&opt.listen).await?;
+    hub.serve(listener).await?;
+    Ok(())
+}
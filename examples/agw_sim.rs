@@ -0,0 +1,83 @@
+//! Simulated radio channel, with one AGW endpoint per station.
+//!
+//! E.g. `agw_sim -l 127.0.0.1:8010 -l 127.0.0.1:8011` gives two stations
+//! that can connect to each other.
+use anyhow::Result;
+use clap::Parser;
+use log::info;
+
+use std::time::Duration;
+
+use agw::r#async::sim::Medium;
+use agw::{Baud, Call};
+
+#[derive(Parser, Debug)]
+struct Opt {
+    #[clap(short, default_value = "0")]
+    verbose: usize,
+
+    /// Address to accept AGW clients on, once per station.
+    #[clap(short, long, required = true)]
+    listen: Vec<String>,
+
+    /// Callsign that every station digipeats for.
+    #[clap(long)]
+    digipeat: Option<Call>,
+
+    /// Bit rate: 1200, 2400, 4800, or 9600.
+    #[clap(long, default_value = "1200")]
+    baud: u32,
+
+    /// Milliseconds until other stations hear a transmission.
+    #[clap(long, default_value = "10")]
+    latency_ms: u64,
+
+    /// Chance, 0.0 to 1.0, of a station not hearing a frame.
+    #[clap(long, default_value = "0")]
+    loss: f64,
+
+    /// Don't lose frames sent at the same time.
+    #[clap(long)]
+    no_collisions: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opt = Opt::parse();
+    stderrlog::new()
+        .module(module_path!())
+        .module("agw")
+        .quiet(false)
+        .verbosity(opt.verbose)
+        .timestamp(stderrlog::Timestamp::Second)
+        .init()
+        .unwrap();
+
+    let baud = match opt.baud {
+        1200 => Baud::B1200,
+        2400 => Baud::B2400,
+        4800 => Baud::B4800,
+        9600 => Baud::B9600,
+        other => anyhow::bail!("unsupported baud rate {other}"),
+    };
+    let medium = Medium::builder()
+        .baud(baud)
+        .latency(Duration::from_millis(opt.latency_ms))
+        .loss(opt.loss)
+        .collisions(!opt.no_collisions)
+        .build();
+    let mut stations = tokio::task::JoinSet::new();
+    for addr in &opt.listen {
+        let mut station = medium.station();
+        if let Some(call) = &opt.digipeat {
+            station = station.digipeat(call.clone());
+        }
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        info!("Station listening on {addr}");
+        stations.spawn(station.serve(listener));
+    }
+    while let Some(ret) = stations.join_next().await {
+        ret??;
+    }
+    Ok(())
+}
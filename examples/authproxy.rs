@@ -1,8 +1,15 @@
+//! AGW proxy that enforces a policy on what clients may do.
+//!
+//! See `agw::policy` for the rule file format. The rule file is reloaded
+//! when it changes.
+use agw::policy::PolicyFile;
+use agw::proxy::Action;
 use agw::Packet;
 use anyhow::Result;
 use clap::Parser;
-use log::error;
+use log::{error, info};
 use std::net::TcpListener;
+use std::sync::Arc;
 
 #[derive(Parser, Debug)]
 struct Opt {
@@ -14,6 +21,10 @@ struct Opt {
 
     #[clap(short = 'c', default_value = "127.0.0.1:8010")]
     agw_addr: String,
+
+    /// Policy rule file.
+    #[clap(short, long)]
+    policy: String,
 }
 
 fn main() -> Result<()> {
@@ -27,7 +38,17 @@ fn main() -> Result<()> {
         .init()
         .unwrap();
 
-    //let mut agw = AGW::new(&opt.agw_addr)?;
+    let policy = Arc::new(PolicyFile::open(&opt.policy)?);
+    {
+        let policy = policy.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_secs(5));
+            if let Err(e) = policy.reload_if_changed() {
+                error!("Failed to reload policy, keeping the old one: {e}");
+            }
+        });
+    }
+
     let listener = TcpListener::bind(&opt.listen)?;
     for stream in listener.incoming() {
         match stream {
@@ -35,20 +56,27 @@ fn main() -> Result<()> {
                 error!("Failed to accept connection: {e}");
             }
             Ok(stream) => {
+                let addr = stream.peer_addr().ok().map(|a| a.ip());
+                info!("Client connected from {addr:?}");
+                let policy = policy.clone();
+                let agw_addr = opt.agw_addr.clone();
                 std::thread::spawn(move || {
-                    let mut s = agw::proxy::Proxy::new(stream).expect("Failed to create stream");
-                    s.run(
-                        &|packet: Packet| {
-                            eprintln!("from server: {packet:?}");
-                            Some(packet)
-                        },
-                        &|packet: Packet| {
-                            eprintln!("from client: {packet:?}");
-                            Some(packet)
-                        },
-                    )
-                    .unwrap();
-                    drop(s);
+                    let mut s = match agw::proxy::Proxy::builder()
+                        .upstream_addr(agw_addr)
+                        .build(stream)
+                    {
+                        Ok(s) => s,
+                        Err(e) => {
+                            error!("Failed to connect upstream: {e}");
+                            return;
+                        }
+                    };
+                    if let Err(e) = s.run_actions(
+                        &|packet: Packet| vec![Action::Forward(packet)],
+                        &|packet: Packet| policy.policy().filter(addr, packet),
+                    ) {
+                        error!("Proxy failed: {e}");
+                    }
                 });
             }
         }
@@ -0,0 +1,350 @@
+//! Run the AGW clients over a transport that injects faults.
+//!
+//! Each run talks to a `FakeTnc` with reads and writes split at random
+//! boundaries, random delays, and frames duplicated, dropped, or cut off by
+//! the server closing the connection. Errors are fine when frames are
+//! duplicated or cut off, but hangs and panics are bugs. When only reads and
+//! writes are split and delayed, the results must also be right. Once the
+//! connection is closed, further calls must fail too.
+use anyhow::Result;
+use clap::Parser;
+use log::{debug, info};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use agw::r#async::server::{Clients, Server};
+use agw::r#async::AGWServer;
+use agw::testing::fault::{Faults, Faulty};
+use agw::testing::{BlockingMock, FakeTnc, Mock, Script};
+use agw::{Call, Packet, Pid, Port};
+
+#[derive(Parser, Debug)]
+struct Opt {
+    #[clap(short, default_value = "0")]
+    verbose: usize,
+
+    /// Number of runs per client.
+    #[clap(short, long, default_value = "100")]
+    iterations: u64,
+
+    /// RNG seed, to make failures reproducible.
+    #[clap(short, long, default_value = "1")]
+    seed: u64,
+
+    /// Seconds before a run counts as hung.
+    #[clap(long, default_value = "10")]
+    timeout: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Client {
+    Async,
+    V1,
+    V2,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+    /// Only split and delay.
+    Clean,
+    Duplicate,
+    /// Drop frames on the way to a mock, which records what got through.
+    Drop,
+    /// Server closes the connection in the middle of a frame.
+    Close,
+}
+
+#[derive(Clone, Debug)]
+struct Case {
+    client: Client,
+    mode: Mode,
+    client_faults: Faults,
+    server_faults: Faults,
+    payload_len: usize,
+}
+
+/// Xorshift, for picking the faults of each run.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: usize) -> usize {
+        usize::try_from(self.next() % n as u64).expect("can't happen: below n")
+    }
+}
+
+impl Case {
+    fn new(client: Client, rng: &mut Rng) -> Self {
+        let mode = match rng.below(4) {
+            0 => Mode::Clean,
+            1 => Mode::Duplicate,
+            2 => Mode::Drop,
+            _ => Mode::Close,
+        };
+        let base = |rng: &mut Rng| {
+            let delay = if rng.below(3) == 0 { 2 } else { 0 };
+            Faults::new()
+                .seed(rng.next())
+                .split(rng.below(4) != 0)
+                .max_delay(Duration::from_millis(delay))
+        };
+        let mut client_faults = base(rng);
+        let mut server_faults = base(rng);
+        match mode {
+            Mode::Clean => {}
+            Mode::Duplicate => {
+                client_faults = client_faults.duplicate(0.3);
+                server_faults = server_faults.duplicate(0.3);
+            }
+            Mode::Drop => client_faults = client_faults.drop(0.3),
+            Mode::Close => server_faults = server_faults.close_after(rng.below(8)),
+        }
+        Self {
+            client,
+            mode,
+            client_faults,
+            server_faults,
+            payload_len: 1 + rng.below(2000),
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        (0..self.payload_len)
+            .map(|n| u8::try_from(n % 251).expect("can't happen: below 251"))
+            .collect()
+    }
+
+    /// Whether errors are expected.
+    fn may_fail(&self) -> bool {
+        matches!(self.mode, Mode::Duplicate | Mode::Close)
+    }
+}
+
+/// Serve a client with a `FakeTnc`, going through the server faults.
+async fn serve<S>(con: S, faults: Faults)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let clients = Clients::new();
+    let server = Server::with_clients(FakeTnc::new(clients.clone()), clients);
+    if let Err(e) = server
+        .handle(AGWServer::new(Faulty::new(con, faults)))
+        .await
+    {
+        debug!("Server: {e}");
+    }
+}
+
+/// Unproto frames to send in drop mode.
+struct Unprotos {
+    src: Call,
+    dst: Call,
+    data: Vec<Vec<u8>>,
+}
+
+impl Unprotos {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            src: "M0THC-1".parse()?,
+            dst: "ID".parse()?,
+            data: (0..20).map(|i| format!("frame {i}").into_bytes()).collect(),
+        })
+    }
+
+    fn packets(&self) -> Vec<Packet> {
+        self.data
+            .iter()
+            .map(|data| Packet::Unproto {
+                port: Port(0),
+                pid: Pid(0xF0),
+                src: self.src.clone(),
+                dst: self.dst.clone(),
+                data: data.clone(),
+            })
+            .collect()
+    }
+
+    /// Check that the frames that got through were sent, and undamaged.
+    fn check(&self, got: &[Packet]) -> Result<()> {
+        let sent = self.packets();
+        for p in got {
+            anyhow::ensure!(sent.contains(p), "received {p:?}, which wasn't sent");
+        }
+        info!("{} of {} frames got through", got.len(), sent.len());
+        Ok(())
+    }
+}
+
+async fn run_async(case: &Case) -> Result<()> {
+    let (client, server) = tokio::io::duplex(4096);
+    let client = Faulty::new(client, case.client_faults.clone());
+    let agw = agw::r#async::AGW::from_stream(client)?;
+    if let Mode::Drop = case.mode {
+        let mock = Mock::start(Script::new(), server);
+        let ui = Unprotos::new()?;
+        for p in ui.packets() {
+            agw.send(p).await?;
+        }
+        return ui.check(mock.finish().await?.packets());
+    }
+    tokio::spawn(serve(server, case.server_faults.clone()));
+    let me: Call = "M0THC-1".parse()?;
+    let peer: Call = "M0THC-2".parse()?;
+    let ret = async {
+        let mut con = agw.connect(Port(0), Pid(0xF0), &me, &peer, &[]).await?;
+        let payload = case.payload();
+        con.send(&payload).await?;
+        let mut got = vec![0; payload.len()];
+        con.read_exact(&mut got).await?;
+        anyhow::ensure!(got == payload, "echoed data differs");
+        Ok(())
+    }
+    .await;
+    if ret.is_err() && matches!(case.mode, Mode::Close) {
+        let again = agw.connect(Port(0), Pid(0xF0), &me, &peer, &[]).await;
+        anyhow::ensure!(again.is_err(), "connected after connection closed");
+    }
+    ret
+}
+
+/// Connect a socket pair, with the server end served by a mock or a
+/// `FakeTnc` in its own thread.
+fn blocking_server(case: &Case) -> Result<(UnixStream, Option<BlockingMock>)> {
+    let (client, server) = UnixStream::pair()?;
+    if let Mode::Drop = case.mode {
+        return Ok((client, Some(BlockingMock::start(Script::new(), server)?)));
+    }
+    let faults = case.server_faults.clone();
+    server.set_nonblocking(true)?;
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to create runtime");
+        rt.block_on(async {
+            let con = tokio::net::UnixStream::from_std(server).expect("failed to convert socket");
+            serve(con, faults).await;
+        });
+    });
+    Ok((client, None))
+}
+
+fn run_v1(case: &Case) -> Result<()> {
+    let (sock, mock) = blocking_server(case)?;
+    let mut agw = agw::AGW::from_streams(
+        Faulty::new(sock.try_clone()?, case.client_faults.clone()),
+        Faulty::new(sock.try_clone()?, case.client_faults.clone()),
+    );
+    let ret = (|| {
+        if let Some(mock) = mock {
+            let ui = Unprotos::new()?;
+            for data in &ui.data {
+                agw.unproto(Port(0), Pid(0xF0), &ui.src, &ui.dst, data)?;
+            }
+            return ui.check(mock.finish()?.packets());
+        }
+        anyhow::ensure!(agw.version()? == (2005, 127), "wrong version");
+        anyhow::ensure!(agw.port_info()?.ports.len() == 1, "wrong port info");
+        agw.port_cap(Port(0))?;
+        Ok(())
+    })();
+    if ret.is_err() && matches!(case.mode, Mode::Close) {
+        anyhow::ensure!(
+            agw.version().is_err(),
+            "query worked after connection closed"
+        );
+    }
+    // v1 doesn't close the connection when dropped.
+    let _ = sock.shutdown(std::net::Shutdown::Both);
+    ret
+}
+
+fn run_v2(case: &Case) -> Result<()> {
+    let (sock, mock) = blocking_server(case)?;
+    let agw = agw::v2::AGW::new(
+        Faulty::new(sock.try_clone()?, case.client_faults.clone()),
+        Faulty::new(sock, case.client_faults.clone()),
+    )?;
+    if let Some(mock) = mock {
+        let ui = Unprotos::new()?;
+        for data in &ui.data {
+            agw.unproto(Port(0), Pid(0xF0), &ui.src, &ui.dst, data)?;
+        }
+        return ui.check(mock.finish()?.packets());
+    }
+    let ret = (|| {
+        anyhow::ensure!(agw.version()? == (2005, 127), "wrong version");
+        anyhow::ensure!(agw.port_info()?.ports.len() == 1, "wrong port info");
+        agw.port_cap(Port(0))?;
+        agw.frames_outstanding(Port(0))?;
+        Ok(())
+    })();
+    if ret.is_err() && matches!(case.mode, Mode::Close) {
+        anyhow::ensure!(
+            agw.version().is_err(),
+            "query worked after connection closed"
+        );
+    }
+    ret
+}
+
+fn run(case: &Case) -> Result<()> {
+    match case.client {
+        Client::Async => tokio::runtime::Runtime::new()?.block_on(run_async(case)),
+        Client::V1 => run_v1(case),
+        Client::V2 => run_v2(case),
+    }
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::parse();
+    stderrlog::new()
+        .module(module_path!())
+        .module("agw")
+        .quiet(false)
+        .verbosity(opt.verbose)
+        .timestamp(stderrlog::Timestamp::Second)
+        .init()
+        .unwrap();
+    let mut rng = Rng(opt.seed.max(1));
+    let mut lossy = 0;
+    for n in 0..opt.iterations {
+        for client in [Client::Async, Client::V1, Client::V2] {
+            let case = Case::new(client, &mut rng);
+            debug!("Run {n}: {case:?}");
+            let (tx, rx) = std::sync::mpsc::channel();
+            let c2 = case.clone();
+            let thread = std::thread::spawn(move || {
+                let _ = tx.send(run(&c2));
+            });
+            match rx.recv_timeout(Duration::from_secs(opt.timeout)) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) if case.may_fail() => debug!("Run {n}: Failed as allowed: {e}"),
+                Ok(Err(e)) => anyhow::bail!("run {n} failed: {e}\n{case:#?}"),
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                    anyhow::bail!("run {n} hung\n{case:#?}");
+                }
+                // The sender was dropped without sending: a panic.
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                    let _ = thread.join();
+                    anyhow::bail!("run {n} panicked\n{case:#?}");
+                }
+            }
+            if case.may_fail() {
+                lossy += 1;
+            }
+        }
+    }
+    println!(
+        "{} runs, {lossy} of them with errors allowed, no hangs or panics",
+        opt.iterations * 3
+    );
+    Ok(())
+}
@@ -28,6 +28,7 @@ struct Opt {
     #[clap()]
     dst: String,
 
+    /// AGW port, starting at 0.
     #[clap(short, default_value = "0")]
     port: u8,
 }
@@ -142,6 +142,7 @@ struct Opts {
     #[clap(short = 'C', default_value = "/dev/null")]
     cq_log: String,
 
+    /// AGW port, starting at 0.
     #[clap(short, default_value = "0")]
     port: u8,
 
@@ -6,16 +6,28 @@ use std::pin::Pin;
 use std::sync::{Arc, Mutex, Weak};
 use std::task::{Context, Poll};
 
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 
-use crate::{parse_header, Call, Header, Packet, Pid, Port, HEADER_LEN};
+use crate::tap::{Direction, Tap};
+use crate::wrap::{unwrap_packet, wrap_packet, Envelope, Wrapper};
+use crate::{Call, Frame, Packet, Pid, Port, HEADER_LEN};
 use crate::{Error, Result};
 
+pub mod hub;
+pub mod proxy;
+pub mod server;
+pub mod sim;
+
 const PID_AX25: Pid = Pid(0xf0);
+const READ_CHUNK: usize = 4096;
 const CONNECTION_TIMEOUT: std::time::Duration = std::time::Duration::from_mins(5);
+const MONITOR_QUEUE: usize = 100;
+
+/// Max length of a message received with `Connection::recv_message()`.
+pub const MAX_MESSAGE_LEN: usize = 1024 * 1024;
 
 type RuleIdent = u64;
 
@@ -45,9 +57,35 @@ impl Drop for RuleHandle {
 
 #[derive(Clone)]
 pub enum RuleMatch {
-    Data { port: Port, src: Call, dst: Call },
-    ConnectionEstablished { port: Port, src: Call, dst: Call },
-    IncomingConnect { port: Port, dst: Call },
+    Data {
+        port: Port,
+        src: Call,
+        dst: Call,
+    },
+    ConnectionEstablished {
+        port: Port,
+        src: Call,
+        dst: Call,
+    },
+    IncomingConnect {
+        port: Port,
+        dst: Call,
+    },
+
+    /// All connected mode packets for the local callsign `call`.
+    Callsign {
+        port: Port,
+        call: Call,
+    },
+
+    /// Monitored frames, if monitoring is on.
+    Monitor,
+
+    /// Raw AX.25 frames, if raw mode is on.
+    RawFrame,
+
+    /// Any packet of the given kind.
+    Kind(u8),
 }
 
 /// 3-tuple for a connection.
@@ -93,6 +131,9 @@ enum RuleSink {
         tx: mpsc::Sender<Packet>,
         server_state: Option<SharedServerConnectionState>,
     },
+    /// Like `Packet`, but never waits: if the queue is full, the packet is
+    /// dropped.
+    Lossy(mpsc::Sender<Packet>),
     Listener(mpsc::Sender<PendingConnection>),
 }
 
@@ -112,6 +153,7 @@ impl RuleMatch {
                     pid: _,
                     src: src2,
                     dst: dst2,
+                    text: _,
                 } => {
                     return port == port2 && src == src2 && dst == dst2;
                 }
@@ -123,6 +165,7 @@ impl RuleMatch {
                     pid: _,
                     src: src2,
                     dst: dst2,
+                    text: _,
                 } = packet
                 {
                     return port == port2 && src == src2 && dst == dst2;
@@ -134,11 +177,40 @@ impl RuleMatch {
                     pid: _,
                     src: _,
                     dst: dst2,
+                    text: _,
                 } = packet
                 {
                     return port == port2 && dst == dst2;
                 }
             }
+            RuleMatch::Callsign { port, call } => match packet {
+                Packet::IncomingConnect {
+                    port: port2,
+                    dst: dst2,
+                    ..
+                }
+                | Packet::ConnectionEstablished {
+                    port: port2,
+                    dst: dst2,
+                    ..
+                }
+                | Packet::Data {
+                    port: port2,
+                    dst: dst2,
+                    ..
+                }
+                | Packet::Disconnect {
+                    port: port2,
+                    dst: dst2,
+                    ..
+                } => {
+                    return port == port2 && call == dst2;
+                }
+                _ => {}
+            },
+            RuleMatch::Monitor => return matches!(packet, Packet::Monitor { .. }),
+            RuleMatch::RawFrame => return matches!(packet, Packet::RawFrame { .. }),
+            RuleMatch::Kind(kind) => return packet.kind() == *kind,
         }
         false
     }
@@ -156,6 +228,7 @@ pub struct Router {
     // of any server data sent before the client proved it received the UA.
     server_connections: Mutex<HashMap<ServerConnectionKey, Weak<Mutex<ServerConnectionState>>>>,
     rules: Arc<Mutex<Vec<Rule>>>,
+    tap: Mutex<Option<Arc<dyn Tap>>>,
 }
 
 impl Router {
@@ -166,6 +239,19 @@ impl Router {
             outgoing: Mutex::new(None),
             server_connections: Mutex::new(HashMap::new()),
             rules: Arc::new(Mutex::new(Vec::new())),
+            tap: Mutex::new(None),
+        }
+    }
+
+    /// Show every packet sent or received to `tap`, or stop doing so.
+    pub fn set_tap(&self, tap: Option<Arc<dyn Tap>>) {
+        *self.tap.lock().unwrap() = tap;
+    }
+
+    fn tap(&self, dir: Direction, frame: &Frame) {
+        let tap = self.tap.lock().unwrap().clone();
+        if let Some(tap) = tap {
+            tap.frame(dir, frame);
         }
     }
     /// Add packet listener. When a packet matches the rules, send it on the
@@ -179,6 +265,14 @@ impl Router {
             },
         )
     }
+    /// Like `add()`, but if `tx` is full the packet is dropped instead of
+    /// waiting for room.
+    ///
+    /// For receivers that may not keep up, and mustn't hold up everyone
+    /// else.
+    pub fn add_lossy(self: &Arc<Self>, m: RuleMatch, tx: mpsc::Sender<Packet>) -> RuleHandle {
+        self.add_inner(m, RuleSink::Lossy(tx))
+    }
     fn add_server_connection(
         &self,
         key: &ServerConnectionKey,
@@ -255,6 +349,12 @@ impl Router {
         self.rules.lock().unwrap().push(Rule { ident, m, sink });
         RuleHandle::new(ident, Arc::downgrade(&self.rules))
     }
+    /// Drop all rules, e.g. because upstream is gone.
+    ///
+    /// This closes the channels, so whoever waits for packets finds out.
+    fn close(&self) {
+        self.rules.lock().unwrap().clear();
+    }
     pub fn del(&self, ident: RuleIdent) {
         // TODO: there has to be a more efficient way.
         //
@@ -284,14 +384,30 @@ impl Router {
                                 server_state.buffered.clear();
                             }
                         }
-                        tx.send(packet.clone()).await.map_err(Error::other)?;
+                        if tx.send(packet.clone()).await.is_err() {
+                            // The receiver went away, but its rule handle
+                            // was not yet dropped.
+                            debug!("agw: Rule {} receiver gone", rule.ident);
+                            continue;
+                        }
                     }
+                    RuleSink::Lossy(tx) => match tx.try_send(packet.clone()) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            warn!("agw: Rule {} receiver full, dropping packet", rule.ident);
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => {
+                            debug!("agw: Rule {} receiver gone", rule.ident);
+                            continue;
+                        }
+                    },
                     RuleSink::Listener(tx) => {
                         let Packet::IncomingConnect {
                             port,
                             pid: _,
                             src,
                             dst,
+                            text: _,
                         } = &packet
                         else {
                             continue;
@@ -369,22 +485,22 @@ struct Pipo {
     //rx: tokio::sync::Mutex<mpsc::Receiver<Packet>>,
 }
 
-enum PIPOState {
-    AwaitHeader,
-    GotHeader(Header),
-}
-
 impl Pipo {
-    fn new(con: TcpStream, router: Arc<Router>) -> Result<Self> {
+    fn new<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        con: S,
+        router: Arc<Router>,
+        max_data_len: u32,
+    ) -> Result<Self> {
         //let (tx1, rx1) = mpsc::channel(10); // TODO: magic number.
         let (tx2, rx2) = mpsc::channel(10); // TODO: magic number.
         router.set_outgoing(tx2.clone())?;
 
         // TODO: probably should split this task in two.
         tokio::spawn(async move {
-            Self::run(con, router, rx2)
-                .await
-                .expect("Pipo run() failed");
+            if let Err(e) = Self::run(con, router.clone(), rx2, max_data_len).await {
+                warn!("agw/pipo: AGW connection failed: {e}");
+            }
+            router.close();
         });
         Ok(Pipo {
             tx: tx2,
@@ -392,64 +508,69 @@ impl Pipo {
         })
     }
     async fn send(&self, packet: Packet) -> Result<()> {
-        self.tx.send(packet).await.map_err(Error::other)
+        self.tx
+            .send(packet)
+            .await
+            .map_err(|_| Error::ConnectionClosed)
     }
     /*    async fn recv(&self) -> Option<Packet> {
         self.rx.lock().await.recv().await
     } */
-    async fn run(
-        mut con: TcpStream,
+    async fn run<S: AsyncRead + AsyncWrite + Unpin>(
+        mut con: S,
         router: Arc<Router>,
         mut rx: mpsc::Receiver<Packet>,
+        max_data_len: u32,
     ) -> Result<()> {
-        let mut state = PIPOState::AwaitHeader;
+        // Reading into a buffer, as opposed to `read_exact()`, is what makes
+        // this safe to use in `select!`: a partial frame stays in `buf` while
+        // we go off and write.
+        let mut buf = Vec::new();
+        let mut chunk = [0_u8; READ_CHUNK];
         loop {
-            match state {
-                PIPOState::AwaitHeader => {
-                    let mut header = [0_u8; HEADER_LEN];
-                    tokio::select! {
-                    // TODO: what happens to partial reads?
-                    ok = con.read_exact(&mut header) => {
-                        ok?;
-                        state = PIPOState::GotHeader(parse_header(&header)?);
-                    },
-                    p = rx.recv() => match p {
-                        Some(p) => con.write_all(&p.serialize()).await?,
-                        // TODO: continue reading even while write
-                        // blocks.
-                        None => return Ok(()),
-                    },
-                    };
-                }
-                PIPOState::GotHeader(ref header) => {
-                    if header.data_len > 0 {
-                        let mut payload = vec![0; header.data_len as usize];
-                        tokio::select! {
-                            ok = con.read_exact(&mut payload) => {
-                                ok?;
-                                let packet = Packet::parse(header, &payload)?;
-                                debug!("agw/pipo: Processing packet len {}", header.data_len);
-                                trace!("agw/pipo: Processing packet {packet:?}");
-                                router.process(packet).await?;
-                                state = PIPOState::AwaitHeader;
-                            },
-                            p = rx.recv() => match p {
-                                Some(p) => con.write_all(&p.serialize()).await?,
-                                // TODO: should we continue receiving
-                                // from con, still? Could deadlock?
-                                None => return Ok(()),
-                            },
-                        };
-                    } else {
-                        // Disconnect.
-                        let packet = Packet::parse(header, &[])?;
-                        debug!("agw/pipo: Processing (should be Disconnect) {packet:?}");
-                        router.process(packet).await?;
-                        state = PIPOState::AwaitHeader;
-                    }
-                }
+            while let Some((frame, n)) = Frame::parse_buf(&buf, max_data_len)? {
+                buf.drain(..n);
+                debug!("agw/pipo: Processing packet len {}", n - HEADER_LEN);
+                trace!("agw/pipo: Processing packet {:?}", frame.packet());
+                router.tap(Direction::Rx, &frame);
+                router.process(frame.into_packet()).await?;
             }
+            tokio::select! {
+                n = con.read(&mut chunk) => match n? {
+                    0 if buf.is_empty() => return Ok(()),
+                    0 => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                    n => buf.extend_from_slice(&chunk[..n]),
+                },
+                p = rx.recv() => match p {
+                    Some(p) => match Frame::from_packet(p) {
+                        Ok(frame) => {
+                            router.tap(Direction::Tx, &frame);
+                            con.write_all(&frame.to_bytes()).await?;
+                        }
+                        Err(e) => warn!("agw/pipo: Dropping unsendable packet: {e}"),
+                    },
+                    // TODO: continue reading even while write
+                    // blocks.
+                    None => return Ok(()),
+                },
+            };
+        }
+    }
+}
+
+/// Turn the peer closing the connection into `None`.
+fn closed_is_none<T>(packet: Result<T>) -> Result<Option<T>> {
+    match packet {
+        Ok(packet) => Ok(Some(packet)),
+        Err(Error::Io(e))
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset
+            ) =>
+        {
+            Ok(None)
         }
+        Err(e) => Err(e),
     }
 }
 
@@ -458,71 +579,105 @@ impl Pipo {
 /// This is intended for code that is implementing an AGW server rather than
 /// talking to one. It does not spawn background tasks or route packets to
 /// per-connection objects; it simply reads and writes `Packet` values on a
-/// single accepted TCP stream.
-pub struct AGWServer {
-    con: TcpStream,
+/// single accepted stream, usually TCP.
+pub struct AGWServer<S = TcpStream> {
+    con: S,
+    max_data_len: u32,
+    // Partially received frame.
+    buf: Vec<u8>,
+    tap: Option<Arc<dyn Tap>>,
 }
 
-impl AGWServer {
-    /// Wrap an accepted AGW TCP connection.
+impl<S: AsyncRead + AsyncWrite + Unpin> AGWServer<S> {
+    /// Wrap an accepted AGW connection.
     #[must_use]
-    pub fn new(con: TcpStream) -> Self {
-        Self { con }
+    pub fn new(con: S) -> Self {
+        Self::with_max_data_len(con, crate::DEFAULT_MAX_DATA_LEN)
     }
 
-    /// Return the peer socket address.
-    ///
-    /// # Errors
+    /// Wrap an accepted AGW connection, rejecting client frames with
+    /// payloads longer than `max_data_len`.
     ///
-    /// If the underlying socket query fails.
-    pub fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr> {
-        self.con.peer_addr()
+    /// Clients are not necessarily trusted, so this is the limit on how much
+    /// memory one frame can make the server allocate.
+    #[must_use]
+    pub fn with_max_data_len(con: S, max_data_len: u32) -> Self {
+        Self {
+            con,
+            max_data_len,
+            buf: Vec::new(),
+            tap: None,
+        }
     }
 
-    /// Return the local socket address.
+    /// Show every packet sent or received to `tap`, or stop doing so.
     ///
-    /// # Errors
-    ///
-    /// If the underlying socket query fails.
-    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
-        self.con.local_addr()
+    /// E.g. a `record::Recorder` to record the session.
+    pub fn set_tap(&mut self, tap: Option<Arc<dyn Tap>>) {
+        self.tap = tap;
     }
 
-    /// Borrow the underlying TCP stream.
+    /// Borrow the underlying stream.
     #[must_use]
-    pub fn get_ref(&self) -> &TcpStream {
+    pub fn get_ref(&self) -> &S {
         &self.con
     }
 
-    /// Mutably borrow the underlying TCP stream.
+    /// Mutably borrow the underlying stream.
     #[must_use]
-    pub fn get_mut(&mut self) -> &mut TcpStream {
+    pub fn get_mut(&mut self) -> &mut S {
         &mut self.con
     }
 
-    /// Consume the wrapper and return the underlying TCP stream.
+    /// Consume the wrapper and return the underlying stream.
     #[must_use]
-    pub fn into_inner(self) -> TcpStream {
+    pub fn into_inner(self) -> S {
         self.con
     }
 
     /// Receive the next AGW packet from the client.
     ///
+    /// This is cancel safe. If used in `select!` and another branch
+    /// completes first, any partially read frame is kept for the next call.
+    ///
     /// # Errors
     ///
     /// If the TCP stream fails or the AGW packet is malformed.
     pub async fn recv(&mut self) -> Result<Packet> {
-        let mut header = [0_u8; HEADER_LEN];
-        self.con.read_exact(&mut header).await?;
-        let header = parse_header(&header)?;
-        let payload = if header.data_len > 0 {
-            let mut payload = vec![0; header.data_len as usize];
-            self.con.read_exact(&mut payload).await?;
-            payload
-        } else {
-            Vec::new()
-        };
-        Packet::parse(&header, &payload)
+        Ok(self.recv_frame().await?.into_packet())
+    }
+
+    /// Receive the next AGW frame from the client, keeping the bytes it was
+    /// parsed from.
+    ///
+    /// Like `recv()`, this is cancel safe.
+    ///
+    /// # Errors
+    ///
+    /// If the TCP stream fails or the AGW packet is malformed.
+    pub async fn recv_frame(&mut self) -> Result<Frame> {
+        loop {
+            if let Some((frame, n)) = Frame::parse_buf(&self.buf, self.max_data_len)? {
+                self.buf.drain(..n);
+                if let Some(tap) = &self.tap {
+                    tap.frame(Direction::Rx, &frame);
+                }
+                return Ok(frame);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Read whatever is available into the buffer.
+    async fn fill(&mut self) -> Result<()> {
+        let mut chunk = [0_u8; READ_CHUNK];
+        match self.con.read(&mut chunk).await? {
+            0 => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            n => {
+                self.buf.extend_from_slice(&chunk[..n]);
+                Ok(())
+            }
+        }
     }
 
     /// Send an AGW packet to the client.
@@ -531,14 +686,59 @@ impl AGWServer {
     ///
     /// If the TCP stream fails.
     pub async fn send(&mut self, packet: &Packet) -> Result<()> {
-        self.con.write_all(&packet.serialize()).await?;
+        self.send_frame(&Frame::from_packet(packet.clone())?).await
+    }
+
+    /// Send a received AGW frame on to the client, byte for byte.
+    ///
+    /// # Errors
+    ///
+    /// If the TCP stream fails.
+    pub async fn send_frame(&mut self, frame: &Frame) -> Result<()> {
+        if let Some(tap) = &self.tap {
+            tap.frame(Direction::Tx, frame);
+        }
+        self.con.write_all(&frame.to_bytes()).await?;
         Ok(())
     }
 }
 
+impl AGWServer<TcpStream> {
+    /// Return the peer socket address.
+    ///
+    /// # Errors
+    ///
+    /// If the underlying socket query fails.
+    pub fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.con.peer_addr()
+    }
+
+    /// Return the local socket address.
+    ///
+    /// # Errors
+    ///
+    /// If the underlying socket query fails.
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.con.local_addr()
+    }
+}
+
 pub struct AGW {
     con: Pipo,
     router: Arc<Router>,
+    paclen: Mutex<HashMap<Port, usize>>,
+    wrapper: Mutex<Option<Arc<dyn Wrapper + Send + Sync>>>,
+    monitors: Mutex<Monitors>,
+}
+
+/// Whether monitoring should be, and is, on.
+#[derive(Default)]
+struct Monitors {
+    // Number of `Monitor`s, which need monitoring on.
+    count: usize,
+    // Whether monitoring was last turned on upstream. It's left on if
+    // turning it off fails, and then the next `Monitor` doesn't toggle it.
+    upstream: bool,
 }
 
 impl AGW {
@@ -548,11 +748,50 @@ impl AGW {
     ///
     /// If connection establishment fails.
     pub async fn new(addr: &str) -> Result<AGW> {
+        Self::with_max_data_len(addr, crate::DEFAULT_MAX_DATA_LEN).await
+    }
+
+    /// Connect to AGWPE, treating frames with payloads longer than
+    /// `max_data_len` as a protocol error.
+    ///
+    /// # Errors
+    ///
+    /// If connection establishment fails.
+    pub async fn with_max_data_len(addr: &str, max_data_len: u32) -> Result<AGW> {
+        Self::from_stream_with_max_data_len(TcpStream::connect(addr).await?, max_data_len)
+    }
+
+    /// Use an already connected stream to AGWPE.
+    ///
+    /// E.g. one end of `tokio::io::duplex()`, for tests.
+    ///
+    /// # Errors
+    ///
+    /// If called outside a tokio runtime.
+    pub fn from_stream<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        stream: S,
+    ) -> Result<AGW> {
+        Self::from_stream_with_max_data_len(stream, crate::DEFAULT_MAX_DATA_LEN)
+    }
+
+    /// Use an already connected stream to AGWPE, treating frames with
+    /// payloads longer than `max_data_len` as a protocol error.
+    ///
+    /// # Errors
+    ///
+    /// If called outside a tokio runtime.
+    pub fn from_stream_with_max_data_len<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        stream: S,
+        max_data_len: u32,
+    ) -> Result<AGW> {
         let router = Arc::new(Router::new());
         let r2 = router.clone();
         Ok(Self {
-            con: Pipo::new(TcpStream::connect(addr).await?, r2)?,
+            con: Pipo::new(stream, r2, max_data_len)?,
             router,
+            paclen: Mutex::new(HashMap::new()),
+            wrapper: Mutex::new(None),
+            monitors: Mutex::new(Monitors::default()),
         })
     }
     /// Send some data on connection.
@@ -561,9 +800,111 @@ impl AGW {
     ///
     /// Errors if the underlying connection fails.
     pub async fn send(&self, data: Packet) -> Result<()> {
+        data.validate()?;
         self.con.send(data).await
     }
 
+    /// Show every packet sent or received to `tap`, or stop doing so.
+    ///
+    /// E.g. a `pcap::Writer` to capture traffic.
+    pub fn set_tap(&self, tap: Option<Arc<dyn Tap>>) {
+        self.router.set_tap(tap);
+    }
+
+    /// Set the max payload of each frame sent on new connections on `port`.
+    ///
+    /// AGW servers don't report this, so set it to match the PACLEN the
+    /// TNC uses for the port. Defaults to `DATA_CHUNK_LEN`. Zero is treated
+    /// as one.
+    pub fn set_paclen(&self, port: Port, paclen: usize) {
+        self.paclen.lock().unwrap().insert(port, paclen.max(1));
+    }
+
+    /// Max payload of each frame sent on new connections on `port`.
+    #[must_use]
+    pub fn paclen(&self, port: Port) -> usize {
+        self.paclen
+            .lock()
+            .unwrap()
+            .get(&port)
+            .copied()
+            .unwrap_or(crate::DATA_CHUNK_LEN)
+    }
+
+    /// Wrap the payload of unproto frames sent with `unproto()`, and unwrap
+    /// UI frames received by a `Monitor`, or stop doing so.
+    ///
+    /// Connections have their own wrapper, set with
+    /// `Connection::set_wrapper()`.
+    pub fn set_wrapper(&self, wrapper: Option<Arc<dyn Wrapper + Send + Sync>>) {
+        *self.wrapper.lock().unwrap() = wrapper;
+    }
+
+    fn wrapper(&self) -> Option<Arc<dyn Wrapper + Send + Sync>> {
+        self.wrapper.lock().unwrap().clone()
+    }
+
+    /// Send unproto (UI) frame, wrapped if a wrapper is set.
+    ///
+    /// # Errors
+    ///
+    /// If the wrapper fails, the packet is invalid, or the underlying
+    /// connection fails.
+    pub async fn unproto(
+        &self,
+        port: Port,
+        pid: Pid,
+        src: &Call,
+        dst: &Call,
+        data: &[u8],
+    ) -> Result<()> {
+        let packet = Packet::Unproto {
+            port,
+            pid,
+            src: src.clone(),
+            dst: dst.clone(),
+            data: data.to_vec(),
+        };
+        let packet = match self.wrapper() {
+            Some(wrapper) => wrap_packet(wrapper.as_ref(), packet)?,
+            None => packet,
+        };
+        self.send(packet).await
+    }
+
+    /// Receive monitored frames, turning monitoring on while any `Monitor`
+    /// exists.
+    ///
+    /// Received unproto frames arrive as monitored UI frames. A `Monitor`
+    /// that doesn't keep up loses frames, rather than holding up
+    /// connections.
+    ///
+    /// # Errors
+    ///
+    /// If the underlying connection fails.
+    pub async fn monitor(&self) -> Result<Monitor<'_>> {
+        let (tx, rx) = mpsc::channel(MONITOR_QUEUE);
+        let rule_handle = self.router.add_lossy(RuleMatch::Monitor, tx);
+        // Created before sending, so that it's dropped on failure.
+        let monitor = Monitor {
+            agw: self,
+            _rule_handle: rule_handle,
+            rx,
+        };
+        let toggle = {
+            let mut monitors = self.monitors.lock().unwrap();
+            monitors.count += 1;
+            !std::mem::replace(&mut monitors.upstream, true)
+        };
+        if toggle {
+            if let Err(e) = self.send(Packet::ToggleMonitor).await {
+                self.monitors.lock().unwrap().upstream = false;
+                return Err(e);
+            }
+        }
+        Ok(monitor)
+    }
+
     /// Register callsign.
     ///
     /// The specs say that registering the callsign is mandatory.
@@ -617,12 +958,16 @@ impl AGW {
             rx,
             server_state,
             read_buf: vec![],
+            paclen: self.paclen(port),
+            wrapper: None,
             pending_write: None,
             pending_shutdown: None,
             disconnected: false,
         }
     }
 
+    /// Connect to `dst`, through the digipeaters in `via`, if any.
+    ///
     /// # Errors
     ///
     /// If the underlying connection fails.
@@ -632,7 +977,7 @@ impl AGW {
         pid: Pid,
         src: &Call,
         dst: &Call,
-        _via: &[Call],
+        via: &[Call],
     ) -> Result<Connection<'a>> {
         let (tx, mut rx) = mpsc::channel(1);
 
@@ -647,7 +992,7 @@ impl AGW {
         );
 
         // Also register to receive data.
-        let (txd, rxd) = mpsc::channel(10); // TODO: magic number.
+        let (txd, mut rxd) = mpsc::channel(10); // TODO: magic number.
         let rule_handle = self.router.add(
             RuleMatch::Data {
                 port,
@@ -658,23 +1003,49 @@ impl AGW {
         );
 
         // Send connection establish.
-        if let Err(e) = self
-            .send(Packet::Connect {
+        self.send(if via.is_empty() {
+            Packet::Connect {
                 port,
                 pid,
                 src: src.clone(),
                 dst: dst.clone(),
-            })
-            .await
-        {
-            return Err(Error::msg(format!("{e:?}")));
-        }
+            }
+        } else {
+            Packet::ConnectVia {
+                port,
+                pid,
+                src: src.clone(),
+                dst: dst.clone(),
+                via: via.to_vec(),
+            }
+        })
+        .await?;
 
-        // Wait for connection established.
-        let estab = tokio::time::timeout(CONNECTION_TIMEOUT, rx.recv())
-            .await
-            .map_err(Error::other)?
-            .ok_or(Error::msg("no packet"));
+        // Wait for connection established. A disconnect before that means the
+        // remote end refused, or never answered.
+        let estab = tokio::time::timeout(CONNECTION_TIMEOUT, async {
+            loop {
+                tokio::select! {
+                    // Data can follow right after the connection is
+                    // established, and must be left for the connection.
+                    biased;
+                    p = rx.recv() => break p.ok_or(Error::ConnectionClosed),
+                    p = rxd.recv() => match p {
+                        Some(Packet::Disconnect { .. }) => {
+                            break Err(Error::Rejected(format!(
+                                "{dst} did not accept connection from {src}"
+                            )));
+                        }
+                        Some(other) => {
+                            debug!("agw: Ignoring packet before connection established: {other:?}");
+                        }
+                        None => break Err(Error::ConnectionClosed),
+                    },
+                }
+            }
+        })
+        .await
+        .map_err(|_| Error::Timeout)?;
         drop(ident);
 
         let estab = estab?;
@@ -685,6 +1056,7 @@ impl AGW {
                 pid: _,
                 src: _,
                 dst: _,
+                text: _,
             } => {
                 trace!("agw: Connection established!");
                 Ok(self.make_connection(
@@ -697,8 +1069,50 @@ impl AGW {
                     None,
                 ))
             }
-            other => {
-                panic!("received unexpected packet: {other:?}")
+            other => Err(Error::msg(format!("received unexpected packet: {other:?}"))),
+        }
+    }
+}
+
+/// Receiver of monitored frames.
+///
+/// Created from an AGW object, using `.monitor()`. Monitoring is turned off
+/// when the last one is dropped.
+pub struct Monitor<'a> {
+    agw: &'a AGW,
+    _rule_handle: RuleHandle,
+    rx: mpsc::Receiver<Packet>,
+}
+
+impl Monitor<'_> {
+    /// Receive the next monitored frame.
+    ///
+    /// UI frames are unwrapped, if the AGW object has a wrapper.
+    ///
+    /// # Errors
+    ///
+    /// `Error::ConnectionClosed` if the AGW connection closes. If a UI frame
+    /// fails to unwrap, its error is returned; only that frame is lost, so
+    /// the caller can carry on receiving.
+    pub async fn recv(&mut self) -> Result<Packet> {
+        let packet = self.rx.recv().await.ok_or(Error::ConnectionClosed)?;
+        match self.agw.wrapper() {
+            Some(wrapper) => unwrap_packet(wrapper.as_ref(), packet),
+            None => Ok(packet),
+        }
+    }
+}
+
+impl Drop for Monitor<'_> {
+    fn drop(&mut self) {
+        let mut monitors = self.agw.monitors.lock().unwrap();
+        monitors.count -= 1;
+        if monitors.count == 0 && monitors.upstream {
+            // Can't wait here. If the queue is full, monitoring stays on,
+            // and the next `Monitor` uses it as is.
+            match self.agw.con.tx.try_send(Packet::ToggleMonitor) {
+                Ok(()) => monitors.upstream = false,
+                Err(e) => warn!("agw: Failed to turn monitoring off: {e}"),
             }
         }
     }
@@ -720,7 +1134,7 @@ impl<'a> Listener<'a> {
     ///
     /// If the underlying connection fails.
     pub async fn accept(&mut self) -> Result<Connection<'a>> {
-        let pending = self.rx.recv().await.ok_or(Error::msg("recv failed"))?;
+        let pending = self.rx.recv().await.ok_or(Error::ConnectionClosed)?;
         Ok(self.agw.make_connection(
             pending.port,
             pending.pid,
@@ -758,6 +1172,8 @@ pub struct Connection<'a> {
     rx: mpsc::Receiver<Packet>,
     server_state: Option<SharedServerConnectionState>,
     read_buf: Vec<u8>,
+    paclen: usize,
+    wrapper: Option<Arc<dyn Wrapper + Send + Sync>>,
     pending_write: Option<PendingWrite>,
     pending_shutdown: Option<PendingSend>,
 }
@@ -787,30 +1203,127 @@ impl Connection<'_> {
         self.pid
     }
 
+    /// Return the max payload of each frame sent.
+    #[must_use]
+    pub fn paclen(&self) -> usize {
+        self.paclen
+    }
+
+    /// Set the max payload of each frame sent.
+    ///
+    /// Defaults to what `AGW::set_paclen()` set for the port. If a wrapper
+    /// is set, this is the payload length after wrapping, so each frame
+    /// carries the wrapper's overhead less data. Zero is treated as one.
+    pub fn set_paclen(&mut self, paclen: usize) {
+        self.paclen = paclen.max(1);
+    }
+
+    /// Wrap the payload of every data frame sent, and unwrap every one
+    /// received, or stop doing so.
+    ///
+    /// E.g. a `wrap::HmacWrapper` to sign the data. Each frame is wrapped on
+    /// its own, so a frame that fails to unwrap only loses that frame.
+    pub fn set_wrapper(&mut self, wrapper: Option<Arc<dyn Wrapper + Send + Sync>>) {
+        self.wrapper = wrapper;
+    }
+
     /// Receive packet from connection.
     ///
     /// # Errors
     ///
-    /// Fails if the connection fails.
+    /// Fails if the connection fails, or if a data frame fails to unwrap.
+    /// The connection is still usable after an unwrap error.
     pub async fn recv(&mut self) -> Result<Packet> {
         let _ = &self.connect_string;
-        self.rx.recv().await.ok_or(Error::msg("recv failed"))
+        let packet = self.rx.recv().await.ok_or(Error::ConnectionClosed)?;
+        self.unwrap(packet)
     }
     /// Send data on connection.
     ///
     /// # Errors
     ///
-    /// Fails if the connection fails.
+    /// Fails if the connection fails, or the wrapper fails.
     pub async fn send(&mut self, data: &[u8]) -> Result<()> {
         if self.disconnected {
-            return Err(Error::msg("connection disconnected"));
+            return Err(Error::ConnectionClosed);
+        }
+        for packet in self.data_packet(data.to_vec()).split_data(self.chunk_len()) {
+            let packet = self.wrap(packet)?;
+            self.agw.send(packet.clone()).await?;
+            self.buffer_server_data(&packet);
         }
-        let packet = self.data_packet(data.to_vec());
-        self.agw.send(packet.clone()).await?;
-        self.buffer_server_data(&packet);
         Ok(())
     }
 
+    /// Send a message, for the other end to receive whole with
+    /// `recv_message()`.
+    ///
+    /// The message is sent as a 4 byte big endian length followed by the
+    /// message, split into as many frames as needed.
+    ///
+    /// # Errors
+    ///
+    /// If the message is longer than `MAX_MESSAGE_LEN`, or the connection
+    /// fails.
+    pub async fn send_message(&mut self, msg: &[u8]) -> Result<()> {
+        if msg.len() > MAX_MESSAGE_LEN {
+            return Err(Error::PayloadTooLarge {
+                kind: b'D',
+                len: msg.len(),
+                max: MAX_MESSAGE_LEN,
+            });
+        }
+        let len = u32::try_from(msg.len())?;
+        self.send(&[&len.to_be_bytes()[..], msg].concat()).await
+    }
+
+    /// Receive a message sent with `send_message()`.
+    ///
+    /// Reassembles the message from however many frames it arrived in.
+    /// Shares its buffer with `AsyncRead`, so the two can be mixed as long
+    /// as the other end agrees on where messages are.
+    ///
+    /// # Errors
+    ///
+    /// `Error::ConnectionClosed` if the connection is disconnected, even in
+    /// the middle of a message. If a frame fails to unwrap, its error is
+    /// returned and the partial message is dropped; since where the next
+    /// message starts is then unknown, the connection should be closed.
+    pub async fn recv_message(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(len) = self.read_buf.first_chunk::<4>() {
+                let len = u32::from_be_bytes(*len) as usize;
+                if len > MAX_MESSAGE_LEN {
+                    return Err(Error::bad_payload(
+                        b'D',
+                        format!("message too long: {len} > {MAX_MESSAGE_LEN}"),
+                    ));
+                }
+                if self.read_buf.len() >= 4 + len {
+                    let msg = self.read_buf[4..4 + len].to_vec();
+                    self.read_buf.drain(..4 + len);
+                    return Ok(msg);
+                }
+            }
+            if self.disconnected {
+                return Err(Error::ConnectionClosed);
+            }
+            match self.recv().await {
+                Ok(Packet::Data { data, .. }) => self.read_buf.extend(data),
+                Ok(Packet::Disconnect { .. }) | Err(Error::ConnectionClosed) => {
+                    self.disconnected = true;
+                }
+                Ok(other) => {
+                    debug!("agw: Ignoring non-data packet on connection: {other:?}");
+                }
+                Err(e) => {
+                    self.read_buf.clear();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
     fn data_packet(&self, data: Vec<u8>) -> Packet {
         Packet::Data {
             port: self.port,
@@ -821,12 +1334,33 @@ impl Connection<'_> {
         }
     }
 
+    /// Max data to put in each frame, leaving room for the wrapper.
+    fn chunk_len(&self) -> usize {
+        let overhead = self.wrapper.as_ref().map_or(0, |w| w.overhead());
+        self.paclen.saturating_sub(overhead).max(1)
+    }
+
+    fn wrap(&self, packet: Packet) -> Result<Packet> {
+        match &self.wrapper {
+            Some(wrapper) => wrap_packet(wrapper.as_ref(), packet),
+            None => Ok(packet),
+        }
+    }
+
+    fn unwrap(&self, packet: Packet) -> Result<Packet> {
+        match &self.wrapper {
+            Some(wrapper) => unwrap_packet(wrapper.as_ref(), packet),
+            None => Ok(packet),
+        }
+    }
+
     fn disconnect_packet(&self) -> Packet {
         Packet::Disconnect {
             port: self.port,
             pid: self.pid,
             src: self.src.clone(),
             dst: self.dst.clone(),
+            text: String::new(),
         }
     }
 
@@ -932,7 +1466,35 @@ impl AsyncRead for Connection<'_> {
         }
         loop {
             match Pin::new(&mut this.rx).poll_recv(cx) {
-                Poll::Ready(Some(Packet::Data { data, .. })) => {
+                Poll::Ready(Some(Packet::Data {
+                    src,
+                    dst,
+                    pid,
+                    data,
+                    ..
+                })) => {
+                    // Drop just the bad frame, and let the caller decide
+                    // whether to carry on reading.
+                    let data = match &this.wrapper {
+                        None => data,
+                        Some(wrapper) => match wrapper.unwrap_from(
+                            &Envelope {
+                                src: &src,
+                                dst: &dst,
+                                pid,
+                            },
+                            &data,
+                        ) {
+                            Ok(data) => data,
+                            Err(e) => {
+                                warn!("agw: Dropping data frame that failed to unwrap: {e}");
+                                return Poll::Ready(Err(std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    e,
+                                )));
+                            }
+                        },
+                    };
                     if data.is_empty() {
                         continue;
                     }
@@ -986,7 +1548,11 @@ impl AsyncWrite for Connection<'_> {
         if buf.is_empty() {
             return Poll::Ready(Ok(0));
         }
-        let packet = this.data_packet(buf.to_vec());
+        // Partial writes are fine, so just send what fits in one frame.
+        let buf = &buf[..buf.len().min(this.chunk_len())];
+        let packet = this
+            .wrap(this.data_packet(buf.to_vec()))
+            .map_err(std::io::Error::other)?;
         this.pending_write = Some(PendingWrite {
             len: buf.len(),
             packet: packet.clone(),
@@ -1020,3 +1586,334 @@ impl AsyncWrite for Connection<'_> {
         this.poll_pending_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrap::tests::Brackets;
+
+    #[tokio::test]
+    async fn server_rejects_huge_frame() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = AGWServer::with_max_data_len(server, 10);
+        let mut header = crate::Header::new(Port(0), b'D', Pid(0xF0), None, None, 11).to_bytes();
+        client.write_all(&header).await.unwrap();
+        assert!(matches!(
+            server.recv().await,
+            Err(Error::FrameTooLarge {
+                kind: b'D',
+                len: 11,
+                max: 10
+            })
+        ));
+
+        // Clients talking to a server are protected the same way, by
+        // dropping the connection.
+        let (client, mut server) = tokio::io::duplex(1024);
+        let agw = AGW::from_stream_with_max_data_len(client, 10).unwrap();
+        header[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+        server.write_all(&header).await.unwrap();
+        assert!(matches!(
+            agw.connect(
+                Port(0),
+                Pid(0xF0),
+                &"M0THC-1".parse().unwrap(),
+                &"M0THC-2".parse().unwrap(),
+                &[]
+            )
+            .await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn client_survives_unknown_kind() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = AGWServer::new(server);
+        let agw = AGW::from_stream(client).unwrap();
+        server
+            .send(&Packet::Unknown {
+                header: crate::Header::new(Port(0), b'Q', Pid(0), None, None, 0),
+                data: vec![1, 2, 3],
+            })
+            .await
+            .unwrap();
+        let con = connect(&agw, &mut server).await;
+        assert_eq!(con.dst(), &"M0THC-2".parse::<Call>().unwrap());
+    }
+
+    /// Connect M0THC-1 to M0THC-2, with `server` accepting.
+    async fn connect<'a>(
+        agw: &'a AGW,
+        server: &mut AGWServer<tokio::io::DuplexStream>,
+    ) -> Connection<'a> {
+        let me: Call = "M0THC-1".parse().unwrap();
+        let peer: Call = "M0THC-2".parse().unwrap();
+        let (con, ()) = tokio::join!(agw.connect(Port(0), Pid(0xF0), &me, &peer, &[]), async {
+            let Packet::Connect {
+                port,
+                pid,
+                src,
+                dst,
+            } = server.recv().await.unwrap()
+            else {
+                panic!("expected connect");
+            };
+            server
+                .send(&Packet::ConnectionEstablished {
+                    port,
+                    pid,
+                    src: dst,
+                    dst: src,
+                    text: String::new(),
+                })
+                .await
+                .unwrap();
+        });
+        con.unwrap()
+    }
+
+    #[tokio::test]
+    async fn data_with_nonzero_header_fields_is_delivered() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = AGWServer::new(server);
+        let agw = AGW::from_stream(client).unwrap();
+        let mut con = connect(&agw, &mut server).await;
+
+        let mut header = crate::Header::new(
+            Port(0),
+            b'D',
+            Pid(0xF0),
+            Some("M0THC-2".parse().unwrap()),
+            Some("M0THC-1".parse().unwrap()),
+            2,
+        );
+        header.user = 7;
+        header.reserved = [1, 0, 0, 0, 0];
+        let mut frame = header.to_bytes().to_vec();
+        frame.extend_from_slice(b"hi");
+        server.get_mut().write_all(&frame).await.unwrap();
+        let Packet::Data { data, .. } = con.recv().await.unwrap() else {
+            panic!("expected data");
+        };
+        assert_eq!(data, b"hi");
+    }
+
+    #[tokio::test]
+    async fn paclen_includes_wrapper_overhead() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = AGWServer::new(server);
+        let agw = AGW::from_stream(client).unwrap();
+        agw.set_paclen(Port(0), 5);
+        let mut con = connect(&agw, &mut server).await;
+        con.set_wrapper(Some(Arc::new(Brackets)));
+        con.send(b"abcdefg").await.unwrap();
+        for want in [&b"<abc>"[..], b"<def>", b"<g>"] {
+            let Packet::Data { data, .. } = server.recv().await.unwrap() else {
+                panic!("expected data");
+            };
+            assert_eq!(data, want);
+        }
+
+        // Writes are partial, to fit in a frame.
+        assert_eq!(con.write(b"hijk").await.unwrap(), 3);
+        let Packet::Data { data, .. } = server.recv().await.unwrap() else {
+            panic!("expected data");
+        };
+        assert_eq!(data, b"<hij>");
+    }
+
+    #[tokio::test]
+    async fn unproto_is_wrapped() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = AGWServer::new(server);
+        let agw = AGW::from_stream(client).unwrap();
+        let src: Call = "M0THC-1".parse().unwrap();
+        let dst: Call = "ID".parse().unwrap();
+        agw.unproto(Port(0), Pid(0xF0), &src, &dst, b"plain")
+            .await
+            .unwrap();
+        agw.set_wrapper(Some(Arc::new(Brackets)));
+        agw.unproto(Port(0), Pid(0xF0), &src, &dst, b"hi")
+            .await
+            .unwrap();
+        for want in [&b"plain"[..], b"<hi>"] {
+            let Packet::Unproto { data, .. } = server.recv().await.unwrap() else {
+                panic!("expected unproto");
+            };
+            assert_eq!(data, want);
+        }
+    }
+
+    #[tokio::test]
+    async fn monitor_unwraps_ui_frames() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = AGWServer::new(server);
+        let agw = AGW::from_stream(client).unwrap();
+        agw.set_wrapper(Some(Arc::new(Brackets)));
+        let mut monitor = agw.monitor().await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), Packet::ToggleMonitor);
+        // Only the first one turns monitoring on.
+        let second = agw.monitor().await.unwrap();
+
+        let head = &b" 1:Fm M0THC-2 To ID <UI pid=F0 Len=4 >[12:00:00]\r"[..];
+        let ui = |data: &[u8]| Packet::Monitor {
+            kind: crate::MonitorKind::Unproto,
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: "M0THC-2".parse().unwrap(),
+            dst: "ID".parse().unwrap(),
+            data: [head, data].concat(),
+        };
+        server.send(&ui(b"bad\r")).await.unwrap();
+        server.send(&ui(b"<hi>\r")).await.unwrap();
+        assert!(monitor.recv().await.is_err());
+        assert_eq!(monitor.recv().await.unwrap(), ui(b"hi"));
+
+        // The last one turns it off.
+        drop(second);
+        drop(monitor);
+        assert_eq!(server.recv().await.unwrap(), Packet::ToggleMonitor);
+    }
+
+    #[tokio::test]
+    async fn monitor_toggle_survives_full_queue() {
+        let (client, server) = tokio::io::duplex(HEADER_LEN);
+        let mut server = AGWServer::new(server);
+        let agw = AGW::from_stream(client).unwrap();
+        let monitor = agw.monitor().await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), Packet::ToggleMonitor);
+
+        // The server isn't reading, so the send queue fills up.
+        let mut queued = 0;
+        while agw.con.tx.try_send(Packet::VersionQuery).is_ok() {
+            queued += 1;
+        }
+        // Turning monitoring off fails, so it stays on.
+        drop(monitor);
+        for _ in 0..queued {
+            assert_eq!(server.recv().await.unwrap(), Packet::VersionQuery);
+        }
+
+        // And isn't turned off by the next `Monitor`.
+        let monitor = agw.monitor().await.unwrap();
+        agw.send(Packet::VersionQuery).await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), Packet::VersionQuery);
+        drop(monitor);
+        assert_eq!(server.recv().await.unwrap(), Packet::ToggleMonitor);
+    }
+
+    /// Send a data frame from M0THC-2 to M0THC-1.
+    async fn send_data(server: &mut AGWServer<tokio::io::DuplexStream>, data: &[u8]) {
+        server
+            .send(&Packet::Data {
+                port: Port(0),
+                pid: Pid(0xF0),
+                src: "M0THC-2".parse().unwrap(),
+                dst: "M0THC-1".parse().unwrap(),
+                data: data.to_vec(),
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn paclen_splits_data() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = AGWServer::new(server);
+        let agw = AGW::from_stream(client).unwrap();
+        assert_eq!(agw.paclen(Port(0)), crate::DATA_CHUNK_LEN);
+        agw.set_paclen(Port(1), 0);
+        assert_eq!(agw.paclen(Port(1)), 1);
+        agw.set_paclen(Port(0), 4);
+        let mut con = connect(&agw, &mut server).await;
+        assert_eq!(con.paclen(), 4);
+        con.send(b"abcdefghij").await.unwrap();
+        for want in [&b"abcd"[..], b"efgh", b"ij"] {
+            let Packet::Data { data, .. } = server.recv().await.unwrap() else {
+                panic!("expected data");
+            };
+            assert_eq!(data, want);
+        }
+        con.set_paclen(0);
+        assert_eq!(con.paclen(), 1);
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = AGWServer::new(server);
+        let agw = AGW::from_stream(client).unwrap();
+        agw.set_paclen(Port(0), 4);
+        let mut con = connect(&agw, &mut server).await;
+        con.send_message(b"hello world").await.unwrap();
+        let mut sent = Vec::new();
+        while sent.len() < 4 + 11 {
+            let Packet::Data { data, .. } = server.recv().await.unwrap() else {
+                panic!("expected data");
+            };
+            assert!(data.len() <= 4);
+            sent.extend(data);
+        }
+        assert_eq!(sent, b"\0\0\0\x0bhello world");
+
+        // Messages split and joined any which way.
+        send_data(&mut server, b"\0\0").await;
+        send_data(&mut server, b"\0\x05hel").await;
+        send_data(&mut server, b"lo\0\0\0\0\0\0\0\x03b").await;
+        send_data(&mut server, b"ye").await;
+        assert_eq!(con.recv_message().await.unwrap(), b"hello");
+        assert_eq!(con.recv_message().await.unwrap(), b"");
+        assert_eq!(con.recv_message().await.unwrap(), b"bye");
+    }
+
+    #[tokio::test]
+    async fn message_errors() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = AGWServer::new(server);
+        let agw = AGW::from_stream(client).unwrap();
+        let mut con = connect(&agw, &mut server).await;
+        assert!(matches!(
+            con.send_message(&vec![0; MAX_MESSAGE_LEN + 1]).await,
+            Err(Error::PayloadTooLarge { .. })
+        ));
+
+        // A frame failing to unwrap drops the partial message.
+        con.set_wrapper(Some(Arc::new(Brackets)));
+        send_data(&mut server, b"<\0\0\0\x05he>").await;
+        send_data(&mut server, b"llo").await;
+        assert!(con.recv_message().await.is_err());
+        con.set_wrapper(None);
+
+        let too_long = u32::try_from(MAX_MESSAGE_LEN + 1).unwrap().to_be_bytes();
+        send_data(&mut server, &too_long).await;
+        assert!(matches!(
+            con.recv_message().await,
+            Err(Error::BadPayload { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn message_cut_by_disconnect() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = AGWServer::new(server);
+        let agw = AGW::from_stream(client).unwrap();
+        let mut con = connect(&agw, &mut server).await;
+        send_data(&mut server, b"\0\0\0\x05he").await;
+        server
+            .send(&Packet::Disconnect {
+                port: Port(0),
+                pid: Pid(0xF0),
+                src: "M0THC-2".parse().unwrap(),
+                dst: "M0THC-1".parse().unwrap(),
+                text: String::new(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            con.recv_message().await,
+            Err(Error::ConnectionClosed)
+        ));
+        assert!(matches!(con.send(b"x").await, Err(Error::ConnectionClosed)));
+    }
+}
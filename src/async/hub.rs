@@ -0,0 +1,985 @@
+//! Multiplexing AGW hub.
+//!
+//! Many AGW clients share one upstream AGW connection. Useful with e.g.
+//! Direwolf, which copes poorly with many applications connected at once.
+//!
+//! * Connected mode traffic goes to the client that owns the local callsign.
+//!   A client owns a callsign by registering it, or by connecting from it.
+//!   Only one client can own a callsign at a time.
+//! * Clients can only send data on, and disconnect, their own connections.
+//! * Monitored and raw frames go to every client that turned them on.
+//! * Replies to queries go to the clients that asked. Queries for the same
+//!   kind and port already waiting for a reply aren't sent upstream again,
+//!   but share the reply.
+//! * A client that doesn't keep up loses packets, rather than holding up
+//!   everyone else.
+//! * When a client goes away, its connections are disconnected and its
+//!   callsigns unregistered upstream.
+//!
+//! ```no_run
+//! # async fn f() -> agw::Result<()> {
+//! let hub = agw::r#async::hub::Hub::new("127.0.0.1:8000").await?;
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:8010").await?;
+//! hub.serve(listener).await
+//! # }
+//! ```
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use super::{closed_is_none, AGWServer, RuleHandle, RuleMatch, AGW};
+use crate::ratelimit::{ClientLimiter, Held, RateLimiter};
+use crate::{Call, Error, Packet, Pid, Port, Result};
+
+const CLIENT_QUEUE: usize = 100;
+
+/// Kinds of query replies, which go to whoever asked. A reply has the same
+/// kind as its query.
+const QUERY_KINDS: &[u8] = b"RGgyH";
+
+/// How long to wait for upstream to reply to a query, before asking again.
+///
+/// Not every server answers every query. E.g. Direwolf ignores 'H'.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Kind of `RegisterCallsignReply`, which goes to the owner of the callsign.
+const REGISTER_KIND: u8 = b'X';
+
+type ClientId = u64;
+
+/// Connection, as (port, local, remote).
+type ConnKey = (Port, Call, Call);
+
+/// Which query a query or reply is about: its kind, and port.
+///
+/// Version and port info aren't per port, so they're always `Port(0)`.
+fn query_key(packet: &Packet) -> Option<(u8, Port)> {
+    let port = match packet {
+        Packet::VersionQuery
+        | Packet::VersionReply { .. }
+        | Packet::PortInfoQuery
+        | Packet::PortInfoReply(_) => Port(0),
+        Packet::PortCapQuery(port)
+        | Packet::PortCapReply { port, .. }
+        | Packet::FramesOutstandingPortQuery(port)
+        | Packet::FramesOutstandingPortReply(port, _)
+        | Packet::CallsignHeardQuery(port)
+        | Packet::CallsignHeardReply { port, .. } => *port,
+        _ => return None,
+    };
+    Some((packet.kind(), port))
+}
+
+/// Clients waiting for the reply to a query.
+struct Pending {
+    sent: Instant,
+    waiters: Vec<(ClientId, mpsc::Sender<Packet>)>,
+}
+
+/// Send to a client, dropping the packet if it doesn't keep up.
+fn deliver(tx: &mpsc::Sender<Packet>, packet: Packet) {
+    // If the client is gone, that's fine.
+    if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(packet) {
+        warn!("agw/hub: Dropping packet for client: queue full");
+    }
+}
+
+struct Owner {
+    client: ClientId,
+    tx: mpsc::Sender<Packet>,
+    _rule: RuleHandle,
+}
+
+#[derive(Default)]
+struct State {
+    next_client: ClientId,
+    owners: HashMap<(Port, Call), Owner>,
+    // Clients waiting for a reply, by kind and port.
+    pending: HashMap<(u8, Port), Pending>,
+    monitors: usize,
+    raws: usize,
+}
+
+/// Per client state.
+struct Client {
+    id: ClientId,
+    tx: mpsc::Sender<Packet>,
+    monitor: Option<RuleHandle>,
+    raw: Option<RuleHandle>,
+    limiter: Option<ClientLimiter>,
+    // Packets waiting for transmit budget, and when to check again.
+    held: Held<()>,
+    retry: Option<Duration>,
+    // Callsigns registered upstream, to unregister when the client leaves.
+    registered: HashSet<(Port, Call)>,
+    // Open connections, to disconnect when the client leaves.
+    connections: HashMap<ConnKey, Pid>,
+}
+
+impl Client {
+    /// Whether the client has the connection open, or is opening it.
+    fn has_connection(&self, port: Port, src: &Call, dst: &Call) -> bool {
+        self.connections
+            .contains_key(&(port, src.clone(), dst.clone()))
+    }
+
+    /// Keep track of connections and registrations, from what upstream
+    /// sends the client.
+    fn observe(&mut self, packet: &Packet) {
+        match packet {
+            Packet::IncomingConnect {
+                port,
+                pid,
+                src,
+                dst,
+                ..
+            } => {
+                self.connections
+                    .insert((*port, dst.clone(), src.clone()), *pid);
+            }
+            Packet::Disconnect { port, src, dst, .. } => {
+                self.connections.remove(&(*port, dst.clone(), src.clone()));
+            }
+            Packet::RegisterCallsignReply {
+                port,
+                call,
+                success: false,
+            } => {
+                self.registered.remove(&(*port, call.clone()));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// AGW hub, sharing one upstream between many clients.
+pub struct Hub {
+    agw: AGW,
+    state: Mutex<State>,
+    closed: watch::Sender<bool>,
+    limiter: Mutex<Option<Arc<RateLimiter>>>,
+    _rules: Vec<RuleHandle>,
+}
+
+impl Hub {
+    /// Connect to upstream AGW endpoint.
+    ///
+    /// # Errors
+    ///
+    /// If connection establishment fails.
+    pub async fn new(addr: &str) -> Result<Arc<Hub>> {
+        Ok(Self::with_agw(AGW::new(addr).await?))
+    }
+
+    /// Create hub on an existing upstream connection.
+    ///
+    /// The connection should not be used for anything else, and monitoring
+    /// and raw mode should be off.
+    #[must_use]
+    pub fn with_agw(agw: AGW) -> Arc<Hub> {
+        let (tx, rx) = mpsc::channel(CLIENT_QUEUE);
+        let rules = QUERY_KINDS
+            .iter()
+            .chain(&[REGISTER_KIND])
+            .map(|&kind| agw.router.add(RuleMatch::Kind(kind), tx.clone()))
+            .collect();
+        let hub = Arc::new(Hub {
+            agw,
+            state: Mutex::new(State::default()),
+            closed: watch::Sender::new(false),
+            limiter: Mutex::new(None),
+            _rules: rules,
+        });
+        tokio::spawn(Self::replies(Arc::downgrade(&hub), rx));
+        hub
+    }
+
+    /// Limit what each client may transmit, or stop doing so.
+    ///
+    /// Applies to clients connecting after this call.
+    pub fn set_rate_limiter(&self, limiter: Option<Arc<RateLimiter>>) {
+        *self.limiter.lock().unwrap() = limiter;
+    }
+
+    /// Route replies from upstream, until upstream closes.
+    async fn replies(hub: Weak<Hub>, mut rx: mpsc::Receiver<Packet>) {
+        while let Some(packet) = rx.recv().await {
+            let Some(hub) = hub.upgrade() else {
+                return;
+            };
+            if let Some(limiter) = &*hub.limiter.lock().unwrap() {
+                limiter.observe(&packet);
+            }
+            let txs: Vec<mpsc::Sender<Packet>> = {
+                let mut state = hub.state.lock().unwrap();
+                match &packet {
+                    Packet::RegisterCallsignReply {
+                        port,
+                        call,
+                        success,
+                    } => {
+                        let key = (*port, call.clone());
+                        let tx = state.owners.get(&key).map(|o| o.tx.clone());
+                        if !success {
+                            state.owners.remove(&key);
+                        }
+                        tx.into_iter().collect()
+                    }
+                    _ => query_key(&packet)
+                        .and_then(|key| state.pending.remove(&key))
+                        .map(|p| p.waiters.into_iter().map(|(_, tx)| tx).collect())
+                        .unwrap_or_default(),
+                }
+            };
+            if txs.is_empty() {
+                debug!("agw/hub: Nobody waiting for {packet:?}");
+            }
+            for tx in &txs {
+                deliver(tx, packet.clone());
+            }
+        }
+        info!("agw/hub: Upstream closed");
+        if let Some(hub) = hub.upgrade() {
+            hub.closed.send_replace(true);
+        }
+    }
+
+    /// Accept clients until upstream closes, handling each in its own task.
+    ///
+    /// # Errors
+    ///
+    /// If accepting fails, or upstream closes.
+    pub async fn serve(self: &Arc<Self>, listener: TcpListener) -> Result<()> {
+        let mut closed = self.closed.subscribe();
+        loop {
+            let (stream, addr) = tokio::select! {
+                conn = listener.accept() => conn?,
+                () = async { drop(closed.wait_for(|&closed| closed).await) } => {
+                    return Err(Error::ConnectionClosed);
+                },
+            };
+            info!("agw/hub: Client connected from {addr}");
+            let hub = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = hub.handle(AGWServer::new(stream)).await {
+                    warn!("agw/hub: Client {addr} failed: {e}");
+                }
+                info!("agw/hub: Client {addr} disconnected");
+            });
+        }
+    }
+
+    /// Handle one client until it disconnects, or upstream closes.
+    ///
+    /// # Errors
+    ///
+    /// If the client or upstream connection fails.
+    pub async fn handle(&self, con: AGWServer) -> Result<()> {
+        let peer = con
+            .peer_addr()
+            .map_or_else(|_| "unknown".to_string(), |a| a.to_string());
+        self.handle_stream(con, peer).await
+    }
+
+    /// Handle one client, with `peer` naming it for rate limiting.
+    async fn handle_stream<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut con: AGWServer<S>,
+        peer: String,
+    ) -> Result<()> {
+        let (tx, mut rx) = mpsc::channel(CLIENT_QUEUE);
+        let mut client = Client {
+            id: {
+                let mut state = self.state.lock().unwrap();
+                state.next_client += 1;
+                state.next_client
+            },
+            tx,
+            monitor: None,
+            raw: None,
+            limiter: self
+                .limiter
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|limiter| limiter.client(peer)),
+            held: Held::new(),
+            retry: None,
+            registered: HashSet::new(),
+            connections: HashMap::new(),
+        };
+        let ret = self.client_loop(&mut client, &mut con, &mut rx).await;
+        self.remove(&mut client).await;
+        ret
+    }
+
+    async fn client_loop<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        client: &mut Client,
+        con: &mut AGWServer<S>,
+        rx: &mut mpsc::Receiver<Packet>,
+    ) -> Result<()> {
+        let mut closed = self.closed.subscribe();
+        loop {
+            tokio::select! {
+                packet = con.recv() => {
+                    let Some(packet) = closed_is_none(packet)? else {
+                        return Ok(());
+                    };
+                    self.client_packet(client, con, packet).await?;
+                },
+                // Never `None`, since `client` holds a sender.
+                Some(packet) = rx.recv() => {
+                    client.observe(&packet);
+                    con.send(&packet).await?;
+                },
+                () = tokio::time::sleep(client.retry.unwrap_or_default()), if client.retry.is_some() => {
+                    self.release(client).await?;
+                },
+                // Upstream closed, or the hub is gone.
+                () = async { drop(closed.wait_for(|&closed| closed).await) } => {
+                    return Err(Error::ConnectionClosed);
+                },
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    async fn client_packet<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        client: &mut Client,
+        con: &mut AGWServer<S>,
+        packet: Packet,
+    ) -> Result<()> {
+        debug!("agw/hub: Client {} sent {packet:?}", client.id);
+        if let Some(key) = query_key(&packet) {
+            if !self.ask(client, key) {
+                debug!("agw/hub: Already waiting for reply to {packet:?}");
+                return Ok(());
+            }
+        }
+        match packet {
+            Packet::RegisterCallsign(port, ref call) if !self.claim(client, port, call) => {
+                info!("agw/hub: {call} is already registered by another client");
+                return con
+                    .send(&Packet::RegisterCallsignReply {
+                        port,
+                        call: call.clone(),
+                        success: false,
+                    })
+                    .await;
+            }
+            Packet::Connect {
+                port,
+                pid,
+                ref src,
+                ref dst,
+            }
+            | Packet::ConnectVia {
+                port,
+                pid,
+                ref src,
+                ref dst,
+                ..
+            } if !self.claim(client, port, src) => {
+                info!("agw/hub: {src} belongs to another client");
+                return con
+                    .send(&Packet::Disconnect {
+                        port,
+                        pid,
+                        src: dst.clone(),
+                        dst: src.clone(),
+                        text: String::new(),
+                    })
+                    .await;
+            }
+            Packet::Data {
+                port,
+                ref src,
+                ref dst,
+                ..
+            } if !client.has_connection(port, src, dst) => {
+                info!(
+                    "agw/hub: Client {} sent data on unknown connection {src}->{dst}",
+                    client.id
+                );
+                return Ok(());
+            }
+            Packet::Disconnect {
+                port,
+                pid,
+                ref src,
+                ref dst,
+                ..
+            } if !client.has_connection(port, src, dst) => {
+                info!(
+                    "agw/hub: Client {} disconnected unknown connection {src}->{dst}",
+                    client.id
+                );
+                return con
+                    .send(&Packet::Disconnect {
+                        port,
+                        pid,
+                        src: dst.clone(),
+                        dst: src.clone(),
+                        text: String::new(),
+                    })
+                    .await;
+            }
+            Packet::ToggleMonitor => {
+                return self
+                    .toggle(&mut client.monitor, RuleMatch::Monitor, &client.tx, |s| {
+                        &mut s.monitors
+                    })
+                    .await;
+            }
+            Packet::ToggleRaw => {
+                return self
+                    .toggle(&mut client.raw, RuleMatch::RawFrame, &client.tx, |s| {
+                        &mut s.raws
+                    })
+                    .await;
+            }
+            _ => {}
+        }
+        if !self.track(client, &packet) {
+            return Ok(());
+        }
+        if let Some(limiter) = &client.limiter {
+            client.held.push(limiter, packet, ());
+            return self.release(client).await;
+        }
+        self.agw.send(packet).await
+    }
+
+    /// Send upstream the held packets that the client has budget for.
+    ///
+    /// Held packets don't hold up the client's receive side.
+    async fn release(&self, client: &mut Client) -> Result<()> {
+        let Some(limiter) = &client.limiter else {
+            return Ok(());
+        };
+        let (ready, retry) = client.held.release(limiter);
+        client.retry = retry;
+        for (packet, ()) in ready {
+            self.agw.send(packet).await?;
+        }
+        Ok(())
+    }
+
+    /// Keep track of what the client registers and connects, returning
+    /// whether to send the packet upstream.
+    fn track(&self, client: &mut Client, packet: &Packet) -> bool {
+        match packet {
+            Packet::RegisterCallsign(port, call) => {
+                client.registered.insert((*port, call.clone()));
+            }
+            Packet::UnregisterCallsign(port, call) => {
+                if !client.registered.remove(&(*port, call.clone())) {
+                    info!("agw/hub: {call} is not registered by this client");
+                    return false;
+                }
+                self.state
+                    .lock()
+                    .unwrap()
+                    .owners
+                    .remove(&(*port, call.clone()));
+            }
+            Packet::Connect {
+                port,
+                pid,
+                src,
+                dst,
+            }
+            | Packet::ConnectVia {
+                port,
+                pid,
+                src,
+                dst,
+                ..
+            } => {
+                client
+                    .connections
+                    .insert((*port, src.clone(), dst.clone()), *pid);
+            }
+            Packet::Disconnect { port, src, dst, .. } => {
+                client
+                    .connections
+                    .remove(&(*port, src.clone(), dst.clone()));
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Wait for the reply to a query, returning whether to send the query
+    /// upstream.
+    ///
+    /// It's not sent if the same query is already waiting for a reply,
+    /// unless that's been waiting for too long.
+    fn ask(&self, client: &Client, key: (u8, Port)) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let pending = state.pending.entry(key).or_insert_with(|| Pending {
+            sent: now,
+            waiters: Vec::new(),
+        });
+        let send = pending.waiters.is_empty() || now - pending.sent >= QUERY_TIMEOUT;
+        if send {
+            pending.sent = now;
+        }
+        if !pending.waiters.iter().any(|(id, _)| *id == client.id) {
+            pending.waiters.push((client.id, client.tx.clone()));
+        }
+        send
+    }
+
+    /// Make `client` owner of the callsign, unless someone else is.
+    fn claim(&self, client: &Client, port: Port, call: &Call) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.owners.entry((port, call.clone())) {
+            Entry::Occupied(o) => o.get().client == client.id,
+            Entry::Vacant(v) => {
+                let rule = self.agw.router.add_lossy(
+                    RuleMatch::Callsign {
+                        port,
+                        call: call.clone(),
+                    },
+                    client.tx.clone(),
+                );
+                v.insert(Owner {
+                    client: client.id,
+                    tx: client.tx.clone(),
+                    _rule: rule,
+                });
+                true
+            }
+        }
+    }
+
+    /// Turn monitoring or raw mode on or off for a client.
+    ///
+    /// Upstream has it on as long as any client does.
+    async fn toggle(
+        &self,
+        rule: &mut Option<RuleHandle>,
+        m: RuleMatch,
+        tx: &mpsc::Sender<Packet>,
+        users: fn(&mut State) -> &mut usize,
+    ) -> Result<()> {
+        let upstream = match m {
+            RuleMatch::RawFrame => Packet::ToggleRaw,
+            _ => Packet::ToggleMonitor,
+        };
+        let changed = {
+            let mut state = self.state.lock().unwrap();
+            let users = users(&mut state);
+            if rule.take().is_some() {
+                *users -= 1;
+                *users == 0
+            } else {
+                *rule = Some(self.agw.router.add_lossy(m, tx.clone()));
+                *users += 1;
+                *users == 1
+            }
+        };
+        if changed {
+            self.agw.send(upstream).await?;
+        }
+        Ok(())
+    }
+
+    /// Release everything the client held.
+    ///
+    /// Open connections are disconnected, and registered callsigns
+    /// unregistered, upstream.
+    async fn remove(&self, client: &mut Client) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.owners.retain(|_, o| o.client != client.id);
+            for pending in state.pending.values_mut() {
+                pending.waiters.retain(|(id, _)| *id != client.id);
+            }
+        }
+        for ((port, src, dst), pid) in client.connections.drain() {
+            info!("agw/hub: Disconnecting {src} from {dst} for departed client");
+            let _ = self
+                .agw
+                .send(Packet::Disconnect {
+                    port,
+                    pid,
+                    src,
+                    dst,
+                    text: String::new(),
+                })
+                .await;
+        }
+        for (port, call) in client.registered.drain() {
+            let _ = self.agw.send(Packet::UnregisterCallsign(port, call)).await;
+        }
+        if client.monitor.is_some() {
+            let tx = client.tx.clone();
+            let _ = self
+                .toggle(&mut client.monitor, RuleMatch::Monitor, &tx, |s| {
+                    &mut s.monitors
+                })
+                .await;
+        }
+        if client.raw.is_some() {
+            let tx = client.tx.clone();
+            let _ = self
+                .toggle(&mut client.raw, RuleMatch::RawFrame, &tx, |s| &mut s.raws)
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MonitorKind;
+    use tokio::io::DuplexStream;
+
+    type Peer = AGWServer<DuplexStream>;
+
+    /// Hub, and the fake upstream AGW server it talks to.
+    fn start() -> (Arc<Hub>, Peer) {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let hub = Hub::with_agw(AGW::from_stream(client).unwrap());
+        (hub, AGWServer::new(server))
+    }
+
+    /// Connect a client to the hub, with `buf` bytes of buffering.
+    fn client(hub: &Arc<Hub>, buf: usize) -> Peer {
+        let (client, server) = tokio::io::duplex(buf);
+        let hub = hub.clone();
+        tokio::spawn(async move {
+            hub.handle_stream(AGWServer::new(server), "test".to_string())
+                .await
+        });
+        AGWServer::new(client)
+    }
+
+    fn call(s: &str) -> Call {
+        s.parse().unwrap()
+    }
+
+    /// Make sure the hub has handled everything `client` sent before.
+    async fn sync(client: &mut Peer, upstream: &mut Peer) {
+        client.send(&Packet::VersionQuery).await.unwrap();
+        assert_eq!(upstream.recv().await.unwrap(), Packet::VersionQuery);
+        let reply = Packet::VersionReply {
+            major: 2000,
+            minor: 1,
+        };
+        upstream.send(&reply).await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), reply);
+    }
+
+    #[tokio::test]
+    async fn slow_client_doesnt_block_others() {
+        let (hub, mut upstream) = start();
+        let mut slow = client(&hub, 64);
+        let mut fast = client(&hub, 1 << 16);
+        slow.send(&Packet::ToggleMonitor).await.unwrap();
+        assert_eq!(upstream.recv().await.unwrap(), Packet::ToggleMonitor);
+        fast.send(&Packet::ToggleMonitor).await.unwrap();
+        sync(&mut fast, &mut upstream).await;
+
+        // The slow client never reads, so its queue fills up.
+        for n in 0..(3 * CLIENT_QUEUE) {
+            let packet = Packet::Monitor {
+                kind: MonitorKind::Unproto,
+                port: Port(0),
+                pid: Pid(0xF0),
+                src: call("M0THC-1"),
+                dst: call("APZ001"),
+                data: n.to_string().into_bytes(),
+            };
+            upstream.send(&packet).await.unwrap();
+            let got = tokio::time::timeout(Duration::from_secs(5), fast.recv())
+                .await
+                .expect("blocked by slow client")
+                .unwrap();
+            assert_eq!(got, packet);
+        }
+        drop(slow);
+    }
+
+    #[tokio::test]
+    async fn replies_go_by_kind_and_port() {
+        let (hub, mut upstream) = start();
+        let mut a = client(&hub, 1 << 16);
+        let mut b = client(&hub, 1 << 16);
+
+        // Upstream never answers this one.
+        a.send(&Packet::CallsignHeardQuery(Port(0))).await.unwrap();
+        assert_eq!(
+            upstream.recv().await.unwrap(),
+            Packet::CallsignHeardQuery(Port(0))
+        );
+
+        a.send(&Packet::FramesOutstandingPortQuery(Port(0)))
+            .await
+            .unwrap();
+        b.send(&Packet::FramesOutstandingPortQuery(Port(1)))
+            .await
+            .unwrap();
+        assert_eq!(
+            upstream.recv().await.unwrap(),
+            Packet::FramesOutstandingPortQuery(Port(0))
+        );
+        assert_eq!(
+            upstream.recv().await.unwrap(),
+            Packet::FramesOutstandingPortQuery(Port(1))
+        );
+
+        // Answered out of order.
+        let reply_b = Packet::FramesOutstandingPortReply(Port(1), 2);
+        let reply_a = Packet::FramesOutstandingPortReply(Port(0), 1);
+        upstream.send(&reply_b).await.unwrap();
+        upstream.send(&reply_a).await.unwrap();
+        assert_eq!(b.recv().await.unwrap(), reply_b);
+        assert_eq!(a.recv().await.unwrap(), reply_a);
+    }
+
+    #[tokio::test]
+    async fn same_query_shares_reply() {
+        let (hub, mut upstream) = start();
+        let mut a = client(&hub, 1 << 16);
+        let mut b = client(&hub, 1 << 16);
+        a.send(&Packet::PortInfoQuery).await.unwrap();
+        assert_eq!(upstream.recv().await.unwrap(), Packet::PortInfoQuery);
+        b.send(&Packet::PortInfoQuery).await.unwrap();
+        // Still only sent once, by the time this comes through.
+        sync(&mut b, &mut upstream).await;
+
+        let reply = Packet::PortInfoReply(crate::v1::PortsInfo {
+            count: 0,
+            ports: Vec::new(),
+        });
+        upstream.send(&reply).await.unwrap();
+        assert_eq!(a.recv().await.unwrap(), reply);
+        assert_eq!(b.recv().await.unwrap(), reply);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_query_is_sent_again() {
+        let (hub, mut upstream) = start();
+        let mut a = client(&hub, 1 << 16);
+        let mut b = client(&hub, 1 << 16);
+        let query = Packet::CallsignHeardQuery(Port(0));
+        a.send(&query).await.unwrap();
+        assert_eq!(upstream.recv().await.unwrap(), query);
+
+        tokio::time::advance(QUERY_TIMEOUT).await;
+        b.send(&query).await.unwrap();
+        assert_eq!(upstream.recv().await.unwrap(), query);
+
+        let reply = Packet::CallsignHeardReply {
+            port: Port(0),
+            data: vec![0],
+        };
+        upstream.send(&reply).await.unwrap();
+        assert_eq!(a.recv().await.unwrap(), reply);
+        assert_eq!(b.recv().await.unwrap(), reply);
+    }
+
+    #[tokio::test]
+    async fn departing_client_is_cleaned_up() {
+        let (hub, mut upstream) = start();
+        let me = call("M0THC-1");
+        let peer = call("M0THC-2");
+        let mut a = client(&hub, 1 << 16);
+        a.send(&Packet::RegisterCallsign(Port(0), me.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            upstream.recv().await.unwrap(),
+            Packet::RegisterCallsign(Port(0), me.clone())
+        );
+        let connect = Packet::Connect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: me.clone(),
+            dst: peer.clone(),
+        };
+        a.send(&connect).await.unwrap();
+        assert_eq!(upstream.recv().await.unwrap(), connect);
+        let established = Packet::ConnectionEstablished {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: peer.clone(),
+            dst: me.clone(),
+            text: String::new(),
+        };
+        upstream.send(&established).await.unwrap();
+        assert!(matches!(
+            a.recv().await.unwrap(),
+            Packet::ConnectionEstablished { .. }
+        ));
+
+        drop(a);
+        assert_eq!(
+            upstream.recv().await.unwrap(),
+            Packet::Disconnect {
+                port: Port(0),
+                pid: Pid(0xF0),
+                src: me.clone(),
+                dst: peer,
+                text: String::new(),
+            }
+        );
+        assert_eq!(
+            upstream.recv().await.unwrap(),
+            Packet::UnregisterCallsign(Port(0), me.clone())
+        );
+
+        // The callsign is free for others.
+        let mut b = client(&hub, 1 << 16);
+        b.send(&Packet::RegisterCallsign(Port(0), me.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            upstream.recv().await.unwrap(),
+            Packet::RegisterCallsign(Port(0), me)
+        );
+    }
+
+    #[tokio::test]
+    async fn cant_unregister_others_callsign() {
+        let (hub, mut upstream) = start();
+        let me = call("M0THC-1");
+        let mut a = client(&hub, 1 << 16);
+        let mut b = client(&hub, 1 << 16);
+        a.send(&Packet::RegisterCallsign(Port(0), me.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            upstream.recv().await.unwrap(),
+            Packet::RegisterCallsign(Port(0), me.clone())
+        );
+        b.send(&Packet::UnregisterCallsign(Port(0), me.clone()))
+            .await
+            .unwrap();
+        // Not sent upstream.
+        sync(&mut b, &mut upstream).await;
+        b.send(&Packet::RegisterCallsign(Port(0), me.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            b.recv().await.unwrap(),
+            Packet::RegisterCallsignReply {
+                port: Port(0),
+                call: me,
+                success: false,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn others_connections_are_left_alone() {
+        let (hub, mut upstream) = start();
+        let me = call("M0THC-1");
+        let peer = call("M0THC-2");
+        let mut a = client(&hub, 1 << 16);
+        let mut b = client(&hub, 1 << 16);
+        let connect = Packet::Connect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: me.clone(),
+            dst: peer.clone(),
+        };
+        a.send(&connect).await.unwrap();
+        assert_eq!(upstream.recv().await.unwrap(), connect);
+
+        // Neither is sent upstream.
+        let data = |data: &[u8]| Packet::Data {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: me.clone(),
+            dst: peer.clone(),
+            data: data.to_vec(),
+        };
+        b.send(&data(b"not yours")).await.unwrap();
+        let disconnect = Packet::Disconnect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: me.clone(),
+            dst: peer.clone(),
+            text: String::new(),
+        };
+        b.send(&disconnect).await.unwrap();
+        assert_eq!(
+            b.recv().await.unwrap(),
+            Packet::Disconnect {
+                port: Port(0),
+                pid: Pid(0xF0),
+                src: peer.clone(),
+                dst: me.clone(),
+                text: String::new(),
+            }
+        );
+        sync(&mut b, &mut upstream).await;
+
+        // The owner still can.
+        a.send(&data(b"mine")).await.unwrap();
+        assert_eq!(upstream.recv().await.unwrap(), data(b"mine"));
+        a.send(&disconnect).await.unwrap();
+        assert_eq!(upstream.recv().await.unwrap(), disconnect);
+    }
+
+    #[tokio::test]
+    async fn throttled_client_keeps_receiving() {
+        use crate::ratelimit::{Limits, Overflow};
+        let (hub, mut upstream) = start();
+        hub.set_rate_limiter(Some(Arc::new(RateLimiter::new(
+            Limits {
+                frames_per_minute: Some(1),
+                ..Limits::default()
+            },
+            Limits::default(),
+            Overflow::Queue,
+        ))));
+        let me = call("M0THC-1");
+        let mut a = client(&hub, 1 << 16);
+        a.send(&Packet::RegisterCallsign(Port(0), me.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            upstream.recv().await.unwrap(),
+            Packet::RegisterCallsign(Port(0), me.clone())
+        );
+        let unproto = |text: &str| Packet::Unproto {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: me.clone(),
+            dst: call("ID"),
+            data: text.as_bytes().to_vec(),
+        };
+        a.send(&unproto("one")).await.unwrap();
+        a.send(&unproto("two")).await.unwrap();
+        assert_eq!(upstream.recv().await.unwrap(), unproto("one"));
+
+        let data = Packet::Data {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call("M0THC-2"),
+            dst: me.clone(),
+            data: b"hello".to_vec(),
+        };
+        upstream.send(&data).await.unwrap();
+        let got = tokio::time::timeout(Duration::from_secs(5), a.recv())
+            .await
+            .expect("blocked while throttled")
+            .unwrap();
+        assert_eq!(got, data);
+    }
+}
@@ -0,0 +1,424 @@
+//! Tokio based AGW proxy.
+//!
+//! Same idea as `crate::proxy`, but each proxied client is a task instead of
+//! four threads, so one process can serve many clients.
+//!
+//! ```no_run
+//! # async fn f() -> agw::Result<()> {
+//! use agw::r#async::proxy::Proxy;
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:9011").await?;
+//! loop {
+//!     let (down, _) = listener.accept().await?;
+//!     tokio::spawn(async move {
+//!         let mut proxy = Proxy::builder().upstream_addr("192.0.2.1:8000").build(down).await?;
+//!         proxy.run(Some, Some).await
+//!     });
+//! }
+//! # }
+//! ```
+use std::sync::Arc;
+
+use log::{debug, info};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use super::{closed_is_none, AGWServer};
+use crate::proxy::{Action, Upstream, DEFAULT_UPSTREAM};
+use crate::ratelimit::{ClientLimiter, Held, RateLimiter};
+use crate::{Frame, Packet, Result};
+
+/// Builder for `Proxy`.
+pub struct ProxyBuilder {
+    upstream: Upstream<TcpStream>,
+    max_data_len: u32,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl ProxyBuilder {
+    /// New builder, with upstream `DEFAULT_UPSTREAM`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            upstream: Upstream::Addr(DEFAULT_UPSTREAM.to_string()),
+            max_data_len: crate::DEFAULT_MAX_DATA_LEN,
+            limiter: None,
+        }
+    }
+
+    /// Connect to upstream at `addr` when building.
+    #[must_use]
+    pub fn upstream_addr(mut self, addr: impl Into<String>) -> Self {
+        self.upstream = Upstream::Addr(addr.into());
+        self
+    }
+
+    /// Use an already connected stream as upstream.
+    #[must_use]
+    pub fn upstream_stream(mut self, stream: TcpStream) -> Self {
+        self.upstream = Upstream::Stream(stream);
+        self
+    }
+
+    /// Reject frames with payloads longer than `max_data_len` from either
+    /// side.
+    #[must_use]
+    pub fn max_data_len(mut self, max_data_len: u32) -> Self {
+        self.max_data_len = max_data_len;
+        self
+    }
+
+    /// Limit what the client may transmit.
+    ///
+    /// Over budget frames are held back or dropped, depending on the rate
+    /// limiter's `Overflow` setting. Frames from upstream keep flowing
+    /// while the client's are held back.
+    #[must_use]
+    pub fn rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Set up the proxy between `down` and upstream.
+    ///
+    /// # Errors
+    ///
+    /// If failing to connect to upstream.
+    pub async fn build(self, down: TcpStream) -> Result<Proxy> {
+        let up = match self.upstream {
+            Upstream::Addr(addr) => TcpStream::connect(addr).await?,
+            Upstream::Stream(stream) => stream,
+        };
+        // AGWServer is only a packet stream, so it works for the upstream
+        // side too.
+        let limiter = self.limiter.map(|limiter| {
+            limiter.client(
+                down.peer_addr()
+                    .map_or_else(|_| "unknown".to_string(), |a| a.to_string()),
+            )
+        });
+        Ok(Proxy {
+            up: AGWServer::with_max_data_len(up, self.max_data_len),
+            down: AGWServer::with_max_data_len(down, self.max_data_len),
+            limiter,
+        })
+    }
+}
+
+impl Default for ProxyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// AGW proxy between one client (downstream) and one AGW endpoint
+/// (upstream).
+pub struct Proxy {
+    up: AGWServer,
+    down: AGWServer,
+    limiter: Option<ClientLimiter>,
+}
+
+impl Proxy {
+    /// Builder for a proxy.
+    #[must_use]
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder::new()
+    }
+
+    /// Run the proxy until either side disconnects.
+    ///
+    /// `cb_up` is called for packets from upstream, and `cb_down` for packets
+    /// from downstream. Whatever packet they return is forwarded to the other
+    /// side. Returning `None` drops the packet.
+    ///
+    /// # Errors
+    ///
+    /// If the underlying connections fail, or other protocol error.
+    pub async fn run<U, D>(&mut self, cb_up: U, cb_down: D) -> Result<()>
+    where
+        U: Fn(Packet) -> Option<Packet>,
+        D: Fn(Packet) -> Option<Packet>,
+    {
+        self.run_actions(
+            |packet| cb_up(packet).map(Action::Forward).into_iter().collect(),
+            |packet| cb_down(packet).map(Action::Forward).into_iter().collect(),
+        )
+        .await
+    }
+
+    /// Run the proxy until either side disconnects, with callbacks returning
+    /// any number of actions.
+    ///
+    /// When one side disconnects, the other side is shut down too.
+    ///
+    /// # Errors
+    ///
+    /// If the underlying connections fail, or other protocol error.
+    pub async fn run_actions<U, D>(&mut self, cb_up: U, cb_down: D) -> Result<()>
+    where
+        U: Fn(Packet) -> Vec<Action>,
+        D: Fn(Packet) -> Vec<Action>,
+    {
+        info!("Running async proxy");
+        let ret = self.run_loop(cb_up, cb_down).await;
+        let _ = self.up.get_mut().shutdown().await;
+        let _ = self.down.get_mut().shutdown().await;
+        ret
+    }
+
+    async fn run_loop<U, D>(&mut self, cb_up: U, cb_down: D) -> Result<()>
+    where
+        U: Fn(Packet) -> Vec<Action>,
+        D: Fn(Packet) -> Vec<Action>,
+    {
+        let mut held = Held::new();
+        let mut retry = None;
+        loop {
+            tokio::select! {
+                frame = self.down.recv_frame() => {
+                    let Some(frame) = closed_is_none(frame)? else {
+                        info!("agw: Downstream disconnected");
+                        return Ok(());
+                    };
+                    debug!("agw: Got {:?} from downstream", frame.packet());
+                    let actions = cb_down(frame.packet().clone());
+                    let held = self.limiter.as_ref().map(|limiter| (&mut held, limiter));
+                    dispatch(actions, &frame, &mut self.down, &mut self.up, held).await?;
+                },
+                frame = self.up.recv_frame() => {
+                    let Some(frame) = closed_is_none(frame)? else {
+                        info!("agw: Upstream disconnected");
+                        return Ok(());
+                    };
+                    debug!("agw: Got {:?} from upstream", frame.packet());
+                    if let Some(limiter) = &self.limiter {
+                        limiter.observe(frame.packet());
+                    }
+                    let actions = cb_up(frame.packet().clone());
+                    dispatch(actions, &frame, &mut self.up, &mut self.down, None).await?;
+                },
+                () = tokio::time::sleep(retry.unwrap_or_default()), if retry.is_some() => {},
+            }
+            if let Some(limiter) = &self.limiter {
+                let (ready, wait) = held.release(limiter);
+                for (packet, frame) in ready {
+                    forward(&mut self.up, &packet, frame.as_ref()).await?;
+                }
+                retry = wait;
+            }
+        }
+    }
+}
+
+/// Send a forwarded packet, as the original frame if unchanged.
+async fn forward(to: &mut AGWServer, packet: &Packet, frame: Option<&Frame>) -> Result<()> {
+    match frame {
+        Some(frame) => to.send_frame(frame).await,
+        None => to.send(packet).await,
+    }
+}
+
+/// Carry out actions triggered by `frame`. Forwarded packets are put in
+/// `held`, if set, to wait for rate limiter budget.
+///
+/// Forwarding the packet unchanged sends on the frame byte for byte.
+async fn dispatch(
+    actions: Vec<Action>,
+    frame: &Frame,
+    from: &mut AGWServer,
+    to: &mut AGWServer,
+    mut held: Option<(&mut Held<Option<Frame>>, &ClientLimiter)>,
+) -> Result<()> {
+    for action in actions {
+        match action {
+            Action::Forward(packet) => {
+                debug!("agw: … forwarding {packet:?}");
+                let unchanged = packet == *frame.packet();
+                match &mut held {
+                    Some((held, limiter)) => {
+                        held.push(limiter, packet, unchanged.then(|| frame.clone()));
+                    }
+                    None => forward(to, &packet, unchanged.then_some(frame)).await?,
+                }
+            }
+            Action::Reply(packet) => {
+                debug!("agw: … replying {packet:?}");
+                from.send(&packet).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Header, Pid, Port};
+    use tokio::io::AsyncReadExt;
+
+    /// Connected pair of TCP streams.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (b, _) = listener.accept().await.unwrap();
+        (a, b)
+    }
+
+    /// Proxy with `cb_down`, returning the client and upstream server ends.
+    async fn start<D>(cb_down: D) -> (TcpStream, TcpStream)
+    where
+        D: Fn(Packet) -> Vec<Action> + Send + 'static,
+    {
+        start_with(Proxy::builder(), cb_down).await
+    }
+
+    /// Like `start()`, with settings from `builder`.
+    async fn start_with<D>(builder: ProxyBuilder, cb_down: D) -> (TcpStream, TcpStream)
+    where
+        D: Fn(Packet) -> Vec<Action> + Send + 'static,
+    {
+        let (client, down) = tcp_pair().await;
+        let (up, server) = tcp_pair().await;
+        let mut proxy = builder.upstream_stream(up).build(down).await.unwrap();
+        tokio::spawn(async move {
+            proxy
+                .run_actions(|p| vec![Action::Forward(p)], cb_down)
+                .await
+        });
+        (client, server)
+    }
+
+    fn data_frame(text: &[u8]) -> Vec<u8> {
+        let mut header = Header::new(
+            Port(0),
+            b'D',
+            Pid(0xF0),
+            Some("M0THC-1".parse().unwrap()),
+            Some("M0THC-2".parse().unwrap()),
+            u32::try_from(text.len()).unwrap(),
+        );
+        header.user = 7;
+        header.reserved = [1, 2, 3, 4, 5];
+        [&header.to_bytes()[..], text].concat()
+    }
+
+    #[tokio::test]
+    async fn forwards_frames_byte_for_byte() {
+        let (mut client, mut server) = start(|p| vec![Action::Forward(p)]).await;
+        let frame = data_frame(b"hello");
+        client.write_all(&frame).await.unwrap();
+        let mut got = vec![0; frame.len()];
+        server.read_exact(&mut got).await.unwrap();
+        assert_eq!(got, frame);
+    }
+
+    #[tokio::test]
+    async fn forwards_changed_packets_reserialized() {
+        let (mut client, server) = start(|p| match p {
+            Packet::Data {
+                port,
+                pid,
+                src,
+                dst,
+                ..
+            } => vec![Action::Forward(Packet::Data {
+                port,
+                pid,
+                src,
+                dst,
+                data: b"changed".to_vec(),
+            })],
+            p => vec![Action::Forward(p)],
+        })
+        .await;
+        client.write_all(&data_frame(b"hello")).await.unwrap();
+        let mut server = AGWServer::new(server);
+        let frame = server.recv_frame().await.unwrap();
+        assert!(matches!(frame.packet(), Packet::Data { data, .. } if data == b"changed"));
+        // Canonical header, as nothing is known about the original fields.
+        assert_eq!(frame.header().user, 0);
+    }
+
+    #[tokio::test]
+    async fn connects_to_upstream_addr() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (mut client, down) = tcp_pair().await;
+        let mut proxy = Proxy::builder()
+            .upstream_addr(addr)
+            .build(down)
+            .await
+            .unwrap();
+        tokio::spawn(async move { proxy.run(Some, Some).await });
+        let (server, _) = listener.accept().await.unwrap();
+        let mut server = AGWServer::new(server);
+        client
+            .write_all(&Packet::VersionQuery.serialize())
+            .await
+            .unwrap();
+        assert_eq!(server.recv().await.unwrap(), Packet::VersionQuery);
+    }
+
+    #[tokio::test]
+    async fn shutdown_propagates() {
+        // Upstream closing closes downstream.
+        let (mut client, server) = start(|p| vec![Action::Forward(p)]).await;
+        drop(server);
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+
+        // And the other way around.
+        let (client, mut server) = start(|p| vec![Action::Forward(p)]).await;
+        drop(client);
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn throttled_client_doesnt_block_upstream() {
+        use crate::ratelimit::{Limits, Overflow};
+        use std::time::Duration;
+        let limiter = Arc::new(RateLimiter::new(
+            Limits {
+                frames_per_minute: Some(1),
+                ..Limits::default()
+            },
+            Limits::default(),
+            Overflow::Queue,
+        ));
+        let (client, server) = start_with(Proxy::builder().rate_limiter(limiter.clone()), |p| {
+            vec![Action::Forward(p)]
+        })
+        .await;
+        let mut client = AGWServer::new(client);
+        let mut server = AGWServer::new(server);
+        let unproto = |text: &str| Packet::Unproto {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: "M0THC-1".parse().unwrap(),
+            dst: "ID".parse().unwrap(),
+            data: text.as_bytes().to_vec(),
+        };
+        client.send(&unproto("one")).await.unwrap();
+        client.send(&unproto("two")).await.unwrap();
+        client.send(&Packet::VersionQuery).await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), unproto("one"));
+
+        // The rest are held back, in order.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), server.recv())
+                .await
+                .is_err()
+        );
+
+        // While traffic to the client keeps flowing.
+        server.send(&unproto("reply")).await.unwrap();
+        let got = tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .expect("blocked by throttled client")
+            .unwrap();
+        assert_eq!(got, unproto("reply"));
+        assert_eq!(limiter.stats().queued, 1);
+    }
+}
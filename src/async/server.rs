@@ -0,0 +1,916 @@
+//! Framework for writing AGW servers, such as TNCs and emulators.
+//!
+//! Implement `AgwBackend`, and `Server` handles the clients: it answers
+//! their queries, keeps track of which client registered which callsign and
+//! owns which connection, and turns monitoring and raw mode on and off per
+//! client.
+//!
+//! Traffic from the radio side goes to clients with `Clients::deliver()`.
+//!
+//! ```no_run
+//! use agw::r#async::server::{AgwBackend, Clients, Server};
+//! use agw::{Baud, Call, Packet, Pid, Port, PortCaps, PortInfo};
+//!
+//! /// Loops connected mode data back to the sender.
+//! struct Echo {
+//!     clients: Clients,
+//! }
+//!
+//! impl AgwBackend for Echo {
+//!     fn ports(&self) -> Vec<PortInfo> {
+//!         vec![PortInfo {
+//!             port: Port(0),
+//!             descr: "Echo".to_string(),
+//!         }]
+//!     }
+//!     fn port_caps(&self, _port: Port) -> PortCaps {
+//!         PortCaps::new(Baud::B1200)
+//!     }
+//!     async fn data(
+//!         &self,
+//!         port: Port,
+//!         pid: Pid,
+//!         src: Call,
+//!         dst: Call,
+//!         data: Vec<u8>,
+//!     ) -> agw::Result<()> {
+//!         let _ = self.clients.deliver(&Packet::Data {
+//!             port,
+//!             pid,
+//!             src: dst,
+//!             dst: src,
+//!             data,
+//!         });
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # async fn f() -> agw::Result<()> {
+//! let clients = Clients::new();
+//! let server = Server::with_clients(
+//!     Echo {
+//!         clients: clients.clone(),
+//!     },
+//!     clients,
+//! );
+//! server
+//!     .serve(tokio::net::TcpListener::bind("127.0.0.1:8010").await?)
+//!     .await
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use super::{closed_is_none, AGWServer};
+use crate::{Call, Packet, Pid, Port, PortCaps, PortInfo, PortsInfo, Result};
+
+const CLIENT_QUEUE: usize = 100;
+
+type ClientId = u64;
+
+/// What an AGW server does.
+///
+/// Queries are answered from the sync methods. The async methods act on
+/// what clients send, and are awaited before the client's next packet is
+/// handled. Methods not implemented accept everything, and do nothing.
+pub trait AgwBackend: Send + Sync + 'static {
+    /// Version to report.
+    fn version(&self) -> (u16, u16) {
+        (2005, 127)
+    }
+
+    /// Ports to report.
+    fn ports(&self) -> Vec<PortInfo>;
+
+    /// Capabilities of a port.
+    fn port_caps(&self, port: Port) -> PortCaps;
+
+    /// Raw payload of the heard callsigns reply. See
+    /// `Packet::CallsignHeardReply`.
+    fn heard(&self, _port: Port) -> Vec<u8> {
+        vec![0]
+    }
+
+    /// Frames queued for sending on a port.
+    fn frames_outstanding(&self, _port: Port) -> usize {
+        0
+    }
+
+    /// Whether a client may register a callsign.
+    ///
+    /// Only called if no other client has it registered.
+    fn register(&self, _port: Port, _call: &Call) -> bool {
+        true
+    }
+
+    /// Connect to `dst`, returning whether the remote end accepted.
+    ///
+    /// `src` is the client's callsign, and `via` is the digipeater path.
+    fn connect(
+        &self,
+        _port: Port,
+        _pid: Pid,
+        _src: Call,
+        _dst: Call,
+        _via: Vec<Call>,
+    ) -> impl Future<Output = Result<bool>> + Send {
+        async { Ok(true) }
+    }
+
+    /// Disconnect a connection.
+    ///
+    /// Called when the client asks, or when the client goes away with the
+    /// connection still up.
+    fn disconnect(
+        &self,
+        _port: Port,
+        _pid: Pid,
+        _src: Call,
+        _dst: Call,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Send data on a connection.
+    fn data(
+        &self,
+        _port: Port,
+        _pid: Pid,
+        _src: Call,
+        _dst: Call,
+        _data: Vec<u8>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Send an unproto (UI) frame.
+    fn unproto(
+        &self,
+        _port: Port,
+        _pid: Pid,
+        _src: Call,
+        _dst: Call,
+        _via: Vec<Call>,
+        _data: Vec<u8>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Connection, as (port, local callsign, remote callsign).
+type ConnKey = (Port, Call, Call);
+
+struct ClientState {
+    tx: mpsc::Sender<Packet>,
+    monitor: bool,
+    raw: bool,
+}
+
+#[derive(Default)]
+struct State {
+    next_client: ClientId,
+    clients: HashMap<ClientId, ClientState>,
+    owners: HashMap<(Port, Call), ClientId>,
+    connections: HashMap<ConnKey, (ClientId, Pid)>,
+}
+
+/// The connected clients, for sending them traffic.
+///
+/// Cheap to clone. All clones refer to the same clients.
+#[derive(Clone, Default)]
+pub struct Clients {
+    state: Arc<Mutex<State>>,
+}
+
+impl Clients {
+    /// Create an empty set of clients, to give to `Server::with_clients()`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send a packet from the radio side to the clients it's for.
+    ///
+    /// As with packets from an AGW server, `src` is the remote callsign and
+    /// `dst` the local one.
+    ///
+    /// * `IncomingConnect` goes to the client that registered `dst`, which
+    ///   then owns the connection.
+    /// * `ConnectionEstablished`, `Data` and `Disconnect` go to the client
+    ///   that owns the connection, or failing that the one that registered
+    ///   `dst`.
+    /// * `Monitor` and `RawFrame` go to every client that turned monitoring
+    ///   or raw mode on.
+    ///
+    /// A client that doesn't keep up loses packets. Returns whether anyone
+    /// got the packet.
+    #[must_use]
+    pub fn deliver(&self, packet: &Packet) -> bool {
+        let txs: Vec<_> = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let ids: Vec<ClientId> = match packet {
+                Packet::IncomingConnect {
+                    port,
+                    pid,
+                    src,
+                    dst,
+                    ..
+                } => match state.owners.get(&(*port, dst.clone())) {
+                    Some(&id) => {
+                        state
+                            .connections
+                            .insert((*port, dst.clone(), src.clone()), (id, *pid));
+                        vec![id]
+                    }
+                    None => Vec::new(),
+                },
+                Packet::ConnectionEstablished { port, src, dst, .. }
+                | Packet::Data { port, src, dst, .. }
+                | Packet::Disconnect { port, src, dst, .. } => {
+                    let key = (*port, dst.clone(), src.clone());
+                    let conn = if matches!(packet, Packet::Disconnect { .. }) {
+                        state.connections.remove(&key)
+                    } else {
+                        state.connections.get(&key).copied()
+                    };
+                    conn.map(|(id, _)| id)
+                        .or_else(|| state.owners.get(&(*port, dst.clone())).copied())
+                        .into_iter()
+                        .collect()
+                }
+                Packet::Monitor { .. } => state
+                    .clients
+                    .iter()
+                    .filter(|(_, c)| c.monitor)
+                    .map(|(&id, _)| id)
+                    .collect(),
+                Packet::RawFrame { .. } => state
+                    .clients
+                    .iter()
+                    .filter(|(_, c)| c.raw)
+                    .map(|(&id, _)| id)
+                    .collect(),
+                _ => Vec::new(),
+            };
+            ids.iter()
+                .filter_map(|id| state.clients.get(id))
+                .map(|c| c.tx.clone())
+                .collect()
+        };
+        if txs.is_empty() {
+            debug!("agw/server: Nobody to deliver {packet:?} to");
+            return false;
+        }
+        let mut delivered = false;
+        for tx in txs {
+            match tx.try_send(packet.clone()) {
+                Ok(()) => delivered = true,
+                Err(e) => warn!("agw/server: Dropping packet for client: {e}"),
+            }
+        }
+        delivered
+    }
+
+    /// Return whether some client has registered the callsign.
+    #[must_use]
+    pub fn is_registered(&self, port: Port, call: &Call) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .owners
+            .contains_key(&(port, call.clone()))
+    }
+
+    fn add(&self, tx: mpsc::Sender<Packet>) -> ClientId {
+        let mut state = self.state.lock().unwrap();
+        state.next_client += 1;
+        let id = state.next_client;
+        state.clients.insert(
+            id,
+            ClientState {
+                tx,
+                monitor: false,
+                raw: false,
+            },
+        );
+        id
+    }
+
+    /// Forget the client, returning the connections it still had up.
+    fn remove(&self, id: ClientId) -> Vec<(ConnKey, Pid)> {
+        let mut state = self.state.lock().unwrap();
+        state.clients.remove(&id);
+        state.owners.retain(|_, owner| *owner != id);
+        let mut gone = Vec::new();
+        state.connections.retain(|key, &mut (owner, pid)| {
+            if owner == id {
+                gone.push((key.clone(), pid));
+            }
+            owner != id
+        });
+        gone
+    }
+
+    /// Make `id` owner of the callsign, unless someone else is.
+    fn claim(&self, id: ClientId, port: Port, call: &Call) -> bool {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .owners
+            .entry((port, call.clone()))
+            .or_insert(id)
+            == id
+    }
+
+    fn is_owner(&self, id: ClientId, port: Port, call: &Call) -> bool {
+        self.state.lock().unwrap().owners.get(&(port, call.clone())) == Some(&id)
+    }
+
+    fn unclaim(&self, port: Port, call: &Call) {
+        self.state
+            .lock()
+            .unwrap()
+            .owners
+            .remove(&(port, call.clone()));
+    }
+
+    fn toggle(&self, id: ClientId, raw: bool) {
+        if let Some(c) = self.state.lock().unwrap().clients.get_mut(&id) {
+            let flag = if raw { &mut c.raw } else { &mut c.monitor };
+            *flag = !*flag;
+        }
+    }
+
+    fn connection(&self, key: &ConnKey) -> Option<ClientId> {
+        self.state
+            .lock()
+            .unwrap()
+            .connections
+            .get(key)
+            .map(|&(id, _)| id)
+    }
+
+    fn set_connection(&self, key: ConnKey, conn: Option<(ClientId, Pid)>) {
+        let mut state = self.state.lock().unwrap();
+        match conn {
+            Some(conn) => state.connections.insert(key, conn),
+            None => state.connections.remove(&key),
+        };
+    }
+}
+
+/// AGW server, handing client requests to an `AgwBackend`.
+pub struct Server<B: AgwBackend> {
+    backend: B,
+    clients: Clients,
+}
+
+impl<B: AgwBackend> Server<B> {
+    /// Create server for a backend that doesn't need to send clients
+    /// anything on its own.
+    #[must_use]
+    pub fn new(backend: B) -> Arc<Self> {
+        Self::with_clients(backend, Clients::new())
+    }
+
+    /// Create server, with `clients` being the handle the backend uses to
+    /// send clients traffic.
+    #[must_use]
+    pub fn with_clients(backend: B, clients: Clients) -> Arc<Self> {
+        Arc::new(Self { backend, clients })
+    }
+
+    /// Return the backend.
+    #[must_use]
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Return the connected clients.
+    #[must_use]
+    pub fn clients(&self) -> &Clients {
+        &self.clients
+    }
+
+    /// Accept clients forever, handling each in its own task.
+    ///
+    /// # Errors
+    ///
+    /// If accepting fails.
+    pub async fn serve(self: &Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            info!("agw/server: Client connected from {addr}");
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle(AGWServer::new(stream)).await {
+                    warn!("agw/server: Client {addr} failed: {e}");
+                }
+                info!("agw/server: Client {addr} disconnected");
+            });
+        }
+    }
+
+    /// Handle one client until it disconnects.
+    ///
+    /// The client can be on any stream, e.g. one end of
+    /// `tokio::io::duplex()`.
+    ///
+    /// Connections the client still has up are then disconnected.
+    ///
+    /// # Errors
+    ///
+    /// If the client connection fails.
+    pub async fn handle<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        mut con: AGWServer<S>,
+    ) -> Result<()> {
+        let (tx, mut rx) = mpsc::channel(CLIENT_QUEUE);
+        let id = self.clients.add(tx);
+        let ret = self.client_loop(id, &mut con, &mut rx).await;
+        for ((port, src, dst), pid) in self.clients.remove(id) {
+            if let Err(e) = self.backend.disconnect(port, pid, src, dst).await {
+                warn!("agw/server: Failed to disconnect after client went away: {e}");
+            }
+        }
+        ret
+    }
+
+    async fn client_loop<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        id: ClientId,
+        con: &mut AGWServer<S>,
+        rx: &mut mpsc::Receiver<Packet>,
+    ) -> Result<()> {
+        loop {
+            tokio::select! {
+                packet = con.recv() => {
+                    let Some(packet) = closed_is_none(packet)? else {
+                        return Ok(());
+                    };
+                    self.client_packet(id, con, packet).await?;
+                },
+                // Never `None`, since `self.clients` holds a sender.
+                Some(packet) = rx.recv() => con.send(&packet).await?,
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    async fn client_packet<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        id: ClientId,
+        con: &mut AGWServer<S>,
+        packet: Packet,
+    ) -> Result<()> {
+        debug!("agw/server: Client {id} sent {packet:?}");
+        let backend = &self.backend;
+        match packet {
+            Packet::VersionQuery => {
+                let (major, minor) = backend.version();
+                con.send(&Packet::VersionReply { major, minor }).await?;
+            }
+            Packet::PortInfoQuery => {
+                let ports = backend.ports();
+                con.send(&Packet::PortInfoReply(PortsInfo {
+                    count: ports.len(),
+                    ports,
+                }))
+                .await?;
+            }
+            Packet::PortCapQuery(port) => {
+                let caps = backend.port_caps(port);
+                con.send(&Packet::PortCapReply { port, caps }).await?;
+            }
+            Packet::CallsignHeardQuery(port) => {
+                let data = backend.heard(port);
+                con.send(&Packet::CallsignHeardReply { port, data }).await?;
+            }
+            Packet::FramesOutstandingPortQuery(port) => {
+                let n = backend.frames_outstanding(port);
+                con.send(&Packet::FramesOutstandingPortReply(port, n))
+                    .await?;
+            }
+            Packet::RegisterCallsign(port, call) => {
+                let mine = self.clients.is_owner(id, port, &call);
+                let success = mine
+                    || (self.clients.claim(id, port, &call) && {
+                        let ok = backend.register(port, &call);
+                        if !ok {
+                            self.clients.unclaim(port, &call);
+                        }
+                        ok
+                    });
+                con.send(&Packet::RegisterCallsignReply {
+                    port,
+                    call,
+                    success,
+                })
+                .await?;
+            }
+            Packet::UnregisterCallsign(port, call) => {
+                if self.clients.is_owner(id, port, &call) {
+                    self.clients.unclaim(port, &call);
+                }
+            }
+            Packet::ToggleMonitor => self.clients.toggle(id, false),
+            Packet::ToggleRaw => self.clients.toggle(id, true),
+            Packet::Connect {
+                port,
+                pid,
+                src,
+                dst,
+            } => {
+                self.connect(id, con, port, pid, src, dst, Vec::new())
+                    .await?;
+            }
+            Packet::ConnectVia {
+                port,
+                pid,
+                src,
+                dst,
+                via,
+            } => {
+                self.connect(id, con, port, pid, src, dst, via).await?;
+            }
+            Packet::Disconnect {
+                port,
+                pid,
+                src,
+                dst,
+                ..
+            } => {
+                let key = (port, src.clone(), dst.clone());
+                if self.clients.connection(&key) == Some(id) {
+                    self.clients.set_connection(key, None);
+                    if let Err(e) = backend
+                        .disconnect(port, pid, src.clone(), dst.clone())
+                        .await
+                    {
+                        warn!("agw/server: Disconnect failed: {e}");
+                    }
+                }
+                con.send(&Packet::Disconnect {
+                    port,
+                    pid,
+                    src: dst,
+                    dst: src,
+                    text: String::new(),
+                })
+                .await?;
+            }
+            Packet::Data {
+                port,
+                pid,
+                src,
+                dst,
+                data,
+            } => {
+                if self.clients.connection(&(port, src.clone(), dst.clone())) != Some(id) {
+                    info!("agw/server: Client {id} sent data on unknown connection {src}->{dst}");
+                } else if let Err(e) = backend.data(port, pid, src, dst, data).await {
+                    warn!("agw/server: Sending data failed: {e}");
+                }
+            }
+            Packet::Unproto {
+                port,
+                pid,
+                src,
+                dst,
+                data,
+            } => {
+                if let Err(e) = backend.unproto(port, pid, src, dst, Vec::new(), data).await {
+                    warn!("agw/server: Sending unproto failed: {e}");
+                }
+            }
+            Packet::UnprotoVia {
+                port,
+                pid,
+                src,
+                dst,
+                via,
+                data,
+            } => {
+                if let Err(e) = backend.unproto(port, pid, src, dst, via, data).await {
+                    warn!("agw/server: Sending unproto failed: {e}");
+                }
+            }
+            other => info!("agw/server: Client {id} sent unexpected {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn connect<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        id: ClientId,
+        con: &mut AGWServer<S>,
+        port: Port,
+        pid: Pid,
+        src: Call,
+        dst: Call,
+        via: Vec<Call>,
+    ) -> Result<()> {
+        let key = (port, src.clone(), dst.clone());
+        let accepted = if !self.clients.claim(id, port, &src) {
+            info!("agw/server: {src} belongs to another client");
+            false
+        } else if self.clients.connection(&key).is_some() {
+            info!("agw/server: {src}->{dst} is already connected");
+            false
+        } else {
+            // Claim the connection while connecting, so that data the
+            // backend delivers right away has somewhere to go.
+            self.clients.set_connection(key.clone(), Some((id, pid)));
+            match self
+                .backend
+                .connect(port, pid, src.clone(), dst.clone(), via)
+                .await
+            {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("agw/server: Connect failed: {e}");
+                    false
+                }
+            }
+        };
+        let reply = if accepted {
+            Packet::ConnectionEstablished {
+                port,
+                pid,
+                src: dst,
+                dst: src,
+                text: String::new(),
+            }
+        } else {
+            if self.clients.connection(&key) == Some(id) {
+                self.clients.set_connection(key, None);
+            }
+            Packet::Disconnect {
+                port,
+                pid,
+                src: dst,
+                dst: src,
+                text: String::new(),
+            }
+        };
+        con.send(&reply).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Baud;
+    use tokio::io::DuplexStream;
+
+    /// Backend that records what it's asked to do.
+    struct Recorder {
+        accept: bool,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Recorder {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl AgwBackend for Recorder {
+        fn ports(&self) -> Vec<PortInfo> {
+            Vec::new()
+        }
+        fn port_caps(&self, _port: Port) -> PortCaps {
+            PortCaps::new(Baud::B1200)
+        }
+        async fn connect(
+            &self,
+            _port: Port,
+            _pid: Pid,
+            src: Call,
+            dst: Call,
+            _via: Vec<Call>,
+        ) -> Result<bool> {
+            self.record(format!("connect {src} {dst}"));
+            Ok(self.accept)
+        }
+        async fn disconnect(&self, _port: Port, _pid: Pid, src: Call, dst: Call) -> Result<()> {
+            self.record(format!("disconnect {src} {dst}"));
+            Ok(())
+        }
+        async fn data(
+            &self,
+            _port: Port,
+            _pid: Pid,
+            src: Call,
+            dst: Call,
+            data: Vec<u8>,
+        ) -> Result<()> {
+            self.record(format!(
+                "data {src} {dst} {}",
+                String::from_utf8_lossy(&data)
+            ));
+            Ok(())
+        }
+    }
+
+    type Client = AGWServer<DuplexStream>;
+
+    fn server(accept: bool) -> Arc<Server<Recorder>> {
+        Server::new(Recorder {
+            accept,
+            calls: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Connect a client, returning it and the task handling it.
+    fn client(server: &Arc<Server<Recorder>>) -> (Client, tokio::task::JoinHandle<Result<()>>) {
+        let (client, con) = tokio::io::duplex(1 << 16);
+        let server = server.clone();
+        let task = tokio::spawn(async move { server.handle(AGWServer::new(con)).await });
+        (AGWServer::new(client), task)
+    }
+
+    fn calls(server: &Server<Recorder>) -> Vec<String> {
+        server.backend().calls.lock().unwrap().clone()
+    }
+
+    fn call(s: &str) -> Call {
+        s.parse().unwrap()
+    }
+
+    fn connect() -> Packet {
+        Packet::Connect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call("M0THC-1"),
+            dst: call("M0THC-2"),
+        }
+    }
+
+    /// Disconnect from `src` to `dst`.
+    fn disconnect(src: &str, dst: &str) -> Packet {
+        Packet::Disconnect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call(src),
+            dst: call(dst),
+            text: String::new(),
+        }
+    }
+
+    fn data(src: &str, dst: &str, data: &[u8]) -> Packet {
+        Packet::Data {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call(src),
+            dst: call(dst),
+            data: data.to_vec(),
+        }
+    }
+
+    /// Make sure the server has handled everything the client sent.
+    async fn sync(client: &mut Client) {
+        client.send(&Packet::VersionQuery).await.unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::VersionReply { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn connect_and_disconnect() {
+        let server = server(true);
+        let (mut a, _task) = client(&server);
+        a.send(&connect()).await.unwrap();
+        assert!(matches!(
+            a.recv().await.unwrap(),
+            Packet::ConnectionEstablished { .. }
+        ));
+        a.send(&data("M0THC-1", "M0THC-2", b"hello")).await.unwrap();
+        a.send(&disconnect("M0THC-1", "M0THC-2")).await.unwrap();
+        assert_eq!(a.recv().await.unwrap(), disconnect("M0THC-2", "M0THC-1"));
+
+        // Gone, so data is not sent.
+        a.send(&data("M0THC-1", "M0THC-2", b"late")).await.unwrap();
+        sync(&mut a).await;
+        assert_eq!(
+            calls(&server),
+            [
+                "connect M0THC-1 M0THC-2",
+                "data M0THC-1 M0THC-2 hello",
+                "disconnect M0THC-1 M0THC-2",
+            ]
+        );
+        assert_eq!(
+            server
+                .clients()
+                .connection(&(Port(0), call("M0THC-1"), call("M0THC-2"))),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_connect_is_forgotten() {
+        let server = server(false);
+        let (mut a, _task) = client(&server);
+        for _ in 0..2 {
+            a.send(&connect()).await.unwrap();
+            assert_eq!(a.recv().await.unwrap(), disconnect("M0THC-2", "M0THC-1"));
+        }
+        a.send(&data("M0THC-1", "M0THC-2", b"hello")).await.unwrap();
+        sync(&mut a).await;
+        // Asked again the second time, since the first one was forgotten.
+        assert_eq!(
+            calls(&server),
+            ["connect M0THC-1 M0THC-2", "connect M0THC-1 M0THC-2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn departing_client_is_disconnected() {
+        let server = server(true);
+        let (mut a, task) = client(&server);
+        a.send(&Packet::RegisterCallsign(Port(0), call("M0THC-1")))
+            .await
+            .unwrap();
+        a.send(&connect()).await.unwrap();
+        assert!(matches!(
+            a.recv().await.unwrap(),
+            Packet::RegisterCallsignReply { success: true, .. }
+        ));
+        assert!(matches!(
+            a.recv().await.unwrap(),
+            Packet::ConnectionEstablished { .. }
+        ));
+        // And one the other way.
+        assert!(server.clients().deliver(&Packet::IncomingConnect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call("M0THC-3"),
+            dst: call("M0THC-1"),
+            text: String::new(),
+        }));
+        assert!(matches!(
+            a.recv().await.unwrap(),
+            Packet::IncomingConnect { .. }
+        ));
+
+        drop(a);
+        task.await.unwrap().unwrap();
+        let mut got = calls(&server);
+        got.sort();
+        assert_eq!(
+            got,
+            [
+                "connect M0THC-1 M0THC-2",
+                "disconnect M0THC-1 M0THC-2",
+                "disconnect M0THC-1 M0THC-3",
+            ]
+        );
+        assert!(!server.clients().is_registered(Port(0), &call("M0THC-1")));
+        assert!(!server
+            .clients()
+            .deliver(&data("M0THC-2", "M0THC-1", b"anyone?")));
+    }
+
+    #[tokio::test]
+    async fn others_connections_are_left_alone() {
+        let server = server(true);
+        let (mut a, _task_a) = client(&server);
+        let (mut b, _task_b) = client(&server);
+        a.send(&connect()).await.unwrap();
+        assert!(matches!(
+            a.recv().await.unwrap(),
+            Packet::ConnectionEstablished { .. }
+        ));
+
+        // The callsign is taken.
+        b.send(&connect()).await.unwrap();
+        assert_eq!(b.recv().await.unwrap(), disconnect("M0THC-2", "M0THC-1"));
+        b.send(&data("M0THC-1", "M0THC-2", b"hijack"))
+            .await
+            .unwrap();
+        b.send(&disconnect("M0THC-1", "M0THC-2")).await.unwrap();
+        assert_eq!(b.recv().await.unwrap(), disconnect("M0THC-2", "M0THC-1"));
+        sync(&mut b).await;
+        assert_eq!(calls(&server), ["connect M0THC-1 M0THC-2"]);
+
+        // Still A's.
+        assert!(server
+            .clients()
+            .deliver(&data("M0THC-2", "M0THC-1", b"hello")));
+        assert_eq!(
+            a.recv().await.unwrap(),
+            data("M0THC-2", "M0THC-1", b"hello")
+        );
+    }
+}
@@ -0,0 +1,1057 @@
+//! Simulated radio channel, for testing applications without radios.
+//!
+//! A `Medium` is a radio channel shared by simulated stations. Each
+//! `Station` is a TNC with an AGW endpoint, so AGW clients on different
+//! stations can talk to each other with connected mode and unproto, through
+//! digipeating stations if they like.
+//!
+//! The medium models airtime from its baud rate, latency, random frame loss,
+//! and collisions between stations that start sending before hearing each
+//! other. Stations wait for the channel to be clear before sending, and
+//! connected mode resends lost frames.
+//!
+//! Connected mode is AX.25-like, with one frame in flight at a time. It only
+//! needs to work with other simulated stations.
+//!
+//! ```no_run
+//! use agw::r#async::sim::Medium;
+//! use agw::Baud;
+//! # async fn f() -> agw::Result<()> {
+//! let medium = Medium::builder().baud(Baud::B9600).loss(0.1).build();
+//! for addr in ["127.0.0.1:8010", "127.0.0.1:8011"] {
+//!     let listener = tokio::net::TcpListener::bind(addr).await?;
+//!     tokio::spawn(medium.station().serve(listener));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+
+use super::server::{AgwBackend, Clients, Server};
+use crate::ratelimit::FRAME_OVERHEAD;
+use crate::{Baud, Call, Error, MonitorKind, Packet, Pid, Port, PortCaps, PortInfo, Result};
+
+/// Stations have one radio port.
+const PORT: Port = Port(0);
+
+const PID_NO_L3: Pid = Pid(0xF0);
+
+/// Max payload of I frames, used to pick the resend timer.
+const PACLEN: usize = 256;
+
+/// Times to resend a frame before giving up on the connection.
+const N2: u32 = 10;
+
+/// How often stations check their resend timers.
+const TICK: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Control {
+    Ui,
+    I { ns: u8, nr: u8 },
+    Rr { nr: u8 },
+    Sabm,
+    Ua,
+    Disc,
+    Dm,
+}
+
+/// AX.25 frame on the simulated channel.
+#[derive(Clone, Debug)]
+struct Frame {
+    src: Call,
+    dst: Call,
+    // Digipeater path, and whether each hop has repeated the frame.
+    via: Vec<(Call, bool)>,
+    control: Control,
+    pid: Pid,
+    data: Vec<u8>,
+}
+
+impl Frame {
+    fn new(src: &Call, dst: &Call, via: &[Call], control: Control) -> Self {
+        Self {
+            src: src.clone(),
+            dst: dst.clone(),
+            via: via.iter().map(|hop| (hop.clone(), false)).collect(),
+            control,
+            pid: PID_NO_L3,
+            data: Vec::new(),
+        }
+    }
+
+    /// Length on air, in bytes.
+    fn len(&self) -> usize {
+        FRAME_OVERHEAD + 7 * (2 + self.via.len()) + self.data.len()
+    }
+
+    /// Frame as sent on air, without FCS, or `None` if a callsign doesn't
+    /// fit an AX.25 address.
+    fn ax25(&self) -> Option<Vec<u8>> {
+        // Poll/final bit.
+        const PF: u8 = 0x10;
+        let (control, response) = match self.control {
+            Control::Ui => (0x03, false),
+            Control::I { ns, nr } => (nr << 5 | ns << 1, false),
+            Control::Rr { nr } => (0x01 | nr << 5, true),
+            Control::Sabm => (0x2f | PF, false),
+            Control::Ua => (0x63 | PF, true),
+            Control::Disc => (0x43 | PF, false),
+            Control::Dm => (0x0f | PF, true),
+        };
+        crate::pcap::encode(
+            &self.src, &self.dst, &self.via, response, control, self.pid.0, &self.data,
+        )
+    }
+
+    /// Frame as an AGW server shows it to monitoring clients.
+    fn monitor(&self) -> Packet {
+        let kind = match self.control {
+            Control::Ui => MonitorKind::Unproto,
+            Control::I { .. } => MonitorKind::Info,
+            _ => MonitorKind::Supervisory,
+        };
+        let control = match self.control {
+            Control::Ui => format!("UI pid={:02X} Len={}", self.pid.0, self.data.len()),
+            Control::I { ns, nr } => format!(
+                "I R{nr} S{ns} pid={:02X} Len={}",
+                self.pid.0,
+                self.data.len()
+            ),
+            Control::Rr { nr } => format!("RR R{nr}"),
+            Control::Sabm => "SABM P".to_string(),
+            Control::Ua => "UA F".to_string(),
+            Control::Disc => "DISC P".to_string(),
+            Control::Dm => "DM F".to_string(),
+        };
+        let via = if self.via.is_empty() {
+            String::new()
+        } else {
+            let hops: Vec<_> = self
+                .via
+                .iter()
+                .map(|(hop, repeated)| format!("{hop}{}", if *repeated { "*" } else { "" }))
+                .collect();
+            format!(" Via {}", hops.join(","))
+        };
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            % 86400;
+        let mut data = format!(
+            " {}:Fm {} To {}{via} <{control} >[{:02}:{:02}:{:02}]\r",
+            PORT.number(),
+            self.src,
+            self.dst,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+        .into_bytes();
+        data.extend(&self.data);
+        data.extend(b"\r\0");
+        Packet::Monitor {
+            kind,
+            port: PORT,
+            pid: self.pid,
+            src: self.src.clone(),
+            dst: self.dst.clone(),
+            data,
+        }
+    }
+}
+
+/// Builder for `Medium`.
+pub struct MediumBuilder {
+    baud: Baud,
+    latency: Duration,
+    loss: f64,
+    collisions: bool,
+    seed: u64,
+}
+
+impl Default for MediumBuilder {
+    fn default() -> Self {
+        Self {
+            baud: Baud::B1200,
+            latency: Duration::from_millis(10),
+            loss: 0.0,
+            collisions: true,
+            seed: 1,
+        }
+    }
+}
+
+impl MediumBuilder {
+    /// Create builder for a 1200 baud medium with 10ms latency, no random
+    /// loss, and collisions.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the bit rate. `Baud::Unknown` means 1200.
+    #[must_use]
+    pub fn baud(mut self, baud: Baud) -> Self {
+        self.baud = baud;
+        self
+    }
+
+    /// Set the time from a station starting to send until others hear it.
+    #[must_use]
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Set the chance, 0.0 to 1.0, of each station not hearing a frame.
+    #[must_use]
+    pub fn loss(mut self, loss: f64) -> Self {
+        self.loss = loss.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set whether frames sent at the same time are lost.
+    ///
+    /// If not, a station that starts sending before hearing another station
+    /// waits for it instead.
+    #[must_use]
+    pub fn collisions(mut self, collisions: bool) -> Self {
+        self.collisions = collisions;
+        self
+    }
+
+    /// Set the seed of the random loss, for repeatable runs.
+    #[must_use]
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Create the medium.
+    #[must_use]
+    pub fn build(self) -> Medium {
+        Medium {
+            inner: Arc::new(MediumInner {
+                baud: self.baud,
+                bps: self.baud.bits_per_second().unwrap_or(1200),
+                latency: self.latency,
+                loss: self.loss,
+                collisions: self.collisions,
+                // Xorshift state must not be zero.
+                rng: Mutex::new(self.seed.max(1)),
+                stations: Mutex::new(Vec::new()),
+                next_station: AtomicUsize::new(0),
+                carrier: Mutex::new(None),
+            }),
+        }
+    }
+}
+
+type StationId = usize;
+
+/// A transmission on the channel.
+struct Carrier {
+    start: Instant,
+    end: Instant,
+    collided: Arc<AtomicBool>,
+}
+
+struct MediumInner {
+    baud: Baud,
+    bps: u32,
+    latency: Duration,
+    loss: f64,
+    collisions: bool,
+    rng: Mutex<u64>,
+    stations: Mutex<Vec<(StationId, mpsc::UnboundedSender<Frame>)>>,
+    next_station: AtomicUsize,
+    carrier: Mutex<Option<Carrier>>,
+}
+
+/// Simulated radio channel, shared by stations.
+///
+/// Cheap to clone. All clones refer to the same channel.
+#[derive(Clone)]
+pub struct Medium {
+    inner: Arc<MediumInner>,
+}
+
+impl Medium {
+    /// Create builder for a medium.
+    #[must_use]
+    pub fn builder() -> MediumBuilder {
+        MediumBuilder::new()
+    }
+
+    /// Add a station to the medium.
+    #[must_use]
+    pub fn station(&self) -> Station {
+        let id = self.inner.next_station.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.inner.stations.lock().unwrap().push((id, tx));
+        Station {
+            id,
+            medium: self.clone(),
+            rx,
+            digipeat: Vec::new(),
+        }
+    }
+
+    fn airtime(&self, bytes: usize) -> Duration {
+        Duration::from_secs(8 * bytes as u64) / self.inner.bps
+    }
+
+    /// True with the configured loss probability.
+    fn lost(&self) -> bool {
+        if self.inner.loss <= 0.0 {
+            return false;
+        }
+        let mut s = self.inner.rng.lock().unwrap();
+        *s ^= *s << 13;
+        *s ^= *s >> 7;
+        *s ^= *s << 17;
+        let r = u32::try_from(*s >> 32).expect("can't happen: 32 bits");
+        f64::from(r) / f64::from(u32::MAX) < self.inner.loss
+    }
+
+    /// Send a frame, returning once it's been sent.
+    async fn transmit(&self, from: StationId, frame: Frame) {
+        let airtime = self.airtime(frame.len());
+        let latency = self.inner.latency;
+        let collided = loop {
+            let wait = {
+                let mut carrier = self.inner.carrier.lock().unwrap();
+                let now = Instant::now();
+                match carrier.as_mut() {
+                    Some(c) if now < c.end && self.inner.collisions && now < c.start + latency => {
+                        // Started before hearing the other station, so both
+                        // frames are lost.
+                        debug!("agw/sim: Collision");
+                        c.collided.store(true, Ordering::Relaxed);
+                        c.end = c.end.max(now + airtime);
+                        break c.collided.clone();
+                    }
+                    Some(c) if now < c.end => c.end - now,
+                    _ => {
+                        let collided = Arc::new(AtomicBool::new(false));
+                        *carrier = Some(Carrier {
+                            start: now,
+                            end: now + airtime,
+                            collided: collided.clone(),
+                        });
+                        break collided;
+                    }
+                }
+            };
+            tokio::time::sleep(wait).await;
+        };
+        tokio::time::sleep(airtime).await;
+
+        // The sender is free to send its next frame while this one is still
+        // on its way, so that the next one holds the channel before anyone
+        // else hears this one.
+        let medium = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(latency).await;
+            if collided.load(Ordering::Relaxed) {
+                return;
+            }
+            medium.inner.stations.lock().unwrap().retain(|(id, tx)| {
+                if *id == from || medium.lost() {
+                    return true;
+                }
+                // Stations that are gone stop listening.
+                tx.send(frame.clone()).is_ok()
+            });
+        });
+    }
+}
+
+/// Simulated TNC on a `Medium`.
+pub struct Station {
+    id: StationId,
+    medium: Medium,
+    rx: mpsc::UnboundedReceiver<Frame>,
+    digipeat: Vec<Call>,
+}
+
+impl Station {
+    /// Repeat frames whose next digipeater hop is `call`.
+    #[must_use]
+    pub fn digipeat(mut self, call: Call) -> Self {
+        self.digipeat.push(call);
+        self
+    }
+
+    /// Run the station, serving AGW clients from `listener`.
+    ///
+    /// # Errors
+    ///
+    /// If accepting fails.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        let clients = Clients::new();
+        let outstanding = Arc::new(AtomicUsize::new(0));
+        let (radio_tx, mut radio_rx) = mpsc::unbounded_channel();
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        {
+            let medium = self.medium.clone();
+            let outstanding = outstanding.clone();
+            let id = self.id;
+            tokio::spawn(async move {
+                while let Some(frame) = radio_rx.recv().await {
+                    medium.transmit(id, frame).await;
+                    outstanding.fetch_sub(1, Ordering::Relaxed);
+                }
+            });
+        }
+        let tnc = Tnc {
+            medium: self.medium.clone(),
+            clients: clients.clone(),
+            radio: radio_tx,
+            outstanding: outstanding.clone(),
+            digipeat: self.digipeat,
+            links: HashMap::new(),
+        };
+        tokio::spawn(tnc.run(self.rx, cmd_rx));
+        let backend = SimBackend {
+            baud: self.medium.inner.baud,
+            cmds: cmd_tx,
+            outstanding,
+        };
+        Server::with_clients(backend, clients).serve(listener).await
+    }
+}
+
+enum Command {
+    Connect {
+        src: Call,
+        dst: Call,
+        via: Vec<Call>,
+        pid: Pid,
+        reply: oneshot::Sender<bool>,
+    },
+    Disconnect {
+        src: Call,
+        dst: Call,
+    },
+    Data {
+        src: Call,
+        dst: Call,
+        data: Vec<u8>,
+    },
+    Unproto(Frame),
+}
+
+struct SimBackend {
+    baud: Baud,
+    cmds: mpsc::UnboundedSender<Command>,
+    outstanding: Arc<AtomicUsize>,
+}
+
+impl SimBackend {
+    fn command(&self, cmd: Command) -> Result<()> {
+        self.cmds.send(cmd).map_err(|_| Error::ConnectionClosed)
+    }
+}
+
+impl AgwBackend for SimBackend {
+    fn ports(&self) -> Vec<PortInfo> {
+        vec![PortInfo {
+            port: PORT,
+            descr: "Simulated radio".to_string(),
+        }]
+    }
+
+    fn port_caps(&self, _port: Port) -> PortCaps {
+        PortCaps {
+            max_frame: 1,
+            ..PortCaps::new(self.baud)
+        }
+    }
+
+    fn frames_outstanding(&self, _port: Port) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    async fn connect(
+        &self,
+        _port: Port,
+        pid: Pid,
+        src: Call,
+        dst: Call,
+        via: Vec<Call>,
+    ) -> Result<bool> {
+        let (reply, rx) = oneshot::channel();
+        self.command(Command::Connect {
+            src,
+            dst,
+            via,
+            pid,
+            reply,
+        })?;
+        rx.await.map_err(|_| Error::ConnectionClosed)
+    }
+
+    async fn disconnect(&self, _port: Port, _pid: Pid, src: Call, dst: Call) -> Result<()> {
+        self.command(Command::Disconnect { src, dst })
+    }
+
+    async fn data(
+        &self,
+        _port: Port,
+        _pid: Pid,
+        src: Call,
+        dst: Call,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        self.command(Command::Data { src, dst, data })
+    }
+
+    async fn unproto(
+        &self,
+        _port: Port,
+        pid: Pid,
+        src: Call,
+        dst: Call,
+        via: Vec<Call>,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let mut frame = Frame::new(&src, &dst, &via, Control::Ui);
+        frame.pid = pid;
+        frame.data = data;
+        self.command(Command::Unproto(frame))
+    }
+}
+
+enum LinkState {
+    Connecting(Option<oneshot::Sender<bool>>),
+    Connected,
+    Disconnecting,
+}
+
+/// Connected mode state of one connection.
+struct Link {
+    local: Call,
+    remote: Call,
+    via: Vec<Call>,
+    pid: Pid,
+    state: LinkState,
+    vs: u8,
+    vr: u8,
+    queue: VecDeque<Vec<u8>>,
+    // Sent, but not yet acknowledged.
+    unacked: Option<(u8, Vec<u8>)>,
+    // Disconnect once the queue is sent.
+    close: bool,
+    deadline: Option<Instant>,
+    retries: u32,
+    t1: Duration,
+}
+
+impl Link {
+    fn frame(&self, control: Control) -> Frame {
+        Frame::new(&self.local, &self.remote, &self.via, control)
+    }
+
+    fn i_frame(&self, ns: u8, data: &[u8]) -> Frame {
+        let mut frame = self.frame(Control::I { ns, nr: self.vr });
+        frame.pid = self.pid;
+        frame.data = data.to_vec();
+        frame
+    }
+
+    /// Disconnect notification for the client.
+    fn disconnected(&self) -> Packet {
+        Packet::Disconnect {
+            port: PORT,
+            pid: self.pid,
+            src: self.remote.clone(),
+            dst: self.local.clone(),
+            text: String::new(),
+        }
+    }
+
+    fn start_timer(&mut self) {
+        self.deadline = Some(Instant::now() + self.t1);
+    }
+
+    /// Handle acknowledgement of frames up to `nr`.
+    fn ack(&mut self, nr: u8) {
+        if let Some((ns, _)) = self.unacked {
+            if nr == (ns + 1) % 8 {
+                self.unacked = None;
+                self.deadline = None;
+                self.retries = 0;
+            }
+        }
+    }
+
+    /// Frame to send next, if any.
+    fn pump(&mut self) -> Option<Frame> {
+        if !matches!(self.state, LinkState::Connected) || self.unacked.is_some() {
+            return None;
+        }
+        if let Some(data) = self.queue.pop_front() {
+            let frame = self.i_frame(self.vs, &data);
+            self.unacked = Some((self.vs, data));
+            self.vs = (self.vs + 1) % 8;
+            self.start_timer();
+            return Some(frame);
+        }
+        if self.close {
+            self.state = LinkState::Disconnecting;
+            self.start_timer();
+            return Some(self.frame(Control::Disc));
+        }
+        None
+    }
+}
+
+/// Station state, run as a task.
+struct Tnc {
+    medium: Medium,
+    clients: Clients,
+    radio: mpsc::UnboundedSender<Frame>,
+    outstanding: Arc<AtomicUsize>,
+    digipeat: Vec<Call>,
+    // By (local, remote).
+    links: HashMap<(Call, Call), Link>,
+}
+
+impl Tnc {
+    async fn run(
+        mut self,
+        mut rx: mpsc::UnboundedReceiver<Frame>,
+        mut cmds: mpsc::UnboundedReceiver<Command>,
+    ) {
+        let mut tick = tokio::time::interval(TICK);
+        loop {
+            tokio::select! {
+                Some(frame) = rx.recv() => self.heard(frame),
+                cmd = cmds.recv() => match cmd {
+                    Some(cmd) => self.command(cmd),
+                    None => return,
+                },
+                _ = tick.tick() => self.timers(),
+            }
+        }
+    }
+
+    fn send(&self, frame: Frame) {
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+        if self.radio.send(frame).is_err() {
+            self.outstanding.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn deliver(&self, packet: &Packet) {
+        if !self.clients.deliver(packet) {
+            debug!("agw/sim: Nobody took {packet:?}");
+        }
+    }
+
+    /// Resend timer for a path.
+    fn t1(&self, via: &[Call]) -> Duration {
+        let hop = self
+            .medium
+            .airtime(FRAME_OVERHEAD + 7 * (2 + via.len()) + PACLEN)
+            + self.medium.inner.latency;
+        hop * 3 * u32::try_from(via.len() + 1).unwrap_or(u32::MAX)
+    }
+
+    fn new_link(
+        &self,
+        local: Call,
+        remote: Call,
+        via: Vec<Call>,
+        pid: Pid,
+        state: LinkState,
+    ) -> Link {
+        Link {
+            t1: self.t1(&via),
+            local,
+            remote,
+            via,
+            pid,
+            state,
+            vs: 0,
+            vr: 0,
+            queue: VecDeque::new(),
+            unacked: None,
+            close: false,
+            deadline: None,
+            retries: 0,
+        }
+    }
+
+    fn command(&mut self, cmd: Command) {
+        match cmd {
+            Command::Connect {
+                src,
+                dst,
+                via,
+                pid,
+                reply,
+            } => {
+                let key = (src.clone(), dst.clone());
+                if self.links.contains_key(&key) {
+                    let _ = reply.send(false);
+                    return;
+                }
+                let mut link =
+                    self.new_link(src, dst, via, pid, LinkState::Connecting(Some(reply)));
+                link.start_timer();
+                self.send(link.frame(Control::Sabm));
+                self.links.insert(key, link);
+            }
+            Command::Disconnect { src, dst } => {
+                let key = (src, dst);
+                let Some(link) = self.links.get_mut(&key) else {
+                    return;
+                };
+                if let LinkState::Connecting(reply) = &mut link.state {
+                    if let Some(reply) = reply.take() {
+                        let _ = reply.send(false);
+                    }
+                    // Tell the other end, in case it heard the SABM.
+                    let disc = link.frame(Control::Disc);
+                    self.links.remove(&key);
+                    self.send(disc);
+                    return;
+                }
+                link.close = true;
+                if let Some(frame) = link.pump() {
+                    self.send(frame);
+                }
+            }
+            Command::Data { src, dst, data } => {
+                let Some(link) = self.links.get_mut(&(src, dst)) else {
+                    debug!("agw/sim: Data for unknown link");
+                    return;
+                };
+                link.queue.extend(data.chunks(PACLEN).map(<[u8]>::to_vec));
+                if let Some(frame) = link.pump() {
+                    self.send(frame);
+                }
+            }
+            Command::Unproto(frame) => self.send(frame),
+        }
+    }
+
+    fn heard(&mut self, mut frame: Frame) {
+        self.deliver(&frame.monitor());
+        if let Some(ax25) = frame.ax25() {
+            self.deliver(&Packet::RawFrame {
+                port: PORT,
+                data: [&[0][..], &ax25].concat(),
+            });
+        }
+
+        // Frames still on their way through digipeaters are not for us,
+        // except to repeat.
+        if let Some((hop, repeated)) = frame.via.iter_mut().find(|(_, repeated)| !repeated) {
+            if self.digipeat.contains(hop) {
+                *repeated = true;
+                self.send(frame);
+            }
+            return;
+        }
+        if frame.control == Control::Ui {
+            return;
+        }
+        let key = (frame.dst.clone(), frame.src.clone());
+        let Some(link) = self.links.get_mut(&key) else {
+            self.unknown_link(&frame);
+            return;
+        };
+        match frame.control {
+            Control::Sabm => {
+                // Other end restarted the connection.
+                if let LinkState::Connecting(reply) = &mut link.state {
+                    if let Some(reply) = reply.take() {
+                        let _ = reply.send(true);
+                    }
+                }
+                link.state = LinkState::Connected;
+                (link.vs, link.vr, link.unacked, link.deadline) = (0, 0, None, None);
+                let ua = link.frame(Control::Ua);
+                self.send(ua);
+            }
+            Control::Ua => match &mut link.state {
+                LinkState::Connecting(reply) => {
+                    if let Some(reply) = reply.take() {
+                        let _ = reply.send(true);
+                    }
+                    link.state = LinkState::Connected;
+                    (link.deadline, link.retries) = (None, 0);
+                    if let Some(frame) = link.pump() {
+                        self.send(frame);
+                    }
+                }
+                LinkState::Disconnecting => {
+                    self.links.remove(&key);
+                }
+                LinkState::Connected => {}
+            },
+            Control::Dm | Control::Disc => {
+                let link = self
+                    .links
+                    .remove(&key)
+                    .expect("can't happen: checked above");
+                if frame.control == Control::Disc {
+                    self.send(link.frame(Control::Ua));
+                }
+                match link.state {
+                    LinkState::Connecting(Some(reply)) => {
+                        let _ = reply.send(false);
+                    }
+                    LinkState::Connected => self.deliver(&link.disconnected()),
+                    LinkState::Connecting(None) | LinkState::Disconnecting => {}
+                }
+            }
+            Control::I { ns, nr } => {
+                if !matches!(link.state, LinkState::Connected) {
+                    return;
+                }
+                link.ack(nr);
+                let mut packets = Vec::new();
+                if ns == link.vr {
+                    link.vr = (link.vr + 1) % 8;
+                    packets.push(Packet::Data {
+                        port: PORT,
+                        pid: frame.pid,
+                        src: link.remote.clone(),
+                        dst: link.local.clone(),
+                        data: std::mem::take(&mut frame.data),
+                    });
+                }
+                // Acknowledge even repeats, in case our ack was lost.
+                let mut frames = vec![link.frame(Control::Rr { nr: link.vr })];
+                frames.extend(link.pump());
+                for packet in &packets {
+                    self.deliver(packet);
+                }
+                for frame in frames {
+                    self.send(frame);
+                }
+            }
+            Control::Rr { nr } => {
+                link.ack(nr);
+                if let Some(frame) = link.pump() {
+                    self.send(frame);
+                }
+            }
+            Control::Ui => {}
+        }
+    }
+
+    /// Handle a connected mode frame for a connection we don't have.
+    fn unknown_link(&mut self, frame: &Frame) {
+        // Frames for other stations are none of our business.
+        if !self.clients.is_registered(PORT, &frame.dst) {
+            return;
+        }
+        let via: Vec<Call> = frame.via.iter().rev().map(|(hop, _)| hop.clone()).collect();
+        let reply = |control| Frame::new(&frame.dst, &frame.src, &via, control);
+        match frame.control {
+            Control::Sabm => {
+                let accepted = self.clients.deliver(&Packet::IncomingConnect {
+                    port: PORT,
+                    pid: PID_NO_L3,
+                    src: frame.src.clone(),
+                    dst: frame.dst.clone(),
+                    text: String::new(),
+                });
+                if accepted {
+                    info!("agw/sim: {} connected to {}", frame.src, frame.dst);
+                    let link = self.new_link(
+                        frame.dst.clone(),
+                        frame.src.clone(),
+                        via.clone(),
+                        PID_NO_L3,
+                        LinkState::Connected,
+                    );
+                    self.links
+                        .insert((frame.dst.clone(), frame.src.clone()), link);
+                    self.send(reply(Control::Ua));
+                } else {
+                    self.send(reply(Control::Dm));
+                }
+            }
+            Control::Disc | Control::I { .. } => self.send(reply(Control::Dm)),
+            _ => {}
+        }
+    }
+
+    fn timers(&mut self) {
+        let now = Instant::now();
+        let mut frames = Vec::new();
+        let mut failed = Vec::new();
+        for (key, link) in &mut self.links {
+            if link.deadline.is_none_or(|d| d > now) {
+                continue;
+            }
+            link.retries += 1;
+            if link.retries > N2 {
+                failed.push(key.clone());
+                continue;
+            }
+            link.start_timer();
+            frames.push(match (&link.state, &link.unacked) {
+                (LinkState::Connecting(_), _) => link.frame(Control::Sabm),
+                (LinkState::Disconnecting, _) => link.frame(Control::Disc),
+                (LinkState::Connected, Some((ns, data))) => link.i_frame(*ns, data),
+                (LinkState::Connected, None) => {
+                    link.deadline = None;
+                    continue;
+                }
+            });
+        }
+        for frame in frames {
+            self.send(frame);
+        }
+        for key in failed {
+            let link = self.links.remove(&key).expect("can't happen: just found");
+            info!(
+                "agw/sim: Connection {}->{} timed out",
+                link.local, link.remote
+            );
+            match link.state {
+                LinkState::Connecting(Some(reply)) => {
+                    let _ = reply.send(false);
+                }
+                LinkState::Connected => self.deliver(&link.disconnected()),
+                LinkState::Connecting(None) | LinkState::Disconnecting => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::r#async::AGW;
+
+    /// Serve the station, returning its address.
+    async fn serve(station: Station) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(station.serve(listener));
+        addr
+    }
+
+    /// Serve the station, and connect a client to it.
+    async fn client(station: Station) -> AGW {
+        AGW::new(&serve(station).await).await.unwrap()
+    }
+
+    /// Connect `a` to `b` through `via`, and exchange messages both ways.
+    async fn talk(a: &AGW, b: &AGW, via: &[Call]) {
+        let (src, dst): (Call, Call) = ("M0THC-1".parse().unwrap(), "M0THC-2".parse().unwrap());
+        let mut listener = b.listen(PORT, &dst).await.unwrap();
+        let (out, inc) = tokio::join!(
+            a.connect(PORT, PID_NO_L3, &src, &dst, via),
+            listener.accept()
+        );
+        let (mut out, mut inc) = (out.unwrap(), inc.unwrap());
+        assert_eq!(inc.dst(), &src);
+
+        // Longer than a frame, so it takes more than one.
+        let msg: Vec<u8> = (0..=255).cycle().take(600).collect();
+        out.send_message(&msg).await.unwrap();
+        assert_eq!(inc.recv_message().await.unwrap(), msg);
+        inc.send_message(b"73").await.unwrap();
+        assert_eq!(out.recv_message().await.unwrap(), b"73");
+
+        out.shutdown().await.unwrap();
+        assert!(matches!(
+            inc.recv_message().await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn lossy_connection() {
+        let medium = Medium::builder()
+            .baud(Baud::B9600)
+            .latency(Duration::from_millis(1))
+            .loss(0.2)
+            .seed(42)
+            .build();
+        let a = client(medium.station()).await;
+        let b = client(medium.station()).await;
+        talk(&a, &b, &[]).await;
+    }
+
+    #[tokio::test]
+    async fn digipeated_connection() {
+        let medium = Medium::builder()
+            .baud(Baud::B9600)
+            .latency(Duration::from_millis(1))
+            .build();
+        let digi: Call = "M0THC-9".parse().unwrap();
+        let a = client(medium.station()).await;
+        let b = client(medium.station()).await;
+        serve(medium.station().digipeat(digi.clone())).await;
+        let mut monitor = b.monitor().await.unwrap();
+        talk(&a, &b, std::slice::from_ref(&digi)).await;
+
+        // The far end heard the connection request repeated.
+        let mut repeated = false;
+        while let Ok(Ok(packet)) =
+            tokio::time::timeout(Duration::from_millis(100), monitor.recv()).await
+        {
+            if let Packet::Monitor { data, .. } = packet {
+                let text = String::from_utf8_lossy(&data);
+                repeated |= text.contains("Via M0THC-9* <SABM");
+            }
+        }
+        assert!(repeated);
+    }
+
+    #[test]
+    fn frames_encode_to_ax25() {
+        let (src, dst, digi): (Call, Call, Call) = (
+            "M0QQQ-8".parse().unwrap(),
+            "APZ001".parse().unwrap(),
+            "WIDE1-1".parse().unwrap(),
+        );
+        let mut ui = Frame::new(&src, &dst, std::slice::from_ref(&digi), Control::Ui);
+        ui.via[0].1 = true;
+        ui.data = b"hello".to_vec();
+        assert_eq!(
+            ui.ax25().unwrap(),
+            [
+                0x82, 0xa0, 0xb4, 0x60, 0x60, 0x62, 0xe0, // APZ001, command.
+                0x9a, 0x60, 0xa2, 0xa2, 0xa2, 0x40, 0x70, // M0QQQ-8.
+                0xae, 0x92, 0x88, 0x8a, 0x62, 0x40, 0xe3, // WIDE1-1*, last.
+                0x03, 0xf0, b'h', b'e', b'l', b'l', b'o',
+            ]
+        );
+
+        // Responses set the C bit of the source, and have no PID.
+        let rr = Frame::new(&src, &dst, &[], Control::Rr { nr: 3 });
+        let rr = rr.ax25().unwrap();
+        assert_eq!(rr.len(), 15);
+        assert_eq!(rr[6] & 0x80, 0);
+        assert_eq!(rr[13], 0xf1);
+        assert_eq!(rr[14], 0x01 | 3 << 5);
+
+        let i = Frame::new(&src, &dst, &[], Control::I { ns: 2, nr: 5 });
+        assert_eq!(i.ax25().unwrap()[14..], [5 << 5 | 2 << 1, 0xf0]);
+
+        let alias: Call = "LONGALIAS".parse().unwrap();
+        assert!(Frame::new(&src, &alias, &[], Control::Ui).ax25().is_none());
+    }
+}
@@ -1,15 +1,89 @@
 use crate::{Error, Result};
 
+/// Max length of the base callsign, i.e. without SSID, in AX.25.
+const MAX_BASE_LEN: usize = 6;
+
+/// Max SSID in AX.25.
+const MAX_SSID: u8 = 15;
+
 /// Callsign, including SSID.
 ///
 /// Max length is 10, because that's the max length in the AGW protocol.
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+///
+/// There are three levels of validation:
+///
+/// * `from_bytes()` is for callsigns in AGW headers. It only requires ASCII
+///   letters, digits and `-`, so that whatever the server sends, such as
+///   `M0QQQ-00`, doesn't break the connection.
+/// * `str::parse()` is for user input. AGW also carries things that are not
+///   real callsigns, such as APRS aliases, so it accepts any base of ASCII
+///   letters and digits, optionally followed by `-` and an SSID 0-15, up to
+///   10 characters in all.
+/// * `parse_strict()` additionally requires what fits in an AX.25 address:
+///   an upper case base of at most 6 characters.
+///
+/// ```
+/// use agw::Call;
+/// let call: Call = "M0QQQ-8".parse()?;
+/// assert_eq!(call.base(), "M0QQQ");
+/// assert_eq!(call.ssid(), 8);
+/// assert!("WIDE2-2".parse::<Call>().is_ok());
+/// assert!("M0QQQ-00".parse::<Call>().is_err());
+/// assert_eq!(Call::from_bytes(b"M0QQQ-00")?.ssid(), 0);
+/// assert!(Call::parse_strict("LONGALIAS").is_err());
+/// # Ok::<(), agw::Error>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Call {
     bytes: [u8; 10],
 }
 
+/// Split a callsign into base and SSID, checking the rules for user input.
+fn split(s: &[u8]) -> Result<(&[u8], u8)> {
+    let bad = |why: &str| {
+        Err(Error::InvalidCallsign(format!(
+            "callsign {:?} {why}",
+            String::from_utf8_lossy(s)
+        )))
+    };
+    let (base, ssid) = match s.iter().position(|&b| b == b'-') {
+        None => (s, 0),
+        Some(pos) => {
+            let ssid = &s[pos + 1..];
+            let valid = match ssid {
+                [d] | [b'1', d] => d.is_ascii_digit(),
+                _ => false,
+            };
+            if !valid {
+                return bad("has invalid SSID");
+            }
+            let ssid = std::str::from_utf8(ssid)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|&n| n <= MAX_SSID);
+            let Some(ssid) = ssid else {
+                return bad("has SSID out of range 0-15");
+            };
+            (&s[..pos], ssid)
+        }
+    };
+    if base.is_empty() && !s.is_empty() {
+        return bad("has empty base");
+    }
+    if let Some(item) = base.iter().find(|b| !b.is_ascii_alphanumeric()) {
+        return Err(Error::InvalidCallsign(format!(
+            "callsign includes invalid character {item:?}"
+        )));
+    }
+    Ok((base, ssid))
+}
+
 impl Call {
-    /// Create callsign from ASCII bytes.
+    /// Create callsign from ASCII bytes, as found in AGW headers.
+    ///
+    /// This is lenient: the SSID, if any, is not checked. Bytes after the
+    /// first NUL are kept as is, so that callsigns from AGW headers are
+    /// passed on unchanged. Use `str::parse()` for user input.
     ///
     /// # Errors
     ///
@@ -17,25 +91,48 @@ impl Call {
     /// characters.
     pub fn from_bytes(bytes: &[u8]) -> Result<Call> {
         if bytes.len() > 10 {
-            return Err(Error::Msg(format!(
+            return Err(Error::InvalidCallsign(format!(
                 "callsign '{bytes:?}' is longer than 10 characters"
             )));
         }
         // NOTE: Callsigns here are not just real callsigns, but also
         // virtual ones like WIDE1-1 and APZ001.
-        let mut arr = [0; 10];
-        for (i, &item) in bytes.iter().enumerate() {
-            // TODO: is slash valid?
-            if item != 0 && !item.is_ascii_alphanumeric() && item != b'-' {
-                return Err(Error::Msg(format!(
-                    "callsign includes invalid character {item:?}"
-                )));
-            }
-            arr[i] = item;
+        // TODO: is slash valid?
+        if let Some(item) = bytes
+            .iter()
+            .find(|&&b| b != 0 && !b.is_ascii_alphanumeric() && b != b'-')
+        {
+            return Err(Error::InvalidCallsign(format!(
+                "callsign includes invalid character {item:?}"
+            )));
         }
+        let mut arr = [0; 10];
+        arr[..bytes.len()].copy_from_slice(bytes);
         Ok(Call { bytes: arr })
     }
 
+    /// Parse a real AX.25 callsign, such as "M0QQQ-8".
+    ///
+    /// # Errors
+    ///
+    /// If the base callsign is longer than 6 characters or not upper case,
+    /// or the SSID is not 0-15.
+    pub fn parse_strict(s: &str) -> Result<Call> {
+        let call: Call = s.parse()?;
+        let base = call.base();
+        if base.is_empty() || base.len() > MAX_BASE_LEN {
+            return Err(Error::InvalidCallsign(format!(
+                "callsign {s:?} base is not 1-{MAX_BASE_LEN} characters"
+            )));
+        }
+        if base.bytes().any(|b| b.is_ascii_lowercase()) {
+            return Err(Error::InvalidCallsign(format!(
+                "callsign {s:?} is not upper case"
+            )));
+        }
+        Ok(call)
+    }
+
     /// Bytes of the callsign string.
     #[must_use]
     pub fn as_bytes(&self) -> &[u8] {
@@ -49,6 +146,30 @@ impl Call {
         str::from_utf8(&self.bytes).expect("can't happen: call contains non-UTF8")
     }
 
+    /// The callsign text, up to the first NUL.
+    fn text(&self) -> &[u8] {
+        let end = self.bytes.iter().position(|&b| b == 0).unwrap_or(10);
+        &self.bytes[..end]
+    }
+
+    /// Callsign without the SSID, e.g. "M0QQQ" for "M0QQQ-8".
+    #[allow(clippy::missing_panics_doc)]
+    #[must_use]
+    pub fn base(&self) -> &str {
+        let text = self.text();
+        let end = text.iter().position(|&b| b == b'-').unwrap_or(text.len());
+        str::from_utf8(&text[..end]).expect("can't happen: call contains non-UTF8")
+    }
+
+    /// SSID, or 0 if there is none.
+    ///
+    /// Callsigns from `from_bytes()` may have an SSID that isn't a number
+    /// 0-15. That is also returned as 0.
+    #[must_use]
+    pub fn ssid(&self) -> u8 {
+        split(self.text()).map_or(0, |(_, ssid)| ssid)
+    }
+
     /// Return true if the callsign is empty.
     ///
     /// Sometimes this is the correct thing, for incoming/outgoing AGW packets.
@@ -68,7 +189,62 @@ impl Call {
 impl std::str::FromStr for Call {
     type Err = Error;
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        Self::from_bytes(s.as_bytes())
+        let call = Self::from_bytes(s.as_bytes())?;
+        split(s.as_bytes())?;
+        Ok(call)
+    }
+}
+
+/// AX.25 address field: the base shifted left one bit and padded with
+/// spaces, followed by the SSID byte.
+///
+/// The SSID byte has the reserved bits set, and the C/H and extension bits
+/// clear.
+impl TryFrom<&Call> for [u8; 7] {
+    type Error = Error;
+    fn try_from(call: &Call) -> Result<[u8; 7]> {
+        let call = Call::parse_strict(&call.to_string())?;
+        let mut v = [b' ' << 1; 7];
+        for (o, b) in v.iter_mut().zip(call.base().bytes()) {
+            *o = b << 1;
+        }
+        v[6] = 0x60 | (call.ssid() << 1);
+        Ok(v)
+    }
+}
+
+/// Parse an AX.25 address field, ignoring the C/H and extension bits.
+impl TryFrom<[u8; 7]> for Call {
+    type Error = Error;
+    fn try_from(addr: [u8; 7]) -> Result<Call> {
+        let base: String = addr[..6]
+            .iter()
+            .map(|b| char::from(b >> 1))
+            .collect::<String>()
+            .trim_end_matches(' ')
+            .to_string();
+        let ssid = (addr[6] >> 1) & 0x0f;
+        if ssid == 0 {
+            Call::parse_strict(&base)
+        } else {
+            Call::parse_strict(&format!("{base}-{ssid}"))
+        }
+    }
+}
+
+/// Serialized as the callsign string, e.g. "M0QQQ-8".
+#[cfg(feature = "serde")]
+impl serde::Serialize for Call {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Call {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(serde::de::Error::custom)
     }
 }
 
@@ -84,3 +260,86 @@ impl std::fmt::Display for Call {
         write!(f, "{s}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_and_ssid() {
+        let call: Call = "M0QQQ-15".parse().unwrap();
+        assert_eq!((call.base(), call.ssid()), ("M0QQQ", 15));
+        let call: Call = "M0QQQ".parse().unwrap();
+        assert_eq!((call.base(), call.ssid()), ("M0QQQ", 0));
+        assert_eq!(call.to_string(), "M0QQQ");
+    }
+
+    #[test]
+    fn user_input_is_checked() {
+        for bad in [
+            "M0QQQ-16",
+            "M0QQQ-00",
+            "M0QQQ-",
+            "-1",
+            "M0QQQ-8-1",
+            "M0 QQ",
+            "M0QQQ/P",
+        ] {
+            assert!(
+                matches!(bad.parse::<Call>(), Err(Error::InvalidCallsign(_))),
+                "{bad:?} parsed"
+            );
+        }
+        // 10 characters in all, with or without SSID.
+        assert!("ABCDEFGHIJ".parse::<Call>().is_ok());
+        assert!("ABCDEFG-10".parse::<Call>().is_ok());
+        assert!("ABCDEFGHIJK".parse::<Call>().is_err());
+        assert!("ABCDEFGH-10".parse::<Call>().is_err());
+    }
+
+    #[test]
+    fn wire_callsigns_are_lenient() {
+        let call = Call::from_bytes(b"M0QQQ-00\0\0").unwrap();
+        assert_eq!((call.base(), call.ssid()), ("M0QQQ", 0));
+        let call = Call::from_bytes(b"M0QQQ-99").unwrap();
+        assert_eq!((call.base(), call.ssid()), ("M0QQQ", 0));
+        // Kept as is.
+        assert_eq!(
+            Call::from_bytes(b"AB\0C").unwrap().as_bytes(),
+            b"AB\0C\0\0\0\0\0\0"
+        );
+        assert!(Call::from_bytes(b"M0 QQ").is_err());
+
+        // And don't break header parsing.
+        let mut header =
+            crate::Header::new(crate::Port(0), b'D', crate::Pid(0xF0), None, None, 0).to_bytes();
+        header[8..16].copy_from_slice(b"M0QQQ-00");
+        let header = crate::parse_header(&header).unwrap();
+        assert_eq!(header.src().unwrap().to_string(), "M0QQQ-00");
+    }
+
+    #[test]
+    fn strict() {
+        assert!(Call::parse_strict("M0QQQ-8").is_ok());
+        assert!(Call::parse_strict("WIDE2-2").is_ok());
+        assert!(Call::parse_strict("m0qqq").is_err());
+        assert!(Call::parse_strict("LONGALIAS").is_err());
+        assert!(Call::parse_strict("").is_err());
+    }
+
+    #[test]
+    fn ax25_address() {
+        let call: Call = "M0QQQ-8".parse().unwrap();
+        let addr = <[u8; 7]>::try_from(&call).unwrap();
+        assert_eq!(addr, [0x9a, 0x60, 0xa2, 0xa2, 0xa2, 0x40, 0x70]);
+        // C/H and extension bits are ignored.
+        let mut flagged = addr;
+        flagged[6] |= 0x81;
+        assert_eq!(Call::try_from(flagged).unwrap(), call);
+        let call: Call = "ID".parse().unwrap();
+        assert_eq!(
+            Call::try_from(<[u8; 7]>::try_from(&call).unwrap()).unwrap(),
+            call
+        );
+    }
+}
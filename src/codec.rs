@@ -0,0 +1,151 @@
+//! tokio-util codec for AGW frames.
+//!
+//! ```no_run
+//! # async fn f() -> agw::Result<()> {
+//! use tokio_util::codec::Framed;
+//! let stream = tokio::net::TcpStream::connect("127.0.0.1:8010").await?;
+//! let framed = Framed::new(stream, agw::codec::AgwCodec::new());
+//! # Ok(())
+//! # }
+//! ```
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Error, Packet, Result, HEADER_LEN};
+
+/// Decoder and encoder of AGW frames, for use with
+/// `tokio_util::codec::Framed`.
+#[derive(Clone, Debug)]
+pub struct AgwCodec {
+    max_data_len: u32,
+}
+
+impl AgwCodec {
+    /// Create codec with the default max frame size.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_max_data_len(crate::DEFAULT_MAX_DATA_LEN)
+    }
+
+    /// Create codec that rejects frames with payloads longer than
+    /// `max_data_len`.
+    #[must_use]
+    pub fn with_max_data_len(max_data_len: u32) -> Self {
+        Self { max_data_len }
+    }
+}
+
+impl Default for AgwCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for AgwCodec {
+    type Item = Packet;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>> {
+        if let Some((packet, n)) = Packet::parse_frame(src, self.max_data_len)? {
+            src.advance(n);
+            return Ok(Some(packet));
+        }
+        // Once the header is in, make room for the rest of the frame in one go.
+        if let Some(header) = src.first_chunk::<HEADER_LEN>() {
+            let header = crate::parse_header(header)?;
+            let len = header.checked_data_len(self.max_data_len)?;
+            src.reserve(HEADER_LEN + len - src.len());
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder<Packet> for AgwCodec {
+    type Error = Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<()> {
+        self.encode(&packet, dst)
+    }
+}
+
+impl Encoder<&Packet> for AgwCodec {
+    type Error = Error;
+
+    fn encode(&mut self, packet: &Packet, dst: &mut BytesMut) -> Result<()> {
+        packet.serialize_into(&mut dst.writer())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Call, Pid, Port};
+
+    fn data(text: &str) -> Packet {
+        Packet::Data {
+            port: Port(1),
+            pid: Pid(0xF0),
+            src: "M0THC-1".parse::<Call>().unwrap(),
+            dst: "M0THC-2".parse::<Call>().unwrap(),
+            data: text.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn decodes_split_frames() {
+        let mut codec = AgwCodec::new();
+        let mut wire = BytesMut::new();
+        codec.encode(data("hello"), &mut wire).unwrap();
+        codec.encode(&data("world"), &mut wire).unwrap();
+
+        // One byte at a time.
+        let mut buf = BytesMut::new();
+        let mut got = Vec::new();
+        for b in &wire {
+            buf.put_u8(*b);
+            if let Some(packet) = codec.decode(&mut buf).unwrap() {
+                got.push(packet);
+            }
+        }
+        assert_eq!(got, vec![data("hello"), data("world")]);
+        assert!(buf.is_empty());
+
+        // Both at once.
+        let mut buf = wire.clone();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(data("hello")));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(data("world")));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_huge_frame_from_header() {
+        let mut codec = AgwCodec::with_max_data_len(4);
+        let mut wire = BytesMut::new();
+        codec.encode(data("hello"), &mut wire).unwrap();
+        wire.truncate(HEADER_LEN);
+        assert!(matches!(
+            codec.decode(&mut wire),
+            Err(Error::FrameTooLarge {
+                kind: b'D',
+                len: 5,
+                max: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn encode_validates() {
+        let mut wire = BytesMut::new();
+        let packet = Packet::Unproto {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: "M0THC-1".parse().unwrap(),
+            dst: "ID".parse().unwrap(),
+            data: vec![0; crate::MAX_INFO_LEN + 1],
+        };
+        assert!(matches!(
+            AgwCodec::new().encode(packet, &mut wire),
+            Err(Error::PayloadTooLarge { .. })
+        ));
+    }
+}
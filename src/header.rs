@@ -1,15 +1,35 @@
 use crate::{Call, Pid, Port};
+use crate::{Error, Result};
 
-#[derive(Clone, Debug)]
+/// AGW frame header.
+///
+/// Holds every byte of the header, so that `parse_header()` followed by
+/// `to_bytes()` gives back the same bytes.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Header {
+    /// Port, 0-based as on the wire.
     pub port: Port,
     pub pid: Pid,
     pub data_kind: u8,
     pub data_len: u32,
     pub src: Option<Call>,
     pub dst: Option<Call>,
+
+    /// Reserved bytes 1-3, 5 and 7, in that order. Normally zero.
+    pub reserved: [u8; 5],
+
+    /// The "user" field, bytes 32-35. Normally zero.
+    pub user: u32,
 }
 pub const HEADER_LEN: usize = 36;
+
+/// Default cap on the payload length of a received frame.
+///
+/// The length comes straight off the wire, so without a cap a single bad
+/// header makes the reader allocate up to 4GiB. Real AGW frames are at most a
+/// few hundred bytes, with port info and heard lists being the largest.
+pub const DEFAULT_MAX_DATA_LEN: u32 = 64 * 1024;
 impl Header {
     /// Create new header.
     // TODO remove this.
@@ -29,26 +49,96 @@ impl Header {
             data_len,
             src,
             dst,
+            reserved: [0; 5],
+            user: 0,
         }
     }
 
+    /// Source callsign, for packet kinds where it's mandatory.
+    ///
+    /// # Errors
+    ///
+    /// If the source callsign is empty.
+    pub fn src(&self) -> Result<Call> {
+        self.src.clone().ok_or(Error::MissingField {
+            kind: self.data_kind,
+            field: "src",
+        })
+    }
+
+    /// Destination callsign, for packet kinds where it's mandatory.
+    ///
+    /// # Errors
+    ///
+    /// If the destination callsign is empty.
+    pub fn dst(&self) -> Result<Call> {
+        self.dst.clone().ok_or(Error::MissingField {
+            kind: self.data_kind,
+            field: "dst",
+        })
+    }
+
+    /// Payload length, checked against a maximum frame size.
+    ///
+    /// # Errors
+    ///
+    /// If the payload is longer than `max`.
+    pub fn checked_data_len(&self, max: u32) -> Result<usize> {
+        if self.data_len > max {
+            return Err(Error::FrameTooLarge {
+                kind: self.data_kind,
+                len: self.data_len,
+                max,
+            });
+        }
+        Ok(usize::try_from(self.data_len)?)
+    }
+
     /// Serialize header.
     #[must_use]
     pub fn serialize(&self) -> Vec<u8> {
-        let mut v = vec![0; HEADER_LEN];
-        // Either the port parameter is not used (such as version or port info
-        // query), or it maps port 1 to byte 0, 2 to 1, etc.
-        v[0] = self.port.0.saturating_sub(1);
+        self.to_bytes().to_vec()
+    }
+
+    /// Serialize header, without allocating.
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut v = [0; HEADER_LEN];
+        v[0] = self.port.0;
+        v[1..4].copy_from_slice(&self.reserved[..3]);
         v[4] = self.data_kind;
+        v[5] = self.reserved[3];
         v[6] = self.pid.0;
+        v[7] = self.reserved[4];
 
         if let Some(src) = &self.src {
-            v.splice(8..18, src.as_bytes().iter().copied());
+            v[8..18].copy_from_slice(src.as_bytes());
         }
         if let Some(dst) = &self.dst {
-            v.splice(18..28, dst.as_bytes().iter().copied());
+            v[18..28].copy_from_slice(dst.as_bytes());
         }
-        v.splice(28..32, u32::to_le_bytes(self.data_len));
+        v[28..32].copy_from_slice(&self.data_len.to_le_bytes());
+        v[32..36].copy_from_slice(&self.user.to_le_bytes());
         v
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_len_is_capped() {
+        let mut h = Header::new(Port(0), b'D', Pid(0xF0), None, None, 100);
+        assert_eq!(h.checked_data_len(100).unwrap(), 100);
+        h.data_len = u32::MAX;
+        assert!(matches!(
+            h.checked_data_len(DEFAULT_MAX_DATA_LEN),
+            Err(Error::FrameTooLarge {
+                kind: b'D',
+                len: u32::MAX,
+                max: DEFAULT_MAX_DATA_LEN
+            })
+        ));
+    }
+}
@@ -11,8 +11,8 @@ mod call;
 mod header;
 mod packet;
 pub use call::Call;
-pub use header::{Header, HEADER_LEN};
-pub use packet::{Packet, Pid, Port};
+pub use header::{Header, DEFAULT_MAX_DATA_LEN, HEADER_LEN};
+pub use packet::{Frame, MonitorKind, Packet, Pid, Port, DATA_CHUNK_LEN, MAX_HOPS, MAX_INFO_LEN};
 
 pub mod wrap;
 
@@ -21,7 +21,17 @@ pub use v1::*;
 pub mod v2;
 
 pub mod r#async;
+#[cfg(feature = "codec")]
+pub mod codec;
+pub mod pcap;
+pub mod policy;
 pub mod proxy;
+pub mod ratelimit;
+pub mod record;
+pub mod tap;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tnc2;
 
 #[derive(thiserror::Error, Debug, Clone)]
 pub enum Error {
@@ -39,6 +49,92 @@ pub enum Error {
     #[error("From int error")]
     IntConvert(#[from] std::num::TryFromIntError),
 
+    /// A callsign that can't be represented, e.g. too long or with invalid
+    /// characters.
+    #[error("Invalid callsign: {0}")]
+    InvalidCallsign(String),
+
+    /// A line that can't be parsed as TNC2 monitor format.
+    #[error("Invalid TNC2 line: {0}")]
+    InvalidTnc2(String),
+
+    /// A policy rule file that can't be parsed.
+    #[error("Invalid policy on line {line}: {msg}")]
+    InvalidPolicy { line: usize, msg: String },
+
+    /// A signed message failed verification, e.g. because it was tampered
+    /// with, signed with an unknown key, or replayed.
+    #[error("Bad signature: {0}")]
+    BadSignature(String),
+
+    /// A packet payload had the wrong length for its kind.
+    #[error("Bad packet length for kind {:?}: expected {expected}, got {got}", char::from(*kind))]
+    BadPacketLength {
+        kind: u8,
+        expected: usize,
+        got: usize,
+    },
+
+    /// The header announced a payload longer than the configured maximum.
+    #[error("Frame of kind {:?} too large: {len} > {max}", char::from(*kind))]
+    FrameTooLarge { kind: u8, len: u32, max: u32 },
+
+    /// A packet to be sent has a payload longer than allowed for its kind.
+    #[error("Payload for kind {:?} too large: {len} > {max}", char::from(*kind))]
+    PayloadTooLarge { kind: u8, len: usize, max: usize },
+
+    /// A connect path has more digipeaters than AX.25 allows.
+    #[error("Too many hops: {got} > {max}")]
+    TooManyHops { max: usize, got: usize },
+
+    /// The header had a data kind this crate doesn't know about.
+    #[error("Unknown packet kind {:?}", char::from(*.0))]
+    UnknownPacketKind(u8),
+
+    /// A header field required for the packet kind, such as the source
+    /// callsign, was empty.
+    #[error("Packet kind {:?} missing {field}", char::from(*kind))]
+    MissingField { kind: u8, field: &'static str },
+
+    /// The packet payload could not be parsed.
+    #[error("Bad payload for kind {:?}: {msg}", char::from(*kind))]
+    BadPayload { kind: u8, msg: String },
+
+    /// A session recording that can't be read, e.g. because it's not a
+    /// recording, or is corrupt.
+    #[error("Bad recording: {0}")]
+    BadRecording(String),
+
+    /// A pcap file that can't be read, e.g. because it's not pcap, not
+    /// AX.25, or is corrupt.
+    #[error("Bad pcap file: {0}")]
+    BadPcap(String),
+
+    /// The other end of a replayed session did not send what was recorded.
+    #[error("Replay diverged at record #{index}: expected {expected:?}, got {got:?}")]
+    ReplayDiverged {
+        index: usize,
+        expected: Box<Packet>,
+        got: Box<Packet>,
+    },
+
+    /// The port does not exist on the AGW endpoint.
+    #[error("No such port {0:?}")]
+    NoSuchPort(Port),
+
+    /// The AGW connection, or the AX.25 connection on top of it, was closed.
+    #[error("Connection closed")]
+    ConnectionClosed,
+
+    /// Timed out waiting for a reply.
+    #[error("Timed out")]
+    Timeout,
+
+    /// The request was refused, e.g. the remote station did not accept the
+    /// connection.
+    #[error("Rejected: {0}")]
+    Rejected(String),
+
     /// A wrapper around another error.
     #[error("{msg:?}: {source:?}")]
     Other {
@@ -70,7 +166,135 @@ impl Error {
             msg: None,
         }
     }
+    fn bad_payload<T: Into<String>>(kind: u8, msg: T) -> Error {
+        Error::BadPayload {
+            kind,
+            msg: msg.into(),
+        }
+    }
 }
 
 /// Result convenience type.
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Fill `buf` from `r`, like `read_exact()`, but return `false` if the
+/// stream ends before the first byte. Ending partway is still an error.
+pub(crate) fn read_exact_or_eof(
+    r: &mut impl std::io::Read,
+    buf: &mut [u8],
+) -> std::io::Result<bool> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) if n == 0 => return Ok(false),
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(m) => n += m,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "testing")]
+    fn connect() -> (Call, Call, Packet) {
+        let me: Call = "M0THC-1".parse().unwrap();
+        let peer: Call = "M0THC-2".parse().unwrap();
+        let packet = Packet::Connect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: me.clone(),
+            dst: peer.clone(),
+        };
+        (me, peer, packet)
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn closed_agw_connection_is_connection_closed() {
+        use crate::testing::{pair, Script};
+        let (me, peer, packet) = connect();
+        let (agw, mock) = pair(Script::new().expect(packet)).unwrap();
+        let con = agw.connect(Port(0), Pid(0xF0), &me, &peer, &[]);
+        let (con, ()) = tokio::join!(con, async {
+            mock.finish().await.unwrap();
+        });
+        assert!(matches!(con.err(), Some(Error::ConnectionClosed)));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_connect_is_timeout() {
+        use crate::testing::{pair, Script};
+        let (me, peer, packet) = connect();
+        let (agw, _mock) = pair(Script::new().expect(packet)).unwrap();
+        let con = agw.connect(Port(0), Pid(0xF0), &me, &peer, &[]).await;
+        assert!(matches!(con.err(), Some(Error::Timeout)));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn refused_connect_is_rejected() {
+        use crate::testing::{pair, Script};
+        let (me, peer, packet) = connect();
+        let script = Script::new().expect(packet).reply(Packet::Disconnect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: peer.clone(),
+            dst: me.clone(),
+            text: String::new(),
+        });
+        let (agw, _mock) = pair(script).unwrap();
+        let con = agw.connect(Port(0), Pid(0xF0), &me, &peer, &[]).await;
+        assert!(matches!(con.err(), Some(Error::Rejected(_))));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn missing_port_is_no_such_port() {
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let script = crate::testing::Script::new()
+            .expect(Packet::PortInfoQuery)
+            .reply(Packet::PortInfoReply(PortsInfo {
+                count: 1,
+                ports: vec![PortInfo {
+                    port: Port(0),
+                    descr: "Only port".to_string(),
+                }],
+            }));
+        let mock = crate::testing::BlockingMock::start(script, server).unwrap();
+        let mut agw = AGW::from_streams(client.try_clone().unwrap(), client);
+        let caps = agw.port_cap(Port(1));
+        assert!(matches!(caps, Err(Error::NoSuchPort(Port(1)))), "{caps:?}");
+        mock.finish().unwrap();
+    }
+
+    #[test]
+    fn kinds_display_as_chars() {
+        let e = Error::BadPacketLength {
+            kind: b'R',
+            expected: 8,
+            got: 3,
+        };
+        assert_eq!(
+            e.to_string(),
+            "Bad packet length for kind 'R': expected 8, got 3"
+        );
+        assert_eq!(
+            Error::UnknownPacketKind(b'Q').to_string(),
+            "Unknown packet kind 'Q'"
+        );
+        assert_eq!(
+            Error::MissingField {
+                kind: b'D',
+                field: "src"
+            }
+            .to_string(),
+            "Packet kind 'D' missing src"
+        );
+    }
+}
@@ -2,7 +2,7 @@ use log::{debug, trace};
 use std::fmt::Write;
 
 use crate::v1::{Baud, PortCaps, PortInfo, PortsInfo};
-use crate::{Call, Header};
+use crate::{Call, Header, HEADER_LEN};
 use crate::{Error, Result};
 
 const CMD_VERSION: u8 = b'R';
@@ -11,21 +11,162 @@ const CMD_CONNECT: u8 = b'C';
 const CMD_CONNECT_VIA: u8 = b'v';
 const CMD_DISCONNECT: u8 = b'd';
 const CMD_REGISTER_CALLSIGN: u8 = b'X';
+const CMD_UNREGISTER_CALLSIGN: u8 = b'x';
 const CMD_DATA: u8 = b'D';
 const CMD_UNPROTO: u8 = b'M';
+const CMD_UNPROTO_VIA: u8 = b'V';
+const CMD_TOGGLE_MONITOR: u8 = b'm';
+const CMD_RAW: u8 = b'K';
+const CMD_TOGGLE_RAW: u8 = b'k';
 const CMD_PORT_INFO: u8 = b'G';
 const CMD_CALLSIGN_HEARD: u8 = b'H';
 const CMD_PORT_CAP: u8 = b'g';
 
-/// Port number.
+/// Max number of digipeaters in an AX.25 path.
+pub const MAX_HOPS: usize = 7;
+
+/// Max length of an AX.25 information field.
+pub const MAX_INFO_LEN: usize = 256;
+
+/// Max payload of each AGW frame when sending connected mode data.
+///
+/// See `Packet::split_data()`.
+pub const DATA_CHUNK_LEN: usize = 200;
+
+/// Digipeater path, as sent in 'v' and 'V' frames: a hop count, and then 10
+/// bytes per hop.
+fn via_bytes(via: &[Call]) -> Result<Vec<u8>> {
+    let mut hops = Vec::with_capacity(1 + via.len() * 10);
+    hops.push(u8::try_from(via.len())?);
+    for call in via {
+        hops.extend_from_slice(call.as_bytes());
+    }
+    Ok(hops)
+}
+
+/// Parse digipeater path from the start of a 'v' or 'V' payload, returning
+/// the path and the rest of the payload.
+fn parse_via(kind: u8, data: &[u8]) -> Result<(Vec<Call>, &[u8])> {
+    let Some(&nhops) = data.first() else {
+        return Err(Error::BadPacketLength {
+            kind,
+            expected: 1,
+            got: 0,
+        });
+    };
+    let len = 1 + usize::from(nhops) * 10;
+    if data.len() < len {
+        return Err(Error::BadPacketLength {
+            kind,
+            expected: len,
+            got: data.len(),
+        });
+    }
+    let via = data[1..len]
+        .chunks_exact(10)
+        .map(Call::from_bytes)
+        .collect::<Result<Vec<_>>>()?;
+    Ok((via, &data[len..]))
+}
+
+/// Write one AGW frame.
+fn frame<W: std::io::Write + ?Sized>(
+    w: &mut W,
+    port: Port,
+    kind: u8,
+    pid: Pid,
+    src: Option<&Call>,
+    dst: Option<&Call>,
+    data: &[u8],
+) -> Result<()> {
+    let header = Header::new(
+        port,
+        kind,
+        pid,
+        src.cloned(),
+        dst.cloned(),
+        u32::try_from(data.len())?,
+    );
+    w.write_all(&header.to_bytes())?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+/// Port number, as sent on the wire.
+///
+/// This is 0-based everywhere in this crate: `Port(0)` is the first radio
+/// port. AGW servers list that port as "Port1" in port info replies, which
+/// is what `number()` returns.
+///
+/// ```
+/// use agw::Port;
+/// assert_eq!(Port::from_number(1), Some(Port(0)));
+/// assert_eq!(Port(0).number(), 1);
+/// assert_eq!(Port::from_number(0), None);
+/// ```
 #[derive(Copy, Clone, Debug, PartialEq, Hash, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Port(pub u8);
 
+impl Port {
+    /// Port from the 1-based number used in port info replies.
+    #[must_use]
+    pub fn from_number(n: u16) -> Option<Port> {
+        Some(Port(u8::try_from(n.checked_sub(1)?).ok()?))
+    }
+
+    /// The 1-based port number used in port info replies.
+    #[must_use]
+    pub fn number(self) -> u16 {
+        u16::from(self.0) + 1
+    }
+}
+
+/// Kind of monitored frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MonitorKind {
+    /// 'U': UI frame.
+    Unproto,
+
+    /// 'I': Connected mode information frame, between other stations.
+    Info,
+
+    /// 'S': Supervisory frame.
+    Supervisory,
+
+    /// 'T': Frame sent by this AGW server.
+    Sent,
+}
+
+impl MonitorKind {
+    fn from_byte(b: u8) -> Option<MonitorKind> {
+        Some(match b {
+            b'U' => MonitorKind::Unproto,
+            b'I' => MonitorKind::Info,
+            b'S' => MonitorKind::Supervisory,
+            b'T' => MonitorKind::Sent,
+            _ => return None,
+        })
+    }
+
+    fn byte(self) -> u8 {
+        match self {
+            MonitorKind::Unproto => b'U',
+            MonitorKind::Info => b'I',
+            MonitorKind::Supervisory => b'S',
+            MonitorKind::Sent => b'T',
+        }
+    }
+}
+
 /// PID number.
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Pid(pub u8);
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Packet {
     /// Application: Version query.
     VersionQuery,
@@ -78,6 +219,9 @@ pub enum Packet {
     PortInfoReply(PortsInfo),
 
     RegisterCallsign(Port, Call),
+
+    /// Application: Unregister callsign. There is no reply.
+    UnregisterCallsign(Port, Call),
     Connect {
         port: Port,
         pid: Pid,
@@ -91,23 +235,41 @@ pub enum Packet {
         dst: Call,
         via: Vec<Call>,
     },
+
+    /// AGWPE: Someone connected to us.
+    ///
+    /// `text` is the "*** CONNECTED To Station" text as sent by the AGW
+    /// server. If empty, the standard text is generated when serializing.
     IncomingConnect {
         port: Port,
         pid: Pid,
         src: Call,
         dst: Call,
+        text: String,
     },
+
+    /// AGWPE: Outgoing connection is up.
+    ///
+    /// `text` is the "*** CONNECTED With Station" text as sent by the AGW
+    /// server. If empty, the standard text is generated when serializing.
     ConnectionEstablished {
         port: Port,
         pid: Pid,
         src: Call,
         dst: Call,
+        text: String,
     },
+
+    /// Disconnect request, or notification of disconnection.
+    ///
+    /// Applications send this with an empty `text`. AGW servers usually
+    /// include a "*** DISCONNECTED From Station" text.
     Disconnect {
         port: Port,
         pid: Pid,
         src: Call,
         dst: Call,
+        text: String,
     },
     Unproto {
         port: Port,
@@ -116,6 +278,54 @@ pub enum Packet {
         dst: Call,
         data: Vec<u8>,
     },
+
+    /// Application: Send UI frame via digipeaters.
+    UnprotoVia {
+        port: Port,
+        pid: Pid,
+        src: Call,
+        dst: Call,
+        via: Vec<Call>,
+        data: Vec<u8>,
+    },
+
+    /// Application: Turn reception of monitored frames on or off.
+    ///
+    /// Each one sent flips the setting. It starts out off.
+    ToggleMonitor,
+
+    /// AGWPE: Monitored frame.
+    ///
+    /// `data` is the AGW server's text description of the frame, such as
+    /// `" 1:Fm M0QQQ-8 To APZ001 Via WIDE1-1* <UI pid=F0 Len=5 >[12:00:00]\r"`,
+    /// followed by the frame payload. See `tnc2` for a more readable form.
+    Monitor {
+        kind: MonitorKind,
+        port: Port,
+        pid: Pid,
+        src: Call,
+        dst: Call,
+        data: Vec<u8>,
+    },
+
+    /// Application: Turn reception of raw frames on or off.
+    ///
+    /// Each one sent flips the setting. It starts out off.
+    ToggleRaw,
+
+    /// Raw AX.25 frame, received or to send.
+    ///
+    /// `data` is a KISS command byte, normally 0, followed by the AX.25
+    /// frame without FCS.
+    RawFrame {
+        port: Port,
+        data: Vec<u8>,
+    },
+
+    /// Connected mode data.
+    ///
+    /// This is sent as a single AGW frame. Use `split_data()` to split long
+    /// payloads into frames the AGW server will accept.
     Data {
         port: Port,
         pid: Pid,
@@ -124,183 +334,353 @@ pub enum Packet {
         data: Vec<u8>,
     },
     // FramesOutstandingConnection(u32), // Y
-    // HeardStations(String) // H
-    // MonitorConnected(Vec<u8>) // I
-    // MonitorSupervisory(Vec<u8>) // S
-    // Raw() // R.
-    // Unknown
+    /// A frame of a kind this crate doesn't know about, or one that the
+    /// typed packets can't represent byte for byte.
+    ///
+    /// The header still gives the length, so the stream stays framed. This
+    /// keeps the header and payload as they were, so that the frame can be
+    /// passed on unchanged, e.g. by a proxy.
+    Unknown {
+        header: Header,
+        data: Vec<u8>,
+    },
 }
 
 impl Packet {
     /// Serialize packet for AGW connection.
-    #[allow(clippy::too_many_lines)]
+    ///
+    /// This does not check protocol limits. Use `try_serialize()` or
+    /// `serialize_into()` for that.
     #[allow(clippy::missing_panics_doc)]
     #[must_use]
     pub fn serialize(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        self.write_frames(&mut v)
+            .expect("packet can't be encoded, use try_serialize()");
+        v
+    }
+
+    /// Serialize packet for AGW connection, after checking that it's valid.
+    ///
+    /// # Errors
+    ///
+    /// If the packet breaks protocol limits, such as too many hops or too
+    /// long a payload, or is missing callsigns or has invalid ones.
+    pub fn try_serialize(&self) -> Result<Vec<u8>> {
+        let mut v = Vec::new();
+        self.serialize_into(&mut v)?;
+        Ok(v)
+    }
+
+    /// Serialize packet into a writer, after checking that it's valid.
+    ///
+    /// # Errors
+    ///
+    /// If the packet is invalid (see `try_serialize()`), or writing fails.
+    pub fn serialize_into<W: std::io::Write + ?Sized>(&self, w: &mut W) -> Result<()> {
+        self.validate()?;
+        self.write_frames(w)
+    }
+
+    /// Check that the packet can be sent as is.
+    ///
+    /// # Errors
+    ///
+    /// If the packet breaks protocol limits, such as too many hops or too
+    /// long a payload, or is missing callsigns or has invalid ones.
+    pub fn validate(&self) -> Result<()> {
+        fn call(kind: u8, field: &'static str, call: &Call) -> Result<()> {
+            if call.is_empty() {
+                return Err(Error::MissingField { kind, field });
+            }
+            // Same rules as user input, which allow aliases that don't fit
+            // an AX.25 address. Header callsigns from `from_bytes()` such as
+            // "M0QQQ-00" are refused.
+            call.to_string().parse::<Call>()?;
+            Ok(())
+        }
+        fn payload(kind: u8, len: usize, max: usize) -> Result<()> {
+            if len > max {
+                Err(Error::PayloadTooLarge { kind, len, max })
+            } else {
+                Ok(())
+            }
+        }
+        let kind = self.kind();
         match self {
-            Packet::VersionQuery => {
-                Header::new(Port(0), CMD_VERSION, Pid(0), None, None, 0).serialize()
-            }
-            Packet::FramesOutstandingPortQuery(port) => {
-                Header::new(*port, CMD_FRAMES_OUTSTANDING_PORT, Pid(0), None, None, 0).serialize()
-            }
-            Packet::FramesOutstandingPortReply(port, n) => [
-                Header::new(*port, CMD_FRAMES_OUTSTANDING_PORT, Pid(0), None, None, 4).serialize(),
-                u32::try_from(*n)
-                    .expect("can't happen. Has to fit")
-                    .to_le_bytes()
-                    .to_vec(),
-            ]
-            .concat(),
+            Packet::RegisterCallsign(_, c)
+            | Packet::UnregisterCallsign(_, c)
+            | Packet::RegisterCallsignReply { call: c, .. } => {
+                call(kind, "src", c)?;
+            }
+            Packet::Connect { src, dst, .. }
+            | Packet::IncomingConnect { src, dst, .. }
+            | Packet::ConnectionEstablished { src, dst, .. }
+            | Packet::Disconnect { src, dst, .. } => {
+                call(kind, "src", src)?;
+                call(kind, "dst", dst)?;
+            }
+            Packet::ConnectVia { src, dst, via, .. } => {
+                call(kind, "src", src)?;
+                call(kind, "dst", dst)?;
+                if via.len() > MAX_HOPS {
+                    return Err(Error::TooManyHops {
+                        max: MAX_HOPS,
+                        got: via.len(),
+                    });
+                }
+                for hop in via {
+                    call(kind, "via", hop)?;
+                }
+            }
+            Packet::Unproto { src, dst, data, .. } => {
+                call(kind, "src", src)?;
+                call(kind, "dst", dst)?;
+                payload(kind, data.len(), MAX_INFO_LEN)?;
+            }
+            Packet::UnprotoVia {
+                src,
+                dst,
+                via,
+                data,
+                ..
+            } => {
+                call(kind, "src", src)?;
+                call(kind, "dst", dst)?;
+                if via.len() > MAX_HOPS {
+                    return Err(Error::TooManyHops {
+                        max: MAX_HOPS,
+                        got: via.len(),
+                    });
+                }
+                for hop in via {
+                    call(kind, "via", hop)?;
+                }
+                payload(kind, data.len(), MAX_INFO_LEN)?;
+            }
+            Packet::Data { src, dst, data, .. } | Packet::Monitor { src, dst, data, .. } => {
+                call(kind, "src", src)?;
+                call(kind, "dst", dst)?;
+                payload(kind, data.len(), crate::DEFAULT_MAX_DATA_LEN as usize)?;
+            }
+            Packet::FramesOutstandingPortReply(_, n) => {
+                u32::try_from(*n)?;
+            }
+            Packet::CallsignHeardReply { data, .. }
+            | Packet::RawFrame { data, .. }
+            | Packet::Unknown { data, .. } => {
+                payload(kind, data.len(), crate::DEFAULT_MAX_DATA_LEN as usize)?;
+            }
+            Packet::VersionQuery
+            | Packet::ToggleMonitor
+            | Packet::ToggleRaw
+            | Packet::VersionReply { .. }
+            | Packet::FramesOutstandingPortQuery(_)
+            | Packet::PortCapQuery(_)
+            | Packet::PortCapReply { .. }
+            | Packet::CallsignHeardQuery(_)
+            | Packet::PortInfoQuery
+            | Packet::PortInfoReply(_) => {}
+        }
+        Ok(())
+    }
+
+    /// The AGW data kind byte used for this packet.
+    #[must_use]
+    pub fn kind(&self) -> u8 {
+        match self {
+            Packet::VersionQuery | Packet::VersionReply { .. } => CMD_VERSION,
+            Packet::FramesOutstandingPortQuery(_) | Packet::FramesOutstandingPortReply(_, _) => {
+                CMD_FRAMES_OUTSTANDING_PORT
+            }
+            Packet::RegisterCallsign(_, _) | Packet::RegisterCallsignReply { .. } => {
+                CMD_REGISTER_CALLSIGN
+            }
+            Packet::UnregisterCallsign(_, _) => CMD_UNREGISTER_CALLSIGN,
+            Packet::PortCapQuery(_) | Packet::PortCapReply { .. } => CMD_PORT_CAP,
+            Packet::CallsignHeardQuery(_) | Packet::CallsignHeardReply { .. } => CMD_CALLSIGN_HEARD,
+            Packet::PortInfoQuery | Packet::PortInfoReply(_) => CMD_PORT_INFO,
+            Packet::Connect { .. }
+            | Packet::IncomingConnect { .. }
+            | Packet::ConnectionEstablished { .. } => CMD_CONNECT,
+            Packet::ConnectVia { .. } => CMD_CONNECT_VIA,
+            Packet::Disconnect { .. } => CMD_DISCONNECT,
+            Packet::Unproto { .. } => CMD_UNPROTO,
+            Packet::UnprotoVia { .. } => CMD_UNPROTO_VIA,
+            Packet::ToggleMonitor => CMD_TOGGLE_MONITOR,
+            Packet::Monitor { kind, .. } => kind.byte(),
+            Packet::ToggleRaw => CMD_TOGGLE_RAW,
+            Packet::RawFrame { .. } => CMD_RAW,
+            Packet::Data { .. } => CMD_DATA,
+            Packet::Unknown { header, .. } => header.data_kind,
+        }
+    }
+
+    /// The AGW header this packet is sent with.
+    ///
+    /// Useful for looking at fields, such as the PID, the same way for all
+    /// kinds of packets, including `Unknown`.
+    ///
+    /// # Errors
+    ///
+    /// If the packet can't be encoded at all.
+    pub fn header(&self) -> Result<Header> {
+        let mut v = Vec::new();
+        self.write_frames(&mut v)?;
+        let header = v
+            .first_chunk::<HEADER_LEN>()
+            .ok_or_else(|| Error::msg("can't happen: frame shorter than header"))?;
+        crate::parse_header(header)
+    }
+
+    /// Write the AGW frame(s) for this packet, without checking protocol
+    /// limits.
+    #[allow(clippy::too_many_lines)]
+    fn write_frames<W: std::io::Write + ?Sized>(&self, w: &mut W) -> Result<()> {
+        match self {
+            Packet::VersionQuery => frame(w, Port(0), CMD_VERSION, Pid(0), None, None, &[]),
+            Packet::FramesOutstandingPortQuery(port) => frame(
+                w,
+                *port,
+                CMD_FRAMES_OUTSTANDING_PORT,
+                Pid(0),
+                None,
+                None,
+                &[],
+            ),
+            Packet::FramesOutstandingPortReply(port, n) => frame(
+                w,
+                *port,
+                CMD_FRAMES_OUTSTANDING_PORT,
+                Pid(0),
+                None,
+                None,
+                &u32::try_from(*n)?.to_le_bytes(),
+            ),
             Packet::VersionReply { major, minor } => {
-                let data = vec![
-                    u8::try_from(*major & 0xff).expect("can't happen"),
-                    (*major >> 8) as u8,
-                    0,
-                    0,
-                    u8::try_from(*minor & 0xff).expect("can't happen"),
-                    (*minor >> 8) as u8,
-                    0,
-                    0,
-                ];
-                [
-                    Header::new(
-                        Port(0),
-                        CMD_VERSION,
-                        Pid(0),
-                        None,
-                        None,
-                        u32::try_from(data.len()).expect("can't happen"),
-                    )
-                    .serialize(),
-                    data,
-                ]
-                .concat()
+                let [major0, major1] = major.to_le_bytes();
+                let [minor0, minor1] = minor.to_le_bytes();
+                frame(
+                    w,
+                    Port(0),
+                    CMD_VERSION,
+                    Pid(0),
+                    None,
+                    None,
+                    &[major0, major1, 0, 0, minor0, minor1, 0, 0],
+                )
             }
             Packet::RegisterCallsignReply {
                 port,
                 call,
                 success,
-            } => [
-                Header::new(
-                    *port,
-                    CMD_REGISTER_CALLSIGN,
-                    Pid(0),
-                    Some(call.clone()),
-                    None,
-                    1,
-                )
-                .serialize(),
-                vec![u8::from(*success)],
-            ]
-            .concat(),
+            } => frame(
+                w,
+                *port,
+                CMD_REGISTER_CALLSIGN,
+                Pid(0),
+                Some(call),
+                None,
+                &[u8::from(*success)],
+            ),
             Packet::Connect {
                 port,
                 pid,
                 src,
                 dst,
-            } => {
-                Header::new(*port, b'C', *pid, Some(src.clone()), Some(dst.clone()), 0).serialize()
-            }
+            } => frame(w, *port, CMD_CONNECT, *pid, Some(src), Some(dst), &[]),
             Packet::IncomingConnect {
                 port,
                 pid,
                 src,
                 dst,
-            } => [
-                Header::new(
+                text,
+            } => {
+                let text = if text.is_empty() {
+                    format!("*** CONNECTED To Station {}", src.as_str())
+                } else {
+                    text.clone()
+                };
+                frame(
+                    w,
                     *port,
                     CMD_CONNECT,
                     *pid,
-                    Some(src.clone()),
-                    Some(dst.clone()),
-                    u32::try_from(
-                        format!("*** CONNECTED To Station {}", src.as_str())
-                            .as_bytes()
-                            .len(),
-                    )
-                    .expect("can't happen"),
+                    Some(src),
+                    Some(dst),
+                    text.as_bytes(),
                 )
-                .serialize(),
-                format!("*** CONNECTED To Station {}", src.as_str())
-                    .as_bytes()
-                    .to_vec(),
-            ]
-            .concat(),
+            }
             Packet::ConnectionEstablished {
                 port,
                 pid,
                 src,
                 dst,
-            } => [
-                Header::new(
+                text,
+            } => {
+                let text = if text.is_empty() {
+                    format!("*** CONNECTED With Station {}", src.as_str())
+                } else {
+                    text.clone()
+                };
+                frame(
+                    w,
                     *port,
                     CMD_CONNECT,
                     *pid,
-                    Some(src.clone()),
-                    Some(dst.clone()),
-                    u32::try_from(
-                        format!("*** CONNECTED With Station {}", src.as_str())
-                            .as_bytes()
-                            .len(),
-                    )
-                    .expect("can't happen"),
+                    Some(src),
+                    Some(dst),
+                    text.as_bytes(),
                 )
-                .serialize(),
-                format!("*** CONNECTED With Station {}", src.as_str())
-                    .as_bytes()
-                    .to_vec(),
-            ]
-            .concat(),
+            }
             Packet::ConnectVia {
                 port,
                 pid,
                 src,
                 dst,
                 via,
-            } => {
-                /*
-                const MAX_HOPS: usize = 7;
-                if via.len() > MAX_HOPS {
-                    return Err(Error::msg(format!(
-                    "tried to connect through too many hops: {} > {MAX_HOPS}",
-                    via.len()
-                    )));
-                }
-                */
-                let mut hops = Vec::new();
-                hops.push(u8::try_from(via.len()).expect("TODO: error or something"));
-                for call in via {
-                    hops.extend_from_slice(call.as_bytes());
-                }
-                let h = Header::new(
-                    *port,
-                    CMD_CONNECT_VIA,
-                    *pid,
-                    Some(src.clone()),
-                    Some(dst.clone()),
-                    u32::try_from(hops.len()).expect("TODO: error or something"),
-                )
-                .serialize();
-                [h, hops.clone()].concat()
-            }
-            Packet::RegisterCallsign(port, src) => Header::new(
+            } => frame(
+                w,
+                *port,
+                CMD_CONNECT_VIA,
+                *pid,
+                Some(src),
+                Some(dst),
+                &via_bytes(via)?,
+            ),
+            Packet::RegisterCallsign(port, src) => frame(
+                w,
                 *port,
                 CMD_REGISTER_CALLSIGN,
                 Pid(0),
-                Some(src.clone()),
+                Some(src),
+                None,
+                &[],
+            ),
+            Packet::UnregisterCallsign(port, src) => frame(
+                w,
+                *port,
+                CMD_UNREGISTER_CALLSIGN,
+                Pid(0),
+                Some(src),
                 None,
-                0,
-            )
-            .serialize(),
+                &[],
+            ),
             Packet::Disconnect {
                 port,
                 pid,
                 src,
                 dst,
-            } => {
-                Header::new(*port, b'd', *pid, Some(src.clone()), Some(dst.clone()), 0).serialize()
-            }
+                text,
+            } => frame(
+                w,
+                *port,
+                CMD_DISCONNECT,
+                *pid,
+                Some(src),
+                Some(dst),
+                text.as_bytes(),
+            ),
             Packet::Data {
                 port,
                 pid,
@@ -308,91 +688,74 @@ impl Packet {
                 dst,
                 data,
             } => {
-                let mut chunks = Vec::new();
                 trace!("agw: Sending data with pid {pid:?}");
-                // TODO: magic number.
-                for chunk in data.chunks(200) {
-                    chunks.push(
-                        Header::new(
-                            *port,
-                            CMD_DATA,
-                            *pid,
-                            Some(src.clone()),
-                            Some(dst.clone()),
-                            u32::try_from(chunk.len())
-                                .expect("TODO: error this, or make it impossible"),
-                        )
-                        .serialize(),
-                    );
-                    chunks.push(chunk.to_vec());
-                }
-                chunks
+                frame(w, *port, CMD_DATA, *pid, Some(src), Some(dst), data)
             }
-            .concat(),
             Packet::Unproto {
                 port,
                 pid,
                 src,
                 dst,
                 data,
-            } => [
-                Header::new(
+            } => frame(w, *port, CMD_UNPROTO, *pid, Some(src), Some(dst), data),
+            Packet::UnprotoVia {
+                port,
+                pid,
+                src,
+                dst,
+                via,
+                data,
+            } => {
+                let mut payload = via_bytes(via)?;
+                payload.extend_from_slice(data);
+                frame(
+                    w,
                     *port,
-                    CMD_UNPROTO,
+                    CMD_UNPROTO_VIA,
                     *pid,
-                    Some(src.clone()),
-                    Some(dst.clone()),
-                    u32::try_from(data.len()).expect("TODO: return err or something"),
+                    Some(src),
+                    Some(dst),
+                    &payload,
                 )
-                .serialize(),
-                data.clone(),
-            ]
-            .concat(),
-            Packet::PortInfoQuery => {
-                Header::new(Port(0), CMD_PORT_INFO, Pid(0), None, None, 0).serialize()
             }
+            Packet::ToggleMonitor => frame(w, Port(0), CMD_TOGGLE_MONITOR, Pid(0), None, None, &[]),
+            Packet::Monitor {
+                kind,
+                port,
+                pid,
+                src,
+                dst,
+                data,
+            } => frame(w, *port, kind.byte(), *pid, Some(src), Some(dst), data),
+            Packet::ToggleRaw => frame(w, Port(0), CMD_TOGGLE_RAW, Pid(0), None, None, &[]),
+            Packet::RawFrame { port, data } => frame(w, *port, CMD_RAW, Pid(0), None, None, data),
+            Packet::PortInfoQuery => frame(w, Port(0), CMD_PORT_INFO, Pid(0), None, None, &[]),
             Packet::PortInfoReply(info) => {
                 let mut payload = format!("{};", info.count);
                 for port in &info.ports {
-                    let _ = write!(payload, "Port{} {};", port.port.0, port.descr);
+                    let _ = write!(payload, "Port{} {};", port.port.number(), port.descr);
                 }
                 payload.push('\0');
-                [
-                    Header::new(
-                        Port(0),
-                        CMD_PORT_INFO,
-                        Pid(0),
-                        None,
-                        None,
-                        u32::try_from(payload.len()).expect("can't happen"),
-                    )
-                    .serialize(),
-                    payload.into_bytes(),
-                ]
-                .concat()
-            }
-            Packet::CallsignHeardQuery(port) => {
-                Header::new(*port, CMD_CALLSIGN_HEARD, Pid(0), None, None, 0).serialize()
-            }
-            Packet::CallsignHeardReply { port, data } => [
-                Header::new(
-                    *port,
-                    CMD_CALLSIGN_HEARD,
+                frame(
+                    w,
+                    Port(0),
+                    CMD_PORT_INFO,
                     Pid(0),
                     None,
                     None,
-                    u32::try_from(data.len()).expect("can't happen"),
+                    payload.as_bytes(),
                 )
-                .serialize(),
-                data.clone(),
-            ]
-            .concat(),
-            Packet::PortCapQuery(port) => {
-                Header::new(*port, CMD_PORT_CAP, Pid(0), None, None, 0).serialize()
-            }
-            Packet::PortCapReply { port, caps } => [
-                Header::new(*port, CMD_PORT_CAP, Pid(0), None, None, 12).serialize(),
-                vec![
+            }
+            Packet::CallsignHeardQuery(port) => {
+                frame(w, *port, CMD_CALLSIGN_HEARD, Pid(0), None, None, &[])
+            }
+            Packet::CallsignHeardReply { port, data } => {
+                frame(w, *port, CMD_CALLSIGN_HEARD, Pid(0), None, None, data)
+            }
+            Packet::PortCapQuery(port) => frame(w, *port, CMD_PORT_CAP, Pid(0), None, None, &[]),
+            Packet::PortCapReply { port, caps } => {
+                let mut data = [0; 12];
+                data[..8].copy_from_slice(&[
                     match caps.rate {
                         Baud::Unknown => 0xff,
                         Baud::B1200 => 0,
@@ -407,14 +770,142 @@ impl Packet {
                     caps.slot_time,
                     caps.max_frame,
                     caps.active_connections,
-                ],
-                caps.bytes_per_2min.to_le_bytes().to_vec(),
-            ]
-            .concat(),
+                ]);
+                data[8..].copy_from_slice(&caps.bytes_per_2min.to_le_bytes());
+                frame(w, *port, CMD_PORT_CAP, Pid(0), None, None, &data)
+            }
+            Packet::Unknown { header, data } => {
+                let mut header = header.clone();
+                header.data_len = u32::try_from(data.len())?;
+                w.write_all(&header.to_bytes())?;
+                w.write_all(data)?;
+                Ok(())
+            }
         }
     }
-    #[allow(clippy::too_many_lines)]
+
+    /// Split a `Data` packet into packets of at most `chunk_len` bytes of
+    /// payload each.
+    ///
+    /// Other packets, and empty `Data` packets, are returned as is.
+    ///
+    /// # Panics
+    ///
+    /// If `chunk_len` is zero.
+    #[must_use]
+    pub fn split_data(self, chunk_len: usize) -> Vec<Packet> {
+        match self {
+            Packet::Data {
+                port,
+                pid,
+                src,
+                dst,
+                data,
+            } if data.len() > chunk_len => data
+                .chunks(chunk_len)
+                .map(|chunk| Packet::Data {
+                    port,
+                    pid,
+                    src: src.clone(),
+                    dst: dst.clone(),
+                    data: chunk.to_vec(),
+                })
+                .collect(),
+            other => vec![other],
+        }
+    }
+
+    /// Parse one packet from the start of a buffer of AGW stream data.
+    ///
+    /// Returns the packet and the number of bytes it took up, or `None` if
+    /// the buffer does not yet hold a whole frame. This makes it possible to
+    /// read from the stream in whatever chunks arrive, without losing partial
+    /// frames.
+    ///
+    /// Like `parse()`, the packet serializes back to the same bytes. Use
+    /// `Frame::parse_buf()` for the typed packet of non-canonical frames.
+    ///
+    /// # Errors
+    ///
+    /// If the frame is malformed, or its payload is longer than
+    /// `max_data_len`.
+    pub fn parse_frame(buf: &[u8], max_data_len: u32) -> Result<Option<(Packet, usize)>> {
+        let Some(header) = buf.first_chunk::<HEADER_LEN>() else {
+            return Ok(None);
+        };
+        let header = crate::parse_header(header)?;
+        let len = header.checked_data_len(max_data_len)?;
+        let Some(data) = buf.get(HEADER_LEN..HEADER_LEN + len) else {
+            return Ok(None);
+        };
+        Ok(Some((Self::parse(&header, data)?, HEADER_LEN + len)))
+    }
+
+    /// Parse packet from header and payload.
+    ///
+    /// The packet serializes back to exactly the bytes it was parsed from.
+    /// Frames of unknown kind, and frames that the typed packet can't
+    /// reproduce byte for byte, such as ones with nonzero reserved header
+    /// bytes, are returned as `Packet::Unknown`. That way they can still be
+    /// passed on unchanged, and newer server extensions don't break the
+    /// connection. Use `parse_lossy()` or `Frame` to get the typed packet for
+    /// those too.
+    ///
+    /// # Errors
+    ///
+    /// If the packet is of a known kind, but malformed.
     pub fn parse(header: &Header, data: &[u8]) -> Result<Packet> {
+        let packet = Self::parse_lossy(header, data)?;
+        if matches!(packet, Packet::Unknown { .. }) {
+            return Ok(packet);
+        }
+        let exact = packet.try_serialize().is_ok_and(|bytes| {
+            bytes.get(..HEADER_LEN) == Some(&header.to_bytes()[..]) && bytes[HEADER_LEN..] == *data
+        });
+        if exact {
+            return Ok(packet);
+        }
+        debug!(
+            "agw: Keeping non-canonical {:?} frame as is",
+            char::from(header.data_kind)
+        );
+        Ok(Packet::Unknown {
+            header: header.clone(),
+            data: data.to_vec(),
+        })
+    }
+
+    /// Parse packet from header and payload, into a typed packet if the kind
+    /// is known.
+    ///
+    /// Unlike `parse()`, anything the typed packet can't hold, such as
+    /// nonzero reserved header bytes, is dropped. Frames of unknown kind are
+    /// still returned as `Packet::Unknown`.
+    ///
+    /// # Errors
+    ///
+    /// If the packet is of a known kind, but malformed.
+    pub fn parse_lossy(header: &Header, data: &[u8]) -> Result<Packet> {
+        match Self::parse_strict(header, data) {
+            Err(Error::UnknownPacketKind(kind)) => {
+                debug!("agw: Got unknown packet kind {:?}", char::from(kind));
+                Ok(Packet::Unknown {
+                    header: header.clone(),
+                    data: data.to_vec(),
+                })
+            }
+            other => other,
+        }
+    }
+
+    /// Parse packet from header and payload, like `parse_lossy()`, but
+    /// rejecting unknown kinds.
+    ///
+    /// # Errors
+    ///
+    /// If the packet is malformed, or of a kind this crate doesn't know.
+    #[allow(clippy::too_many_lines)]
+    pub fn parse_strict(header: &Header, data: &[u8]) -> Result<Packet> {
         Ok(match header.data_kind {
             CMD_VERSION => {
                 if data.is_empty() {
@@ -434,21 +925,16 @@ impl Packet {
                     );
                     Packet::VersionReply { major, minor }
                 } else {
-                    return Err(Error::msg(format!(
-                        "version packet had wrong length {}, {data:?}",
-                        header.data_kind
-                    )));
+                    return Err(Error::BadPacketLength {
+                        kind: header.data_kind,
+                        expected: 8,
+                        got: data.len(),
+                    });
                 }
             }
             CMD_CONNECT => {
-                let src = header
-                    .src
-                    .clone()
-                    .ok_or(Error::msg("connect missing src"))?;
-                let dst = header
-                    .dst
-                    .clone()
-                    .ok_or(Error::msg("connect missing src"))?;
+                let src = header.src()?;
+                let dst = header.dst()?;
                 if data.is_empty() {
                     debug!("agw: Got Connect {src:?} to {dst:?}");
                     Packet::Connect {
@@ -458,7 +944,8 @@ impl Packet {
                         dst,
                     }
                 } else {
-                    let s = String::from_utf8(data.to_vec()).map_err(Error::other)?;
+                    let s = std::str::from_utf8(data)
+                        .map_err(|e| Error::bad_payload(header.data_kind, e.to_string()))?;
                     if s.starts_with("*** CONNECTED WITH")
                         || s.starts_with("*** CONNECTED With Station ")
                     {
@@ -468,6 +955,7 @@ impl Packet {
                             pid: header.pid,
                             src,
                             dst,
+                            text: s.to_string(),
                         }
                     } else if s.starts_with("*** CONNECTED To Station") {
                         debug!("agw: Got IncomingConnect {s}");
@@ -476,34 +964,26 @@ impl Packet {
                             pid: header.pid,
                             src,
                             dst,
+                            text: s.to_string(),
                         }
                     } else {
-                        return Err(Error::msg(format!("unknown C {s}")));
+                        return Err(Error::bad_payload(
+                            header.data_kind,
+                            format!("unknown connect text {s:?}"),
+                        ));
                     }
                 }
             }
             CMD_CONNECT_VIA => {
-                let src = header
-                    .src
-                    .clone()
-                    .ok_or(Error::msg("connect via missing src"))?;
-                let dst = header
-                    .dst
-                    .clone()
-                    .ok_or(Error::msg("connect via missing dst"))?;
-                let Some(&nhops) = data.first() else {
-                    return Err(Error::msg("connect via missing hop count"));
-                };
-                let expected = 1 + usize::from(nhops) * 10;
-                if data.len() != expected {
-                    return Err(Error::msg(format!(
-                        "connect via had wrong length {} != {expected}",
-                        data.len()
-                    )));
-                }
-                let mut via = Vec::with_capacity(usize::from(nhops));
-                for chunk in data[1..].chunks_exact(10) {
-                    via.push(Call::from_bytes(chunk)?);
+                let src = header.src()?;
+                let dst = header.dst()?;
+                let (via, rest) = parse_via(header.data_kind, data)?;
+                if !rest.is_empty() {
+                    return Err(Error::BadPacketLength {
+                        kind: header.data_kind,
+                        expected: data.len() - rest.len(),
+                        got: data.len(),
+                    });
                 }
                 debug!("agw: Got ConnectVia from {src:?} to {dst:?} via {via:?}");
                 Packet::ConnectVia {
@@ -517,46 +997,73 @@ impl Packet {
             CMD_DISCONNECT => Packet::Disconnect {
                 port: header.port,
                 pid: header.pid,
-                src: header
-                    .src
-                    .clone()
-                    .ok_or(Error::msg("disconnect missing src"))?,
-                dst: header
-                    .dst
-                    .clone()
-                    .ok_or(Error::msg("disconnect missing dst"))?,
+                src: header.src()?,
+                dst: header.dst()?,
+                text: String::from_utf8_lossy(data).into_owned(),
             },
             CMD_UNPROTO => Packet::Unproto {
                 port: header.port,
                 pid: header.pid,
-                src: header
-                    .src
-                    .clone()
-                    .ok_or(Error::msg("unproto with missing src"))?,
-                dst: header
-                    .dst
-                    .clone()
-                    .ok_or(Error::msg("unproto with missing dst"))?,
+                src: header.src()?,
+                dst: header.dst()?,
+                data: data.to_vec(),
+            },
+            CMD_UNPROTO_VIA => {
+                let (via, rest) = parse_via(header.data_kind, data)?;
+                Packet::UnprotoVia {
+                    port: header.port,
+                    pid: header.pid,
+                    src: header.src()?,
+                    dst: header.dst()?,
+                    via,
+                    data: rest.to_vec(),
+                }
+            }
+            CMD_TOGGLE_MONITOR | CMD_TOGGLE_RAW => {
+                if !data.is_empty() {
+                    return Err(Error::BadPacketLength {
+                        kind: header.data_kind,
+                        expected: 0,
+                        got: data.len(),
+                    });
+                }
+                if header.data_kind == CMD_TOGGLE_RAW {
+                    Packet::ToggleRaw
+                } else {
+                    Packet::ToggleMonitor
+                }
+            }
+            CMD_RAW => Packet::RawFrame {
+                port: header.port,
+                data: data.to_vec(),
+            },
+            kind if MonitorKind::from_byte(kind).is_some() => Packet::Monitor {
+                kind: MonitorKind::from_byte(kind).expect("can't happen: checked above"),
+                port: header.port,
+                pid: header.pid,
+                src: header.src()?,
+                dst: header.dst()?,
                 data: data.to_vec(),
             },
             CMD_DATA => Packet::Data {
                 port: header.port,
                 pid: header.pid,
-                src: header
-                    .src
-                    .clone()
-                    .ok_or(Error::msg("data with missing src"))?,
-                dst: header
-                    .dst
-                    .clone()
-                    .ok_or(Error::msg("data with missing dst"))?,
+                src: header.src()?,
+                dst: header.dst()?,
                 data: data.to_vec(),
             },
+            CMD_UNREGISTER_CALLSIGN => {
+                if !data.is_empty() {
+                    return Err(Error::BadPacketLength {
+                        kind: header.data_kind,
+                        expected: 0,
+                        got: data.len(),
+                    });
+                }
+                Packet::UnregisterCallsign(header.port, header.src()?)
+            }
             CMD_REGISTER_CALLSIGN => {
-                let call = header
-                    .src
-                    .clone()
-                    .ok_or(Error::msg("callsign packet missing src"))?;
+                let call = header.src()?;
                 if data.is_empty() {
                     Packet::RegisterCallsign(header.port, call)
                 } else if data.len() == 1 {
@@ -566,10 +1073,11 @@ impl Packet {
                         success: data[0] != 0,
                     }
                 } else {
-                    return Err(Error::msg(format!(
-                        "callsign registration packet had wrong length {}, {data:?}",
-                        data.len()
-                    )));
+                    return Err(Error::BadPacketLength {
+                        kind: header.data_kind,
+                        expected: 1,
+                        got: data.len(),
+                    });
                 }
             }
             CMD_FRAMES_OUTSTANDING_PORT => {
@@ -580,42 +1088,46 @@ impl Packet {
                         header.port,
                         usize::try_from(u32::from_le_bytes(
                             data.try_into().expect("can't happen: bytes to u32"),
-                        ))
-                        .expect("TODO: some error"),
+                        ))?,
                     )
                 } else {
-                    return Err(Error::msg(format!(
-                        "frames outstanding packet had wrong length {}, {data:?}",
-                        data.len()
-                    )));
+                    return Err(Error::BadPacketLength {
+                        kind: header.data_kind,
+                        expected: 4,
+                        got: data.len(),
+                    });
                 }
             }
             CMD_PORT_INFO => {
                 if data.is_empty() {
                     Packet::PortInfoQuery
                 } else {
-                    let s = std::str::from_utf8(data).map_err(Error::other)?;
+                    let kind = header.data_kind;
+                    let s = std::str::from_utf8(data)
+                        .map_err(|e| Error::bad_payload(kind, e.to_string()))?;
                     let mut parts = s.splitn(2, ';');
                     let count = parts
                         .next()
-                        .ok_or(Error::msg("port info reply missing count"))?
+                        .ok_or(Error::bad_payload(kind, "port info reply missing count"))?
                         .parse()
-                        .map_err(Error::other)?;
+                        .map_err(|e| Error::bad_payload(kind, format!("port count: {e}")))?;
                     let ports = parts
                         .next()
-                        .ok_or(Error::msg("port info reply missing ports"))?
+                        .ok_or(Error::bad_payload(kind, "port info reply missing ports"))?
                         .split(';')
                         .map(std::string::ToString::to_string)
                         .filter(|s| !s.is_empty() && s != "\0")
                         .map(|entry| {
                             let entry = entry.trim_end_matches('\0');
-                            let rest = entry
-                                .strip_prefix("Port")
-                                .ok_or(Error::msg(format!("bad port line {entry:?}")))?;
-                            let split = rest
-                                .find(char::is_whitespace)
-                                .ok_or(Error::msg(format!("bad port line {entry:?}")))?;
-                            let port = Port(rest[..split].parse().map_err(Error::other)?);
+                            let bad =
+                                || Error::bad_payload(kind, format!("bad port line {entry:?}"));
+                            let rest = entry.strip_prefix("Port").ok_or_else(bad)?;
+                            let split = rest.find(char::is_whitespace).ok_or_else(bad)?;
+                            let port = rest[..split]
+                                .parse()
+                                .ok()
+                                .and_then(Port::from_number)
+                                .ok_or_else(bad)?;
                             Ok::<_, Error>(PortInfo {
                                 port,
                                 descr: rest[split..].trim_start().to_string(),
@@ -662,18 +1174,831 @@ impl Packet {
                         },
                     }
                 } else {
-                    return Err(Error::msg(format!(
-                        "port cap reply had wrong length {}, {data:?}",
-                        data.len()
-                    )));
+                    return Err(Error::BadPacketLength {
+                        kind: header.data_kind,
+                        expected: 12,
+                        got: data.len(),
+                    });
+                }
+            }
+            _ => return Err(Error::UnknownPacketKind(header.data_kind)),
+        })
+    }
+}
+
+/// A received AGW frame: the parsed packet, along with the header and
+/// payload it was parsed from.
+///
+/// `Packet::parse()` turns frames that the typed packet can't reproduce,
+/// such as ones with nonzero reserved header bytes or `user` field, into
+/// `Packet::Unknown`. A `Frame` holds the typed packet from
+/// `Packet::parse_lossy()` instead, along with the original bytes, so that
+/// e.g. a proxy can both route on the packet and pass frames on byte for
+/// byte.
+///
+/// ```
+/// use agw::{Frame, Header, Packet, Pid, Port};
+/// let mut header = Header::new(
+///     Port(0),
+///     b'D',
+///     Pid(0xF0),
+///     Some("M0THC-1".parse()?),
+///     Some("M0THC-2".parse()?),
+///     2,
+/// );
+/// header.user = 42;
+/// let bytes = [&header.to_bytes()[..], b"hi"].concat();
+/// let (frame, _) = Frame::parse_buf(&bytes, 100)?.unwrap();
+/// assert!(matches!(frame.packet(), Packet::Data { .. }));
+/// assert_eq!(frame.to_bytes(), bytes);
+/// # Ok::<(), agw::Error>(())
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    header: Header,
+    data: Vec<u8>,
+    packet: Packet,
+}
+
+impl Frame {
+    /// Parse frame from header and payload, like `Packet::parse_lossy()`.
+    ///
+    /// # Errors
+    ///
+    /// If the packet is of a known kind, but malformed.
+    pub fn parse(header: Header, data: Vec<u8>) -> Result<Frame> {
+        let packet = Packet::parse_lossy(&header, &data)?;
+        Ok(Frame {
+            header,
+            data,
+            packet,
+        })
+    }
+
+    /// Parse one frame from the start of a buffer of AGW stream data.
+    ///
+    /// Like `Packet::parse_frame()`, returns `None` if the buffer does not
+    /// yet hold a whole frame.
+    ///
+    /// # Errors
+    ///
+    /// If the frame is malformed, or its payload is longer than
+    /// `max_data_len`.
+    pub fn parse_buf(buf: &[u8], max_data_len: u32) -> Result<Option<(Frame, usize)>> {
+        let Some(header) = buf.first_chunk::<HEADER_LEN>() else {
+            return Ok(None);
+        };
+        let header = crate::parse_header(header)?;
+        let len = header.checked_data_len(max_data_len)?;
+        let Some(data) = buf.get(HEADER_LEN..HEADER_LEN + len) else {
+            return Ok(None);
+        };
+        Ok(Some((
+            Self::parse(header, data.to_vec())?,
+            HEADER_LEN + len,
+        )))
+    }
+
+    /// Serialize a packet into a frame.
+    ///
+    /// # Errors
+    ///
+    /// If the packet is invalid. See `Packet::try_serialize()`.
+    pub fn from_packet(packet: Packet) -> Result<Frame> {
+        let mut data = packet.try_serialize()?;
+        let header = data
+            .first_chunk::<HEADER_LEN>()
+            .ok_or_else(|| Error::msg("can't happen: frame shorter than header"))?;
+        let header = crate::parse_header(header)?;
+        data.drain(..HEADER_LEN);
+        Ok(Frame {
+            header,
+            data,
+            packet,
+        })
+    }
+
+    /// The parsed packet.
+    #[must_use]
+    pub fn packet(&self) -> &Packet {
+        &self.packet
+    }
+
+    /// Consume the frame, returning the parsed packet.
+    #[must_use]
+    pub fn into_packet(self) -> Packet {
+        self.packet
+    }
+
+    /// Header as received.
+    #[must_use]
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Payload as received.
+    #[must_use]
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The frame exactly as received.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.header.to_bytes()[..], &self.data].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Frame;
+
+    fn call(s: &str) -> Call {
+        s.parse().unwrap()
+    }
+
+    fn header(kind: u8, src: Option<&str>, dst: Option<&str>, len: usize) -> Header {
+        Header::new(
+            Port(0),
+            kind,
+            Pid(0xF0),
+            src.map(call),
+            dst.map(call),
+            u32::try_from(len).unwrap(),
+        )
+    }
+
+    #[test]
+    fn short_payload_is_bad_length() {
+        let h = header(b'R', None, None, 3);
+        assert!(matches!(
+            Packet::parse(&h, &[1, 2, 3]),
+            Err(Error::BadPacketLength {
+                kind: b'R',
+                expected: 8,
+                got: 3
+            })
+        ));
+        let h = header(b'g', None, None, 11);
+        assert!(matches!(
+            Packet::parse(&h, &[0; 11]),
+            Err(Error::BadPacketLength {
+                kind: b'g',
+                expected: 12,
+                got: 11
+            })
+        ));
+    }
+
+    #[test]
+    fn missing_callsign_is_missing_field() {
+        let h = header(b'D', None, Some("M0THC-2"), 1);
+        assert!(matches!(
+            Packet::parse(&h, b"x"),
+            Err(Error::MissingField {
+                kind: b'D',
+                field: "src"
+            })
+        ));
+        let h = header(b'D', Some("M0THC-1"), None, 1);
+        assert!(matches!(
+            Packet::parse(&h, b"x"),
+            Err(Error::MissingField {
+                kind: b'D',
+                field: "dst"
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_kind_is_typed_error_in_strict_mode() {
+        let h = header(b'Q', None, None, 0);
+        assert!(matches!(
+            Packet::parse_strict(&h, &[]),
+            Err(Error::UnknownPacketKind(b'Q'))
+        ));
+    }
+
+    #[test]
+    fn bad_connect_text_is_bad_payload() {
+        let h = header(b'C', Some("M0THC-1"), Some("M0THC-2"), 5);
+        assert!(matches!(
+            Packet::parse(&h, b"hello"),
+            Err(Error::BadPayload { kind: b'C', .. })
+        ));
+    }
+
+    #[test]
+    fn parse_frame_rejects_huge_frame_before_payload_arrives() {
+        let mut bytes = header(b'D', Some("M0THC-1"), Some("M0THC-2"), 0).to_bytes();
+        bytes[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            Packet::parse_frame(&bytes, crate::DEFAULT_MAX_DATA_LEN),
+            Err(Error::FrameTooLarge { kind: b'D', .. })
+        ));
+    }
+
+    #[test]
+    fn parse_frame_waits_for_whole_frame() {
+        let bytes = Packet::Data {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call("M0THC-1"),
+            dst: call("M0THC-2"),
+            data: b"hello".to_vec(),
+        }
+        .serialize();
+        for n in 0..bytes.len() {
+            assert!(Packet::parse_frame(&bytes[..n], 100).unwrap().is_none());
+        }
+        let (_, n) = Packet::parse_frame(&bytes, 100).unwrap().unwrap();
+        assert_eq!(n, bytes.len());
+    }
+
+    #[test]
+    fn unknown_kind_keeps_stream_framed() {
+        let mut unknown = header(b'Q', None, None, 3);
+        unknown.user = 42;
+        let mut buf = unknown.to_bytes().to_vec();
+        buf.extend_from_slice(&[1, 2, 3]);
+        let data = Packet::Data {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call("M0THC-1"),
+            dst: call("M0THC-2"),
+            data: b"hello".to_vec(),
+        };
+        buf.extend_from_slice(&data.serialize());
+
+        let (first, n) = Packet::parse_frame(&buf, 100).unwrap().unwrap();
+        assert_eq!(
+            first,
+            Packet::Unknown {
+                header: unknown,
+                data: vec![1, 2, 3]
+            }
+        );
+        // Passed on unchanged.
+        assert_eq!(first.serialize(), buf[..n]);
+        let (second, m) = Packet::parse_frame(&buf[n..], 100).unwrap().unwrap();
+        assert_eq!(second, data);
+        assert_eq!(n + m, buf.len());
+    }
+
+    #[test]
+    fn try_serialize_checks_limits() {
+        let unproto = |via: usize, len: usize| Packet::UnprotoVia {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call("M0THC-1"),
+            dst: call("APZ001"),
+            via: vec![call("WIDE1-1"); via],
+            data: vec![b'x'; len],
+        };
+        let ok = unproto(MAX_HOPS, MAX_INFO_LEN);
+        let mut v = Vec::new();
+        ok.serialize_into(&mut v).unwrap();
+        assert_eq!(v, ok.try_serialize().unwrap());
+        assert_eq!(v, ok.serialize());
+
+        assert!(matches!(
+            unproto(MAX_HOPS + 1, 1).try_serialize(),
+            Err(Error::TooManyHops { max: MAX_HOPS, got }) if got == MAX_HOPS + 1
+        ));
+        assert!(matches!(
+            unproto(1, MAX_INFO_LEN + 1).try_serialize(),
+            Err(Error::PayloadTooLarge { kind: b'V', len, max: MAX_INFO_LEN }) if len == MAX_INFO_LEN + 1
+        ));
+    }
+
+    #[test]
+    fn try_serialize_requires_callsigns() {
+        let packet = Packet::Connect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call("M0THC-1"),
+            dst: Call::from_bytes(&[]).unwrap(),
+        };
+        assert!(matches!(
+            packet.try_serialize(),
+            Err(Error::MissingField {
+                kind: b'C',
+                field: "dst"
+            })
+        ));
+        let mut v = Vec::new();
+        assert!(packet.serialize_into(&mut v).is_err());
+        assert!(v.is_empty(), "nothing written for invalid packet");
+    }
+
+    #[test]
+    fn try_serialize_checks_callsigns() {
+        for (bad, field) in [
+            (b"M0QQQ-00".as_slice(), "src"),
+            (b"M0QQQ-16", "dst"),
+            (b"-1", "via"),
+        ] {
+            let bad = Call::from_bytes(bad).unwrap();
+            let mut packet = Packet::ConnectVia {
+                port: Port(0),
+                pid: Pid(0xF0),
+                src: call("M0THC-1"),
+                dst: call("M0THC-2"),
+                via: vec![call("WIDE1-1")],
+            };
+            let Packet::ConnectVia { src, dst, via, .. } = &mut packet else {
+                unreachable!();
+            };
+            match field {
+                "src" => *src = bad.clone(),
+                "dst" => *dst = bad.clone(),
+                _ => via[0] = bad.clone(),
+            }
+            assert!(
+                matches!(packet.try_serialize(), Err(Error::InvalidCallsign(_))),
+                "{bad} as {field}"
+            );
+        }
+        // Aliases that don't fit an AX.25 address are still allowed.
+        let packet = Packet::Unproto {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call("M0THC-1"),
+            dst: call("LONGALIAS"),
+            data: b"hi".to_vec(),
+        };
+        packet.try_serialize().unwrap();
+    }
+
+    /// One packet of every variant.
+    #[allow(clippy::too_many_lines)]
+    fn all_variants() -> Vec<Packet> {
+        let port = Port(1);
+        let pid = Pid(0xF0);
+        let src = call("M0THC-1");
+        let dst = call("APZ001");
+        let mut raw = header(b'Q', None, None, 3);
+        raw.reserved = [1, 2, 3, 4, 5];
+        raw.user = 0x1234_5678;
+        vec![
+            Packet::VersionQuery,
+            Packet::VersionReply {
+                major: 2005,
+                minor: 127,
+            },
+            Packet::FramesOutstandingPortQuery(port),
+            Packet::FramesOutstandingPortReply(port, 3),
+            Packet::RegisterCallsign(port, src.clone()),
+            Packet::UnregisterCallsign(port, src.clone()),
+            Packet::RegisterCallsignReply {
+                port,
+                call: src.clone(),
+                success: false,
+            },
+            Packet::PortInfoQuery,
+            Packet::PortInfoReply(PortsInfo {
+                count: 2,
+                ports: vec![
+                    PortInfo {
+                        port: Port(0),
+                        descr: "First".to_string(),
+                    },
+                    PortInfo {
+                        port: Port(1),
+                        descr: "Second port".to_string(),
+                    },
+                ],
+            }),
+            Packet::PortCapQuery(port),
+            Packet::PortCapReply {
+                port,
+                caps: PortCaps {
+                    rate: Baud::B9600,
+                    traffic_level: Some(3),
+                    tx_delay: 30,
+                    tx_tail: 10,
+                    persist: 63,
+                    slot_time: 10,
+                    max_frame: 4,
+                    active_connections: 1,
+                    bytes_per_2min: 1234,
+                },
+            },
+            Packet::CallsignHeardQuery(port),
+            Packet::CallsignHeardReply {
+                port,
+                data: b"M0THC-2 heard".to_vec(),
+            },
+            Packet::Connect {
+                port,
+                pid,
+                src: src.clone(),
+                dst: dst.clone(),
+            },
+            Packet::ConnectVia {
+                port,
+                pid,
+                src: src.clone(),
+                dst: dst.clone(),
+                via: vec![call("WIDE1-1"), call("WIDE2-2")],
+            },
+            Packet::IncomingConnect {
+                port,
+                pid,
+                src: src.clone(),
+                dst: dst.clone(),
+                text: "*** CONNECTED To Station M0THC-1\r\0".to_string(),
+            },
+            Packet::ConnectionEstablished {
+                port,
+                pid,
+                src: src.clone(),
+                dst: dst.clone(),
+                text: "*** CONNECTED With Station M0THC-1\r\0".to_string(),
+            },
+            Packet::Disconnect {
+                port,
+                pid,
+                src: src.clone(),
+                dst: dst.clone(),
+                text: "*** DISCONNECTED From Station M0THC-1\r\0".to_string(),
+            },
+            Packet::Unproto {
+                port,
+                pid,
+                src: src.clone(),
+                dst: dst.clone(),
+                data: b"hello".to_vec(),
+            },
+            Packet::UnprotoVia {
+                port,
+                pid,
+                src: src.clone(),
+                dst: dst.clone(),
+                via: vec![call("WIDE1-1")],
+                data: b"hello".to_vec(),
+            },
+            Packet::ToggleMonitor,
+            Packet::Monitor {
+                kind: MonitorKind::Unproto,
+                port,
+                pid,
+                src: src.clone(),
+                dst: dst.clone(),
+                data: b" 2:Fm M0THC-1 To APZ001 <UI pid=F0 Len=5 >[12:00:00]\rhello\r\0".to_vec(),
+            },
+            Packet::ToggleRaw,
+            Packet::RawFrame {
+                port,
+                data: vec![0, 0x82, 0xa0, 0xb4, 0x60],
+            },
+            Packet::Data {
+                port,
+                pid,
+                src,
+                dst,
+                data: vec![0, 1, 2, 255],
+            },
+            Packet::Unknown {
+                header: raw,
+                data: vec![0, 0xc0, 0x42],
+            },
+        ]
+    }
+
+    #[test]
+    fn all_variants_round_trip() {
+        for packet in all_variants() {
+            let bytes = packet.try_serialize().unwrap();
+            let (parsed, n) = Packet::parse_frame(&bytes, u32::MAX).unwrap().unwrap();
+            assert_eq!(n, bytes.len());
+            assert_eq!(parsed, packet);
+        }
+    }
+
+    /// Xorshift, so that failures are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, n: usize) -> usize {
+            usize::try_from(self.next() % n as u64).unwrap()
+        }
+        fn byte(&mut self) -> u8 {
+            self.next().to_le_bytes()[0]
+        }
+        fn bytes(&mut self, max: usize) -> Vec<u8> {
+            (0..self.below(max + 1)).map(|_| self.byte()).collect()
+        }
+        fn call(&mut self) -> Call {
+            call(["M0THC-1", "M0THC-2", "WIDE1-1", "APZ001", "N0CALL"][self.below(5)])
+        }
+    }
+
+    /// Build a random, valid AGW frame of the given kind from bytes.
+    ///
+    /// Returns the frame, and whether it's in the form that the typed
+    /// packet serializes to.
+    #[allow(clippy::too_many_lines)]
+    fn random_frame(rng: &mut Rng, kind: u8) -> (Vec<u8>, bool) {
+        // Header fields that must be zero, and payloads that must be empty,
+        // for the frame to be canonical.
+        let zero = |rng: &mut Rng, canonical: &mut bool| {
+            if rng.below(4) == 0 {
+                *canonical = false;
+                rng.byte().max(1)
+            } else {
+                0
+            }
+        };
+        let maybe_call = |rng: &mut Rng, canonical: &mut bool| {
+            if rng.below(4) == 0 {
+                *canonical = false;
+                Some(rng.call())
+            } else {
+                None
+            }
+        };
+        let mut canonical = true;
+        let mut port = Port(rng.byte());
+        let mut pid = Pid(rng.byte());
+        let mut src = Some(rng.call());
+        let mut dst = Some(rng.call());
+        let data = match kind {
+            CMD_CONNECT => match rng.below(3) {
+                0 => Vec::new(),
+                1 => format!("*** CONNECTED With Station {}\r", rng.call()).into_bytes(),
+                _ => format!("*** CONNECTED To Station {}\r\0", rng.call()).into_bytes(),
+            },
+            CMD_DISCONNECT => {
+                let data = rng.bytes(20);
+                canonical &= std::str::from_utf8(&data).is_ok();
+                data
+            }
+            CMD_DATA | CMD_UNPROTO | b'U' | b'I' | b'S' | b'T' => rng.bytes(20),
+            CMD_CONNECT_VIA | CMD_UNPROTO_VIA => {
+                let hops = rng.below(MAX_HOPS + 1);
+                let mut data = vec![u8::try_from(hops).unwrap()];
+                for _ in 0..hops {
+                    data.extend_from_slice(rng.call().as_bytes());
                 }
+                if kind == CMD_UNPROTO_VIA {
+                    data.extend(rng.bytes(20));
+                }
+                data
+            }
+            CMD_REGISTER_CALLSIGN => {
+                pid = Pid(zero(rng, &mut canonical));
+                dst = maybe_call(rng, &mut canonical);
+                match rng.below(3) {
+                    0 => Vec::new(),
+                    1 => vec![u8::from(rng.below(2) == 0)],
+                    _ => {
+                        let b = rng.byte();
+                        canonical &= b <= 1;
+                        vec![b]
+                    }
+                }
+            }
+            CMD_UNREGISTER_CALLSIGN => {
+                pid = Pid(zero(rng, &mut canonical));
+                dst = maybe_call(rng, &mut canonical);
+                Vec::new()
             }
             _ => {
-                return Err(Error::msg(format!(
-                    "unknown packet kind {}",
-                    header.data_kind
-                )));
+                pid = Pid(zero(rng, &mut canonical));
+                src = maybe_call(rng, &mut canonical);
+                dst = maybe_call(rng, &mut canonical);
+                match kind {
+                    CMD_VERSION | CMD_TOGGLE_MONITOR | CMD_TOGGLE_RAW | CMD_PORT_INFO => {
+                        port = Port(zero(rng, &mut canonical));
+                    }
+                    _ => {}
+                }
+                match kind {
+                    // Queries.
+                    CMD_VERSION | CMD_FRAMES_OUTSTANDING_PORT | CMD_PORT_INFO | CMD_PORT_CAP
+                        if rng.below(2) == 0 =>
+                    {
+                        Vec::new()
+                    }
+                    CMD_VERSION => {
+                        let mut data = Vec::with_capacity(8);
+                        for i in 0..8 {
+                            data.push(if i % 4 < 2 {
+                                rng.byte()
+                            } else {
+                                zero(rng, &mut canonical)
+                            });
+                        }
+                        data
+                    }
+                    CMD_FRAMES_OUTSTANDING_PORT => (0..4).map(|_| rng.byte()).collect(),
+                    CMD_PORT_INFO => {
+                        let ports = rng.below(4);
+                        let mut s = format!("{ports};");
+                        for n in 1..=ports {
+                            let _ = write!(s, "Port{n} Radio {};", rng.below(100));
+                        }
+                        if rng.below(4) == 0 {
+                            canonical = false;
+                        } else {
+                            s.push('\0');
+                        }
+                        s.into_bytes()
+                    }
+                    CMD_CALLSIGN_HEARD | CMD_RAW => rng.bytes(20),
+                    CMD_PORT_CAP => {
+                        let mut data: Vec<u8> = (0..12).map(|_| rng.byte()).collect();
+                        data[0] = match rng.below(3) {
+                            0 => 0xff,
+                            1 => u8::try_from(rng.below(4)).unwrap(),
+                            _ => {
+                                canonical &= data[0] <= 3 || data[0] == 0xff;
+                                data[0]
+                            }
+                        };
+                        data
+                    }
+                    // Toggles, and the unknown kind.
+                    _ => Vec::new(),
+                }
             }
+        };
+        let mut header = Header::new(
+            port,
+            kind,
+            pid,
+            src,
+            dst,
+            u32::try_from(data.len()).unwrap(),
+        );
+        for b in &mut header.reserved {
+            *b = zero(rng, &mut canonical);
+        }
+        if rng.below(4) == 0 {
+            header.user = u32::try_from(rng.next() >> 32).unwrap();
+            canonical &= header.user == 0;
+        }
+        ([&header.to_bytes()[..], &data].concat(), canonical)
+    }
+
+    #[test]
+    fn parse_round_trips_random_frames() {
+        const KINDS: &[u8] = &[
+            CMD_VERSION,
+            CMD_CONNECT,
+            CMD_CONNECT_VIA,
+            CMD_DISCONNECT,
+            CMD_UNPROTO,
+            CMD_UNPROTO_VIA,
+            CMD_TOGGLE_MONITOR,
+            CMD_TOGGLE_RAW,
+            CMD_RAW,
+            b'U',
+            b'I',
+            b'S',
+            b'T',
+            CMD_DATA,
+            CMD_UNREGISTER_CALLSIGN,
+            CMD_REGISTER_CALLSIGN,
+            CMD_FRAMES_OUTSTANDING_PORT,
+            CMD_PORT_INFO,
+            CMD_CALLSIGN_HEARD,
+            CMD_PORT_CAP,
+            b'Q',
+        ];
+        let mut rng = Rng(1);
+        for _ in 0..10_000 {
+            let kind = KINDS[rng.below(KINDS.len())];
+            let (bytes, canonical) = random_frame(&mut rng, kind);
+            let (packet, n) = Packet::parse_frame(&bytes, u32::MAX)
+                .unwrap_or_else(|e| panic!("{bytes:02x?}: {e}"))
+                .unwrap();
+            assert_eq!(n, bytes.len());
+            assert_eq!(
+                packet.try_serialize().unwrap(),
+                bytes,
+                "{packet:?} did not round trip"
+            );
+            let unknown = matches!(packet, Packet::Unknown { .. });
+            assert_eq!(
+                unknown,
+                kind == b'Q' || !canonical,
+                "{bytes:02x?} parsed as {packet:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn non_canonical_frames_stay_typed() {
+        let src = call("M0THC-1");
+        let dst = call("M0THC-2");
+
+        // Data with the "user" field set.
+        let mut h = header(b'D', Some("M0THC-1"), Some("M0THC-2"), 2);
+        h.user = 7;
+        h.reserved = [0, 0, 0, 1, 0];
+        let frame = Frame::parse(h.clone(), b"hi".to_vec()).unwrap();
+        assert_eq!(
+            frame.packet(),
+            &Packet::Data {
+                port: Port(0),
+                pid: Pid(0xF0),
+                src: src.clone(),
+                dst: dst.clone(),
+                data: b"hi".to_vec()
+            }
+        );
+        assert_eq!(frame.to_bytes(), [&h.to_bytes()[..], b"hi"].concat());
+        let packet = Packet::parse(&h, b"hi").unwrap();
+        assert!(matches!(packet, Packet::Unknown { .. }));
+        assert_eq!(packet.try_serialize().unwrap(), frame.to_bytes());
+
+        // Disconnect with text that isn't UTF-8.
+        let h = header(b'd', Some("M0THC-1"), Some("M0THC-2"), 2);
+        let frame = Frame::parse(h.clone(), vec![0xff, 0xfe]).unwrap();
+        assert!(matches!(frame.packet(), Packet::Disconnect { .. }));
+        assert_eq!(frame.data(), &[0xff, 0xfe]);
+
+        // Version reply with nonzero reserved bytes.
+        let h = header(b'R', None, None, 8);
+        let data = vec![0xd5, 0x07, 1, 2, 0x7f, 0, 3, 4];
+        let frame = Frame::parse(h, data.clone()).unwrap();
+        assert_eq!(
+            frame.packet(),
+            &Packet::VersionReply {
+                major: 2005,
+                minor: 127
+            }
+        );
+        assert_eq!(frame.data(), &data[..]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        for packet in all_variants() {
+            let json = serde_json::to_string(&packet).unwrap();
+            let back: Packet = serde_json::from_str(&json).unwrap();
+            assert_eq!(back, packet, "{json}");
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_format() {
+        let json = serde_json::to_value(Packet::Connect {
+            port: Port(1),
+            pid: Pid(0xF0),
+            src: call("M0THC-1"),
+            dst: call("M0THC-2"),
         })
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"Connect": {"port": 1, "pid": 240, "src": "M0THC-1", "dst": "M0THC-2"}})
+        );
+        // Callsigns are checked when deserializing.
+        assert!(serde_json::from_str::<Call>("\"M0THC-16\"").is_err());
+    }
+
+    #[test]
+    fn split_data() {
+        let data = |data: &[u8]| Packet::Data {
+            port: Port(1),
+            pid: Pid(0xF0),
+            src: call("M0THC-1"),
+            dst: call("M0THC-2"),
+            data: data.to_vec(),
+        };
+        assert_eq!(
+            data(b"abcdefg").split_data(3),
+            vec![data(b"abc"), data(b"def"), data(b"g")]
+        );
+        assert_eq!(
+            data(b"abcdef").split_data(3),
+            vec![data(b"abc"), data(b"def")]
+        );
+        assert_eq!(data(b"abc").split_data(3), vec![data(b"abc")]);
+        assert_eq!(data(b"").split_data(3), vec![data(b"")]);
+        assert_eq!(
+            Packet::VersionQuery.split_data(3),
+            vec![Packet::VersionQuery]
+        );
+        let unproto = Packet::Unproto {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call("M0THC-1"),
+            dst: call("ID"),
+            data: b"abcdefg".to_vec(),
+        };
+        assert_eq!(unproto.clone().split_data(3), vec![unproto]);
     }
 }
@@ -0,0 +1,513 @@
+//! Capture AX.25 frames to pcap files, readable by Wireshark.
+//!
+//! Frames come from raw ('K') packets, and from monitored frames, which are
+//! turned back into AX.25. Enable those with `Packet::ToggleRaw` and
+//! `Packet::ToggleMonitor`.
+//!
+//! Rebuilding monitored frames is best effort. The AGW server only gives a
+//! text description of the control field, and drops the FCS and C/R bits.
+//!
+//! Only received packets are captured. What the client sends are requests,
+//! which the server may delay, change or never transmit, and connected mode
+//! data has no control field until the server adds one. With monitoring on,
+//! the server instead reports the frames it actually transmits, as
+//! `MonitorKind::Sent` frames, so those are captured without duplicates.
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+
+use crate::tap::{Direction, Tap};
+use crate::{Call, Error, Frame, Packet, Port, Result};
+
+/// pcap link type for AX.25 frames without KISS header or FCS.
+pub const LINKTYPE_AX25: u32 = 3;
+
+const MAGIC_USEC: u32 = 0xa1b2_c3d4;
+const MAGIC_NSEC: u32 = 0xa1b2_3c4d;
+const SNAPLEN: u32 = 65535;
+
+/// Control field from the name the AGW server gives it in monitor text.
+fn control_byte(words: &[&str]) -> Option<u8> {
+    let num = |prefix: char| -> Option<u8> {
+        words
+            .iter()
+            .find_map(|w| w.strip_prefix(prefix)?.parse::<u8>().ok())
+            .filter(|&n| n < 8)
+    };
+    let pf = if words.iter().any(|w| *w == "P" || *w == "F") {
+        0x10
+    } else {
+        0
+    };
+    let supervisory = |base: u8| Some(base | num('R')? << 5 | pf);
+    match *words.first()? {
+        "I" => Some(num('R')? << 5 | pf | num('S')? << 1),
+        "RR" => supervisory(0x01),
+        "RNR" => supervisory(0x05),
+        "REJ" => supervisory(0x09),
+        "SREJ" => supervisory(0x0d),
+        "UI" => Some(0x03 | pf),
+        "SABM" => Some(0x2f | pf),
+        "SABME" => Some(0x6f | pf),
+        "DISC" => Some(0x43 | pf),
+        "DM" => Some(0x0f | pf),
+        "UA" => Some(0x63 | pf),
+        "FRMR" => Some(0x87 | pf),
+        "XID" => Some(0xaf | pf),
+        "TEST" => Some(0xe3 | pf),
+        _ => None,
+    }
+}
+
+/// AX.25 frame, without FCS, of a raw or monitored packet.
+///
+/// Returns `None` for other packets, and for monitored frames that can't be
+/// rebuilt, e.g. because of callsigns that are not valid in AX.25.
+#[must_use]
+pub fn ax25_frame(packet: &Packet) -> Option<Vec<u8>> {
+    let (pid, src, dst, data) = match packet {
+        Packet::RawFrame { data, .. } => return data.get(1..).map(<[u8]>::to_vec),
+        Packet::Monitor {
+            pid,
+            src,
+            dst,
+            data,
+            ..
+        } => (pid, src, dst, data),
+        _ => return None,
+    };
+    let (via, control, payload) = crate::tnc2::split_monitor(data)?;
+    let words: Vec<&str> = control
+        .as_deref()
+        .unwrap_or("UI")
+        .split_whitespace()
+        .collect();
+    let pid = words
+        .iter()
+        .find_map(|w| u8::from_str_radix(w.strip_prefix("pid=")?, 16).ok())
+        .unwrap_or(pid.0);
+    let control = control_byte(&words)?;
+    let response = words.contains(&"R");
+    let via = via
+        .iter()
+        .map(|hop| {
+            let call = hop.trim_end_matches('*').parse().ok()?;
+            Some((call, hop.ends_with('*')))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    encode(src, dst, &via, response, control, pid, payload)
+}
+
+/// AX.25 frame, without FCS, from its parts.
+///
+/// `via` is the digipeater path, and whether each hop has repeated the
+/// frame. Returns `None` if a callsign is not valid in AX.25.
+pub(crate) fn encode(
+    src: &Call,
+    dst: &Call,
+    via: &[(Call, bool)],
+    response: bool,
+    control: u8,
+    pid: u8,
+    payload: &[u8],
+) -> Option<Vec<u8>> {
+    let mut frame = Vec::with_capacity(7 * (2 + via.len()) + 2 + payload.len());
+    let mut dst = <[u8; 7]>::try_from(dst).ok()?;
+    let mut src = <[u8; 7]>::try_from(src).ok()?;
+    if response {
+        src[6] |= 0x80;
+    } else {
+        dst[6] |= 0x80;
+    }
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    for (hop, repeated) in via {
+        let mut addr = <[u8; 7]>::try_from(hop).ok()?;
+        if *repeated {
+            addr[6] |= 0x80;
+        }
+        frame.extend_from_slice(&addr);
+    }
+    let last = frame.len() - 1;
+    frame[last] |= 0x01;
+    frame.push(control);
+    // I and UI frames have a PID.
+    if control & 0x01 == 0 || control & 0xef == 0x03 {
+        frame.push(pid);
+        frame.extend_from_slice(payload);
+    }
+    Some(frame)
+}
+
+/// pcap file writer.
+pub struct Writer<W: Write> {
+    w: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// Create writer, and write the pcap file header.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn new(mut w: W) -> Result<Self> {
+        w.write_all(&MAGIC_USEC.to_le_bytes())?;
+        w.write_all(&2_u16.to_le_bytes())?;
+        w.write_all(&4_u16.to_le_bytes())?;
+        w.write_all(&0_i32.to_le_bytes())?; // Timezone offset.
+        w.write_all(&0_u32.to_le_bytes())?; // Timestamp accuracy.
+        w.write_all(&SNAPLEN.to_le_bytes())?;
+        w.write_all(&LINKTYPE_AX25.to_le_bytes())?;
+        Ok(Self { w })
+    }
+
+    /// Write one AX.25 frame.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails, or the frame is too large.
+    pub fn write_frame(&mut self, ts: SystemTime, frame: &[u8]) -> Result<()> {
+        let ts = ts
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let len = u32::try_from(frame.len())?;
+        self.w
+            .write_all(&u32::try_from(ts.as_secs())?.to_le_bytes())?;
+        self.w.write_all(&ts.subsec_micros().to_le_bytes())?;
+        self.w.write_all(&len.to_le_bytes())?;
+        self.w.write_all(&len.to_le_bytes())?;
+        self.w.write_all(frame)?;
+        Ok(())
+    }
+
+    /// Write the AX.25 frame of a packet, if it has one. See `ax25_frame()`.
+    ///
+    /// Returns true if a frame was written.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn write_packet(&mut self, ts: SystemTime, packet: &Packet) -> Result<bool> {
+        let Some(frame) = ax25_frame(packet) else {
+            return Ok(false);
+        };
+        self.write_frame(ts, &frame)?;
+        Ok(true)
+    }
+
+    /// Flush the underlying writer.
+    ///
+    /// # Errors
+    ///
+    /// If flushing fails.
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.w.flush()?)
+    }
+
+    /// Get back the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+/// Write every raw and monitored frame received, with the current time.
+///
+/// Sent packets are ignored. See the module docs for why.
+impl<W: Write + Send> Tap for Mutex<Writer<W>> {
+    fn frame(&self, dir: Direction, frame: &Frame) {
+        if dir != Direction::Rx {
+            return;
+        }
+        let mut w = self.lock().unwrap();
+        if let Err(e) = w
+            .write_packet(SystemTime::now(), frame.packet())
+            .and_then(|_| w.flush())
+        {
+            warn!("agw/pcap: Failed to write capture: {e}");
+        }
+    }
+}
+
+/// pcap file reader.
+///
+/// Iterates over the frames in the file, as `Packet::RawFrame`.
+pub struct Reader<R: Read> {
+    r: R,
+    port: Port,
+    big_endian: bool,
+    nanos: bool,
+}
+
+impl<R: Read> Reader<R> {
+    /// Create reader, and read the pcap file header.
+    ///
+    /// Frames will be returned as raw frames on `port`, since pcap files
+    /// don't record it.
+    ///
+    /// # Errors
+    ///
+    /// If reading fails, or the file is not an AX.25 pcap file.
+    pub fn new(mut r: R, port: Port) -> Result<Self> {
+        let mut header = [0; 24];
+        r.read_exact(&mut header)?;
+        let magic = [header[0], header[1], header[2], header[3]];
+        let (big_endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (MAGIC_USEC, _) => (false, false),
+            (MAGIC_NSEC, _) => (false, true),
+            (_, MAGIC_USEC) => (true, false),
+            (_, MAGIC_NSEC) => (true, true),
+            _ => return Err(Error::BadPcap("not a pcap file".to_string())),
+        };
+        let reader = Self {
+            r,
+            port,
+            big_endian,
+            nanos,
+        };
+        let linktype = reader.u32_at(&header, 20);
+        if linktype != LINKTYPE_AX25 {
+            return Err(Error::BadPcap(format!(
+                "link type is {linktype}, not AX.25"
+            )));
+        }
+        Ok(reader)
+    }
+
+    fn u32_at(&self, data: &[u8], pos: usize) -> u32 {
+        let b = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+        if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    /// Read the next frame and its timestamp.
+    ///
+    /// Returns `None` at the end of the file.
+    ///
+    /// # Errors
+    ///
+    /// If reading fails, or the file is truncated or corrupt.
+    pub fn read_frame(&mut self) -> Result<Option<(SystemTime, Vec<u8>)>> {
+        let truncated = |e: std::io::Error| {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                Error::BadPcap("truncated record".to_string())
+            } else {
+                e.into()
+            }
+        };
+        let mut header = [0; 16];
+        if !crate::read_exact_or_eof(&mut self.r, &mut header).map_err(truncated)? {
+            return Ok(None);
+        }
+        let secs = self.u32_at(&header, 0);
+        let frac = self.u32_at(&header, 4);
+        let len = self.u32_at(&header, 8);
+        if len > SNAPLEN {
+            return Err(Error::BadPcap(format!("record too large: {len}")));
+        }
+        let frac = if self.nanos {
+            Duration::from_nanos(frac.into())
+        } else {
+            Duration::from_micros(frac.into())
+        };
+        let ts = SystemTime::UNIX_EPOCH + Duration::from_secs(secs.into()) + frac;
+        let mut frame = vec![0; usize::try_from(len)?];
+        self.r.read_exact(&mut frame).map_err(truncated)?;
+        Ok(Some((ts, frame)))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<(SystemTime, Packet)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_frame() {
+            Ok(None) => None,
+            Ok(Some((ts, frame))) => {
+                let mut data = Vec::with_capacity(1 + frame.len());
+                data.push(0);
+                data.extend(frame);
+                Some(Ok((
+                    ts,
+                    Packet::RawFrame {
+                        port: self.port,
+                        data,
+                    },
+                )))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MonitorKind, Pid};
+
+    fn monitor(text: &str) -> Packet {
+        Packet::Monitor {
+            kind: MonitorKind::Unproto,
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: "M0QQQ-8".parse().unwrap(),
+            dst: "APZ001".parse().unwrap(),
+            data: text.as_bytes().to_vec(),
+        }
+    }
+
+    const UI_FRAME: [u8; 28] = [
+        0x82, 0xa0, 0xb4, 0x60, 0x60, 0x62, 0xe0, // APZ001, command.
+        0x9a, 0x60, 0xa2, 0xa2, 0xa2, 0x40, 0x70, // M0QQQ-8.
+        0xae, 0x92, 0x88, 0x8a, 0x62, 0x40, 0xe3, // WIDE1-1*, last.
+        0x03, 0xf0, b'h', b'e', b'l', b'l', b'o',
+    ];
+
+    #[test]
+    fn monitored_ui_frame() {
+        let packet =
+            monitor(" 1:Fm M0QQQ-8 To APZ001 Via WIDE1-1* <UI pid=F0 Len=5 >[12:00:00]\rhello\r\0");
+        assert_eq!(ax25_frame(&packet).unwrap(), UI_FRAME);
+    }
+
+    #[test]
+    fn monitored_payload_ending_in_cr() {
+        let packet = monitor(
+            " 1:Fm M0QQQ-8 To APZ001 Via WIDE1-1* <UI pid=F0 Len=6 >[12:00:00]\rhello\r\r\0",
+        );
+        let mut want = UI_FRAME.to_vec();
+        want.push(b'\r');
+        assert_eq!(ax25_frame(&packet).unwrap(), want);
+    }
+
+    #[test]
+    fn monitored_supervisory_frame() {
+        let packet = monitor(" 1:Fm M0QQQ-8 To APZ001 <RR R3 P >[12:00:00]\r");
+        let frame = ax25_frame(&packet).unwrap();
+        assert_eq!(frame.len(), 15);
+        assert_eq!(frame[14], 0x01 | 3 << 5 | 0x10);
+        assert_eq!(frame[13] & 0x01, 0x01, "last address");
+    }
+
+    #[test]
+    fn raw_frames_drop_port_byte() {
+        let mut data = vec![0];
+        data.extend_from_slice(&UI_FRAME);
+        let packet = Packet::RawFrame {
+            port: Port(0),
+            data,
+        };
+        assert_eq!(ax25_frame(&packet).unwrap(), UI_FRAME);
+        assert_eq!(ax25_frame(&Packet::VersionQuery), None);
+    }
+
+    #[test]
+    fn file_bytes() {
+        let mut w = Writer::new(Vec::new()).unwrap();
+        let ts = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        w.write_frame(ts, &[1, 2, 3]).unwrap();
+        let bytes = w.into_inner();
+        let mut want = vec![
+            0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 3, 0, 0,
+            0,
+        ];
+        want.extend_from_slice(&1_700_000_000_u32.to_le_bytes());
+        want.extend_from_slice(&123_456_u32.to_le_bytes());
+        want.extend_from_slice(&[3, 0, 0, 0, 3, 0, 0, 0, 1, 2, 3]);
+        assert_eq!(bytes, want);
+    }
+
+    #[test]
+    fn read_back() {
+        let tap = Mutex::new(Writer::new(Vec::new()).unwrap());
+        let packet =
+            monitor(" 1:Fm M0QQQ-8 To APZ001 Via WIDE1-1* <UI pid=F0 Len=5 >[12:00:00]\rhello\r");
+        let frame = |packet: &Packet| Frame::from_packet(packet.clone()).unwrap();
+        tap.frame(Direction::Rx, &frame(&packet));
+        tap.frame(Direction::Tx, &frame(&packet));
+        tap.frame(Direction::Rx, &frame(&Packet::VersionQuery));
+        let bytes = tap.into_inner().unwrap().into_inner();
+
+        let reader = Reader::new(&bytes[..], Port(3)).unwrap();
+        let packets: Vec<_> = reader.map(|r| r.unwrap().1).collect();
+        let mut data = vec![0];
+        data.extend_from_slice(&UI_FRAME);
+        assert_eq!(
+            packets,
+            vec![Packet::RawFrame {
+                port: Port(3),
+                data
+            }]
+        );
+    }
+
+    #[test]
+    fn read_big_endian_nanos() {
+        let mut bytes = MAGIC_NSEC.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&[0, 2, 0, 4]);
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&SNAPLEN.to_be_bytes());
+        bytes.extend_from_slice(&LINKTYPE_AX25.to_be_bytes());
+        bytes.extend_from_slice(&10_u32.to_be_bytes());
+        bytes.extend_from_slice(&5_u32.to_be_bytes());
+        bytes.extend_from_slice(&1_u32.to_be_bytes());
+        bytes.extend_from_slice(&1_u32.to_be_bytes());
+        bytes.push(42);
+        let mut reader = Reader::new(&bytes[..], Port(0)).unwrap();
+        let (ts, frame) = reader.read_frame().unwrap().unwrap();
+        assert_eq!(
+            ts,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(10) + Duration::from_nanos(5)
+        );
+        assert_eq!(frame, [42]);
+        assert!(reader.read_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_other_link_types() {
+        let mut bytes = Writer::new(Vec::new()).unwrap().into_inner();
+        bytes[20] = 1;
+        assert!(matches!(
+            Reader::new(&bytes[..], Port(0)),
+            Err(Error::BadPcap(_))
+        ));
+        assert!(matches!(
+            Reader::new(&[0_u8; 24][..], Port(0)),
+            Err(Error::BadPcap(_))
+        ));
+    }
+
+    #[test]
+    fn truncated_records_are_errors() {
+        let tap = Mutex::new(Writer::new(Vec::new()).unwrap());
+        let packet =
+            monitor(" 1:Fm M0QQQ-8 To APZ001 Via WIDE1-1* <UI pid=F0 Len=5 >[12:00:00]\rhello\r");
+        tap.frame(Direction::Rx, &Frame::from_packet(packet).unwrap());
+        let bytes = tap.into_inner().unwrap().into_inner();
+        let record = 16 + UI_FRAME.len();
+        assert_eq!(bytes.len(), 24 + record);
+
+        // Whole file, and clean end of file.
+        let mut reader = Reader::new(&bytes[..], Port(0)).unwrap();
+        assert!(reader.read_frame().unwrap().is_some());
+        assert!(reader.read_frame().unwrap().is_none());
+
+        // Cut in the record header, or in the frame.
+        for len in [24 + 1, 24 + 15, 24 + 16, bytes.len() - 1] {
+            let mut reader = Reader::new(&bytes[..len], Port(0)).unwrap();
+            assert!(
+                matches!(reader.read_frame(), Err(Error::BadPcap(_))),
+                "cut at {len}"
+            );
+        }
+
+        // Too large.
+        let mut bytes = bytes;
+        bytes[24 + 8..24 + 12].copy_from_slice(&(SNAPLEN + 1).to_le_bytes());
+        let mut reader = Reader::new(&bytes[..], Port(0)).unwrap();
+        assert!(matches!(reader.read_frame(), Err(Error::BadPcap(_))));
+    }
+}
@@ -0,0 +1,629 @@
+//! Allow or deny AGW packets from clients, by rules in a file.
+//!
+//! One rule per line. The first rule that matches decides, and if none
+//! matches the packet is denied. Anything after a `#` is a comment.
+//!
+//! A rule is `allow` or `deny`, followed by conditions that all have to
+//! match:
+//!
+//! * `addr=`: Client address, e.g. `127.0.0.1` or `192.168.0.0/16`.
+//! * `src=`, `dst=`: Callsign from the AGW header, where `*` matches any
+//!   characters and `?` any one character, e.g. `M0QQQ-*`.
+//! * `port=`: AGW port, 0-based.
+//! * `kind=`: AGW data kind letter, e.g. `D` for connected mode data.
+//! * `pid=`: PID, e.g. `0xF0`.
+//!
+//! Each condition can list several values separated by commas, of which
+//! one has to match. Conditions look at the AGW header, so they work for
+//! packets this crate doesn't know about too.
+//!
+//! ```
+//! use agw::policy::{Policy, Verdict};
+//! use agw::{Packet, Pid, Port};
+//! let policy: Policy = "
+//!     allow kind=R,G,g            # Anyone may ask questions.
+//!     allow addr=127.0.0.0/8 src=M0QQQ-*
+//!     deny
+//! "
+//! .parse()?;
+//! let packet = Packet::Unproto {
+//!     port: Port(0),
+//!     pid: Pid(0xF0),
+//!     src: "M0QQQ-8".parse()?,
+//!     dst: "APZ001".parse()?,
+//!     data: b"hello".to_vec(),
+//! };
+//! let local = "127.0.0.1".parse().ok();
+//! let remote = "192.0.2.1".parse().ok();
+//! assert_eq!(policy.check(local, &packet), Verdict::Allow);
+//! assert_eq!(policy.check(remote, &packet), Verdict::Deny);
+//! # Ok::<(), agw::Error>(())
+//! ```
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use log::{debug, info};
+
+use crate::proxy::Action;
+use crate::{Error, Header, Packet, Pid, Port, PortsInfo, Result};
+
+/// What to do with a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Deny,
+}
+
+/// Network, as address and prefix length.
+#[derive(Clone, Debug)]
+struct Net {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Net {
+    fn parse(s: &str) -> Option<Net> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (s.parse().ok()?, None),
+        };
+        let bits = if matches!(addr, IpAddr::V4(_)) {
+            32
+        } else {
+            128
+        };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(Net { addr, prefix })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        // Clients of a dual stack listener show up as IPv4 mapped.
+        let addr = match addr {
+            IpAddr::V6(a) => a.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        let (net, addr, bits) = match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                (u128::from(u32::from(net)), u128::from(u32::from(addr)), 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => (u128::from(net), u128::from(addr), 128),
+            _ => return false,
+        };
+        self.prefix == 0 || (net ^ addr) >> (bits - self.prefix) == 0
+    }
+}
+
+/// Match callsign against pattern with `*` and `?`, ignoring case.
+fn glob(pattern: &[u8], s: &[u8]) -> bool {
+    match (pattern.split_first(), s.split_first()) {
+        (None, _) => s.is_empty(),
+        (Some((b'*', rest)), _) => glob(rest, s) || (!s.is_empty() && glob(pattern, &s[1..])),
+        (Some((b'?', rest)), Some((_, s_rest))) => glob(rest, s_rest),
+        (Some((p, rest)), Some((c, s_rest))) => p.eq_ignore_ascii_case(c) && glob(rest, s_rest),
+        (Some(_), None) => false,
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    line: usize,
+    verdict: Verdict,
+    addr: Vec<Net>,
+    src: Vec<String>,
+    dst: Vec<String>,
+    port: Vec<Port>,
+    kind: Vec<u8>,
+    pid: Vec<Pid>,
+}
+
+/// True if the condition is not set, or any of its values match.
+fn any<T>(values: &[T], f: impl Fn(&T) -> bool) -> bool {
+    values.is_empty() || values.iter().any(f)
+}
+
+impl Rule {
+    fn matches(&self, addr: Option<IpAddr>, header: &Header) -> bool {
+        let call = |pattern: &String, call: &Option<crate::Call>| {
+            call.as_ref()
+                .is_some_and(|c| glob(pattern.as_bytes(), c.to_string().as_bytes()))
+        };
+        any(&self.addr, |net| addr.is_some_and(|a| net.contains(a)))
+            && any(&self.src, |p| call(p, &header.src))
+            && any(&self.dst, |p| call(p, &header.dst))
+            && any(&self.port, |&p| p == header.port)
+            && any(&self.kind, |&k| k == header.data_kind)
+            && any(&self.pid, |&p| p == header.pid)
+    }
+
+    fn parse(line: usize, text: &str) -> Result<Rule> {
+        let bad = |msg: String| Error::InvalidPolicy { line, msg };
+        let mut words = text.split_whitespace();
+        let verdict = match words.next() {
+            Some("allow") => Verdict::Allow,
+            Some("deny") => Verdict::Deny,
+            other => {
+                return Err(bad(format!("expected allow or deny, got {other:?}")));
+            }
+        };
+        let mut rule = Rule {
+            line,
+            verdict,
+            addr: Vec::new(),
+            src: Vec::new(),
+            dst: Vec::new(),
+            port: Vec::new(),
+            kind: Vec::new(),
+            pid: Vec::new(),
+        };
+        for word in words {
+            let Some((key, values)) = word.split_once('=') else {
+                return Err(bad(format!("expected key=value, got {word:?}")));
+            };
+            for value in values.split(',') {
+                let invalid = || bad(format!("invalid {key} {value:?}"));
+                match key {
+                    "addr" => rule.addr.push(Net::parse(value).ok_or_else(invalid)?),
+                    "src" | "dst" => {
+                        let valid = !value.is_empty()
+                            && value
+                                .bytes()
+                                .all(|b| b.is_ascii_alphanumeric() || b"-*?".contains(&b));
+                        if !valid {
+                            return Err(invalid());
+                        }
+                        let list = if key == "src" {
+                            &mut rule.src
+                        } else {
+                            &mut rule.dst
+                        };
+                        list.push(value.to_string());
+                    }
+                    "port" => rule
+                        .port
+                        .push(value.parse().map(Port).map_err(|_| invalid())?),
+                    "kind" => match value.as_bytes() {
+                        [kind] if kind.is_ascii_alphabetic() => rule.kind.push(*kind),
+                        _ => return Err(invalid()),
+                    },
+                    "pid" => {
+                        let pid = match value.strip_prefix("0x") {
+                            Some(hex) => u8::from_str_radix(hex, 16),
+                            None => value.parse(),
+                        };
+                        rule.pid.push(pid.map(Pid).map_err(|_| invalid())?);
+                    }
+                    _ => return Err(bad(format!("unknown condition {key:?}"))),
+                }
+            }
+        }
+        Ok(rule)
+    }
+}
+
+/// Set of rules.
+///
+/// Created by parsing rule text, e.g. with `str::parse()` or
+/// `Policy::load()`. The default, empty, policy denies everything.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl std::str::FromStr for Policy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Policy> {
+        let rules = s
+            .lines()
+            .enumerate()
+            .filter_map(|(n, line)| {
+                let line = line.split('#').next().unwrap_or_default().trim();
+                (!line.is_empty()).then(|| Rule::parse(n + 1, line))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Policy { rules })
+    }
+}
+
+impl Policy {
+    /// Load policy from a rule file.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be read, or has invalid rules.
+    pub fn load(path: impl AsRef<Path>) -> Result<Policy> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Check a packet from a client at `addr`, if known.
+    #[must_use]
+    pub fn check(&self, addr: Option<IpAddr>, packet: &Packet) -> Verdict {
+        let Ok(header) = packet.header() else {
+            return Verdict::Deny;
+        };
+        match self.rules.iter().find(|r| r.matches(addr, &header)) {
+            Some(rule) => {
+                debug!("policy: Line {} matches {packet:?}", rule.line);
+                rule.verdict
+            }
+            None => Verdict::Deny,
+        }
+    }
+
+    /// Check a packet from a client at `addr`, and decide what a proxy
+    /// should do with it.
+    ///
+    /// Allowed packets are forwarded. Denied packets are logged, and
+    /// answered where AGW has a way to say no:
+    ///
+    /// * `RegisterCallsign` gets a failed `RegisterCallsignReply`.
+    /// * `Connect` and `ConnectVia` get a `Disconnect`.
+    /// * `Data` gets a `Disconnect`, and the connection is disconnected
+    ///   upstream too.
+    /// * `PortInfoQuery` gets an empty list of ports.
+    ///
+    /// Other denied packets, such as unproto frames, are dropped.
+    #[must_use]
+    pub fn filter(&self, addr: Option<IpAddr>, packet: Packet) -> Vec<Action> {
+        if self.check(addr, &packet) == Verdict::Allow {
+            return vec![Action::Forward(packet)];
+        }
+        let who = addr.map_or_else(|| "unknown client".to_string(), |a| a.to_string());
+        info!("policy: Denied {packet:?} from {who}");
+        match packet {
+            Packet::RegisterCallsign(port, call) => {
+                vec![Action::Reply(Packet::RegisterCallsignReply {
+                    port,
+                    call,
+                    success: false,
+                })]
+            }
+            Packet::Connect {
+                port,
+                pid,
+                src,
+                dst,
+            }
+            | Packet::ConnectVia {
+                port,
+                pid,
+                src,
+                dst,
+                ..
+            } => vec![Action::Reply(Packet::Disconnect {
+                port,
+                pid,
+                src: dst,
+                dst: src,
+                text: String::new(),
+            })],
+            Packet::Data {
+                port,
+                pid,
+                src,
+                dst,
+                ..
+            } => vec![
+                Action::Forward(Packet::Disconnect {
+                    port,
+                    pid,
+                    src: src.clone(),
+                    dst: dst.clone(),
+                    text: String::new(),
+                }),
+                Action::Reply(Packet::Disconnect {
+                    port,
+                    pid,
+                    src: dst,
+                    dst: src,
+                    text: String::new(),
+                }),
+            ],
+            Packet::PortInfoQuery => vec![Action::Reply(Packet::PortInfoReply(PortsInfo {
+                count: 0,
+                ports: Vec::new(),
+            }))],
+            _ => Vec::new(),
+        }
+    }
+}
+
+struct Loaded {
+    modified: Option<SystemTime>,
+    policy: Arc<Policy>,
+}
+
+/// Policy from a rule file, which can be reloaded while in use.
+pub struct PolicyFile {
+    path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl PolicyFile {
+    /// Load policy from a rule file.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be read, or has invalid rules.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let loaded = Self::load(&path)?;
+        Ok(Self {
+            path,
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    fn load(path: &Path) -> Result<Loaded> {
+        let modified = std::fs::metadata(path)?.modified().ok();
+        Ok(Loaded {
+            modified,
+            policy: Arc::new(Policy::load(path)?),
+        })
+    }
+
+    /// The policy currently in effect.
+    #[must_use]
+    pub fn policy(&self) -> Arc<Policy> {
+        self.loaded.lock().unwrap().policy.clone()
+    }
+
+    /// Reload the rule file.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be read, or has invalid rules. The old policy then
+    /// stays in effect.
+    pub fn reload(&self) -> Result<()> {
+        let loaded = Self::load(&self.path)?;
+        info!("policy: Reloaded {}", self.path.display());
+        *self.loaded.lock().unwrap() = loaded;
+        Ok(())
+    }
+
+    /// Reload the rule file if it has been modified since last loaded.
+    ///
+    /// Returns true if it was reloaded.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be read, or has invalid rules. The old policy then
+    /// stays in effect.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = std::fs::metadata(&self.path)?.modified().ok();
+        if modified.is_some() && modified == self.loaded.lock().unwrap().modified {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> Net {
+        Net::parse(s).unwrap()
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn unproto(src: &str) -> Packet {
+        Packet::Unproto {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: src.parse().unwrap(),
+            dst: "APZ001".parse().unwrap(),
+            data: b"hello".to_vec(),
+        }
+    }
+
+    #[test]
+    fn glob_matching() {
+        let m = |p: &str, s: &str| glob(p.as_bytes(), s.as_bytes());
+        assert!(m("M0QQQ", "M0QQQ"));
+        assert!(m("m0qqq", "M0QQQ"));
+        assert!(!m("M0QQQ", "M0QQQ-1"));
+        assert!(m("M0QQQ-*", "M0QQQ-1"));
+        assert!(m("M0QQQ-*", "M0QQQ-"));
+        assert!(!m("M0QQQ-*", "M0QQQ"));
+        assert!(m("M0QQQ*", "M0QQQ"));
+        assert!(m("*", ""));
+        assert!(m("*", "M0QQQ-15"));
+        assert!(m("*-1?", "M0QQQ-15"));
+        assert!(!m("*-1?", "M0QQQ-1"));
+        assert!(m("M?QQQ", "M0QQQ"));
+        assert!(!m("M?QQQ", "MQQQ"));
+        assert!(m("*Q*Q*", "M0QQQ"));
+        assert!(!m("*X*", "M0QQQ"));
+        assert!(!m("", "M0QQQ"));
+    }
+
+    #[test]
+    fn ipv4_networks() {
+        let lan = net("192.168.0.0/16");
+        assert!(lan.contains(addr("192.168.0.1")));
+        assert!(lan.contains(addr("192.168.255.255")));
+        assert!(!lan.contains(addr("192.169.0.1")));
+        assert!(!lan.contains(addr("10.0.0.1")));
+
+        let host = net("127.0.0.1");
+        assert!(host.contains(addr("127.0.0.1")));
+        assert!(!host.contains(addr("127.0.0.2")));
+
+        let odd = net("10.0.0.0/9");
+        assert!(odd.contains(addr("10.127.0.1")));
+        assert!(!odd.contains(addr("10.128.0.1")));
+
+        let all = net("0.0.0.0/0");
+        assert!(all.contains(addr("198.51.100.7")));
+        assert!(!all.contains(addr("2001:db8::1")));
+    }
+
+    #[test]
+    fn ipv6_networks() {
+        let doc = net("2001:db8::/32");
+        assert!(doc.contains(addr("2001:db8::1")));
+        assert!(doc.contains(addr("2001:db8:ffff::1")));
+        assert!(!doc.contains(addr("2001:db9::1")));
+        assert!(!doc.contains(addr("192.0.2.1")));
+
+        assert!(net("::1").contains(addr("::1")));
+        assert!(net("::/0").contains(addr("fe80::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_clients_match_ipv4_rules() {
+        let lo = net("127.0.0.0/8");
+        assert!(lo.contains(addr("::ffff:127.0.0.1")));
+        assert!(!lo.contains(addr("::ffff:10.0.0.1")));
+    }
+
+    #[test]
+    fn bad_networks() {
+        for s in [
+            "",
+            "localhost",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/x",
+            "10.0.0/8",
+        ] {
+            assert!(Net::parse(s).is_none(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let policy: Policy = "
+            # Comment.
+            deny src=M0QQQ-1
+            allow src=M0QQQ-* addr=10.0.0.0/8,192.168.0.0/16
+        "
+        .parse()
+        .unwrap();
+        let lan = Some(addr("192.168.1.1"));
+        assert_eq!(policy.check(lan, &unproto("M0QQQ-2")), Verdict::Allow);
+        assert_eq!(policy.check(lan, &unproto("M0QQQ-1")), Verdict::Deny);
+        assert_eq!(
+            policy.check(Some(addr("172.16.0.1")), &unproto("M0QQQ-2")),
+            Verdict::Deny
+        );
+        // Address rules don't match unknown clients.
+        assert_eq!(policy.check(None, &unproto("M0QQQ-2")), Verdict::Deny);
+        // Nothing matches.
+        assert_eq!(policy.check(lan, &unproto("M0THC-2")), Verdict::Deny);
+        assert_eq!(
+            Policy::default().check(lan, &unproto("M0QQQ-2")),
+            Verdict::Deny
+        );
+    }
+
+    #[test]
+    fn header_conditions() {
+        let policy: Policy = "
+            allow kind=R port=0
+            allow kind=M pid=0xF0 dst=APZ*
+            allow src=* dst=M0QQQ-?
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(policy.check(None, &Packet::VersionQuery), Verdict::Allow);
+        assert_eq!(policy.check(None, &unproto("M0THC-1")), Verdict::Allow);
+        assert_eq!(
+            policy.check(None, &Packet::PortCapQuery(Port(1))),
+            Verdict::Deny
+        );
+        // Callsign conditions need the callsign to be there.
+        assert_eq!(
+            policy.check(
+                None,
+                &Packet::RegisterCallsign(Port(0), "M0QQQ-1".parse().unwrap())
+            ),
+            Verdict::Deny
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (text, line) in [
+            ("permit", 1),
+            ("allow\nallow src", 2),
+            ("allow addr=10.0.0.0/40", 1),
+            ("allow src=M0QQQ/1", 1),
+            ("allow src=", 1),
+            ("allow port=x", 1),
+            ("allow kind=RG", 1),
+            ("allow kind=1", 1),
+            ("allow pid=0x100", 1),
+            ("\n\nallow colour=red", 3),
+        ] {
+            match text.parse::<Policy>() {
+                Err(Error::InvalidPolicy { line: got, .. }) => assert_eq!(got, line, "{text:?}"),
+                other => panic!("{text:?}: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn denied_packets_get_answers() {
+        let policy = Policy::default();
+        let me: crate::Call = "M0QQQ-1".parse().unwrap();
+        let peer: crate::Call = "M0THC-1".parse().unwrap();
+        let disconnect = |src: &crate::Call, dst: &crate::Call| Packet::Disconnect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: src.clone(),
+            dst: dst.clone(),
+            text: String::new(),
+        };
+        assert_eq!(
+            policy.filter(None, Packet::RegisterCallsign(Port(0), me.clone())),
+            vec![Action::Reply(Packet::RegisterCallsignReply {
+                port: Port(0),
+                call: me.clone(),
+                success: false,
+            })]
+        );
+        assert_eq!(
+            policy.filter(
+                None,
+                Packet::Connect {
+                    port: Port(0),
+                    pid: Pid(0xF0),
+                    src: me.clone(),
+                    dst: peer.clone(),
+                }
+            ),
+            vec![Action::Reply(disconnect(&peer, &me))]
+        );
+        assert_eq!(
+            policy.filter(
+                None,
+                Packet::Data {
+                    port: Port(0),
+                    pid: Pid(0xF0),
+                    src: me.clone(),
+                    dst: peer.clone(),
+                    data: b"hello".to_vec(),
+                }
+            ),
+            vec![
+                Action::Forward(disconnect(&me, &peer)),
+                Action::Reply(disconnect(&peer, &me)),
+            ]
+        );
+        assert_eq!(policy.filter(None, unproto("M0QQQ-1")), Vec::new());
+
+        let allow: Policy = "allow".parse().unwrap();
+        assert_eq!(
+            allow.filter(None, unproto("M0QQQ-1")),
+            vec![Action::Forward(unproto("M0QQQ-1"))]
+        );
+    }
+}
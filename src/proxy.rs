@@ -1,33 +1,154 @@
 use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::sync::Arc;
 
-use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use crossbeam_channel::{after, never, select, unbounded, Receiver, Sender};
 use log::{debug, info, trace};
 
-use crate::Packet;
+use crate::ratelimit::{ClientLimiter, Held, RateLimiter};
 use crate::{Error, Result};
+use crate::{Frame, Packet};
+
+/// Upstream AGW endpoint used if none is configured.
+pub const DEFAULT_UPSTREAM: &str = "127.0.0.1:8010";
 
 /// AGW proxy stream.
 pub struct Proxy {
     up: ConnectionV2,
     down: ConnectionV2,
+    limiter: Option<ClientLimiter>,
+}
+
+/// Where a proxy gets its upstream connection.
+pub(crate) enum Upstream<S> {
+    Addr(String),
+    Stream(S),
+}
+
+/// Builder for `Proxy`.
+///
+/// ```no_run
+/// # fn main() -> agw::Result<()> {
+/// let listener = std::net::TcpListener::bind("127.0.0.1:9011")?;
+/// let (down, _) = listener.accept()?;
+/// let mut proxy = agw::proxy::Proxy::builder()
+///     .upstream_addr("192.0.2.1:8000")
+///     .build(down)?;
+/// proxy.run(&Some, &Some)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ProxyBuilder {
+    upstream: Upstream<TcpStream>,
+    max_data_len: u32,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl ProxyBuilder {
+    /// New builder, with upstream `DEFAULT_UPSTREAM`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            upstream: Upstream::Addr(DEFAULT_UPSTREAM.to_string()),
+            max_data_len: crate::DEFAULT_MAX_DATA_LEN,
+            limiter: None,
+        }
+    }
+
+    /// Connect to upstream at `addr` when building.
+    #[must_use]
+    pub fn upstream_addr(mut self, addr: impl Into<String>) -> Self {
+        self.upstream = Upstream::Addr(addr.into());
+        self
+    }
+
+    /// Use an already connected stream as upstream.
+    #[must_use]
+    pub fn upstream_stream(mut self, stream: TcpStream) -> Self {
+        self.upstream = Upstream::Stream(stream);
+        self
+    }
+
+    /// Reject frames with payloads longer than `max_data_len` from either
+    /// side.
+    #[must_use]
+    pub fn max_data_len(mut self, max_data_len: u32) -> Self {
+        self.max_data_len = max_data_len;
+        self
+    }
+
+    /// Limit what the client may transmit.
+    ///
+    /// Over budget frames are held back or dropped, depending on the rate
+    /// limiter's `Overflow` setting. Frames from upstream keep flowing
+    /// while the client's are held back.
+    #[must_use]
+    pub fn rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Set up the proxy between `down` and upstream.
+    ///
+    /// # Errors
+    ///
+    /// If failing to connect to upstream.
+    pub fn build(self, down: TcpStream) -> Result<Proxy> {
+        let up = match self.upstream {
+            Upstream::Addr(addr) => TcpStream::connect(addr)?,
+            Upstream::Stream(stream) => stream,
+        };
+        let limiter = self.limiter.map(|limiter| {
+            limiter.client(
+                down.peer_addr()
+                    .map_or_else(|_| "unknown".to_string(), |a| a.to_string()),
+            )
+        });
+        Ok(Proxy {
+            up: ConnectionV2::new(up, self.max_data_len)?,
+            down: ConnectionV2::new(down, self.max_data_len)?,
+            limiter,
+        })
+    }
+}
+
+impl Default for ProxyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Proxy {
-    /// Set up a new proxy.
+    /// Set up a new proxy to `DEFAULT_UPSTREAM`.
     ///
     /// # Errors
     ///
     /// If failing to connect to upstream.
     pub fn new(down: TcpStream) -> Result<Self> {
-        let addr = "127.0.0.1:8010";
-        let up = TcpStream::connect(addr)?;
-        Ok(Self {
-            up: ConnectionV2::new(up)?,
-            down: ConnectionV2::new(down)?,
-        })
+        Self::builder().build(down)
     }
-    /// Run the proxy forever.
+
+    /// Set up a new proxy to `DEFAULT_UPSTREAM`, rejecting frames with
+    /// payloads longer than `max_data_len` from either side.
+    ///
+    /// # Errors
+    ///
+    /// If failing to connect to upstream.
+    pub fn with_max_data_len(down: TcpStream, max_data_len: u32) -> Result<Self> {
+        Self::builder().max_data_len(max_data_len).build(down)
+    }
+
+    /// Builder for a proxy with non-default settings, such as upstream.
+    #[must_use]
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder::new()
+    }
+
+    /// Run the proxy until either side disconnects.
+    ///
+    /// `cb_up` is called for packets from upstream, and `cb_down` for packets
+    /// from downstream. Whatever packet they return is forwarded to the other
+    /// side. Returning `None` drops the packet.
     ///
     /// # Errors
     ///
@@ -36,41 +157,119 @@ impl Proxy {
         &mut self,
         cb_up: &dyn Fn(Packet) -> Option<Packet>,
         cb_down: &dyn Fn(Packet) -> Option<Packet>,
+    ) -> Result<()> {
+        self.run_actions(
+            &|packet| cb_up(packet).map(Action::Forward).into_iter().collect(),
+            &|packet| cb_down(packet).map(Action::Forward).into_iter().collect(),
+        )
+    }
+
+    /// Run the proxy until either side disconnects, with callbacks that can
+    /// do more than forward or drop.
+    ///
+    /// Like `run()`, but the callbacks return any number of actions, which
+    /// are carried out in order. E.g. a filter can refuse a callsign
+    /// registration by replying with a failed `RegisterCallsignReply`,
+    /// instead of forwarding the request.
+    ///
+    /// # Errors
+    ///
+    /// If the underlying connections fail, or other protocol error.
+    pub fn run_actions(
+        &mut self,
+        cb_up: &dyn Fn(Packet) -> Vec<Action>,
+        cb_down: &dyn Fn(Packet) -> Vec<Action>,
     ) -> Result<()> {
         info!("Running proxy");
-        self.up.send(Packet::VersionQuery)?;
+        let mut held = Held::new();
+        let mut retry = None;
         loop {
+            let timer = retry.map_or_else(never, after);
             select! {
-                    recv(self.down.rx) -> packet => {
-                        debug!("agw: Got {packet:?} from downstream");
-                match packet {
-                Ok(packet) => {
-                    if let Some(p) = cb_down(packet) {
-                    debug!("agw: … Transformed into {p:?}");
-                    }
+                recv(self.down.rx) -> frame => {
+                    let Ok(frame) = frame else {
+                        info!("agw: Downstream disconnected");
+                        return self.down.reader_result();
+                    };
+                    debug!("agw: Got {:?} from downstream", frame.packet());
+                    let actions = cb_down(frame.packet().clone());
+                    let held = self.limiter.as_ref().map(|limiter| (&mut held, limiter));
+                    Self::dispatch(actions, &frame, &self.down, &self.up, held)?;
                 },
-                Err(_e) => return Ok(()),
-                }
-                    },
-                    recv(self.up.rx) -> packet => {
-                        debug!("agw: Got {packet:?} from upstream");
-                        match packet {
-                Ok(packet) => {
-                    if let Some(p) = cb_up(packet) {
-                    debug!("agw: Transformed into {p:?}");
+                recv(self.up.rx) -> frame => {
+                    let Ok(frame) = frame else {
+                        info!("agw: Upstream disconnected");
+                        return self.up.reader_result();
+                    };
+                    debug!("agw: Got {:?} from upstream", frame.packet());
+                    if let Some(limiter) = &self.limiter {
+                        limiter.observe(frame.packet());
                     }
+                    let actions = cb_up(frame.packet().clone());
+                    Self::dispatch(actions, &frame, &self.up, &self.down, None)?;
                 },
-                Err(_e) => return Ok(()),
-                        }
-                    },
+                recv(timer) -> _ => {},
             };
+            if let Some(limiter) = &self.limiter {
+                let (ready, wait) = held.release(limiter);
+                for (_, bytes) in ready {
+                    self.up.send(bytes)?;
+                }
+                retry = wait;
+            }
+        }
+    }
+
+    /// Carry out actions triggered by `frame`. Forwarded packets are put in
+    /// `held`, if set, to wait for rate limiter budget.
+    ///
+    /// Forwarding the packet unchanged sends on the frame byte for byte.
+    fn dispatch(
+        actions: Vec<Action>,
+        frame: &Frame,
+        from: &ConnectionV2,
+        to: &ConnectionV2,
+        mut held: Option<(&mut Held<Vec<u8>>, &ClientLimiter)>,
+    ) -> Result<()> {
+        for action in actions {
+            match action {
+                Action::Forward(packet) => {
+                    debug!("agw: … forwarding {packet:?}");
+                    let bytes = if packet == *frame.packet() {
+                        frame.to_bytes()
+                    } else {
+                        packet.try_serialize()?
+                    };
+                    match &mut held {
+                        Some((held, limiter)) => held.push(limiter, packet, bytes),
+                        None => to.send(bytes)?,
+                    }
+                }
+                Action::Reply(packet) => {
+                    debug!("agw: … replying {packet:?}");
+                    from.send(packet.try_serialize()?)?;
+                }
+            }
         }
+        Ok(())
     }
 }
 
+/// What the proxy should do, as decided by a callback.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Send packet on to the other side.
+    Forward(Packet),
+
+    /// Send packet back to the side the triggering packet came from.
+    Reply(Packet),
+}
+
 struct ConnectionV2 {
-    rx: Receiver<Packet>,
-    tx: Sender<Packet>,
+    rx: Receiver<Frame>,
+    // Serialized frames to write.
+    tx: Option<Sender<Vec<u8>>>,
+    stream: TcpStream,
     rxthread: Option<std::thread::JoinHandle<Result<()>>>,
     txthread: Option<std::thread::JoinHandle<Result<()>>>,
 }
@@ -78,19 +277,30 @@ struct ConnectionV2 {
 impl Drop for ConnectionV2 {
     fn drop(&mut self) {
         debug!("agw: Awaiting proxy thread shutdown");
+        // Closing the channel stops the writer, and shutting down the socket
+        // stops the reader, as well as a writer blocked on a peer that isn't
+        // reading.
+        self.tx.take();
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
         let _ = self.txthread.take().unwrap().join();
-        let _ = self.rxthread.take().unwrap().join();
+        if let Some(rxthread) = self.rxthread.take() {
+            let _ = rxthread.join();
+        }
     }
 }
 impl ConnectionV2 {
-    fn rx_loop(mut rstream: TcpStream, tx: &Sender<Packet>) -> Result<()> {
+    /// Read frames until the peer closes the connection between frames.
+    fn rx_loop(mut rstream: TcpStream, tx: &Sender<Frame>, max_data_len: u32) -> Result<()> {
         loop {
             let mut header = [0_u8; crate::HEADER_LEN];
-            rstream.read_exact(&mut header)?;
+            if !crate::read_exact_or_eof(&mut rstream, &mut header)? {
+                return Ok(());
+            }
 
             let header = crate::parse_header(&header)?;
-            let payload = if header.data_len > 0 {
-                let mut payload = vec![0; header.data_len as usize];
+            let len = header.checked_data_len(max_data_len)?;
+            let payload = if len > 0 {
+                let mut payload = vec![0; len];
                 rstream.read_exact(&mut payload)?;
                 payload
             } else {
@@ -98,20 +308,22 @@ impl ConnectionV2 {
             };
             //let reply = parse_reply(&header, &payload)?;
             //tx.send((header, reply))?;
-            let packet = Packet::parse(&header, &payload)?;
-            trace!("agw: ConnectionV2 rx_loop: {packet:?}");
-            tx.send(packet).map_err(Error::other)?;
+            let frame = Frame::parse(header, payload)?;
+            trace!("agw: ConnectionV2 rx_loop: {:?}", frame.packet());
+            tx.send(frame).map_err(Error::other)?;
         }
     }
-    fn new(rstream: TcpStream) -> Result<Self> {
+    fn new(rstream: TcpStream, max_data_len: u32) -> Result<Self> {
+        let stream = rstream.try_clone()?;
         let mut wstream = rstream.try_clone()?;
-        let (rxtx, rxrx) = unbounded::<Packet>();
-        let rxthread = std::thread::spawn(move || -> Result<()> { Self::rx_loop(rstream, &rxtx) });
-        let (txtx, txrx) = unbounded::<Packet>();
+        let (rxtx, rxrx) = unbounded::<Frame>();
+        let rxthread = std::thread::spawn(move || -> Result<()> {
+            Self::rx_loop(rstream, &rxtx, max_data_len)
+        });
+        let (txtx, txrx) = unbounded::<Vec<u8>>();
         let txthread = std::thread::spawn(move || {
-            for packet in txrx {
-                let bytes = packet.serialize();
-                let _ = wstream.write(&bytes)?;
+            for bytes in txrx {
+                wstream.write_all(&bytes)?;
                 debug!("agw: Send: {bytes:?}");
             }
             Ok(())
@@ -120,10 +332,212 @@ impl ConnectionV2 {
             rxthread: Some(rxthread),
             txthread: Some(txthread),
             rx: rxrx,
-            tx: txtx,
+            tx: Some(txtx),
+            stream,
         })
     }
-    fn send(&self, packet: Packet) -> Result<()> {
-        self.tx.send(packet).map_err(Error::other)
+    /// Wait for the reader to stop, after its channel closed, and return
+    /// why it stopped.
+    fn reader_result(&mut self) -> Result<()> {
+        match self.rxthread.take() {
+            Some(rxthread) => rxthread
+                .join()
+                .map_err(|_| Error::msg("proxy reader thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+    fn send(&self, bytes: Vec<u8>) -> Result<()> {
+        self.tx
+            .as_ref()
+            .ok_or(Error::ConnectionClosed)?
+            .send(bytes)
+            .map_err(|_| Error::ConnectionClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Call, Pid, Port, HEADER_LEN};
+
+    /// Connected pair of TCP streams.
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+        (a, b)
+    }
+
+    /// Run a proxy whose downstream callback is `cb_down`, returning the
+    /// client and upstream server ends.
+    fn start(
+        cb_down: impl Fn(Packet) -> Vec<Action> + Send + 'static,
+    ) -> (TcpStream, TcpStream, std::thread::JoinHandle<Result<()>>) {
+        start_with(Proxy::builder(), cb_down)
+    }
+
+    /// Like `start()`, with settings from `builder`.
+    fn start_with(
+        builder: ProxyBuilder,
+        cb_down: impl Fn(Packet) -> Vec<Action> + Send + 'static,
+    ) -> (TcpStream, TcpStream, std::thread::JoinHandle<Result<()>>) {
+        let (client, down) = tcp_pair();
+        let (up, server) = tcp_pair();
+        let mut proxy = builder.upstream_stream(up).build(down).unwrap();
+        let thread =
+            std::thread::spawn(move || proxy.run_actions(&|p| vec![Action::Forward(p)], &cb_down));
+        (client, server, thread)
+    }
+
+    fn send(s: &mut TcpStream, packet: &Packet) {
+        s.write_all(&packet.serialize()).unwrap();
+    }
+
+    fn recv(s: &mut TcpStream) -> Packet {
+        let mut header = [0; HEADER_LEN];
+        s.read_exact(&mut header).unwrap();
+        let header = crate::parse_header(&header).unwrap();
+        let mut data = vec![0; header.checked_data_len(1000).unwrap()];
+        s.read_exact(&mut data).unwrap();
+        Packet::parse(&header, &data).unwrap()
+    }
+
+    fn unproto(text: &str) -> Packet {
+        Packet::Unproto {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: "M0THC-1".parse().unwrap(),
+            dst: "ID".parse().unwrap(),
+            data: text.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn forwards_both_ways() {
+        let (mut client, mut server, thread) = start(|p| vec![Action::Forward(p)]);
+        send(&mut client, &Packet::VersionQuery);
+        assert_eq!(recv(&mut server), Packet::VersionQuery);
+        let reply = Packet::VersionReply {
+            major: 2005,
+            minor: 127,
+        };
+        send(&mut server, &reply);
+        assert_eq!(recv(&mut client), reply);
+
+        // Closing downstream stops the proxy.
+        drop(client);
+        thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn reader_errors_are_returned() {
+        // Payload longer than the proxy accepts.
+        let (mut client, _server, thread) = start(|p| vec![Action::Forward(p)]);
+        let mut header = crate::Header::new(Port(0), b'D', Pid(0xF0), None, None, 0);
+        header.data_len = u32::MAX;
+        client.write_all(&header.to_bytes()).unwrap();
+        assert!(matches!(
+            thread.join().unwrap(),
+            Err(Error::FrameTooLarge { .. })
+        ));
+
+        // Closed partway through a header.
+        let (mut client, _server, thread) = start(|p| vec![Action::Forward(p)]);
+        client.write_all(&[0; HEADER_LEN / 2]).unwrap();
+        drop(client);
+        assert!(matches!(
+            thread.join().unwrap(),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn drop_doesnt_wait_for_blocked_writer() {
+        let (a, _b) = tcp_pair();
+        let con = ConnectionV2::new(a, crate::DEFAULT_MAX_DATA_LEN).unwrap();
+        // More than the socket buffers hold, since the peer isn't reading.
+        for _ in 0..1000 {
+            con.send(vec![0; 64 << 10]).unwrap();
+        }
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        std::thread::spawn(move || {
+            drop(con);
+            tx.send(()).unwrap();
+        });
+        rx.recv_timeout(std::time::Duration::from_secs(10))
+            .expect("drop blocked");
+    }
+
+    #[test]
+    fn honours_actions() {
+        let (mut client, mut server, _thread) = start(|p| match &p {
+            Packet::Unproto { data, .. } if data == b"drop" => vec![],
+            Packet::Unproto { data, .. } if data == b"twice" => {
+                vec![Action::Forward(p.clone()), Action::Forward(p)]
+            }
+            Packet::RegisterCallsign(port, call) => {
+                vec![Action::Reply(Packet::RegisterCallsignReply {
+                    port: *port,
+                    call: call.clone(),
+                    success: false,
+                })]
+            }
+            _ => vec![Action::Forward(p)],
+        });
+        let call: Call = "M0THC-1".parse().unwrap();
+        send(
+            &mut client,
+            &Packet::RegisterCallsign(Port(0), call.clone()),
+        );
+        assert_eq!(
+            recv(&mut client),
+            Packet::RegisterCallsignReply {
+                port: Port(0),
+                call,
+                success: false
+            }
+        );
+        send(&mut client, &unproto("drop"));
+        send(&mut client, &unproto("twice"));
+        send(&mut client, &unproto("last"));
+        assert_eq!(recv(&mut server), unproto("twice"));
+        assert_eq!(recv(&mut server), unproto("twice"));
+        assert_eq!(recv(&mut server), unproto("last"));
+    }
+
+    #[test]
+    fn throttled_client_doesnt_block_upstream() {
+        use crate::ratelimit::{Limits, Overflow};
+        let limiter = Arc::new(RateLimiter::new(
+            Limits {
+                frames_per_minute: Some(1),
+                ..Limits::default()
+            },
+            Limits::default(),
+            Overflow::Queue,
+        ));
+        let (mut client, mut server, _thread) =
+            start_with(Proxy::builder().rate_limiter(limiter.clone()), |p| {
+                vec![Action::Forward(p)]
+            });
+        send(&mut client, &unproto("one"));
+        send(&mut client, &unproto("two"));
+        send(&mut client, &Packet::VersionQuery);
+        assert_eq!(recv(&mut server), unproto("one"));
+
+        // The rest are held back, in order.
+        server
+            .set_read_timeout(Some(std::time::Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0; 1];
+        assert!(server.read(&mut buf).is_err());
+
+        // While traffic to the client keeps flowing.
+        client
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        send(&mut server, &unproto("reply"));
+        assert_eq!(recv(&mut client), unproto("reply"));
+        assert_eq!(limiter.stats().queued, 1);
     }
 }
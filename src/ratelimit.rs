@@ -0,0 +1,688 @@
+//! Transmit rate limiting.
+//!
+//! Budgets are per client and per port, over a sliding window of one
+//! minute. A budget can limit frames, bytes, and duty cycle. Duty cycle is
+//! the share of time spent transmitting, estimated from the frame length
+//! and the port's baud rate.
+//!
+//! One `RateLimiter` is shared by all clients, and each client gets its own
+//! `ClientLimiter` from it.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use agw::ratelimit::{Limits, Overflow, RateLimiter};
+//! let limiter = Arc::new(RateLimiter::new(
+//!     Limits {
+//!         frames_per_minute: Some(10),
+//!         ..Limits::default()
+//!     },
+//!     Limits {
+//!         duty_cycle: Some(0.25),
+//!         ..Limits::default()
+//!     },
+//!     Overflow::Refuse,
+//! ));
+//! let client = limiter.client("127.0.0.1");
+//! let packet = agw::Packet::Unproto {
+//!     port: agw::Port(0),
+//!     pid: agw::Pid(0xF0),
+//!     src: "M0QQQ-8".parse()?,
+//!     dst: "APZ001".parse()?,
+//!     data: b"hello".to_vec(),
+//! };
+//! assert!(client.acquire_blocking(&packet));
+//! assert_eq!(client.stats().frames, 1);
+//! # Ok::<(), agw::Error>(())
+//! ```
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use crate::{Baud, Packet, Port};
+
+/// Length of the sliding window that budgets apply to.
+const WINDOW: Duration = Duration::from_mins(1);
+
+/// Baud rate assumed for ports whose rate is not known.
+const DEFAULT_BAUD: u32 = 1200;
+
+/// Most frames held back for each client with `Overflow::Queue`. Frames
+/// beyond that are refused.
+pub const MAX_HELD: usize = 256;
+
+/// AX.25 framing bytes around the addresses and info field: two flags,
+/// control, PID, and FCS.
+pub(crate) const FRAME_OVERHEAD: usize = 6;
+
+/// Transmit budget per minute. `None` means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub frames_per_minute: Option<u32>,
+    pub bytes_per_minute: Option<u64>,
+
+    /// Max share of time spent transmitting, 0.0 to 1.0.
+    pub duty_cycle: Option<f64>,
+}
+
+/// What to do with a frame that's over budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Hold it until there's budget for it, up to `MAX_HELD` frames per
+    /// client.
+    #[default]
+    Queue,
+
+    /// Drop it.
+    Refuse,
+}
+
+/// Transmit counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stats {
+    /// Frames let through.
+    pub frames: u64,
+
+    /// AX.25 bytes let through.
+    pub bytes: u64,
+
+    /// Frames that had to wait for budget.
+    pub queued: u64,
+
+    /// Frames dropped for being over budget.
+    pub refused: u64,
+}
+
+/// Outcome of checking a frame against the budgets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Within budget, and counted as sent.
+    Allow,
+
+    /// Over budget, but will fit after waiting this long.
+    Wait(Duration),
+
+    /// Will never fit, e.g. larger than the whole byte budget.
+    Refuse,
+}
+
+struct Sent {
+    time: Instant,
+    bytes: u64,
+    airtime: Duration,
+}
+
+/// Frames sent in the last `WINDOW`, and counters.
+#[derive(Default)]
+struct Budget {
+    sent: VecDeque<Sent>,
+    bytes: u64,
+    airtime: Duration,
+    stats: Stats,
+}
+
+impl Budget {
+    fn expire(&mut self, now: Instant) {
+        while let Some(s) = self.sent.front() {
+            if now.duration_since(s.time) < WINDOW {
+                break;
+            }
+            self.bytes -= s.bytes;
+            self.airtime -= s.airtime;
+            self.sent.pop_front();
+        }
+    }
+
+    /// How long until a frame fits, or `None` if it never will.
+    fn wait(
+        &self,
+        limits: &Limits,
+        now: Instant,
+        bytes: u64,
+        airtime: Duration,
+    ) -> Option<Duration> {
+        let fits = |frames: usize, sent_bytes: u64, sent_airtime: Duration| {
+            limits
+                .frames_per_minute
+                .is_none_or(|max| frames < max as usize)
+                && limits
+                    .bytes_per_minute
+                    .is_none_or(|max| sent_bytes + bytes <= max)
+                && limits.duty_cycle.is_none_or(|max| {
+                    (sent_airtime + airtime).as_secs_f64() <= max * WINDOW.as_secs_f64()
+                })
+        };
+        if !fits(0, 0, Duration::ZERO) {
+            return None;
+        }
+        let (mut frames, mut sent_bytes, mut sent_airtime) =
+            (self.sent.len(), self.bytes, self.airtime);
+        if fits(frames, sent_bytes, sent_airtime) {
+            return Some(Duration::ZERO);
+        }
+        for s in &self.sent {
+            frames -= 1;
+            sent_bytes -= s.bytes;
+            sent_airtime -= s.airtime;
+            if fits(frames, sent_bytes, sent_airtime) {
+                return Some((s.time + WINDOW).saturating_duration_since(now));
+            }
+        }
+        Some(Duration::ZERO)
+    }
+
+    fn record(&mut self, now: Instant, bytes: u64, airtime: Duration) {
+        self.sent.push_back(Sent {
+            time: now,
+            bytes,
+            airtime,
+        });
+        self.bytes += bytes;
+        self.airtime += airtime;
+        self.stats.frames += 1;
+        self.stats.bytes += bytes;
+    }
+}
+
+#[derive(Default)]
+struct PortState {
+    baud: Option<u32>,
+    budget: Budget,
+}
+
+/// Port and length in bytes of the AX.25 frame that sending the packet
+/// would transmit, if any.
+fn frame_len(packet: &Packet) -> Option<(Port, usize)> {
+    let addr = |via: usize| 7 * (2 + via);
+    Some(match packet {
+        Packet::Unproto { port, data, .. } | Packet::Data { port, data, .. } => {
+            (*port, FRAME_OVERHEAD + addr(0) + data.len())
+        }
+        Packet::UnprotoVia {
+            port, via, data, ..
+        } => (*port, FRAME_OVERHEAD + addr(via.len()) + data.len()),
+        // SABM has no PID.
+        Packet::Connect { port, .. } => (*port, FRAME_OVERHEAD - 1 + addr(0)),
+        Packet::ConnectVia { port, via, .. } => (*port, FRAME_OVERHEAD - 1 + addr(via.len())),
+        // KISS command byte, then the frame without flags and FCS.
+        Packet::RawFrame { port, data } => (*port, data.len().saturating_sub(1) + 4),
+        _ => return None,
+    })
+}
+
+/// Transmit budgets shared by all clients.
+pub struct RateLimiter {
+    client_limits: Limits,
+    port_limits: Limits,
+    overflow: Overflow,
+    ports: Mutex<HashMap<Port, PortState>>,
+    clients: Mutex<Vec<(String, Weak<Mutex<Budget>>)>>,
+}
+
+impl RateLimiter {
+    /// Create rate limiter with budgets for each client, and for each port.
+    #[must_use]
+    pub fn new(client_limits: Limits, port_limits: Limits, overflow: Overflow) -> Self {
+        Self {
+            client_limits,
+            port_limits,
+            overflow,
+            ports: Mutex::new(HashMap::new()),
+            clients: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Create the budget for a new client.
+    ///
+    /// `name`, e.g. the client address, is what the client is listed as
+    /// in `client_stats()`.
+    #[must_use]
+    pub fn client(self: &Arc<Self>, name: impl Into<String>) -> ClientLimiter {
+        let budget = Arc::new(Mutex::new(Budget::default()));
+        self.clients
+            .lock()
+            .unwrap()
+            .push((name.into(), Arc::downgrade(&budget)));
+        ClientLimiter {
+            limiter: self.clone(),
+            budget,
+        }
+    }
+
+    /// Counters for each current client, by name.
+    #[must_use]
+    pub fn client_stats(&self) -> Vec<(String, Stats)> {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|(_, budget)| budget.strong_count() > 0);
+        clients
+            .iter()
+            .filter_map(|(name, budget)| {
+                Some((name.clone(), budget.upgrade()?.lock().unwrap().stats))
+            })
+            .collect()
+    }
+
+    /// Set the baud rate of a port, used to estimate duty cycle.
+    ///
+    /// Ports default to 1200 baud, until set here or seen in a
+    /// `PortCapReply`.
+    pub fn set_baud(&self, port: Port, baud: Baud) {
+        self.ports.lock().unwrap().entry(port).or_default().baud = baud.bits_per_second();
+    }
+
+    /// Look at a packet from the AGW endpoint, to learn port baud rates.
+    pub fn observe(&self, packet: &Packet) {
+        if let Packet::PortCapReply { port, caps } = packet {
+            self.set_baud(*port, caps.rate);
+        }
+    }
+
+    /// Counters for a port.
+    #[must_use]
+    pub fn port_stats(&self, port: Port) -> Stats {
+        self.ports
+            .lock()
+            .unwrap()
+            .get(&port)
+            .map(|p| p.budget.stats)
+            .unwrap_or_default()
+    }
+
+    /// Counters for all ports together.
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        self.ports
+            .lock()
+            .unwrap()
+            .values()
+            .fold(Stats::default(), |acc, p| Stats {
+                frames: acc.frames + p.budget.stats.frames,
+                bytes: acc.bytes + p.budget.stats.bytes,
+                queued: acc.queued + p.budget.stats.queued,
+                refused: acc.refused + p.budget.stats.refused,
+            })
+    }
+}
+
+/// What `ClientLimiter::step` wants the caller to do.
+enum Step {
+    Done(bool),
+    Sleep(Duration),
+}
+
+/// Transmit budget for one client.
+pub struct ClientLimiter {
+    limiter: Arc<RateLimiter>,
+    budget: Arc<Mutex<Budget>>,
+}
+
+impl ClientLimiter {
+    /// Look at a packet from the AGW endpoint, to learn port baud rates.
+    pub fn observe(&self, packet: &Packet) {
+        self.limiter.observe(packet);
+    }
+
+    /// Counters for this client.
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        self.budget.lock().unwrap().stats
+    }
+
+    /// Check if sending the packet now is within the client and port
+    /// budgets. If it is, it's counted as sent.
+    ///
+    /// Packets that don't transmit anything, such as queries, are always
+    /// allowed and not counted.
+    #[must_use]
+    pub fn check(&self, packet: &Packet) -> Decision {
+        self.check_at(packet, Instant::now())
+    }
+
+    fn check_at(&self, packet: &Packet, now: Instant) -> Decision {
+        let Some((port, len)) = frame_len(packet) else {
+            return Decision::Allow;
+        };
+        let mut ports = self.limiter.ports.lock().unwrap();
+        let port_state = ports.entry(port).or_default();
+        let mut client = self.budget.lock().unwrap();
+        let baud = port_state.baud.unwrap_or(DEFAULT_BAUD);
+        let bytes = len as u64;
+        let airtime = Duration::from_secs(bytes * 8) / baud;
+        port_state.budget.expire(now);
+        client.expire(now);
+        let wait = port_state
+            .budget
+            .wait(&self.limiter.port_limits, now, bytes, airtime)
+            .zip(client.wait(&self.limiter.client_limits, now, bytes, airtime))
+            .map(|(a, b)| a.max(b));
+        match wait {
+            None => Decision::Refuse,
+            Some(Duration::ZERO) => {
+                port_state.budget.record(now, bytes, airtime);
+                client.record(now, bytes, airtime);
+                Decision::Allow
+            }
+            Some(wait) => Decision::Wait(wait),
+        }
+    }
+
+    /// Count a queued or refused frame.
+    fn count(&self, packet: &Packet, f: impl Fn(&mut Stats)) {
+        if let Some((port, _)) = frame_len(packet) {
+            f(&mut self
+                .limiter
+                .ports
+                .lock()
+                .unwrap()
+                .entry(port)
+                .or_default()
+                .budget
+                .stats);
+        }
+        f(&mut self.budget.lock().unwrap().stats);
+    }
+
+    fn step(&self, packet: &Packet, queued: &mut bool) -> Step {
+        match self.check(packet) {
+            Decision::Allow => Step::Done(true),
+            Decision::Wait(wait) if self.limiter.overflow == Overflow::Queue => {
+                if !*queued {
+                    debug!("ratelimit: Queuing for {wait:?}: {packet:?}");
+                    self.count(packet, |s| s.queued += 1);
+                    *queued = true;
+                }
+                Step::Sleep(wait)
+            }
+            Decision::Wait(_) | Decision::Refuse => {
+                debug!("ratelimit: Refusing {packet:?}");
+                self.count(packet, |s| s.refused += 1);
+                Step::Done(false)
+            }
+        }
+    }
+
+    /// Wait for budget to send the packet, or refuse it.
+    ///
+    /// Returns true if the packet may be sent, and false if it should be
+    /// dropped. Blocks the thread while queued.
+    #[must_use]
+    pub fn acquire_blocking(&self, packet: &Packet) -> bool {
+        let mut queued = false;
+        loop {
+            match self.step(packet, &mut queued) {
+                Step::Done(ok) => return ok,
+                Step::Sleep(wait) => std::thread::sleep(wait),
+            }
+        }
+    }
+
+    /// Wait for budget to send the packet, or refuse it.
+    ///
+    /// Returns true if the packet may be sent, and false if it should be
+    /// dropped.
+    pub async fn acquire(&self, packet: &Packet) -> bool {
+        let mut queued = false;
+        loop {
+            match self.step(packet, &mut queued) {
+                Step::Done(ok) => return ok,
+                Step::Sleep(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+}
+
+/// Packets held back for lack of budget, to send in order once there is.
+///
+/// Lets a proxy keep serving the other direction while a client is over
+/// budget, instead of waiting in `acquire()`. Everything the client sends
+/// after a held packet is held behind it, so order is kept.
+pub(crate) struct Held<T> {
+    queue: VecDeque<(Packet, T)>,
+    // Whether the first packet has been counted as queued.
+    queued: bool,
+}
+
+impl<T> Held<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            queued: false,
+        }
+    }
+
+    /// Hold a packet, along with what to send for it.
+    ///
+    /// If `MAX_HELD` packets are already held, it's refused instead.
+    pub(crate) fn push(&mut self, limiter: &ClientLimiter, packet: Packet, item: T) {
+        if self.queue.len() >= MAX_HELD {
+            info!("ratelimit: Too many held packets, dropping {packet:?}");
+            limiter.count(&packet, |s| s.refused += 1);
+            return;
+        }
+        self.queue.push_back((packet, item));
+    }
+
+    /// Take the held packets that may be sent now, in order. Packets the
+    /// limiter refuses are dropped.
+    ///
+    /// Also returns how long until the rest should be tried again, if any
+    /// are still held.
+    pub(crate) fn release(
+        &mut self,
+        limiter: &ClientLimiter,
+    ) -> (Vec<(Packet, T)>, Option<Duration>) {
+        let mut ready = Vec::new();
+        while let Some((packet, _)) = self.queue.front() {
+            match limiter.step(packet, &mut self.queued) {
+                Step::Sleep(wait) => return (ready, Some(wait)),
+                Step::Done(ok) => {
+                    let (packet, item) = self.queue.pop_front().expect("can't happen: front");
+                    self.queued = false;
+                    if ok {
+                        ready.push((packet, item));
+                    } else {
+                        info!("ratelimit: Over transmit budget, dropping {packet:?}");
+                    }
+                }
+            }
+        }
+        (ready, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Pid;
+
+    fn unproto(text: &str) -> Packet {
+        Packet::Unproto {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: "M0THC-1".parse().unwrap(),
+            dst: "ID".parse().unwrap(),
+            data: text.as_bytes().to_vec(),
+        }
+    }
+
+    fn one_frame(overflow: Overflow) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(
+            Limits {
+                frames_per_minute: Some(1),
+                ..Limits::default()
+            },
+            Limits::default(),
+            overflow,
+        ))
+    }
+
+    #[test]
+    fn held_packets_wait_in_order() {
+        let limiter = one_frame(Overflow::Queue);
+        let client = limiter.client("test");
+        let mut held = Held::new();
+        held.push(&client, unproto("one"), 1);
+        held.push(&client, unproto("two"), 2);
+        held.push(&client, Packet::VersionQuery, 3);
+        let (ready, wait) = held.release(&client);
+        assert_eq!(ready, vec![(unproto("one"), 1)]);
+        let wait = wait.unwrap();
+        assert!(wait > Duration::from_secs(50) && wait <= WINDOW, "{wait:?}");
+
+        // Trying again doesn't count it as queued twice.
+        let (ready, wait) = held.release(&client);
+        assert!(ready.is_empty());
+        assert!(wait.is_some());
+        assert_eq!(client.stats().queued, 1);
+        assert_eq!(client.stats().frames, 1);
+    }
+
+    #[test]
+    fn held_packets_refused() {
+        let limiter = one_frame(Overflow::Refuse);
+        let client = limiter.client("test");
+        let mut held = Held::new();
+        held.push(&client, unproto("one"), 1);
+        held.push(&client, unproto("two"), 2);
+        held.push(&client, Packet::VersionQuery, 3);
+        let (ready, wait) = held.release(&client);
+        assert_eq!(ready, vec![(unproto("one"), 1), (Packet::VersionQuery, 3)]);
+        assert_eq!(wait, None);
+        assert_eq!(client.stats().refused, 1);
+        assert_eq!(limiter.port_stats(Port(0)).refused, 1);
+    }
+
+    #[test]
+    fn held_queue_is_bounded() {
+        let limiter = one_frame(Overflow::Queue);
+        let client = limiter.client("test");
+        let mut held = Held::new();
+        for n in 0..=MAX_HELD {
+            held.push(&client, unproto(&n.to_string()), n);
+        }
+        assert_eq!(client.stats().refused, 1);
+        let (ready, _) = held.release(&client);
+        assert_eq!(ready, vec![(unproto("0"), 0)]);
+        assert_eq!(held.queue.len(), MAX_HELD - 1);
+    }
+
+    fn limiter(client: Limits, port: Limits) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(client, port, Overflow::Refuse))
+    }
+
+    /// Unproto packet making an AX.25 frame of `len` bytes.
+    fn frame(len: usize) -> Packet {
+        let packet = unproto(&"x".repeat(len - FRAME_OVERHEAD - 14));
+        assert_eq!(frame_len(&packet), Some((Port(0), len)));
+        packet
+    }
+
+    #[test]
+    fn bytes_per_minute() {
+        let limiter = limiter(
+            Limits {
+                bytes_per_minute: Some(100),
+                ..Limits::default()
+            },
+            Limits::default(),
+        );
+        let client = limiter.client("test");
+        for _ in 0..4 {
+            assert_eq!(client.check(&frame(25)), Decision::Allow);
+        }
+        assert!(matches!(client.check(&frame(25)), Decision::Wait(_)));
+        // Queries don't count.
+        assert_eq!(client.check(&Packet::VersionQuery), Decision::Allow);
+        assert_eq!(client.stats().frames, 4);
+        assert_eq!(client.stats().bytes, 100);
+        assert_eq!(limiter.port_stats(Port(0)).bytes, 100);
+    }
+
+    #[test]
+    fn never_fitting_frame_is_refused() {
+        let limiter = limiter(
+            Limits {
+                bytes_per_minute: Some(100),
+                ..Limits::default()
+            },
+            Limits {
+                duty_cycle: Some(0.01),
+                ..Limits::default()
+            },
+        );
+        let client = limiter.client("test");
+        assert_eq!(client.check(&frame(101)), Decision::Refuse);
+        // 0.6 s of airtime per minute is 90 bytes at 1200 baud.
+        assert_eq!(client.check(&frame(91)), Decision::Refuse);
+        assert_eq!(client.check(&frame(90)), Decision::Allow);
+        assert_eq!(client.stats().frames, 1);
+    }
+
+    #[test]
+    fn duty_cycle() {
+        let limiter = limiter(
+            Limits::default(),
+            Limits {
+                duty_cycle: Some(0.25),
+                ..Limits::default()
+            },
+        );
+        let client = limiter.client("test");
+        // 150 bytes take one second at the default 1200 baud, and 15
+        // seconds per minute is 25%.
+        let now = Instant::now();
+        for _ in 0..15 {
+            assert_eq!(client.check_at(&frame(150), now), Decision::Allow);
+        }
+        assert_eq!(
+            client.check_at(&frame(150), now + Duration::from_secs(1)),
+            Decision::Wait(Duration::from_secs(59))
+        );
+        // The budget is per port, so other clients are held back too.
+        let other = limiter.client("other");
+        assert!(matches!(
+            other.check_at(&frame(150), now),
+            Decision::Wait(_)
+        ));
+    }
+
+    #[test]
+    fn airtime_uses_port_caps_baud() {
+        let limiter = limiter(
+            Limits::default(),
+            Limits {
+                duty_cycle: Some(0.25),
+                ..Limits::default()
+            },
+        );
+        let client = limiter.client("test");
+        client.observe(&Packet::PortCapReply {
+            port: Port(0),
+            caps: crate::PortCaps::new(Baud::B9600),
+        });
+        // 150 bytes take 1/8 of a second at 9600 baud.
+        for _ in 0..120 {
+            assert_eq!(client.check(&frame(150)), Decision::Allow);
+        }
+        assert!(matches!(client.check(&frame(150)), Decision::Wait(_)));
+    }
+
+    #[test]
+    fn window_expires() {
+        let limiter = one_frame(Overflow::Queue);
+        let client = limiter.client("test");
+        let now = Instant::now();
+        assert_eq!(client.check_at(&unproto("one"), now), Decision::Allow);
+        assert_eq!(
+            client.check_at(&unproto("two"), now + Duration::from_secs(20)),
+            Decision::Wait(Duration::from_secs(40))
+        );
+        assert_eq!(
+            client.check_at(&unproto("two"), now + WINDOW),
+            Decision::Allow
+        );
+        assert_eq!(client.stats().frames, 2);
+    }
+}
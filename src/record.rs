@@ -0,0 +1,366 @@
+//! Recording AGW sessions, and replaying them.
+//!
+//! A recording holds every frame sent and received on one connection, with
+//! direction and time, as seen from the end that made the recording. Record
+//! with a `Recorder` as the tap of an `r#async::AGW` or `r#async::AGWServer`.
+//!
+//! The file format is a magic line, followed by one record per frame:
+//!
+//! * Direction: `<` received, `>` sent.
+//! * Time: microseconds since the UNIX epoch, u64 little endian.
+//! * Length of the frame, u32 little endian.
+//! * The AGW frame, header and payload, byte for byte as sent or received.
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, warn};
+
+use crate::r#async::AGWServer;
+use crate::tap::{Direction, Tap};
+use crate::{Error, Frame, Result};
+
+const MAGIC: &[u8] = b"AGWREC1\n";
+const DIR_RX: u8 = b'<';
+const DIR_TX: u8 = b'>';
+
+/// One recorded frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub dir: Direction,
+    pub time: SystemTime,
+    pub frame: Frame,
+}
+
+/// Writer of recordings.
+pub struct Recorder<W: Write> {
+    w: W,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Create recorder, and write the file header.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn new(mut w: W) -> Result<Self> {
+        w.write_all(MAGIC)?;
+        Ok(Self { w })
+    }
+
+    /// Write one record.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn write(&mut self, record: &Record) -> Result<()> {
+        let frame = record.frame.to_bytes();
+        let time = record
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.w.write_all(&[match record.dir {
+            Direction::Rx => DIR_RX,
+            Direction::Tx => DIR_TX,
+        }])?;
+        self.w
+            .write_all(&u64::try_from(time.as_micros())?.to_le_bytes())?;
+        self.w
+            .write_all(&u32::try_from(frame.len())?.to_le_bytes())?;
+        self.w.write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Flush the underlying writer.
+    ///
+    /// # Errors
+    ///
+    /// If flushing fails.
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.w.flush()?)
+    }
+
+    /// Get back the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+/// Record every frame, with the current time.
+impl<W: Write + Send> Tap for Mutex<Recorder<W>> {
+    fn frame(&self, dir: Direction, frame: &Frame) {
+        let record = Record {
+            dir,
+            time: SystemTime::now(),
+            frame: frame.clone(),
+        };
+        let mut w = self.lock().unwrap();
+        if let Err(e) = w.write(&record).and_then(|()| w.flush()) {
+            warn!("agw/record: Failed to write recording: {e}");
+        }
+    }
+}
+
+/// Reader of recordings.
+///
+/// Iterates over the records in the file.
+pub struct Reader<R: Read> {
+    r: R,
+}
+
+impl<R: Read> Reader<R> {
+    /// Create reader, and read the file header.
+    ///
+    /// # Errors
+    ///
+    /// If reading fails, or the file is not a recording.
+    pub fn new(mut r: R) -> Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::BadRecording("not an AGW recording".to_string()));
+        }
+        Ok(Self { r })
+    }
+
+    /// Read the next record.
+    ///
+    /// Returns `None` at the end of the file.
+    ///
+    /// # Errors
+    ///
+    /// If reading fails, or the file is corrupt.
+    pub fn read_record(&mut self) -> Result<Option<Record>> {
+        let mut head = [0; 13];
+        match self.r.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let dir = match head[0] {
+            DIR_RX => Direction::Rx,
+            DIR_TX => Direction::Tx,
+            other => return Err(Error::BadRecording(format!("bad direction {other:?}"))),
+        };
+        let micros = u64::from_le_bytes(head[1..9].try_into().expect("can't happen: 8 bytes"));
+        let len = u32::from_le_bytes(head[9..13].try_into().expect("can't happen: 4 bytes"));
+        let len = usize::try_from(len)?;
+        if len > crate::HEADER_LEN + crate::DEFAULT_MAX_DATA_LEN as usize {
+            return Err(Error::BadRecording(format!("record too large: {len}")));
+        }
+        let mut frame = vec![0; len];
+        self.r.read_exact(&mut frame).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                Error::BadRecording("truncated record".to_string())
+            } else {
+                e.into()
+            }
+        })?;
+        let Some((frame, n)) = Frame::parse_buf(&frame, crate::DEFAULT_MAX_DATA_LEN)? else {
+            return Err(Error::BadRecording("truncated frame".to_string()));
+        };
+        if n != len {
+            return Err(Error::BadRecording(
+                "trailing bytes after frame".to_string(),
+            ));
+        }
+        Ok(Some(Record {
+            dir,
+            time: SystemTime::UNIX_EPOCH + Duration::from_micros(micros),
+            frame,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Replay a recording on a connection, taking the part of the recorder's
+/// end.
+///
+/// Records in direction `send` are sent, in order. Before each one is sent,
+/// all the records before it in the other direction must have been
+/// received, and be byte for byte what was recorded. Timestamps are ignored, so
+/// the replay is as fast as the other end allows.
+///
+/// To emulate an AGW server toward a client, replay a recording made at a
+/// client with `send` being `Direction::Rx`. To drive an AGW server the way a
+/// client did, connect to it, and replay with `Direction::Tx`.
+/// `AGWServer` works for both, since it only reads and writes frames.
+///
+/// # Errors
+///
+/// If the connection fails, or the other end sends something other than
+/// what was recorded.
+pub async fn replay<S, I>(con: &mut AGWServer<S>, records: I, send: Direction) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    I: IntoIterator<Item = Record>,
+{
+    for (n, record) in records.into_iter().enumerate() {
+        if record.dir == send {
+            debug!("agw/replay: #{n}: Sending {:?}", record.frame.packet());
+            con.send_frame(&record.frame).await?;
+        } else {
+            let got = con.recv_frame().await?;
+            debug!("agw/replay: #{n}: Got {:?}", got.packet());
+            if got != record.frame {
+                return Err(Error::ReplayDiverged {
+                    index: n,
+                    expected: Box::new(record.frame.into_packet()),
+                    got: Box::new(got.into_packet()),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Header, Packet, Pid, Port};
+
+    fn frame(packet: Packet) -> Frame {
+        Frame::from_packet(packet).unwrap()
+    }
+
+    fn records() -> Vec<Record> {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_001);
+        let data = |text: &str| Packet::Data {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: "M0THC-1".parse().unwrap(),
+            dst: "M0THC-2".parse().unwrap(),
+            data: text.as_bytes().to_vec(),
+        };
+        vec![
+            Record {
+                dir: Direction::Tx,
+                time,
+                frame: frame(Packet::VersionQuery),
+            },
+            Record {
+                dir: Direction::Rx,
+                time,
+                frame: frame(Packet::VersionReply {
+                    major: 2005,
+                    minor: 127,
+                }),
+            },
+            Record {
+                dir: Direction::Tx,
+                time,
+                frame: frame(data("ping")),
+            },
+            Record {
+                dir: Direction::Rx,
+                time: time + Duration::from_secs(1),
+                frame: frame(data("pong")),
+            },
+        ]
+    }
+
+    fn file(records: &[Record]) -> Vec<u8> {
+        let mut w = Recorder::new(Vec::new()).unwrap();
+        for r in records {
+            w.write(r).unwrap();
+        }
+        w.into_inner()
+    }
+
+    #[test]
+    fn read_back() {
+        let bytes = file(&records());
+        let got = Reader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(got, records());
+    }
+
+    #[test]
+    fn bad_files() {
+        assert!(matches!(
+            Reader::new(&b"NOTAREC\n"[..]),
+            Err(Error::BadRecording(_))
+        ));
+        let mut bytes = file(&records());
+        bytes.pop();
+        let got: Vec<_> = Reader::new(&bytes[..]).unwrap().collect();
+        assert_eq!(got.len(), 4);
+        assert!(matches!(got[3], Err(Error::BadRecording(_))));
+
+        let mut bytes = file(&records()[..1]);
+        bytes[MAGIC.len()] = b'?';
+        let mut reader = Reader::new(&bytes[..]).unwrap();
+        assert!(matches!(reader.read_record(), Err(Error::BadRecording(_))));
+    }
+
+    #[test]
+    fn tap_records() {
+        let tap = Mutex::new(Recorder::new(Vec::new()).unwrap());
+        tap.frame(Direction::Tx, &frame(Packet::VersionQuery));
+        // Recorded as received, not as the packet would serialize.
+        let mut header = Header::new(
+            Port(0),
+            b'D',
+            Pid(0xF0),
+            Some("M0THC-1".parse().unwrap()),
+            Some("M0THC-2".parse().unwrap()),
+            2,
+        );
+        header.user = 7;
+        let received = Frame::parse(header, b"hi".to_vec()).unwrap();
+        tap.frame(Direction::Rx, &received);
+        let bytes = tap.into_inner().unwrap().into_inner();
+        let got: Vec<_> = Reader::new(&bytes[..])
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[0].dir, Direction::Tx);
+        assert_eq!(got[0].frame.packet(), &Packet::VersionQuery);
+        assert_eq!(got[1].dir, Direction::Rx);
+        assert_eq!(got[1].frame.to_bytes(), received.to_bytes());
+    }
+
+    #[tokio::test]
+    async fn replay_both_ends() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut client = AGWServer::new(a);
+        let mut server = AGWServer::new(b);
+        let (c, s) = tokio::join!(
+            replay(&mut client, records(), Direction::Tx),
+            replay(&mut server, records(), Direction::Rx),
+        );
+        c.unwrap();
+        s.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_diverges() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut client = AGWServer::new(a);
+        let mut server = AGWServer::new(b);
+        let mut other = records();
+        other[1].frame = frame(Packet::VersionReply { major: 1, minor: 2 });
+        tokio::spawn(async move { replay(&mut server, other, Direction::Rx).await });
+        let c = replay(&mut client, records(), Direction::Tx).await;
+        let Err(Error::ReplayDiverged {
+            index,
+            expected,
+            got,
+        }) = c
+        else {
+            panic!("expected divergence, got {c:?}");
+        };
+        assert_eq!(index, 1);
+        assert_eq!(&*expected, records()[1].frame.packet());
+        assert_eq!(*got, Packet::VersionReply { major: 1, minor: 2 });
+    }
+}
@@ -0,0 +1,24 @@
+//! Observing frames as they pass through a connection.
+//!
+//! E.g. to write them to a capture file, see `pcap::Writer`.
+use crate::Frame;
+
+/// Which way a frame went, as seen from this end of the connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Direction {
+    /// Received from the other end.
+    Rx,
+
+    /// Sent to the other end.
+    Tx,
+}
+
+/// Something that wants to see every frame on a connection.
+///
+/// Called from the connection's IO path, so it should be quick, and must not
+/// block on the same connection.
+pub trait Tap: Send + Sync {
+    /// Called for each frame sent or received, as it was on the wire.
+    fn frame(&self, dir: Direction, frame: &Frame);
+}
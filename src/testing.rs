@@ -0,0 +1,629 @@
+//! Helpers for testing code that talks to an AGW server.
+//!
+//! `pair()` connects an `r#async::AGW` to a `Mock` over `tokio::io::duplex()`,
+//! so no TCP ports are needed. The mock follows a `Script`, expecting packets
+//! from the client and sending replies, in order. `Mock::finish()` then
+//! returns everything the client sent, with helpers for asserting on it.
+//!
+//! `blocking_pair()` does the same for a `v2::AGW`. For tests that just need
+//! something that behaves like a TNC, `fake_pair()` connects to a `FakeTnc`
+//! instead.
+//!
+//! `fault::Faulty` wraps a stream to inject faults, such as short reads and
+//! writes, and connections closing in the middle of a frame.
+//!
+//! ```
+//! use agw::testing::{pair, Script};
+//! use agw::{Call, Packet, Pid, Port};
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> agw::Result<()> {
+//! let me: Call = "M0THC-1".parse()?;
+//! let peer: Call = "M0THC-2".parse()?;
+//! let script = Script::new()
+//!     .expect(Packet::Connect {
+//!         port: Port(0),
+//!         pid: Pid(0xF0),
+//!         src: me.clone(),
+//!         dst: peer.clone(),
+//!     })
+//!     .reply(Packet::ConnectionEstablished {
+//!         port: Port(0),
+//!         pid: Pid(0xF0),
+//!         src: peer.clone(),
+//!         dst: me.clone(),
+//!         text: String::new(),
+//!     });
+//! let (agw, mock) = pair(script)?;
+//! let mut con = agw.connect(Port(0), Pid(0xF0), &me, &peer, &[]).await?;
+//! con.send(b"hello").await?;
+//!
+//! let received = mock.finish().await?;
+//! assert_eq!(received.data(&me, &peer), b"hello");
+//! # Ok(())
+//! # }
+//! ```
+pub mod fault;
+
+use std::fmt;
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::oneshot;
+
+use crate::r#async::server::{AgwBackend, Clients, Server};
+use crate::r#async::{AGWServer, AGW};
+use crate::{v2, Baud, Call, Error, MonitorKind, Packet, Pid, Port, PortCaps, PortInfo, Result};
+
+/// Buffer size of each direction of the duplex stream.
+const DUPLEX_BUF: usize = 64 * 1024;
+
+/// How long a stopped mock waits for more packets from the client.
+const QUIET: Duration = Duration::from_millis(100);
+
+type Matcher = Box<dyn Fn(&Packet) -> bool + Send>;
+
+enum Step {
+    Expect { desc: String, matcher: Matcher },
+    Reply(Packet),
+}
+
+/// What a `Mock` expects from the client, and what it replies.
+#[derive(Default)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut l = f.debug_list();
+        for step in &self.steps {
+            match step {
+                Step::Expect { desc, .. } => l.entry(&format_args!("expect {desc}")),
+                Step::Reply(packet) => l.entry(&format_args!("reply {packet:?}")),
+            };
+        }
+        l.finish()
+    }
+}
+
+impl Script {
+    /// Create an empty script, which accepts anything.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expect the client to send exactly this packet next.
+    #[must_use]
+    pub fn expect(self, packet: Packet) -> Self {
+        let desc = format!("{packet:?}");
+        self.expect_match(desc, move |p| *p == packet)
+    }
+
+    /// Expect the client to send a packet for which `matcher` returns true.
+    ///
+    /// `desc` is what the packet should have been, for the error message.
+    #[must_use]
+    pub fn expect_match<F>(mut self, desc: impl Into<String>, matcher: F) -> Self
+    where
+        F: Fn(&Packet) -> bool + Send + 'static,
+    {
+        self.steps.push(Step::Expect {
+            desc: desc.into(),
+            matcher: Box::new(matcher),
+        });
+        self
+    }
+
+    /// Send a packet to the client.
+    #[must_use]
+    pub fn reply(mut self, packet: Packet) -> Self {
+        self.steps.push(Step::Reply(packet));
+        self
+    }
+}
+
+/// Packets a `Mock` received from the client.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Received {
+    packets: Vec<Packet>,
+}
+
+impl Received {
+    /// All packets, in the order received.
+    #[must_use]
+    pub fn packets(&self) -> &[Packet] {
+        &self.packets
+    }
+
+    /// Consume, and return the packets.
+    #[must_use]
+    pub fn into_packets(self) -> Vec<Packet> {
+        self.packets
+    }
+
+    /// Return whether the packet was received.
+    #[must_use]
+    pub fn contains(&self, packet: &Packet) -> bool {
+        self.packets.contains(packet)
+    }
+
+    /// Packets for which `f` returns true.
+    pub fn matching<F: Fn(&Packet) -> bool>(&self, f: F) -> Vec<&Packet> {
+        self.packets.iter().filter(|p| f(p)).collect()
+    }
+
+    /// Packets of an AGW data kind, e.g. `b'D'`. See `Packet::kind()`.
+    #[must_use]
+    pub fn of_kind(&self, kind: u8) -> Vec<&Packet> {
+        self.matching(|p| p.kind() == kind)
+    }
+
+    /// Connected mode data sent from `src` to `dst`, all frames joined.
+    #[must_use]
+    pub fn data(&self, src: &Call, dst: &Call) -> Vec<u8> {
+        self.packets
+            .iter()
+            .filter_map(|p| match p {
+                Packet::Data {
+                    src: s,
+                    dst: d,
+                    data,
+                    ..
+                } if s == src && d == dst => Some(data.as_slice()),
+                _ => None,
+            })
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Panic unless the packet was received.
+    #[track_caller]
+    pub fn assert_received(&self, packet: &Packet) {
+        assert!(
+            self.contains(packet),
+            "expected {packet:?}, got {:#?}",
+            self.packets
+        );
+    }
+
+    /// Panic unless some packet matches.
+    #[track_caller]
+    pub fn assert_any<F: Fn(&Packet) -> bool>(&self, desc: &str, f: F) {
+        assert!(
+            self.packets.iter().any(f),
+            "expected {desc}, got {:#?}",
+            self.packets
+        );
+    }
+
+    /// Panic if some packet matches.
+    #[track_caller]
+    pub fn assert_none<F: Fn(&Packet) -> bool>(&self, desc: &str, f: F) {
+        let found = self.matching(f);
+        assert!(found.is_empty(), "expected no {desc}, got {found:#?}");
+    }
+}
+
+/// Receive the next packet from the client.
+///
+/// Once told to stop, packets still on their way are received, until none
+/// has arrived for `QUIET`. Then returns `None`.
+async fn next<S>(
+    con: &mut AGWServer<S>,
+    stop: &mut oneshot::Receiver<()>,
+    stopped: &mut bool,
+) -> Option<Result<Packet>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if !*stopped {
+        tokio::select! {
+            p = con.recv() => return Some(p),
+            _ = stop => *stopped = true,
+        }
+    }
+    tokio::time::timeout(QUIET, con.recv()).await.ok()
+}
+
+/// Run the script, then record whatever else the client sends until told
+/// to stop or the client goes away.
+async fn run<S>(script: Script, con: S, mut stop: oneshot::Receiver<()>) -> Result<Received>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut con = AGWServer::new(con);
+    let mut received = Received::default();
+    let mut stopped = false;
+    let total = script.steps.len();
+    for (n, step) in script.steps.into_iter().enumerate() {
+        match step {
+            Step::Reply(packet) => {
+                debug!("agw/testing: #{n}: Sending {packet:?}");
+                con.send(&packet).await?;
+            }
+            Step::Expect { desc, matcher } => {
+                let packet = match next(&mut con, &mut stop, &mut stopped).await {
+                    Some(Ok(p)) => p,
+                    Some(Err(e)) => {
+                        return Err(Error::msg(format!(
+                            "script failed at step #{n} of {total}, expecting {desc}: {e}"
+                        )));
+                    }
+                    None => {
+                        return Err(Error::msg(format!(
+                            "script stopped at step #{n} of {total}: still expecting {desc}, got {:?}",
+                            received.packets
+                        )));
+                    }
+                };
+                debug!("agw/testing: #{n}: Got {packet:?}");
+                let ok = matcher(&packet);
+                received.packets.push(packet);
+                if !ok {
+                    return Err(Error::msg(format!(
+                        "script diverged at step #{n}: expected {desc}, got {:?}",
+                        received.packets.last().expect("can't happen: just pushed")
+                    )));
+                }
+            }
+        }
+    }
+    // Stopped, or the client went away.
+    while let Some(Ok(p)) = next(&mut con, &mut stop, &mut stopped).await {
+        debug!("agw/testing: After script: Got {p:?}");
+        received.packets.push(p);
+    }
+    Ok(received)
+}
+
+/// Mock AGW server, running a `Script` in its own task.
+///
+/// If the client sends something unexpected, the mock closes the connection.
+pub struct Mock {
+    stop: oneshot::Sender<()>,
+    task: tokio::task::JoinHandle<Result<Received>>,
+}
+
+impl Mock {
+    /// Run the script against a client connected to `con`.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn start<S>(script: Script, con: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (stop, rx) = oneshot::channel();
+        Self {
+            stop,
+            task: tokio::spawn(run(script, con, rx)),
+        }
+    }
+
+    /// Stop the mock, and return what the client sent.
+    ///
+    /// Packets the client already sent are still received, so this takes at
+    /// least 100ms.
+    ///
+    /// # Errors
+    ///
+    /// If the client sent something unexpected, or the script didn't run to
+    /// the end.
+    pub async fn finish(self) -> Result<Received> {
+        let _ = self.stop.send(());
+        self.task
+            .await
+            .map_err(|e| Error::msg(format!("mock task failed: {e}")))?
+    }
+}
+
+/// Mock AGW server for a blocking client, running a `Script` in its own
+/// thread.
+pub struct BlockingMock {
+    stop: oneshot::Sender<()>,
+    thread: std::thread::JoinHandle<Result<Received>>,
+}
+
+impl BlockingMock {
+    /// Run the script against a client connected to `con`.
+    pub fn start(script: Script, con: std::os::unix::net::UnixStream) -> Result<Self> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        con.set_nonblocking(true)?;
+        let con = {
+            let _guard = rt.enter();
+            tokio::net::UnixStream::from_std(con)?
+        };
+        let (stop, rx) = oneshot::channel();
+        let thread = std::thread::spawn(move || rt.block_on(run(script, con, rx)));
+        Ok(Self { stop, thread })
+    }
+
+    /// Stop the mock, and return what the client sent.
+    ///
+    /// Packets the client already sent are still received, so this takes at
+    /// least 100ms.
+    ///
+    /// # Errors
+    ///
+    /// If the client sent something unexpected, or the script didn't run to
+    /// the end.
+    pub fn finish(self) -> Result<Received> {
+        let _ = self.stop.send(());
+        self.thread
+            .join()
+            .map_err(|_| Error::msg("mock thread panicked"))?
+    }
+}
+
+/// Create an async client connected to a mock running the script.
+///
+/// # Errors
+///
+/// If called outside a tokio runtime.
+pub fn pair(script: Script) -> Result<(AGW, Mock)> {
+    let (client, server) = tokio::io::duplex(DUPLEX_BUF);
+    let agw = AGW::from_stream(client)?;
+    Ok((agw, Mock::start(script, server)))
+}
+
+/// Create a blocking client connected to a mock running the script.
+///
+/// `v2::AGW` needs a file descriptor to poll, so this uses a UNIX socket
+/// pair instead of `tokio::io::duplex()`. Works without a tokio runtime.
+///
+/// # Errors
+///
+/// If creating the sockets fails.
+pub fn blocking_pair(script: Script) -> Result<(v2::AGW, BlockingMock)> {
+    let (client, server) = std::os::unix::net::UnixStream::pair()?;
+    let mock = BlockingMock::start(script, server)?;
+    let agw = v2::AGW::new(client.try_clone()?, client)?;
+    Ok((agw, mock))
+}
+
+/// Create an async client connected to a `FakeTnc`.
+///
+/// # Errors
+///
+/// If called outside a tokio runtime.
+pub fn fake_pair() -> Result<AGW> {
+    let (client, server) = tokio::io::duplex(DUPLEX_BUF);
+    let agw = AGW::from_stream(client)?;
+    let clients = Clients::new();
+    let server_ = Server::with_clients(FakeTnc::new(clients.clone()), clients);
+    tokio::spawn(async move {
+        if let Err(e) = server_.handle(AGWServer::new(server)).await {
+            debug!("agw/testing: Fake TNC client failed: {e}");
+        }
+    });
+    Ok(agw)
+}
+
+/// AGW backend that just makes stuff up.
+///
+/// It has one port, accepts every connection, echoes connected mode data back, and hears its own unproto frames on the monitor.
+pub struct FakeTnc {
+    clients: Clients,
+}
+
+impl FakeTnc {
+    /// Create backend, with `clients` being the same as given to
+    /// `Server::with_clients()`.
+    #[must_use]
+    pub fn new(clients: Clients) -> Self {
+        Self { clients }
+    }
+}
+
+impl AgwBackend for FakeTnc {
+    fn ports(&self) -> Vec<PortInfo> {
+        vec![PortInfo {
+            port: Port(0),
+            descr: "Fake AGW port".to_string(),
+        }]
+    }
+
+    fn port_caps(&self, _port: Port) -> PortCaps {
+        PortCaps::new(Baud::B1200)
+    }
+
+    async fn data(&self, port: Port, pid: Pid, src: Call, dst: Call, data: Vec<u8>) -> Result<()> {
+        let _ = self.clients.deliver(&Packet::Data {
+            port,
+            pid,
+            src: dst,
+            dst: src,
+            data,
+        });
+        Ok(())
+    }
+
+    async fn unproto(
+        &self,
+        port: Port,
+        pid: Pid,
+        src: Call,
+        dst: Call,
+        _via: Vec<Call>,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let mut text = format!(
+            " {}:Fm {src} To {dst} <UI pid={:02X} Len={} >[00:00:00]\r",
+            port.number(),
+            pid.0,
+            data.len()
+        )
+        .into_bytes();
+        text.extend(data);
+        text.extend(b"\r\0");
+        let _ = self.clients.deliver(&Packet::Monitor {
+            kind: MonitorKind::Unproto,
+            port,
+            pid,
+            src,
+            dst,
+            data: text,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn calls() -> (Call, Call) {
+        ("M0THC-1".parse().unwrap(), "M0THC-2".parse().unwrap())
+    }
+
+    fn connect(src: &Call, dst: &Call) -> Packet {
+        Packet::Connect {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: src.clone(),
+            dst: dst.clone(),
+        }
+    }
+
+    fn established(src: &Call, dst: &Call) -> Packet {
+        Packet::ConnectionEstablished {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: src.clone(),
+            dst: dst.clone(),
+            text: String::new(),
+        }
+    }
+
+    fn data(src: &Call, dst: &Call, data: &[u8]) -> Packet {
+        Packet::Data {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: src.clone(),
+            dst: dst.clone(),
+            data: data.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn async_script() {
+        let (me, peer) = calls();
+        let script = Script::new()
+            .expect(connect(&me, &peer))
+            .reply(established(&peer, &me))
+            .reply(data(&peer, &me, b"hello"))
+            .expect_match("data", |p| p.kind() == b'D');
+        let (agw, mock) = pair(script).unwrap();
+        let mut con = agw
+            .connect(Port(0), Pid(0xF0), &me, &peer, &[])
+            .await
+            .unwrap();
+        assert_eq!(con.recv().await.unwrap(), data(&peer, &me, b"hello"));
+        con.send(b"hi").await.unwrap();
+        con.send(b" there").await.unwrap();
+
+        let received = mock.finish().await.unwrap();
+        received.assert_received(&connect(&me, &peer));
+        received.assert_any("data", |p| p.kind() == b'D');
+        received.assert_none("unproto", |p| p.kind() == b'M');
+        assert_eq!(received.of_kind(b'D').len(), 2);
+        assert_eq!(received.data(&me, &peer), b"hi there");
+        assert!(received.data(&peer, &me).is_empty());
+        assert_eq!(received.packets().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn async_script_diverges() {
+        let (me, peer) = calls();
+        let (agw, mock) = pair(Script::new().expect(connect(&me, &peer))).unwrap();
+        agw.register_callsign(Port(0), &me).await.unwrap();
+        let err = mock.finish().await.unwrap_err();
+        assert!(err.to_string().contains("diverged at step #0"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn async_script_unfinished() {
+        let (me, peer) = calls();
+        let (_agw, mock) = pair(Script::new().expect(connect(&me, &peer))).unwrap();
+        let err = mock.finish().await.unwrap_err();
+        assert!(err.to_string().contains("still expecting"), "{err}");
+    }
+
+    #[test]
+    fn blocking_script() {
+        let (me, peer) = calls();
+        let script = Script::new()
+            .expect(Packet::VersionQuery)
+            .reply(Packet::VersionReply {
+                major: 2005,
+                minor: 127,
+            })
+            .expect(connect(&me, &peer))
+            .reply(established(&peer, &me))
+            .reply(data(&peer, &me, b"hello"));
+        let (agw, mock) = blocking_pair(script).unwrap();
+        assert_eq!(agw.version().unwrap(), (2005, 127));
+        let mut con = agw.connect(Port(0), me.clone(), peer.clone(), &[]).unwrap();
+        let mut buf = [0; 5];
+        con.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        agw.unproto(Port(0), Pid(0xF0), &me, &peer, b"cq").unwrap();
+
+        let received = mock.finish().unwrap();
+        assert_eq!(
+            received.into_packets(),
+            vec![
+                Packet::VersionQuery,
+                connect(&me, &peer),
+                Packet::Unproto {
+                    port: Port(0),
+                    pid: Pid(0xF0),
+                    src: me,
+                    dst: peer,
+                    data: b"cq".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn blocking_script_diverges() {
+        let (me, peer) = calls();
+        let (agw, mock) = blocking_pair(Script::new().expect(connect(&me, &peer))).unwrap();
+        agw.register_callsign(Port(0), &me).unwrap();
+        let err = mock.finish().unwrap_err();
+        assert!(err.to_string().contains("diverged at step #0"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn fake_tnc() {
+        let (me, peer) = calls();
+        let agw = fake_pair().unwrap();
+        let mut con = agw
+            .connect(Port(0), Pid(0xF0), &me, &peer, &[])
+            .await
+            .unwrap();
+        con.send(b"ping").await.unwrap();
+        assert_eq!(con.recv().await.unwrap(), data(&peer, &me, b"ping"));
+
+        let mut monitor = agw.monitor().await.unwrap();
+        agw.unproto(Port(0), Pid(0xF0), &me, &peer, b"cq")
+            .await
+            .unwrap();
+        match monitor.recv().await.unwrap() {
+            Packet::Monitor {
+                kind: MonitorKind::Unproto,
+                data,
+                ..
+            } => assert!(data.ends_with(b"cq\r\0"), "{data:?}"),
+            other => panic!("expected monitored frame, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,799 @@
+//! Transport that injects faults, for testing how clients cope.
+//!
+//! `Faulty` wraps a stream carrying AGW frames, and can:
+//!
+//! * Split reads and writes at random boundaries.
+//! * Delay reads and writes.
+//! * Drop or duplicate whole AGW frames.
+//! * Close the connection in the middle of a frame.
+//!
+//! It implements both `std::io::Read`/`Write` and tokio's
+//! `AsyncRead`/`AsyncWrite`, so it works for `v1::AGW`, `v2::AGW` and
+//! `r#async::AGW` alike. Faults are applied to each direction independently.
+//! The random choices come from `Faults::seed()`, so a failing run can be
+//! reproduced.
+//!
+//! ```
+//! use agw::testing::fault::{Faults, Faulty};
+//! use agw::testing::{Mock, Script};
+//! use agw::Packet;
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> agw::Result<()> {
+//! let (client, server) = tokio::io::duplex(1024);
+//! let faults = Faults::new().seed(42).split(true).duplicate(0.5);
+//! let agw = agw::r#async::AGW::from_stream(Faulty::new(client, faults))?;
+//! let mock = Mock::start(Script::new().expect(Packet::VersionQuery), server);
+//! agw.send(Packet::VersionQuery).await?;
+//! mock.finish().await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::v2::{self, PollResult};
+use crate::{Result, HEADER_LEN};
+
+const READ_CHUNK: usize = 4096;
+
+/// Frames claiming to be longer than this are passed through unframed.
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// What faults to inject.
+///
+/// Nothing is injected by default.
+#[derive(Clone, Debug)]
+pub struct Faults {
+    seed: u64,
+    split: bool,
+    max_delay: Duration,
+    drop: f64,
+    duplicate: f64,
+    close_after: Option<usize>,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            split: false,
+            max_delay: Duration::ZERO,
+            drop: 0.0,
+            duplicate: 0.0,
+            close_after: None,
+        }
+    }
+}
+
+impl Faults {
+    /// Create a fault set that injects nothing.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the seed of the random choices. Zero is treated as one.
+    #[must_use]
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed.max(1);
+        self
+    }
+
+    /// Read and write at most a random number of bytes at a time.
+    #[must_use]
+    pub fn split(mut self, split: bool) -> Self {
+        self.split = split;
+        self
+    }
+
+    /// Wait up to this long, picked at random, before each read and write.
+    #[must_use]
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Drop each frame with this chance, 0.0 to 1.0.
+    #[must_use]
+    pub fn drop(mut self, chance: f64) -> Self {
+        self.drop = chance;
+        self
+    }
+
+    /// Duplicate each frame with this chance, 0.0 to 1.0.
+    #[must_use]
+    pub fn duplicate(mut self, chance: f64) -> Self {
+        self.duplicate = chance;
+        self
+    }
+
+    /// After passing this many frames in one direction, pass only the first
+    /// part of the next one and then close the connection.
+    ///
+    /// Reads then return end of file, and writes fail with `BrokenPipe`. The
+    /// wrapped stream is dropped, so if it's the only handle the other end
+    /// sees the close too.
+    #[must_use]
+    pub fn close_after(mut self, frames: usize) -> Self {
+        self.close_after = Some(frames);
+        self
+    }
+}
+
+/// Xorshift. Good enough for picking faults, and keeps runs reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Random number in `1..=n`, for `n` at least 1.
+    fn upto(&mut self, n: usize) -> usize {
+        1 + usize::try_from(self.next() % n as u64).expect("can't happen: below n")
+    }
+
+    /// True with the given chance.
+    fn chance(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        let r = u32::try_from(self.next() >> 32).expect("can't happen: 32 bits");
+        f64::from(r) / f64::from(u32::MAX) < p
+    }
+
+    fn delay(&mut self, max: Duration) -> Option<Duration> {
+        if max.is_zero() {
+            return None;
+        }
+        let max = u64::try_from(max.as_micros()).unwrap_or(u64::MAX);
+        Some(Duration::from_micros(self.next() % (max + 1)))
+    }
+}
+
+/// Faults for one direction. Bytes go in as they come, and come out
+/// mangled.
+struct Mangler {
+    faults: Faults,
+    rng: Rng,
+
+    // Bytes not yet a whole frame.
+    partial: Vec<u8>,
+
+    // Bytes to pass on.
+    out: VecDeque<u8>,
+
+    frames: usize,
+    cut: bool,
+    unframed: bool,
+}
+
+impl Mangler {
+    fn new(faults: Faults, seed: u64) -> Self {
+        Self {
+            faults,
+            rng: Rng(seed.max(1)),
+            partial: Vec::new(),
+            out: VecDeque::new(),
+            frames: 0,
+            cut: false,
+            unframed: false,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        if self.cut {
+            return;
+        }
+        if self.unframed {
+            self.out.extend(data);
+            return;
+        }
+        self.partial.extend_from_slice(data);
+        while self.partial.len() >= HEADER_LEN {
+            let len = u32::from_le_bytes(
+                self.partial[28..32]
+                    .try_into()
+                    .expect("can't happen: bytes to u32"),
+            );
+            let len = HEADER_LEN + usize::try_from(len).unwrap_or(usize::MAX);
+            if len > MAX_FRAME_LEN {
+                debug!("agw/fault: Not a frame, passing the rest through");
+                self.unframed = true;
+                self.out.extend(self.partial.drain(..));
+                return;
+            }
+            if self.partial.len() < len {
+                return;
+            }
+            let frame: Vec<u8> = self.partial.drain(..len).collect();
+            if self.faults.close_after == Some(self.frames) {
+                let n = self.rng.upto(len - 1);
+                debug!("agw/fault: Closing after {n} of {len} bytes of a frame");
+                self.out.extend(&frame[..n]);
+                self.cut = true;
+                self.partial.clear();
+                return;
+            }
+            self.frames += 1;
+            if self.rng.chance(self.faults.drop) {
+                debug!("agw/fault: Dropping frame kind {}", frame[4]);
+                continue;
+            }
+            if self.rng.chance(self.faults.duplicate) {
+                debug!("agw/fault: Duplicating frame kind {}", frame[4]);
+                self.out.extend(&frame);
+            }
+            self.out.extend(&frame);
+        }
+    }
+
+    /// How many bytes to read or write next, out of `n`.
+    fn size(&mut self, n: usize) -> usize {
+        if self.faults.split && n > 1 {
+            self.rng.upto(n)
+        } else {
+            n
+        }
+    }
+
+    /// Move up to `buf.len()` bytes out, split as configured.
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let n = self.size(buf.len().min(self.out.len()));
+        for (dst, src) in buf.iter_mut().zip(self.out.drain(..n)) {
+            *dst = src;
+        }
+        n
+    }
+}
+
+fn broken_pipe() -> std::io::Error {
+    std::io::Error::from(std::io::ErrorKind::BrokenPipe)
+}
+
+/// Stream wrapper that injects faults. See the module documentation.
+pub struct Faulty<T> {
+    inner: Option<T>,
+    rx: Mangler,
+    tx: Mangler,
+    read_delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    write_delay: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<T> Faulty<T> {
+    /// Wrap a stream.
+    pub fn new(inner: T, faults: Faults) -> Self {
+        let seed = faults.seed;
+        Self {
+            inner: Some(inner),
+            rx: Mangler::new(faults.clone(), seed),
+            tx: Mangler::new(faults, seed ^ 0x9e37_79b9_7f4a_7c15),
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Return the wrapped stream, unless closed by a fault.
+    pub fn into_inner(self) -> Option<T> {
+        self.inner
+    }
+
+    /// Drop the stream once a fault has closed the connection.
+    fn check_cut(&mut self) {
+        if (self.rx.cut || self.tx.cut) && self.inner.is_some() {
+            debug!("agw/fault: Closing connection");
+            self.inner = None;
+        }
+    }
+
+    /// Delay for async IO. Ready once the delay, if any, is over.
+    fn poll_delay(
+        slot: &mut Option<Pin<Box<tokio::time::Sleep>>>,
+        m: &mut Mangler,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        if slot.is_none() {
+            match m.rng.delay(m.faults.max_delay) {
+                Some(d) => *slot = Some(Box::pin(tokio::time::sleep(d))),
+                None => return Poll::Ready(()),
+            }
+        }
+        ready!(slot
+            .as_mut()
+            .expect("can't happen: delay just set")
+            .as_mut()
+            .poll(cx));
+        *slot = None;
+        Poll::Ready(())
+    }
+}
+
+impl<T: Read> Read for Faulty<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(d) = self.rx.rng.delay(self.rx.faults.max_delay) {
+            std::thread::sleep(d);
+        }
+        loop {
+            if !self.rx.out.is_empty() {
+                return Ok(self.rx.take(buf));
+            }
+            self.check_cut();
+            let Some(inner) = &mut self.inner else {
+                return Ok(0);
+            };
+            let mut chunk = [0_u8; READ_CHUNK];
+            let n = inner.read(&mut chunk)?;
+            if n == 0 {
+                // Pass on any partial frame, and let the reader notice.
+                self.rx.out.extend(self.rx.partial.drain(..));
+                if self.rx.out.is_empty() {
+                    return Ok(0);
+                }
+                self.rx.cut = true;
+                continue;
+            }
+            self.rx.push(&chunk[..n]);
+        }
+    }
+}
+
+impl<T: Write> Write for Faulty<T> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if let Some(d) = self.tx.rng.delay(self.tx.faults.max_delay) {
+            std::thread::sleep(d);
+        }
+        let n = self.tx.size(data.len());
+        self.tx.push(&data[..n]);
+        let out: Vec<u8> = self.tx.out.drain(..).collect();
+        let inner = self.inner.as_mut().ok_or_else(broken_pipe)?;
+        inner.write_all(&out)?;
+        if self.tx.cut {
+            inner.flush()?;
+            self.check_cut();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.as_mut().ok_or_else(broken_pipe)?.flush()
+    }
+}
+
+/// Lets `v2::AGW` wait for data that's already been read from the stream.
+impl<T: v2::Poll> v2::Poll for Faulty<T> {
+    fn poll(&self, other: libc::c_int) -> Result<PollResult> {
+        match &self.inner {
+            Some(inner) if self.rx.out.is_empty() => inner.poll(other),
+            _ => Ok(PollResult::This),
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Faulty<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(Self::poll_delay(&mut this.read_delay, &mut this.rx, cx));
+        loop {
+            if !this.rx.out.is_empty() {
+                let n = this.rx.take(buf.initialize_unfilled());
+                buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            this.check_cut();
+            let Some(inner) = &mut this.inner else {
+                return Poll::Ready(Ok(()));
+            };
+            let mut chunk = [0_u8; READ_CHUNK];
+            let mut rb = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(inner).poll_read(cx, &mut rb))?;
+            if rb.filled().is_empty() {
+                this.rx.out.extend(this.rx.partial.drain(..));
+                if this.rx.out.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                this.rx.cut = true;
+                continue;
+            }
+            this.rx.push(rb.filled());
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Faulty<T> {
+    /// Write out what's been accepted but not yet written.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.tx.out.is_empty() {
+            let inner = self.inner.as_mut().ok_or_else(broken_pipe)?;
+            let (data, _) = self.tx.out.as_slices();
+            let n = ready!(Pin::new(inner).poll_write(cx, data))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.tx.out.drain(..n);
+        }
+        if self.tx.cut {
+            self.check_cut();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Faulty<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if this.inner.is_none() {
+            return Poll::Ready(Err(broken_pipe()));
+        }
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        ready!(Self::poll_delay(&mut this.write_delay, &mut this.tx, cx));
+        let n = this.tx.size(data.len());
+        this.tx.push(&data[..n]);
+        // Accepted either way. The rest is written on the next call, or on
+        // flush.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        let inner = this.inner.as_mut().ok_or_else(broken_pipe)?;
+        Pin::new(inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        match this.inner.as_mut() {
+            Some(inner) => Pin::new(inner).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// Soak tests, like `examples/fault_soak.rs` but with a few fixed runs.
+///
+/// Errors are fine when frames are duplicated or cut off, but hangs and
+/// panics are bugs. Once a fault closes the connection, further calls must
+/// fail too.
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::r#async::server::{Clients, Server};
+    use crate::r#async::AGWServer;
+    use crate::testing::{BlockingMock, FakeTnc, Mock, Script};
+    use crate::{Call, Error, Packet, Pid, Port};
+
+    /// Runs per client, each mode twice.
+    const RUNS: usize = 8;
+
+    /// How long before a run counts as hung.
+    const HANG: Duration = Duration::from_secs(10);
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Mode {
+        /// Only split and delay, so results must be right.
+        Clean,
+        Duplicate,
+        /// Drop frames on the way to a mock, which records what got through.
+        Drop,
+        /// Server closes the connection in the middle of a frame.
+        Close,
+    }
+
+    #[derive(Clone, Debug)]
+    struct Case {
+        mode: Mode,
+        client: Faults,
+        server: Faults,
+        payload_len: usize,
+    }
+
+    impl Case {
+        /// The runs for one client, the same every time for a seed.
+        fn runs(seed: u64) -> Vec<Case> {
+            let mut rng = Rng(seed);
+            let modes = [Mode::Clean, Mode::Duplicate, Mode::Drop, Mode::Close];
+            (0..RUNS)
+                .map(|n| {
+                    let mode = modes[n % modes.len()];
+                    let mut base = || {
+                        let delay = if rng.upto(3) == 1 { 2 } else { 0 };
+                        Faults::new()
+                            .seed(rng.next())
+                            .split(rng.upto(4) != 1)
+                            .max_delay(Duration::from_millis(delay))
+                    };
+                    let (mut client, mut server) = (base(), base());
+                    match mode {
+                        Mode::Clean => {}
+                        Mode::Duplicate => {
+                            client = client.duplicate(0.3);
+                            server = server.duplicate(0.3);
+                        }
+                        Mode::Drop => client = client.drop(0.3),
+                        Mode::Close => server = server.close_after(rng.upto(8) - 1),
+                    }
+                    Case {
+                        mode,
+                        client,
+                        server,
+                        payload_len: rng.upto(2000),
+                    }
+                })
+                .collect()
+        }
+
+        fn payload(&self) -> Vec<u8> {
+            (0..=255).cycle().take(self.payload_len).collect()
+        }
+
+        /// Panic if the run failed when it shouldn't have.
+        #[track_caller]
+        fn check(&self, ret: Result<()>) {
+            if let Err(e) = ret {
+                assert!(
+                    matches!(self.mode, Mode::Duplicate | Mode::Close),
+                    "run failed: {e}\n{self:#?}"
+                );
+            }
+        }
+
+        /// Panic if a call made after the connection was cut worked.
+        #[track_caller]
+        fn check_closed<T>(&self, failed: bool, again: &Result<T>) {
+            if failed && self.mode == Mode::Close {
+                assert!(again.is_err(), "worked after close\n{self:#?}");
+            }
+        }
+    }
+
+    /// Source and destination of the unproto frames sent in drop mode.
+    fn ui_calls() -> (Call, Call) {
+        ("M0THC-1".parse().unwrap(), "ID".parse().unwrap())
+    }
+
+    /// Payloads of the unproto frames sent in drop mode.
+    fn ui_data() -> Vec<Vec<u8>> {
+        (0..20).map(|n| format!("frame {n}").into_bytes()).collect()
+    }
+
+    fn unprotos() -> Vec<Packet> {
+        let (src, dst) = ui_calls();
+        ui_data()
+            .into_iter()
+            .map(|data| Packet::Unproto {
+                port: Port(0),
+                pid: Pid(0xF0),
+                src: src.clone(),
+                dst: dst.clone(),
+                data,
+            })
+            .collect()
+    }
+
+    /// Check that the frames that got through were sent, and undamaged.
+    fn check_unprotos(got: &[Packet]) -> Result<()> {
+        let sent = unprotos();
+        match got.iter().find(|p| !sent.contains(p)) {
+            Some(p) => Err(Error::msg(format!("received {p:?}, which wasn't sent"))),
+            None => Ok(()),
+        }
+    }
+
+    /// Serve a client with a `FakeTnc`, going through the server faults.
+    async fn serve<S>(con: S, faults: Faults)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let clients = Clients::new();
+        let server = Server::with_clients(FakeTnc::new(clients.clone()), clients);
+        if let Err(e) = server
+            .handle(AGWServer::new(Faulty::new(con, faults)))
+            .await
+        {
+            debug!("agw/fault: Server: {e}");
+        }
+    }
+
+    /// Connect a socket pair, with the server end served by a mock in drop
+    /// mode, or else by a `FakeTnc` in its own thread.
+    fn blocking_server(case: &Case) -> (UnixStream, Option<BlockingMock>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        if case.mode == Mode::Drop {
+            return (
+                client,
+                Some(BlockingMock::start(Script::new(), server).unwrap()),
+            );
+        }
+        let faults = case.server.clone();
+        server.set_nonblocking(true).unwrap();
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(async {
+                serve(tokio::net::UnixStream::from_std(server).unwrap(), faults).await;
+            });
+        });
+        (client, None)
+    }
+
+    /// Run a blocking client in its own thread, so hangs are caught.
+    fn run_blocking(case: &Case, f: fn(&Case)) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let c2 = case.clone();
+        std::thread::spawn(move || {
+            f(&c2);
+            let _ = tx.send(());
+        });
+        match rx.recv_timeout(HANG) {
+            Ok(()) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => panic!("run hung\n{case:#?}"),
+            // The sender was dropped without sending: the run panicked,
+            // and already said why.
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => panic!("run panicked"),
+        }
+    }
+
+    async fn run_async(case: &Case) {
+        let (client, server) = tokio::io::duplex(4096);
+        let agw =
+            crate::r#async::AGW::from_stream(Faulty::new(client, case.client.clone())).unwrap();
+        if case.mode == Mode::Drop {
+            let mock = Mock::start(Script::new(), server);
+            for p in unprotos() {
+                agw.send(p).await.unwrap();
+            }
+            case.check(check_unprotos(mock.finish().await.unwrap().packets()));
+            return;
+        }
+        tokio::spawn(serve(server, case.server.clone()));
+        let me: Call = "M0THC-1".parse().unwrap();
+        let peer: Call = "M0THC-2".parse().unwrap();
+        let ret = async {
+            let mut con = agw.connect(Port(0), Pid(0xF0), &me, &peer, &[]).await?;
+            let payload = case.payload();
+            con.send(&payload).await?;
+            let mut got = vec![0; payload.len()];
+            con.read_exact(&mut got).await?;
+            if got != payload {
+                return Err(Error::msg("echoed data differs"));
+            }
+            Ok(())
+        }
+        .await;
+        let failed = ret.is_err();
+        case.check(ret);
+        case.check_closed(
+            failed,
+            &agw.connect(Port(0), Pid(0xF0), &me, &peer, &[]).await,
+        );
+    }
+
+    fn run_v1(case: &Case) {
+        let (sock, mock) = blocking_server(case);
+        let mut agw = crate::AGW::from_streams(
+            Faulty::new(sock.try_clone().unwrap(), case.client.clone()),
+            Faulty::new(sock.try_clone().unwrap(), case.client.clone()),
+        );
+        if let Some(mock) = mock {
+            let (src, dst) = ui_calls();
+            for data in ui_data() {
+                agw.unproto(Port(0), Pid(0xF0), &src, &dst, &data).unwrap();
+            }
+            case.check(check_unprotos(mock.finish().unwrap().packets()));
+        } else {
+            let ret = (|| {
+                if agw.version()? != (2005, 127) {
+                    return Err(Error::msg("wrong version"));
+                }
+                if agw.port_info()?.ports.len() != 1 {
+                    return Err(Error::msg("wrong port info"));
+                }
+                agw.port_cap(Port(0))?;
+                Ok(())
+            })();
+            let failed = ret.is_err();
+            case.check(ret);
+            case.check_closed(failed, &agw.version());
+        }
+        // v1 doesn't close the connection when dropped.
+        let _ = sock.shutdown(std::net::Shutdown::Both);
+    }
+
+    fn run_v2(case: &Case) {
+        let (sock, mock) = blocking_server(case);
+        let agw = v2::AGW::new(
+            Faulty::new(sock.try_clone().unwrap(), case.client.clone()),
+            Faulty::new(sock, case.client.clone()),
+        )
+        .unwrap();
+        if let Some(mock) = mock {
+            let (src, dst) = ui_calls();
+            for data in ui_data() {
+                agw.unproto(Port(0), Pid(0xF0), &src, &dst, &data).unwrap();
+            }
+            case.check(check_unprotos(mock.finish().unwrap().packets()));
+            return;
+        }
+        let ret = (|| {
+            if agw.version()? != (2005, 127) {
+                return Err(Error::msg("wrong version"));
+            }
+            if agw.port_info()?.ports.len() != 1 {
+                return Err(Error::msg("wrong port info"));
+            }
+            agw.port_cap(Port(0))?;
+            agw.frames_outstanding(Port(0))?;
+            Ok(())
+        })();
+        let failed = ret.is_err();
+        case.check(ret);
+        case.check_closed(failed, &agw.version());
+    }
+
+    #[tokio::test]
+    async fn soak_async() {
+        for case in Case::runs(1) {
+            debug!("agw/fault: Run {case:?}");
+            tokio::time::timeout(HANG, run_async(&case))
+                .await
+                .unwrap_or_else(|_| panic!("run hung\n{case:#?}"));
+        }
+    }
+
+    #[test]
+    fn soak_v1() {
+        for case in Case::runs(2) {
+            debug!("agw/fault: Run {case:?}");
+            run_blocking(&case, run_v1);
+        }
+    }
+
+    #[test]
+    fn soak_v2() {
+        for case in Case::runs(3) {
+            debug!("agw/fault: Run {case:?}");
+            run_blocking(&case, run_v2);
+        }
+    }
+}
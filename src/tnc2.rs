@@ -0,0 +1,350 @@
+//! TNC2 monitor format, e.g. `M0QQQ-8>APZ001,WIDE1-1*:hello`.
+//!
+//! Frames other than plain UI frames with PID F0 get their control field
+//! shown before the colon, e.g. `M0QQQ-8>M0QQQ-1 <I R0 S1 pid=F0>:hello`.
+//!
+//! Payload bytes that are not printable ASCII are written as `<0xNN>`, like
+//! Direwolf does, so that lines can be parsed back into the same bytes.
+//!
+//! ```
+//! use agw::{tnc2, Port};
+//! let packet = tnc2::parse(Port(0), "M0QQQ-8>APZ001,WIDE1-1:hello<0x0d>")?;
+//! let line = tnc2::Tnc2::new(&packet).unwrap().to_string();
+//! assert_eq!(line, "M0QQQ-8>APZ001,WIDE1-1:hello<0x0d>");
+//! # Ok::<(), agw::Error>(())
+//! ```
+use crate::{Call, Error, Packet, Pid, Port, Result};
+
+/// The usual PID for UI frames: no layer 3.
+const PID_NO_L3: u8 = 0xF0;
+
+/// A packet that can be shown in TNC2 format.
+///
+/// Created with `Tnc2::new()`, and shown with `Display`.
+pub struct Tnc2<'a> {
+    src: &'a Call,
+    dst: &'a Call,
+    via: Vec<String>,
+    control: Option<String>,
+    payload: &'a [u8],
+}
+
+/// Control field annotation, or none for the common case of a UI frame with
+/// no layer 3.
+fn control(name: &str, pid: Pid) -> Option<String> {
+    match (name, pid.0) {
+        ("UI", PID_NO_L3) => None,
+        (_, PID_NO_L3) => Some(name.to_string()),
+        _ => Some(format!("{name} pid={:02X}", pid.0)),
+    }
+}
+
+/// Split the text AGW servers put before monitored frames, such as
+/// `" 1:Fm M0QQQ-8 To APZ001 Via WIDE1-1* <UI pid=F0 Len=5 >[12:00:00]\r"`,
+/// into digipeater path, control field, and payload.
+pub(crate) fn split_monitor(data: &[u8]) -> Option<(Vec<String>, Option<String>, &[u8])> {
+    let end = data.iter().position(|&b| b == b'\r')?;
+    let head = std::str::from_utf8(&data[..end]).ok()?;
+    let addrs = &head[head.find("Fm ")? + 3..];
+    let (addrs, rest) = addrs.split_once('<')?;
+    let (ctl, _) = rest.split_once('>')?;
+    let via = match addrs.split_once(" Via ") {
+        Some((_, via)) => via
+            .split([',', ' '])
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => Vec::new(),
+    };
+    let ctl: Vec<_> = ctl
+        .split_whitespace()
+        .filter(|s| !s.starts_with("Len="))
+        .collect();
+    let ctl = match ctl.as_slice() {
+        ["UI", "pid=F0"] => None,
+        _ => Some(ctl.join(" ")),
+    };
+    // Only the one terminator the server appends; the payload itself may
+    // end in CR or NUL.
+    let payload = match &data[end + 1..] {
+        [rest @ .., b'\r', 0] | [rest @ .., b'\r'] => rest,
+        payload => payload,
+    };
+    Some((via, ctl, payload))
+}
+
+impl<'a> Tnc2<'a> {
+    /// TNC2 view of a packet, if it's a frame with addresses and payload.
+    ///
+    /// That's `Unproto`, `UnprotoVia`, `Data` and `Monitor`.
+    #[must_use]
+    pub fn new(packet: &'a Packet) -> Option<Self> {
+        let (src, dst, via, control, payload) = match packet {
+            Packet::Unproto {
+                pid,
+                src,
+                dst,
+                data,
+                ..
+            } => (src, dst, Vec::new(), control("UI", *pid), data.as_slice()),
+            Packet::UnprotoVia {
+                pid,
+                src,
+                dst,
+                via,
+                data,
+                ..
+            } => (
+                src,
+                dst,
+                via.iter().map(ToString::to_string).collect(),
+                control("UI", *pid),
+                data.as_slice(),
+            ),
+            Packet::Data {
+                pid,
+                src,
+                dst,
+                data,
+                ..
+            } => (src, dst, Vec::new(), control("I", *pid), data.as_slice()),
+            Packet::Monitor { src, dst, data, .. } => match split_monitor(data) {
+                Some((via, control, payload)) => (src, dst, via, control, payload),
+                None => (src, dst, Vec::new(), None, data.as_slice()),
+            },
+            _ => return None,
+        };
+        Some(Self {
+            src,
+            dst,
+            via,
+            control,
+            payload,
+        })
+    }
+}
+
+impl std::fmt::Display for Tnc2<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}>{}", self.src, self.dst)?;
+        for hop in &self.via {
+            write!(f, ",{hop}")?;
+        }
+        if let Some(control) = &self.control {
+            write!(f, " <{control}>")?;
+        }
+        f.write_str(":")?;
+        for (n, &b) in self.payload.iter().enumerate() {
+            // Escape '<' too where it would otherwise read as an escape.
+            let escape = !(0x20..0x7f).contains(&b)
+                || (b == b'<' && self.payload[n + 1..].starts_with(b"0x"));
+            if escape {
+                write!(f, "<0x{b:02x}>")?;
+            } else {
+                write!(f, "{}", char::from(b))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Undo the `<0xNN>` escaping of payload bytes.
+fn unescape(s: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'<' && tail.starts_with(b"0x") {
+            let hex = tail
+                .get(2..4)
+                .filter(|_| tail.get(4) == Some(&b'>'))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| Error::InvalidTnc2(format!("bad escape in {s:?}")))?;
+            out.push(hex);
+            rest = &tail[5..];
+        } else {
+            out.push(b);
+            rest = tail;
+        }
+    }
+    Ok(out)
+}
+
+/// Parse a TNC2 line into a UI frame to send on `port`.
+///
+/// Gives `Packet::Unproto`, or `Packet::UnprotoVia` if there is a
+/// digipeater path. Digipeaters marked as used (`*`) are sent as unused.
+///
+/// # Errors
+///
+/// If the line is not in TNC2 format, or is a frame other than UI.
+pub fn parse(port: Port, line: &str) -> Result<Packet> {
+    let bad = |why: &str| Error::InvalidTnc2(format!("{why}: {line:?}"));
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (head, payload) = line.split_once(':').ok_or_else(|| bad("no ':'"))?;
+    let (addrs, pid) = match head.split_once(" <") {
+        None => (head, Pid(PID_NO_L3)),
+        Some((addrs, control)) => {
+            let control = control
+                .strip_suffix('>')
+                .ok_or_else(|| bad("unterminated control field"))?;
+            let mut words = control.split_whitespace();
+            if words.next() != Some("UI") {
+                return Err(bad("not a UI frame"));
+            }
+            let pid = match words.next() {
+                None => Pid(PID_NO_L3),
+                Some(pid) => Pid(pid
+                    .strip_prefix("pid=")
+                    .and_then(|pid| u8::from_str_radix(pid, 16).ok())
+                    .ok_or_else(|| bad("bad pid"))?),
+            };
+            (addrs, pid)
+        }
+    };
+    let (src, rest) = addrs.split_once('>').ok_or_else(|| bad("no '>'"))?;
+    let mut rest = rest.split(',');
+    let dst = rest.next().ok_or_else(|| bad("no destination"))?;
+    let via = rest
+        .map(|hop| hop.trim_end_matches('*').parse())
+        .collect::<Result<Vec<Call>>>()?;
+    let src = src.parse()?;
+    let dst = dst.parse()?;
+    let data = unescape(payload)?;
+    Ok(if via.is_empty() {
+        Packet::Unproto {
+            port,
+            pid,
+            src,
+            dst,
+            data,
+        }
+    } else {
+        Packet::UnprotoVia {
+            port,
+            pid,
+            src,
+            dst,
+            via,
+            data,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MonitorKind;
+
+    fn line(packet: &Packet) -> String {
+        Tnc2::new(packet).unwrap().to_string()
+    }
+
+    #[test]
+    fn round_trip() {
+        for text in [
+            "M0QQQ-8>APZ001:hello",
+            "M0QQQ-8>APZ001,WIDE1-1,WIDE2-2:hello",
+            "M0QQQ-8>APZ001 <UI pid=CF>:hello",
+            "M0QQQ-8>APZ001:<0x00><0xff><0x3c>0x41 <tag>",
+            "M0QQQ-8>APZ001:",
+        ] {
+            let packet = parse(Port(0), text).unwrap();
+            assert_eq!(line(&packet), text);
+        }
+    }
+
+    #[test]
+    fn escaped_payload_round_trips() {
+        let data: Vec<u8> = (0..=255).collect();
+        let packet = Packet::Unproto {
+            port: Port(2),
+            pid: Pid(PID_NO_L3),
+            src: "M0QQQ-8".parse().unwrap(),
+            dst: "APZ001".parse().unwrap(),
+            data,
+        };
+        assert_eq!(parse(Port(2), &line(&packet)).unwrap(), packet);
+    }
+
+    #[test]
+    fn parse_marks_digipeaters_unused() {
+        let packet = parse(Port(0), "M0QQQ-8>APZ001,WIDE1-1*,WIDE2-1:hi\r\n").unwrap();
+        let Packet::UnprotoVia { via, data, .. } = packet else {
+            panic!("expected UnprotoVia");
+        };
+        assert_eq!(
+            via,
+            vec!["WIDE1-1".parse().unwrap(), "WIDE2-1".parse().unwrap()]
+        );
+        assert_eq!(data, b"hi");
+    }
+
+    #[test]
+    fn parse_errors() {
+        for bad in [
+            "no colon",
+            "M0QQQ-8:no dst",
+            "M0QQQ-8>APZ001 <I R0 S1>:not UI",
+            "M0QQQ-8>APZ001 <UI pid=F0:unterminated",
+            "M0QQQ-8>APZ001:<0xzz>",
+        ] {
+            assert!(
+                matches!(parse(Port(0), bad), Err(Error::InvalidTnc2(_))),
+                "{bad:?}"
+            );
+        }
+        assert!(matches!(
+            parse(Port(0), "M0QQQ-99>APZ001:bad call"),
+            Err(Error::InvalidCallsign(_))
+        ));
+    }
+
+    #[test]
+    fn monitor_frames() {
+        let monitor = |kind, text: &str| Packet::Monitor {
+            kind,
+            port: Port(0),
+            pid: Pid(PID_NO_L3),
+            src: "M0QQQ-8".parse().unwrap(),
+            dst: "APZ001".parse().unwrap(),
+            data: text.as_bytes().to_vec(),
+        };
+        assert_eq!(
+            line(&monitor(
+                MonitorKind::Unproto,
+                " 1:Fm M0QQQ-8 To APZ001 Via WIDE1-1*,WIDE2-1 <UI pid=F0 Len=5 >[12:00:00]\rhello\r\0"
+            )),
+            "M0QQQ-8>APZ001,WIDE1-1*,WIDE2-1:hello"
+        );
+        assert_eq!(
+            line(&monitor(
+                MonitorKind::Info,
+                " 1:Fm M0QQQ-8 To M0QQQ-1 <I R0 S1 pid=F0 Len=2 >[12:00:00]\rhi\r"
+            )),
+            "M0QQQ-8>APZ001 <I R0 S1 pid=F0>:hi"
+        );
+        assert!(Tnc2::new(&Packet::VersionQuery).is_none());
+    }
+
+    #[test]
+    fn monitor_payload_keeps_trailing_cr() {
+        let data = b" 1:Fm M0QQQ-8 To APZ001 <UI pid=F0 Len=6 >[12:00:00]\rhello\r\r\0";
+        let (_, _, payload) = split_monitor(data).unwrap();
+        assert_eq!(payload, b"hello\r");
+        let (_, _, payload) = split_monitor(&data[..data.len() - 1]).unwrap();
+        assert_eq!(payload, b"hello\r");
+    }
+
+    #[test]
+    fn data_frames() {
+        let packet = Packet::Data {
+            port: Port(0),
+            pid: Pid(PID_NO_L3),
+            src: "M0QQQ-8".parse().unwrap(),
+            dst: "M0QQQ-1".parse().unwrap(),
+            data: b"hi".to_vec(),
+        };
+        assert_eq!(line(&packet), "M0QQQ-8>M0QQQ-1 <I>:hi");
+    }
+}
@@ -2,7 +2,7 @@ use log::{debug, trace, warn};
 use std::collections::LinkedList;
 use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::sync::mpsc;
+use std::sync::{mpsc, LazyLock};
 
 use crate::HEADER_LEN;
 use crate::{Call, Header, Packet, Pid, Port};
@@ -11,7 +11,10 @@ use crate::{Error, Result};
 // TODO: get rid of Reply struct. It's just a subset of Packet.
 
 /// Info about one port.
+///
+/// The AGW server calls the first port "Port1", which is `Port(0)`.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PortInfo {
     pub port: Port,
     pub descr: String,
@@ -19,6 +22,7 @@ pub struct PortInfo {
 
 /// Info about all ports.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PortsInfo {
     /// Number of ports.
     pub count: usize,
@@ -31,6 +35,7 @@ pub struct PortsInfo {
 ///
 /// Normally 1200 or 9600 for classic AX.25.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Baud {
     Unknown,
     B1200,
@@ -40,6 +45,18 @@ pub enum Baud {
 }
 
 impl Baud {
+    /// Bits per second, if known.
+    #[must_use]
+    pub fn bits_per_second(self) -> Option<u32> {
+        match self {
+            Baud::Unknown => None,
+            Baud::B1200 => Some(1200),
+            Baud::B2400 => Some(2400),
+            Baud::B4800 => Some(4800),
+            Baud::B9600 => Some(9600),
+        }
+    }
+
     fn from_byte(b: u8) -> Option<Baud> {
         Some(match b {
             0 => Baud::B1200,
@@ -53,6 +70,7 @@ impl Baud {
 
 /// Port capabilities.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PortCaps {
     /// On air baud rate.
     pub rate: Baud,
@@ -77,7 +95,36 @@ pub struct PortCaps {
     pub bytes_per_2min: u32,
 }
 
+impl PortCaps {
+    /// Caps of an idle port at `rate`, with common TNC defaults: TXDELAY 30,
+    /// TXTAIL 10, persist 63, slot time 10, and MAXFRAME 4.
+    ///
+    /// ```
+    /// use agw::{Baud, PortCaps};
+    /// let caps = PortCaps {
+    ///     max_frame: 1,
+    ///     ..PortCaps::new(Baud::B9600)
+    /// };
+    /// assert_eq!(caps.tx_delay, 30);
+    /// ```
+    #[must_use]
+    pub fn new(rate: Baud) -> Self {
+        Self {
+            rate,
+            traffic_level: None,
+            tx_delay: 30,
+            tx_tail: 10,
+            persist: 63,
+            slot_time: 10,
+            max_frame: 4,
+            active_connections: 0,
+            bytes_per_2min: 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CallsignHeard {
     pub call: Call,
     // TODO: timestamps.
@@ -151,73 +198,105 @@ impl Reply {
     }
 }
 
+static PORT_LINE_RE: LazyLock<regex::Regex> = LazyLock::new(|| {
+    regex::Regex::new(r"^Port(\d+)\s*(.*)$").expect("can't happen: static regex is valid")
+});
+
+fn check_len(header: &Header, data: &[u8], expected: usize) -> Result<()> {
+    if data.len() == expected {
+        Ok(())
+    } else {
+        Err(Error::BadPacketLength {
+            kind: header.data_kind,
+            expected,
+            got: data.len(),
+        })
+    }
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(
+        data[pos..pos + 4]
+            .try_into()
+            .expect("can't happen: bytes to u32"),
+    )
+}
+
+/// Parse a reply payload, and throw the result away.
+///
+/// Only for tests/fuzz_parse.rs, since `Reply` is internal.
+///
+/// # Errors
+///
+/// If the payload is malformed for its kind.
+#[doc(hidden)]
+pub fn fuzz_parse_reply(header: &Header, data: &[u8]) -> Result<()> {
+    parse_reply(header, data).map(drop)
+}
+
 #[allow(clippy::too_many_lines)]
 pub(crate) fn parse_reply(header: &Header, data: &[u8]) -> Result<Reply> {
-    // TODO: confirm data len, since most replies will have fixed size.
     Ok(match header.data_kind {
         b'R' => {
-            let major = u16::from_le_bytes(
-                data[0..2]
-                    .try_into()
-                    .expect("can't happen: two bytes can't be made into u16?"),
-            );
-            let minor = u16::from_le_bytes(
-                data[4..6]
-                    .try_into()
-                    .expect("can't happen: two bytes can't be made into u16?"),
-            );
+            check_len(header, data, 8)?;
+            let major = u16::from_le_bytes([data[0], data[1]]);
+            let minor = u16::from_le_bytes([data[4], data[5]]);
             Reply::Version(major, minor)
         }
-        b'X' => Reply::CallsignRegistration(data[0] == 1),
+        b'X' => {
+            check_len(header, data, 1)?;
+            Reply::CallsignRegistration(data[0] == 1)
+        }
         b'C' => Reply::ConnectionEstablished(Connected {
             port: header.port,
             pid: header.pid,
-            src: header.src.clone().unwrap(),
-            dst: header.dst.clone().unwrap(),
-            data: std::str::from_utf8(data).map_err(Error::other)?.to_string(),
+            src: header.src()?,
+            dst: header.dst()?,
+            data: std::str::from_utf8(data)
+                .map_err(|e| Error::bad_payload(header.data_kind, e.to_string()))?
+                .to_string(),
         }),
         b'D' => Reply::ConnectedData(ConnectedData {
             port: header.port,
             pid: header.pid,
-            src: header.src.clone().unwrap(),
-            dst: header.dst.clone().unwrap(),
+            src: header.src()?,
+            dst: header.dst()?,
             data: data.to_vec(),
         }),
         b'd' => Reply::Disconnect,
         b'T' => Reply::ConnectedSent(data.to_vec()),
         b'U' => Reply::Unproto(data.to_vec()),
         b'G' => {
-            let re = regex::Regex::new(r"^Port(\d+)\s*(.*)$").unwrap();
-
-            let s = std::str::from_utf8(data).map_err(Error::other)?;
+            let s =
+                std::str::from_utf8(data).map_err(|e| Error::bad_payload(b'G', e.to_string()))?;
             let (count, ports) = {
                 let mut np = s.splitn(2, ';');
                 let count = np
                     .next()
-                    .expect("TODO: custom error")
+                    .ok_or(Error::bad_payload(b'G', "port info reply missing count"))?
                     .parse()
-                    .map_err(Error::other)?;
+                    .map_err(|e| Error::bad_payload(b'G', format!("port count: {e}")))?;
                 let ports = np
                     .next()
-                    .expect("TODO: custom error")
+                    .ok_or(Error::bad_payload(b'G', "port info reply missing ports"))?
                     .split(';')
                     .map(std::string::ToString::to_string)
-                    .filter(|s| s != "\0")
+                    .filter(|s| !s.is_empty() && s != "\0")
                     .map(|s| {
-                        let caps = re
+                        let caps = PORT_LINE_RE
                             .captures(&s)
-                            .ok_or(Error::msg(format!("bad port line {s:?}")))?;
-                        let port = Port(
-                            caps.get(1)
-                                .ok_or(Error::msg("Can't happen: port number missing"))?
-                                .as_str()
-                                .parse()
-                                .ok()
-                                .ok_or(Error::msg("Port number not a number"))?,
-                        );
+                            .ok_or(Error::bad_payload(b'G', format!("bad port line {s:?}")))?;
+                        let port = caps
+                            .get(1)
+                            .ok_or(Error::bad_payload(b'G', "port number missing"))?
+                            .as_str()
+                            .parse()
+                            .ok()
+                            .and_then(Port::from_number)
+                            .ok_or(Error::bad_payload(b'G', "port number out of range"))?;
                         let descr = caps
                             .get(2)
-                            .ok_or(Error::msg("Can't happen: descr string missing"))?
+                            .ok_or(Error::bad_payload(b'G', "port description missing"))?
                             .as_str()
                             .to_string();
                         Ok::<_, Error>(PortInfo { port, descr })
@@ -228,6 +307,7 @@ pub(crate) fn parse_reply(header: &Header, data: &[u8]) -> Result<Reply> {
             Reply::PortInfo(PortsInfo { count, ports })
         }
         b'g' => {
+            check_len(header, data, 12)?;
             let rate = data[0];
             let traffic_level = data[1];
             let tx_delay = data[2];
@@ -236,8 +316,7 @@ pub(crate) fn parse_reply(header: &Header, data: &[u8]) -> Result<Reply> {
             let slot_time = data[5];
             let max_frame = data[6];
             let active_connections = data[7];
-            let bytes_per_2min =
-                u32::from_le_bytes(data[8..12].try_into().expect("can't happen: bytes to u32"));
+            let bytes_per_2min = u32_at(data, 8);
 
             let traffic_level = if traffic_level == 0xff {
                 None
@@ -246,7 +325,7 @@ pub(crate) fn parse_reply(header: &Header, data: &[u8]) -> Result<Reply> {
             };
 
             Reply::PortCaps(
-                Port(header.port.0 + 1),
+                header.port,
                 PortCaps {
                     rate: Baud::from_byte(rate).unwrap_or(Baud::Unknown),
                     traffic_level,
@@ -260,18 +339,16 @@ pub(crate) fn parse_reply(header: &Header, data: &[u8]) -> Result<Reply> {
                 },
             )
         }
-        b'y' => Reply::FramesOutstandingPort(
-            Port(header.port.0 + 1),
-            usize::try_from(u32::from_le_bytes(
-                data[0..4].try_into().expect("can't happen: bytes to u32"),
-            ))
-            .expect("TODO: some error"),
-        ),
-        b'Y' => Reply::FramesOutstandingConnection(u32::from_le_bytes(
-            data[0..4].try_into().expect("can't happen: bytes to u32"),
-        )),
+        b'y' => {
+            check_len(header, data, 4)?;
+            Reply::FramesOutstandingPort(header.port, usize::try_from(u32_at(data, 0))?)
+        }
+        b'Y' => {
+            check_len(header, data, 4)?;
+            Reply::FramesOutstandingConnection(u32_at(data, 0))
+        }
         b'H' => Reply::CallsignHeard(
-            Port(header.port.0 + 1),
+            header.port,
             // TODO: implement parse.
             vec![],
         ),
@@ -300,14 +377,19 @@ impl MakeWriter {
     ///
     /// If given data so bad that the serialization fails.
     pub fn data<T: Into<Vec<u8>>>(&self, data: T) -> Result<Vec<u8>> {
-        Ok(Packet::Data {
+        let mut v = Vec::new();
+        for packet in (Packet::Data {
             port: self.port,
             pid: self.pid,
             src: self.src.clone(),
             dst: self.dst.clone(),
             data: data.into(),
+        })
+        .split_data(crate::DATA_CHUNK_LEN)
+        {
+            packet.serialize_into(&mut v)?;
         }
-        .serialize())
+        Ok(v)
     }
     /// Make a disconnect packet.
     #[must_use]
@@ -317,6 +399,7 @@ impl MakeWriter {
             pid: self.pid,
             src: self.src.clone(),
             dst: self.dst.clone(),
+            text: String::new(),
         }
         .serialize()
     }
@@ -413,8 +496,9 @@ impl<'a> Connection<'a> {
                     pid: self.pid,
                     src: self.src.clone(),
                     dst: self.dst.clone(),
+                    text: String::new(),
                 }
-                .serialize(),
+                .try_serialize()?,
             )?;
             self.disconnected = true;
         }
@@ -441,7 +525,7 @@ pub fn parse_header(header: &[u8; HEADER_LEN]) -> Result<Header> {
     let src = if src.is_empty() { None } else { Some(src) };
     let dst = Call::from_bytes(&header[18..28])?;
     let dst = if dst.is_empty() { None } else { Some(dst) };
-    Ok(Header::new(
+    let mut h = Header::new(
         Port(header[0]),
         header[4],
         Pid(header[6]),
@@ -452,7 +536,14 @@ pub fn parse_header(header: &[u8; HEADER_LEN]) -> Result<Header> {
                 .try_into()
                 .expect("can't happen: bytes to u32"),
         ),
-    ))
+    );
+    h.reserved = [header[1], header[2], header[3], header[5], header[7]];
+    h.user = u32::from_le_bytes(
+        header[32..36]
+            .try_into()
+            .expect("can't happen: bytes to u32"),
+    );
+    Ok(h)
 }
 
 /// Command.
@@ -480,35 +571,73 @@ impl AGW {
     ///
     /// If connecting to the server fails.
     pub fn new(addr: &str) -> Result<AGW> {
+        Self::with_max_data_len(addr, crate::DEFAULT_MAX_DATA_LEN)
+    }
+
+    /// Create AGW connection to ip:port, treating frames with payloads longer
+    /// than `max_data_len` as a protocol error.
+    ///
+    /// # Errors
+    ///
+    /// If connecting to the server fails.
+    pub fn with_max_data_len(addr: &str, max_data_len: u32) -> Result<AGW> {
         debug!("agw: Creating AGW to {addr}");
-        let (tx, rx) = mpsc::channel();
-        let (tx2, rx2) = mpsc::channel();
         let wstream = TcpStream::connect(addr).map_err(Error::other)?;
         let rstream = wstream.try_clone().map_err(Error::other)?;
+        Ok(Self::from_streams_with_max_data_len(
+            rstream,
+            wstream,
+            max_data_len,
+        ))
+    }
+
+    /// Create AGW connection over an already connected stream, given as
+    /// its reading and writing halves.
+    ///
+    /// E.g. a `UnixStream` and its `try_clone()`, for tests.
+    pub fn from_streams<R, W>(r: R, w: W) -> AGW
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        Self::from_streams_with_max_data_len(r, w, crate::DEFAULT_MAX_DATA_LEN)
+    }
+
+    /// Create AGW connection over an already connected stream, treating
+    /// frames with payloads longer than `max_data_len` as a protocol error.
+    pub fn from_streams_with_max_data_len<R, W>(r: R, w: W, max_data_len: u32) -> AGW
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let (tx2, rx2) = mpsc::channel();
         let agw = AGW {
             rx,
             tx: tx2,
             rxqueue: LinkedList::new(),
         };
         // Start reader.
-        std::thread::spawn(|| {
-            if let Err(e) = Self::reader(rstream, &tx) {
+        std::thread::spawn(move || {
+            if let Err(e) = Self::reader(r, &tx, max_data_len) {
                 warn!("TCP socket reader connected to AGWPE ended: {e:?}");
             }
             drop(tx);
         });
         // Start writer.
-        std::thread::spawn(|| {
-            if let Err(e) = Self::writer(wstream, &rx2) {
+        std::thread::spawn(move || {
+            if let Err(e) = Self::writer(w, &rx2) {
                 warn!("TCP socket writer connected to AGWPE ended: {e:?}");
             }
             drop(rx2);
         });
-        Ok(agw)
+        agw
     }
 
     fn send(&mut self, msg: &[u8]) -> Result<()> {
-        self.tx.send(msg.to_vec()).map_err(Error::other)?;
+        self.tx
+            .send(msg.to_vec())
+            .map_err(|_| Error::ConnectionClosed)?;
         Ok(())
     }
 
@@ -516,21 +645,25 @@ impl AGW {
         self.tx.clone()
     }
 
-    fn writer(mut stream: TcpStream, rx: &mpsc::Receiver<Vec<u8>>) -> Result<()> {
+    fn writer(mut stream: impl Write, rx: &mpsc::Receiver<Vec<u8>>) -> Result<()> {
         loop {
             let buf = rx.recv().map_err(Error::other)?;
-            // TODO: do full write.
-            let _ = stream.write(&buf).map_err(Error::other)?;
+            stream.write_all(&buf).map_err(Error::other)?;
         }
     }
 
-    fn reader(mut stream: TcpStream, tx: &mpsc::Sender<(Header, Reply)>) -> Result<()> {
+    fn reader(
+        mut stream: impl Read,
+        tx: &mpsc::Sender<(Header, Reply)>,
+        max_data_len: u32,
+    ) -> Result<()> {
         loop {
             let mut header = [0_u8; HEADER_LEN];
             stream.read_exact(&mut header)?;
             let header = parse_header(&header)?;
-            let payload = if header.data_len > 0 {
-                let mut payload = vec![0; header.data_len as usize];
+            let len = header.checked_data_len(max_data_len)?;
+            let payload = if len > 0 {
+                let mut payload = vec![0; len];
                 stream.read_exact(&mut payload)?;
                 payload
             } else {
@@ -564,7 +697,7 @@ impl AGW {
     pub fn version(&mut self) -> Result<(u16, u16)> {
         self.send(&Packet::VersionQuery.serialize())?;
         loop {
-            let (h, r) = self.rx.recv().map_err(Error::other)?;
+            let (h, r) = self.rx.recv().map_err(|_| Error::ConnectionClosed)?;
             match r {
                 Reply::Version(maj, min) => return Ok((maj, min)),
                 other => self.rx_enqueue(h, other),
@@ -576,7 +709,7 @@ impl AGW {
     pub fn frames_outstanding(&mut self, port: Port) -> Result<usize> {
         self.send(&Packet::FramesOutstandingPortQuery(port).serialize())?;
         loop {
-            let (h, r) = self.rx.recv().map_err(Error::other)?;
+            let (h, r) = self.rx.recv().map_err(|_| Error::ConnectionClosed)?;
             match r {
                 Reply::FramesOutstandingPort(p, n) if p == port => return Ok(n),
                 other => self.rx_enqueue(h, other),
@@ -592,7 +725,7 @@ impl AGW {
     pub fn port_info(&mut self) -> Result<PortsInfo> {
         self.send(&Packet::PortInfoQuery.serialize())?;
         loop {
-            let (h, r) = self.rx.recv().map_err(Error::other)?;
+            let (h, r) = self.rx.recv().map_err(|_| Error::ConnectionClosed)?;
             match r {
                 Reply::PortInfo(i) => return Ok(i),
                 other => self.rx_enqueue(h, other),
@@ -608,12 +741,11 @@ impl AGW {
     pub fn port_cap(&mut self, port: Port) -> Result<PortCaps> {
         let ports = self.port_info()?;
         if !ports.ports.iter().any(|p| p.port == port) {
-            return Err(Error::msg(format!("No such port as {port:?}")));
+            return Err(Error::NoSuchPort(port));
         }
-        self.send(&Packet::PortCapQuery(port).serialize())
-            .map_err(Error::other)?;
+        self.send(&Packet::PortCapQuery(port).serialize())?;
         loop {
-            let (h, r) = self.rx.recv().map_err(Error::other)?;
+            let (h, r) = self.rx.recv().map_err(|_| Error::ConnectionClosed)?;
             match r {
                 Reply::PortCaps(p, i) if p == port => return Ok(i),
                 other => self.rx_enqueue(h, other),
@@ -626,10 +758,9 @@ impl AGW {
     // received? Ending with empty callsign one? Direwolf isn't sending me
     // anything.
     pub fn callsign_heard(&mut self, port: Port) -> Result<Vec<CallsignHeard>> {
-        self.send(&Packet::CallsignHeardQuery(port).serialize())
-            .map_err(Error::other)?;
+        self.send(&Packet::CallsignHeardQuery(port).serialize())?;
         loop {
-            let (h, r) = self.rx.recv().map_err(Error::other)?;
+            let (h, r) = self.rx.recv().map_err(|_| Error::ConnectionClosed)?;
             match r {
                 Reply::CallsignHeard(p, i) if p == port => return Ok(i),
                 other => self.rx_enqueue(h, other),
@@ -658,7 +789,7 @@ impl AGW {
                 dst: dst.clone(),
                 data: data.to_vec(),
             }
-            .serialize(),
+            .try_serialize()?,
         )?;
         Ok(())
     }
@@ -676,7 +807,7 @@ impl AGW {
     /// If underlying connection fails.
     pub fn register_callsign(&mut self, port: Port, src: &Call) -> Result<()> {
         debug!("agw: Registering callsign");
-        self.send(&Packet::RegisterCallsign(port, src.clone()).serialize())?;
+        self.send(&Packet::RegisterCallsign(port, src.clone()).try_serialize()?)?;
         Ok(())
     }
 
@@ -701,7 +832,7 @@ impl AGW {
                     src: src.clone(),
                     dst: dst.clone(),
                 }
-                .serialize(),
+                .try_serialize()?,
             )?;
         } else {
             self.send(
@@ -712,13 +843,13 @@ impl AGW {
                     dst: dst.clone(),
                     via: via.to_vec(),
                 }
-                .serialize(),
+                .try_serialize()?,
             )?;
             todo!();
         }
         let connect_string;
         loop {
-            let (head, r) = self.rx.recv().map_err(Error::other)?;
+            let (head, r) = self.rx.recv().map_err(|_| Error::ConnectionClosed)?;
             if (head.src.as_ref() != Some(dst)) || (head.dst.as_ref() != Some(src)) {
                 //eprintln!("Got packet not for us");
                 continue;
@@ -755,16 +886,16 @@ impl AGW {
         // TODO: enforce max size?
         let len = data.len();
         if len > 0 {
-            self.send(
-                &Packet::Data {
-                    port,
-                    pid,
-                    src: src.clone(),
-                    dst: dst.clone(),
-                    data: data.to_vec(),
-                }
-                .serialize(),
-            )?;
+            let packet = Packet::Data {
+                port,
+                pid,
+                src: src.clone(),
+                dst: dst.clone(),
+                data: data.to_vec(),
+            };
+            for packet in packet.split_data(crate::DATA_CHUNK_LEN) {
+                self.send(&packet.try_serialize()?)?;
+            }
         }
         Ok(data.len())
     }
@@ -785,7 +916,7 @@ impl AGW {
                     return Ok(ret);
                 }
                 Reply::Disconnect => {
-                    return Err(Error::msg("remote end disconnected"));
+                    return Err(Error::ConnectionClosed);
                 }
                 _ => {
                     debug!(
@@ -798,7 +929,7 @@ impl AGW {
 
         // Next packet not in the queue. Wait.
         loop {
-            let (h, r) = self.rx.recv().map_err(Error::other)?;
+            let (h, r) = self.rx.recv().map_err(|_| Error::ConnectionClosed)?;
             match r {
                 Reply::ConnectedData(i) => return Ok(i.data),
                 other => self.rx_enqueue(h, other),
@@ -806,3 +937,127 @@ impl AGW {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serialize `packet`, and parse it back with the v1 parsers.
+    fn reply(packet: &Packet) -> (Header, Reply) {
+        let bytes = packet.serialize();
+        let header = parse_header(bytes.first_chunk().unwrap()).unwrap();
+        let reply = parse_reply(&header, &bytes[HEADER_LEN..]).unwrap();
+        (header, reply)
+    }
+
+    #[test]
+    fn port_numbers() {
+        assert_eq!(Port::from_number(0), None);
+        assert_eq!(Port::from_number(1), Some(Port(0)));
+        assert_eq!(Port::from_number(256), Some(Port(255)));
+        assert_eq!(Port::from_number(257), None);
+        for n in 0..=255 {
+            assert_eq!(Port::from_number(Port(n).number()), Some(Port(n)));
+        }
+    }
+
+    #[test]
+    fn header_port_round_trips() {
+        for port in [Port(0), Port(3)] {
+            let header = Header::new(port, b'g', Pid(0), None, None, 0);
+            let bytes = header.to_bytes();
+            assert_eq!(bytes[0], port.0, "wire port is 0-based");
+            assert_eq!(parse_header(&bytes).unwrap(), header);
+        }
+    }
+
+    #[test]
+    fn reply_ports_round_trip() {
+        for port in [Port(0), Port(3)] {
+            let (header, r) = reply(&Packet::PortCapReply {
+                port,
+                caps: PortCaps {
+                    rate: Baud::B1200,
+                    traffic_level: None,
+                    tx_delay: 1,
+                    tx_tail: 2,
+                    persist: 3,
+                    slot_time: 4,
+                    max_frame: 5,
+                    active_connections: 6,
+                    bytes_per_2min: 7,
+                },
+            });
+            assert_eq!(header.port, port);
+            assert!(matches!(r, Reply::PortCaps(p, caps) if p == port && caps.bytes_per_2min == 7));
+
+            let (_, r) = reply(&Packet::FramesOutstandingPortReply(port, 9));
+            assert!(matches!(r, Reply::FramesOutstandingPort(p, 9) if p == port));
+        }
+    }
+
+    #[test]
+    fn short_or_long_payload_is_bad_length() {
+        for (kind, expected) in [(b'R', 8), (b'X', 1), (b'g', 12), (b'y', 4), (b'Y', 4)] {
+            for got in [0, expected - 1, expected + 1] {
+                let header = Header::new(Port(0), kind, Pid(0), None, None, 0);
+                let r = parse_reply(&header, &vec![0; got]);
+                assert!(
+                    matches!(
+                        r,
+                        Err(Error::BadPacketLength { kind: k, expected: e, got: g })
+                            if k == kind && e == expected && g == got
+                    ),
+                    "kind {:?} len {got}: {r:?}",
+                    char::from(kind)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bad_port_info_is_bad_payload() {
+        for data in [
+            b"".as_slice(),
+            b"x;Port1 First;",
+            b"1",
+            b"1;Com1 First;",
+            b"1;Port0 Zero;",
+            b"1;Port257 Big;",
+            b"1;\xff;",
+        ] {
+            let header = Header::new(Port(0), b'G', Pid(0), None, None, 0);
+            let r = parse_reply(&header, data);
+            assert!(
+                matches!(r, Err(Error::BadPayload { kind: b'G', .. })),
+                "{:?}: {r:?}",
+                String::from_utf8_lossy(data)
+            );
+        }
+    }
+
+    #[test]
+    fn port_info_uses_1_based_numbers() {
+        let info = PortsInfo {
+            count: 2,
+            ports: vec![
+                PortInfo {
+                    port: Port(0),
+                    descr: "First".to_string(),
+                },
+                PortInfo {
+                    port: Port(1),
+                    descr: "Second".to_string(),
+                },
+            ],
+        };
+        let bytes = Packet::PortInfoReply(info.clone()).serialize();
+        let text = std::str::from_utf8(&bytes[HEADER_LEN..]).unwrap();
+        assert_eq!(text, "2;Port1 First;Port2 Second;\0");
+        let (_, r) = reply(&Packet::PortInfoReply(info.clone()));
+        let Reply::PortInfo(got) = r else {
+            panic!("expected port info");
+        };
+        assert_eq!(got, info);
+    }
+}
@@ -17,7 +17,9 @@ struct Reader {
 
 impl Reader {
     fn read(&self) -> Reply {
-        self.rx.recv().expect("TODO")
+        self.rx
+            .recv()
+            .unwrap_or(Reply::Error(Error::ConnectionClosed))
     }
 }
 
@@ -36,10 +38,14 @@ struct AgwCon {
 
     shut_fd: std::os::fd::OwnedFd,
     exiting: std::sync::atomic::AtomicBool,
+    max_data_len: u32,
+
+    // Set, with `children` locked, once the connection has failed.
+    closed: Mutex<Option<Error>>,
 }
 
 impl AgwCon {
-    fn new(shut_fd: std::os::fd::OwnedFd) -> Self {
+    fn new(shut_fd: std::os::fd::OwnedFd, max_data_len: u32) -> Self {
         Self {
             id: 0.into(),
             children: Mutex::new(HashMap::new()),
@@ -47,6 +53,8 @@ impl AgwCon {
             txq_notify: std::sync::Condvar::default(),
             exiting: false.into(),
             shut_fd,
+            max_data_len,
+            closed: Mutex::new(None),
         }
     }
 
@@ -78,6 +86,9 @@ impl AgwCon {
     }
     /// Write from application to AGW server.
     fn write(&self, data: &[u8]) -> Result<()> {
+        if let Some(e) = &*self.closed.lock()? {
+            return Err(e.clone());
+        }
         let mut txq = self.txq.lock()?;
         txq.extend(data);
         self.txq_notify.notify_one();
@@ -90,7 +101,13 @@ impl AgwCon {
     fn rx(self: &Arc<Self>) -> Reader {
         let id = self.id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         let (tx, rx) = std::sync::mpsc::channel();
-        self.children.lock().unwrap().insert(id, tx);
+        let mut children = self.children.lock().unwrap();
+        if let Some(e) = &*self.closed.lock().unwrap() {
+            // Nothing more is coming, so don't wait for it.
+            let _ = tx.send(Reply::Error(e.clone()));
+        }
+        children.insert(id, tx);
+        drop(children);
         Reader {
             id,
             parent: Arc::clone(self),
@@ -114,25 +131,43 @@ impl AgwCon {
                     return;
                 }
             }
-            let n = w.write(&txq).expect("write failed");
-            txq.drain(..n);
+            let n = match w.write(&txq) {
+                Ok(0) => Err(std::io::Error::from(std::io::ErrorKind::WriteZero)),
+                other => other,
+            };
+            match n {
+                Ok(n) => {
+                    txq.drain(..n);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("Writer error: {e}");
+                    drop(txq);
+                    self.close(&e.into());
+                    return;
+                }
+            }
+        }
+    }
+    /// Fail all current and future readers.
+    fn close(&self, e: &Error) {
+        let children = self.children.lock().unwrap();
+        self.closed.lock().unwrap().get_or_insert(e.clone());
+        for child in children.values() {
+            if let Err(e) = child.send(Reply::Error(e.clone())) {
+                warn!("Failed to write error to a subscribing client: {e}");
+            }
         }
     }
     fn reader(&self, r: impl Read + Poll) {
-        match self.reader_inner(r) {
-            Ok(()) => {} // TODO: send EOF to all children?
+        let e = match self.reader_inner(r) {
+            Ok(()) => Error::ConnectionClosed,
             Err(e) => {
                 warn!("Reader error: {e}");
-                let children = self.children.lock().unwrap();
-                for child in children.values() {
-                    if let Err(e) =
-                        child.send(Reply::Error(Error::msg(format!("Reader eror: {e}"))))
-                    {
-                        warn!("Failed to write error to a subscribing client: {e}");
-                    }
-                }
+                e
             }
-        }
+        };
+        self.close(&e);
     }
     fn reader_inner(&self, mut r: impl Read + Poll) -> Result<()> {
         use std::os::fd::AsRawFd;
@@ -151,7 +186,7 @@ impl AgwCon {
             }?;
             let header = crate::parse_header(&header)?;
             // Read data.
-            let mut data = vec![0_u8; usize::try_from(header.data_len)?];
+            let mut data = vec![0_u8; header.checked_data_len(self.max_data_len)?];
             r.read_exact(&mut data)?;
 
             // Inform all subscribing children.
@@ -179,6 +214,9 @@ pub struct Connection {
     port: Port,
     pid: Pid,
     parent: Arc<AgwCon>,
+    // Subscribed before connecting, so data right after connection
+    // establishment isn't missed.
+    rx: Reader,
     buf: Vec<u8>,
 }
 
@@ -196,13 +234,12 @@ impl Read for Connection {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         if !self.buf.is_empty() {
             let n = buf.len().min(self.buf.len());
-            buf[..n].copy_from_slice(&self.buf);
+            buf[..n].copy_from_slice(&self.buf[..n]);
             self.buf.drain(..n);
             return Ok(n);
         }
-        let rx = self.parent.clone().rx();
         loop {
-            match rx.read() {
+            match self.rx.read() {
                 Reply::Error(e) => return Err(std::io::Error::other(e)),
                 Reply::ConnectedData(d)
                     if d.src == self.peer
@@ -292,9 +329,19 @@ impl AGW {
     pub fn new<R: Poll + Read + Send + 'static, W: Write + Send + 'static>(
         r: R,
         w: W,
+    ) -> Result<Self> {
+        Self::with_max_data_len(r, w, crate::DEFAULT_MAX_DATA_LEN)
+    }
+
+    /// Create AGW connection, treating frames with payloads longer than
+    /// `max_data_len` as a protocol error.
+    pub fn with_max_data_len<R: Poll + Read + Send + 'static, W: Write + Send + 'static>(
+        r: R,
+        w: W,
+        max_data_len: u32,
     ) -> Result<Self> {
         let (pr, pw) = pipe()?;
-        let parent = Arc::new(AgwCon::new(pr));
+        let parent = Arc::new(AgwCon::new(pr, max_data_len));
         let p2 = parent.clone();
         let join_handle = std::thread::spawn(move || p2.run(r, w));
         Ok(Self {
@@ -416,7 +463,7 @@ impl AGW {
                 dst: dst.clone(),
                 data: data.to_vec(),
             }
-            .serialize(),
+            .try_serialize()?,
         )?;
         Ok(())
     }
@@ -435,7 +482,7 @@ impl AGW {
     pub fn register_callsign(&self, port: Port, src: &Call) -> Result<()> {
         debug!("agw: Registering callsign");
         self.parent
-            .write(&Packet::RegisterCallsign(port, src.clone()).serialize())?;
+            .write(&Packet::RegisterCallsign(port, src.clone()).try_serialize()?)?;
         Ok(())
     }
 
@@ -451,7 +498,7 @@ impl AGW {
                     src: me.clone(),
                     dst: peer.clone(),
                 }
-                .serialize(),
+                .try_serialize()?,
             )?;
         } else {
             self.parent.write(
@@ -462,7 +509,7 @@ impl AGW {
                     dst: peer.clone(),
                     via: via.to_vec(),
                 }
-                .serialize(),
+                .try_serialize()?,
             )?;
             todo!();
         }
@@ -483,6 +530,7 @@ impl AGW {
             me,
             peer,
             parent,
+            rx,
             buf: vec![],
         })
     }
@@ -1,6 +1,24 @@
 use std::io::{Read, Write};
 
-use crate::Result;
+use crate::{Call, MonitorKind, Packet, Pid, Result};
+
+/// Max input to `Wrapper::wrap()` per message. Longer writes are split.
+const MAX_CHUNK: usize = 32 * 1024;
+
+/// Max length of a wrapped message read from the stream.
+const MAX_WRAPPED_LEN: usize = 1024 * 1024;
+
+/// On-air addressing of a packet whose payload is wrapped.
+///
+/// Given to `Wrapper::wrap_from()` and `Wrapper::unwrap_from()`, so that the
+/// wrapping can be bound to it. The AGW port is not included, since port
+/// numbers are local to each AGW server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Envelope<'a> {
+    pub src: &'a Call,
+    pub dst: &'a Call,
+    pub pid: Pid,
+}
 
 /// An interface to wrap data, e.g. for signed packet sending.
 pub trait Wrapper {
@@ -17,42 +35,813 @@ pub trait Wrapper {
     /// Implementation defined. But in the case of signed, if the signature
     /// doesn't match.
     fn unwrap(&self, input: &[u8]) -> Result<Vec<u8>>;
+
+    /// Max number of bytes wrapping adds to its input.
+    ///
+    /// Used to fit wrapped data into frames of a given length. Defaults to
+    /// zero.
+    fn overhead(&self) -> usize {
+        0
+    }
+
+    /// Wrap the payload of a packet sent with addressing `env`.
+    ///
+    /// Defaults to `wrap()`, ignoring the addressing.
+    ///
+    /// # Errors
+    ///
+    /// Implementation defined.
+    fn wrap_from(&self, env: &Envelope<'_>, input: &[u8]) -> Result<Vec<u8>> {
+        let _ = env;
+        self.wrap(input)
+    }
+
+    /// Unwrap the payload of a packet received with addressing `env`.
+    ///
+    /// Defaults to `unwrap()`, ignoring the addressing.
+    ///
+    /// # Errors
+    ///
+    /// Implementation defined. But in the case of signed, if the signature
+    /// doesn't match.
+    fn unwrap_from(&self, env: &Envelope<'_>, input: &[u8]) -> Result<Vec<u8>> {
+        let _ = env;
+        self.unwrap(input)
+    }
 }
 
+/// Stream that wraps everything written to it, and unwraps everything read
+/// from it.
+///
+/// Each write becomes one message on the backend stream: the length of the
+/// wrapped data as 4 bytes big endian, followed by the wrapped data. Reads
+/// unwrap one whole message at a time, so the backend can deliver the bytes
+/// in any chunks it likes.
 pub struct Wrap<T: Read + Write, W: Wrapper> {
     backend: T,
     wrapper: W,
+    // Unwrapped data not yet returned by `read()`.
+    rbuf: Vec<u8>,
+    rpos: usize,
 }
 
 impl<T: Read + Write, W: Wrapper> Wrap<T, W> {
     pub fn new(backend: T, wrapper: W) -> Self {
-        Self { backend, wrapper }
+        Self {
+            backend,
+            wrapper,
+            rbuf: Vec::new(),
+            rpos: 0,
+        }
     }
     pub fn into_inner(self) -> (T, W) {
         (self.backend, self.wrapper)
     }
+
+    /// Read the next message from the backend, or `None` on EOF between
+    /// messages.
+    fn read_message(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let mut len = [0; 4];
+        let mut got = 0;
+        while got < len.len() {
+            match self.backend.read(&mut len[got..]) {
+                Ok(0) if got == 0 => return Ok(None),
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => got += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_WRAPPED_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("wrapped message too long: {len} > {MAX_WRAPPED_LEN}"),
+            ));
+        }
+        let mut msg = vec![0; len];
+        self.backend.read_exact(&mut msg)?;
+        Ok(Some(msg))
+    }
 }
 
 impl<T: Read + Write, W: Wrapper> Read for Wrap<T, W> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let size = self.backend.read(buf)?;
-        let buf2 = &buf[..size];
-        let msg = self
-            .wrapper
-            .wrap(buf2)
-            .map_err(|e| std::io::Error::other(format!("{e}")))?;
-        let msglen = msg.len();
-        buf.copy_from_slice(&msg);
-        Ok(msglen)
+        while self.rpos == self.rbuf.len() {
+            let Some(msg) = self.read_message()? else {
+                return Ok(0);
+            };
+            self.rbuf = self
+                .wrapper
+                .unwrap(&msg)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            self.rpos = 0;
+        }
+        let n = buf.len().min(self.rbuf.len() - self.rpos);
+        buf[..n].copy_from_slice(&self.rbuf[self.rpos..self.rpos + n]);
+        self.rpos += n;
+        Ok(n)
     }
 }
 
 impl<T: Read + Write, W: Wrapper> Write for Wrap<T, W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.backend.write(buf)
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk = &buf[..buf.len().min(MAX_CHUNK)];
+        let msg = self.wrapper.wrap(chunk).map_err(std::io::Error::other)?;
+        let len = u32::try_from(msg.len())
+            .ok()
+            .filter(|&len| len as usize <= MAX_WRAPPED_LEN)
+            .ok_or_else(|| std::io::Error::other("wrapped message too long"))?;
+        let mut frame = Vec::with_capacity(4 + msg.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&msg);
+        self.backend.write_all(&frame)?;
+        Ok(chunk.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
         self.backend.flush()
     }
 }
+
+/// Wrap the payload of a packet.
+///
+/// Only `Data`, `Unproto` and `UnprotoVia` have their payload wrapped. Other
+/// packets are returned as is.
+///
+/// Unlike `Wrap`, which works on a byte stream, this wraps each AX.25 payload
+/// on its own. So wrapped payloads are longer than the input, and a
+/// `Data` packet should be split before being wrapped, not after.
+///
+/// # Errors
+///
+/// If the wrapper fails.
+pub fn wrap_packet(wrapper: &dyn Wrapper, packet: Packet) -> Result<Packet> {
+    Ok(match packet {
+        Packet::Data {
+            port,
+            pid,
+            src,
+            dst,
+            data,
+        } => Packet::Data {
+            // Before the addresses are moved.
+            data: wrapper.wrap_from(
+                &Envelope {
+                    src: &src,
+                    dst: &dst,
+                    pid,
+                },
+                &data,
+            )?,
+            port,
+            pid,
+            src,
+            dst,
+        },
+        Packet::Unproto {
+            port,
+            pid,
+            src,
+            dst,
+            data,
+        } => Packet::Unproto {
+            data: wrapper.wrap_from(
+                &Envelope {
+                    src: &src,
+                    dst: &dst,
+                    pid,
+                },
+                &data,
+            )?,
+            port,
+            pid,
+            src,
+            dst,
+        },
+        Packet::UnprotoVia {
+            port,
+            pid,
+            src,
+            dst,
+            via,
+            data,
+        } => Packet::UnprotoVia {
+            data: wrapper.wrap_from(
+                &Envelope {
+                    src: &src,
+                    dst: &dst,
+                    pid,
+                },
+                &data,
+            )?,
+            port,
+            pid,
+            src,
+            dst,
+            via,
+        },
+        other => other,
+    })
+}
+
+/// Unwrap the payload of a packet.
+///
+/// The reverse of `wrap_packet()`. Also unwraps monitored UI frames, which is
+/// how received unproto frames arrive, leaving the AGW server's text
+/// description in front of the payload as is. Other packets are returned as
+/// is.
+///
+/// # Errors
+///
+/// If the wrapper fails, e.g. because a signature doesn't match. Only this
+/// packet is affected, so the caller can drop it and carry on.
+pub fn unwrap_packet(wrapper: &dyn Wrapper, packet: Packet) -> Result<Packet> {
+    Ok(match packet {
+        Packet::Data {
+            port,
+            pid,
+            src,
+            dst,
+            data,
+        } => Packet::Data {
+            // Before the addresses are moved.
+            data: wrapper.unwrap_from(
+                &Envelope {
+                    src: &src,
+                    dst: &dst,
+                    pid,
+                },
+                &data,
+            )?,
+            port,
+            pid,
+            src,
+            dst,
+        },
+        Packet::Unproto {
+            port,
+            pid,
+            src,
+            dst,
+            data,
+        } => Packet::Unproto {
+            data: wrapper.unwrap_from(
+                &Envelope {
+                    src: &src,
+                    dst: &dst,
+                    pid,
+                },
+                &data,
+            )?,
+            port,
+            pid,
+            src,
+            dst,
+        },
+        Packet::UnprotoVia {
+            port,
+            pid,
+            src,
+            dst,
+            via,
+            data,
+        } => Packet::UnprotoVia {
+            data: wrapper.unwrap_from(
+                &Envelope {
+                    src: &src,
+                    dst: &dst,
+                    pid,
+                },
+                &data,
+            )?,
+            port,
+            pid,
+            src,
+            dst,
+            via,
+        },
+        Packet::Monitor {
+            kind: MonitorKind::Unproto,
+            port,
+            pid,
+            src,
+            dst,
+            data,
+        } => Packet::Monitor {
+            data: unwrap_monitor(
+                wrapper,
+                &Envelope {
+                    src: &src,
+                    dst: &dst,
+                    pid,
+                },
+                &data,
+            )?,
+            kind: MonitorKind::Unproto,
+            port,
+            pid,
+            src,
+            dst,
+        },
+        other => other,
+    })
+}
+
+/// Unwrap the payload of a monitored frame, keeping the text in front.
+///
+/// AGW servers don't reliably set the PID in the header of monitored frames,
+/// so it's taken from the text, if there.
+fn unwrap_monitor(wrapper: &dyn Wrapper, env: &Envelope<'_>, data: &[u8]) -> Result<Vec<u8>> {
+    let Some(end) = data.iter().position(|&b| b == b'\r') else {
+        return wrapper.unwrap_from(env, data);
+    };
+    let (head, mut payload) = data.split_at(end + 1);
+    let env = &Envelope {
+        pid: monitor_pid(head).unwrap_or(env.pid),
+        ..*env
+    };
+    // AGW servers may add a "\r" and NUL after the payload, but the wrapped
+    // payload can end with those bytes too. So try with and without them.
+    loop {
+        match wrapper.unwrap_from(env, payload) {
+            Ok(inner) => return Ok([head, &inner].concat()),
+            Err(e) => match payload {
+                [rest @ .., b'\r' | 0] => payload = rest,
+                _ => return Err(e),
+            },
+        }
+    }
+}
+
+/// PID from the text in front of a monitored frame, such as
+/// `" 1:Fm M0QQQ-8 To APZ001 <UI pid=F0 Len=5 >[12:00:00]\r"`.
+fn monitor_pid(head: &[u8]) -> Option<Pid> {
+    let head = std::str::from_utf8(head).ok()?;
+    let pid = &head[head.find(" pid=")? + 5..];
+    u8::from_str_radix(pid.get(..2)?, 16).ok().map(Pid)
+}
+
+#[cfg(feature = "crypto")]
+pub use signed::HmacWrapper;
+
+#[cfg(feature = "crypto")]
+mod signed {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::{Envelope, Wrapper};
+    use crate::{Call, Error, Result};
+
+    /// Key ID, counter, and tag.
+    const TRAILER_LEN: usize = 4 + 8 + 32;
+
+    /// Signs data with HMAC-SHA256, leaving the data itself readable.
+    ///
+    /// Wrapped data is the input followed by a 44 byte trailer: key ID (4
+    /// bytes), counter (8 bytes), and the HMAC-SHA256 tag over key ID,
+    /// counter, addressing, and input. Integers are big endian. The
+    /// addressing is the packet's source and destination callsigns as 10
+    /// bytes each, like in the AGW header, followed by the PID byte. It's 21
+    /// NUL bytes for `wrap()` and `unwrap()`, which don't know it. It's not
+    /// sent, since the receiver has it from the packet. So a message can't
+    /// be passed off as coming from another station, or replayed to another
+    /// destination.
+    ///
+    /// This is signing, not encryption, so it's allowed on amateur radio
+    /// bands.
+    ///
+    /// The counter goes up by one for each message, and unwrapping rejects
+    /// messages whose counter is not higher than the last one seen for the
+    /// same key and sender. That stops replays, while stations sharing a key
+    /// each have their own counter. It starts at the current time in
+    /// microseconds since the epoch, so it keeps going up across restarts
+    /// without having to be saved.
+    ///
+    /// ```
+    /// use std::io::{Cursor, Read, Write};
+    /// use agw::wrap::{HmacWrapper, Wrap};
+    /// let mut w = Wrap::new(Cursor::new(Vec::new()), HmacWrapper::new(1, b"secret"));
+    /// w.write_all(b"hello")?;
+    /// let (sent, _) = w.into_inner();
+    ///
+    /// let mut r = Wrap::new(Cursor::new(sent.into_inner()), HmacWrapper::new(1, b"secret"));
+    /// let mut got = String::new();
+    /// r.read_to_string(&mut got)?;
+    /// assert_eq!(got, "hello");
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub struct HmacWrapper {
+        key_id: u32,
+        keys: HashMap<u32, Vec<u8>>,
+        counter: AtomicU64,
+        // Highest counter seen, by key ID and sender.
+        seen: Mutex<HashMap<(u32, Option<Call>), u64>>,
+    }
+
+    impl HmacWrapper {
+        /// Sign with, and accept, `key`.
+        #[must_use]
+        pub fn new(key_id: u32, key: &[u8]) -> Self {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros();
+            Self {
+                key_id,
+                keys: HashMap::from([(key_id, key.to_vec())]),
+                counter: AtomicU64::new(u64::try_from(now).unwrap_or(u64::MAX / 2)),
+                seen: Mutex::new(HashMap::new()),
+            }
+        }
+
+        /// Also accept messages signed with another key, e.g. while rotating
+        /// keys.
+        #[must_use]
+        pub fn with_key(mut self, key_id: u32, key: &[u8]) -> Self {
+            self.keys.insert(key_id, key.to_vec());
+            self
+        }
+
+        /// Set the counter for the next message.
+        #[must_use]
+        pub fn with_counter(self, counter: u64) -> Self {
+            self.counter.store(counter, Ordering::Relaxed);
+            self
+        }
+
+        fn mac(
+            key: &[u8],
+            key_id: u32,
+            counter: u64,
+            env: Option<&Envelope<'_>>,
+            data: &[u8],
+        ) -> Hmac<Sha256> {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key)
+                .expect("can't happen: HMAC takes keys of any length");
+            mac.update(&key_id.to_be_bytes());
+            mac.update(&counter.to_be_bytes());
+            match env {
+                Some(env) => {
+                    mac.update(env.src.as_bytes());
+                    mac.update(env.dst.as_bytes());
+                    mac.update(&[env.pid.0]);
+                }
+                None => mac.update(&[0; 21]),
+            }
+            mac.update(data);
+            mac
+        }
+
+        fn sign(&self, env: Option<&Envelope<'_>>, input: &[u8]) -> Vec<u8> {
+            let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+            let tag = Self::mac(&self.keys[&self.key_id], self.key_id, counter, env, input)
+                .finalize()
+                .into_bytes();
+            let mut v = Vec::with_capacity(input.len() + TRAILER_LEN);
+            v.extend_from_slice(input);
+            v.extend_from_slice(&self.key_id.to_be_bytes());
+            v.extend_from_slice(&counter.to_be_bytes());
+            v.extend_from_slice(&tag);
+            v
+        }
+
+        fn verify(&self, env: Option<&Envelope<'_>>, input: &[u8]) -> Result<Vec<u8>> {
+            let Some(data_len) = input.len().checked_sub(TRAILER_LEN) else {
+                return Err(Error::BadSignature(format!(
+                    "message of {} bytes is shorter than the signature",
+                    input.len()
+                )));
+            };
+            let (data, trailer) = input.split_at(data_len);
+            let (key_id, trailer) = trailer.split_at(4);
+            let (counter, tag) = trailer.split_at(8);
+            let key_id = u32::from_be_bytes(key_id.try_into().expect("can't happen: 4 bytes"));
+            let counter = u64::from_be_bytes(counter.try_into().expect("can't happen: 8 bytes"));
+            let key = self
+                .keys
+                .get(&key_id)
+                .ok_or_else(|| Error::BadSignature(format!("unknown key ID {key_id}")))?;
+            Self::mac(key, key_id, counter, env, data)
+                .verify_slice(tag)
+                .map_err(|_| Error::BadSignature("tag mismatch".to_string()))?;
+
+            // Only after verifying, so forged messages can't move the
+            // counter.
+            let mut seen = self.seen.lock()?;
+            let sender = env.map(|env| env.src.clone());
+            let last = seen.entry((key_id, sender)).or_insert(0);
+            if counter <= *last {
+                return Err(Error::BadSignature(format!(
+                    "replayed counter {counter}, last seen {last}"
+                )));
+            }
+            *last = counter;
+            Ok(data.to_vec())
+        }
+    }
+
+    impl Wrapper for HmacWrapper {
+        fn wrap(&self, input: &[u8]) -> Result<Vec<u8>> {
+            Ok(self.sign(None, input))
+        }
+
+        fn unwrap(&self, input: &[u8]) -> Result<Vec<u8>> {
+            self.verify(None, input)
+        }
+
+        fn wrap_from(&self, env: &Envelope<'_>, input: &[u8]) -> Result<Vec<u8>> {
+            Ok(self.sign(Some(env), input))
+        }
+
+        fn unwrap_from(&self, env: &Envelope<'_>, input: &[u8]) -> Result<Vec<u8>> {
+            self.verify(Some(env), input)
+        }
+
+        fn overhead(&self) -> usize {
+            TRAILER_LEN
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{Error, Pid, Port};
+    use std::io::Cursor;
+
+    /// Wrapper that brackets the data, to see where wrapping happens. Adds
+    /// two bytes.
+    pub(crate) struct Brackets;
+
+    impl Wrapper for Brackets {
+        fn wrap(&self, input: &[u8]) -> Result<Vec<u8>> {
+            Ok([b"<", input, b">"].concat())
+        }
+        fn unwrap(&self, input: &[u8]) -> Result<Vec<u8>> {
+            input
+                .strip_prefix(b"<")
+                .and_then(|s| s.strip_suffix(b">"))
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| Error::msg("no brackets"))
+        }
+        fn overhead(&self) -> usize {
+            2
+        }
+    }
+
+    fn call(s: &str) -> Call {
+        s.parse().unwrap()
+    }
+
+    fn unproto(src: &str, data: &[u8]) -> Packet {
+        Packet::Unproto {
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call(src),
+            dst: call("ID"),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let mut w = Wrap::new(Cursor::new(Vec::new()), Brackets);
+        w.write_all(b"hello").unwrap();
+        w.write_all(b" world").unwrap();
+        let (sent, _) = w.into_inner();
+        let sent = sent.into_inner();
+        assert_eq!(&sent[..4], &7u32.to_be_bytes());
+        assert_eq!(&sent[4..11], b"<hello>");
+
+        let mut r = Wrap::new(Cursor::new(sent), Brackets);
+        let mut got = String::new();
+        r.read_to_string(&mut got).unwrap();
+        assert_eq!(got, "hello world");
+    }
+
+    #[test]
+    fn stream_rejects_bad_messages() {
+        let mut r = Wrap::new(Cursor::new(b"\0\0\0\x03abc".to_vec()), Brackets);
+        let err = r.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let huge = u32::try_from(MAX_WRAPPED_LEN + 1).unwrap().to_be_bytes();
+        let mut r = Wrap::new(Cursor::new(huge.to_vec()), Brackets);
+        let err = r.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut r = Wrap::new(Cursor::new(b"\0\0".to_vec()), Brackets);
+        let err = r.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn packets_wrap_payload_only() {
+        let wrapped = wrap_packet(&Brackets, unproto("M0THC-1", b"hi")).unwrap();
+        assert_eq!(wrapped, unproto("M0THC-1", b"<hi>"));
+        assert_eq!(
+            unwrap_packet(&Brackets, wrapped).unwrap(),
+            unproto("M0THC-1", b"hi")
+        );
+        assert_eq!(
+            wrap_packet(&Brackets, Packet::VersionQuery).unwrap(),
+            Packet::VersionQuery
+        );
+        assert!(unwrap_packet(&Brackets, unproto("M0THC-1", b"hi")).is_err());
+    }
+
+    #[test]
+    fn monitored_frames_keep_text() {
+        let monitor = |data: &[u8]| Packet::Monitor {
+            kind: MonitorKind::Unproto,
+            port: Port(0),
+            pid: Pid(0xF0),
+            src: call("M0THC-1"),
+            dst: call("ID"),
+            data: data.to_vec(),
+        };
+        let head = b" 1:Fm M0THC-1 To ID <UI pid=F0 Len=4 >[12:00:00]\r";
+        for tail in [&b""[..], b"\r", b"\r\0"] {
+            let got = unwrap_packet(&Brackets, monitor(&[&head[..], b"<hi>", tail].concat()));
+            assert_eq!(got.unwrap(), monitor(&[&head[..], b"hi"].concat()));
+        }
+    }
+
+    #[cfg(feature = "crypto")]
+    mod hmac {
+        use super::*;
+
+        fn sig_err(r: Result<Vec<u8>>) -> String {
+            match r {
+                Err(Error::BadSignature(msg)) => msg,
+                other => panic!("expected bad signature, got {other:?}"),
+            }
+        }
+
+        #[test]
+        fn round_trip() {
+            let tx = HmacWrapper::new(1, b"secret").with_counter(100);
+            let rx = HmacWrapper::new(1, b"secret");
+            let wrapped = tx.wrap(b"hello").unwrap();
+            assert_eq!(wrapped.len(), 5 + tx.overhead());
+            assert_eq!(tx.overhead(), 44);
+            assert_eq!(&wrapped[..5], b"hello");
+            assert_eq!(&wrapped[5..9], &1u32.to_be_bytes());
+            assert_eq!(&wrapped[9..17], &100u64.to_be_bytes());
+            assert_eq!(rx.unwrap(&wrapped).unwrap(), b"hello");
+        }
+
+        #[test]
+        fn rejects_tampering() {
+            let tx = HmacWrapper::new(1, b"secret");
+            let wrapped = tx.wrap(b"hello").unwrap();
+            // Data, key ID, counter, and tag. The changed key ID is known,
+            // so it fails on the tag.
+            for pos in [0, 6, 12, wrapped.len() - 1] {
+                let mut bad = wrapped.clone();
+                bad[pos] ^= 1;
+                let rx = HmacWrapper::new(1, b"secret").with_key(0x0001_0001, b"secret");
+                sig_err(rx.unwrap(&bad));
+            }
+            let rx = HmacWrapper::new(1, b"secret");
+            assert!(sig_err(rx.unwrap(&wrapped[1..])).contains("mismatch"));
+            assert!(sig_err(rx.unwrap(&wrapped[..43])).contains("shorter"));
+            // Still fine after all that.
+            assert_eq!(rx.unwrap(&wrapped).unwrap(), b"hello");
+        }
+
+        #[test]
+        fn rejects_wrong_key() {
+            let wrapped = HmacWrapper::new(1, b"secret").wrap(b"hello").unwrap();
+            let rx = HmacWrapper::new(1, b"other");
+            assert!(sig_err(rx.unwrap(&wrapped)).contains("mismatch"));
+            let rx = HmacWrapper::new(2, b"secret");
+            assert!(sig_err(rx.unwrap(&wrapped)).contains("unknown key ID 1"));
+
+            // Unless told to accept it.
+            let rx = HmacWrapper::new(2, b"new").with_key(1, b"secret");
+            assert_eq!(rx.unwrap(&wrapped).unwrap(), b"hello");
+        }
+
+        #[test]
+        fn rejects_replay() {
+            let tx = HmacWrapper::new(1, b"secret");
+            let rx = HmacWrapper::new(1, b"secret");
+            let first = tx.wrap(b"one").unwrap();
+            let second = tx.wrap(b"two").unwrap();
+            assert_eq!(rx.unwrap(&second).unwrap(), b"two");
+            assert!(sig_err(rx.unwrap(&second)).contains("replayed"));
+            // Out of order is a replay too.
+            assert!(sig_err(rx.unwrap(&first)).contains("replayed"));
+        }
+
+        #[test]
+        fn senders_sharing_key_have_own_counters() {
+            let rx = HmacWrapper::new(1, b"secret");
+            let a = HmacWrapper::new(1, b"secret").with_counter(1000);
+            let b = HmacWrapper::new(1, b"secret").with_counter(10);
+            let from_a = wrap_packet(&a, unproto("M0THC-1", b"a")).unwrap();
+            let from_b = wrap_packet(&b, unproto("M0THC-2", b"b")).unwrap();
+            assert_eq!(
+                unwrap_packet(&rx, from_a.clone()).unwrap(),
+                unproto("M0THC-1", b"a")
+            );
+            // Lower counter, but a different station.
+            assert_eq!(
+                unwrap_packet(&rx, from_b.clone()).unwrap(),
+                unproto("M0THC-2", b"b")
+            );
+            assert!(unwrap_packet(&rx, from_a).is_err());
+            assert!(unwrap_packet(&rx, from_b).is_err());
+        }
+
+        #[test]
+        fn signature_is_bound_to_sender() {
+            let tx = HmacWrapper::new(1, b"secret");
+            let rx = HmacWrapper::new(1, b"secret");
+            let Packet::Unproto { data, .. } =
+                wrap_packet(&tx, unproto("M0THC-1", b"hello")).unwrap()
+            else {
+                panic!("expected unproto");
+            };
+            let forged = Packet::Unproto {
+                port: Port(0),
+                pid: Pid(0xF0),
+                src: call("M0THC-2"),
+                dst: call("ID"),
+                data,
+            };
+            assert!(matches!(
+                unwrap_packet(&rx, forged),
+                Err(Error::BadSignature(_))
+            ));
+        }
+        #[test]
+        fn signature_is_bound_to_destination() {
+            let tx = HmacWrapper::new(1, b"secret");
+            let rx = HmacWrapper::new(1, b"secret");
+            let Packet::Unproto { data, .. } =
+                wrap_packet(&tx, unproto("M0THC-1", b"hello")).unwrap()
+            else {
+                panic!("expected unproto");
+            };
+            let replayed = |dst: &str, pid: u8| Packet::Unproto {
+                port: Port(1),
+                pid: Pid(pid),
+                src: call("M0THC-1"),
+                dst: call(dst),
+                data: data.clone(),
+            };
+            for (dst, pid) in [("CQ", 0xF0), ("ID", 0xCF)] {
+                assert!(matches!(
+                    unwrap_packet(&rx, replayed(dst, pid)),
+                    Err(Error::BadSignature(_))
+                ));
+            }
+            // The AGW port is local, so it doesn't matter.
+            assert_eq!(
+                unwrap_packet(&rx, replayed("ID", 0xF0)).unwrap(),
+                Packet::Unproto {
+                    port: Port(1),
+                    pid: Pid(0xF0),
+                    src: call("M0THC-1"),
+                    dst: call("ID"),
+                    data: b"hello".to_vec(),
+                }
+            );
+        }
+
+        #[test]
+        fn monitored_frames_use_pid_from_text() {
+            let tx = HmacWrapper::new(1, b"secret");
+            let rx = HmacWrapper::new(1, b"secret");
+            let Packet::Unproto { data, .. } =
+                wrap_packet(&tx, unproto("M0THC-1", b"hello")).unwrap()
+            else {
+                panic!("expected unproto");
+            };
+            let head = b" 1:Fm M0THC-1 To ID <UI pid=F0 Len=49 >[12:00:00]\r";
+            let monitor = |data: &[u8]| Packet::Monitor {
+                kind: MonitorKind::Unproto,
+                port: Port(0),
+                // Not set by the AGW server.
+                pid: Pid(0),
+                src: call("M0THC-1"),
+                dst: call("ID"),
+                data: [&head[..], data].concat(),
+            };
+            assert_eq!(
+                unwrap_packet(&rx, monitor(&data)).unwrap(),
+                monitor(b"hello")
+            );
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Throw mangled AGW frames at the parsers.
+//!
+//! Starts from a corpus of valid frames, mutates them, and feeds the result to
+//! `parse_header`, `Packet::parse`, and the v1 reply parser. Errors are fine,
+//! panics are bugs.
+//!
+//! Every frame that does parse must also serialize back to the exact same
+//! bytes, both as a `Packet` and when kept as a `Frame`.
+//!
+//! The seed and iteration count are fixed, so failures are reproducible.
+use anyhow::Result;
+
+use agw::{
+    Baud, Call, Frame, Header, MonitorKind, Packet, Pid, Port, PortCaps, PortInfo, PortsInfo,
+    HEADER_LEN,
+};
+
+/// Number of mutated frames to try.
+const ITERATIONS: u64 = 100_000;
+
+/// RNG seed.
+const SEED: u64 = 1;
+
+/// Xorshift. Good enough for mangling bytes, and keeps runs reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: usize) -> usize {
+        usize::try_from(self.next() % n as u64).expect("can't happen: below n")
+    }
+    fn byte(&mut self) -> u8 {
+        self.next().to_le_bytes()[0]
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+fn corpus() -> Result<Vec<Vec<u8>>> {
+    let src: Call = "M0QQQ-8".parse()?;
+    let dst: Call = "APZ001".parse()?;
+    let port = Port(0);
+    let pid = Pid(0xf0);
+    let mut raw = Header::new(port, b'K', Pid(0), None, None, 0);
+    raw.reserved = [1, 2, 3, 4, 5];
+    raw.user = 0x1234_5678;
+    Ok([
+        Packet::VersionQuery,
+        Packet::VersionReply {
+            major: 2005,
+            minor: 127,
+        },
+        Packet::FramesOutstandingPortQuery(port),
+        Packet::FramesOutstandingPortReply(port, 3),
+        Packet::RegisterCallsign(port, src.clone()),
+        Packet::UnregisterCallsign(port, src.clone()),
+        Packet::RegisterCallsignReply {
+            port,
+            call: src.clone(),
+            success: true,
+        },
+        Packet::PortInfoQuery,
+        Packet::PortInfoReply(PortsInfo {
+            count: 1,
+            ports: vec![PortInfo {
+                port: Port(0),
+                descr: "Fuzz port".to_string(),
+            }],
+        }),
+        Packet::PortCapQuery(port),
+        Packet::PortCapReply {
+            port,
+            caps: PortCaps::new(Baud::B1200),
+        },
+        Packet::CallsignHeardQuery(port),
+        Packet::Connect {
+            port,
+            pid,
+            src: src.clone(),
+            dst: dst.clone(),
+        },
+        Packet::ConnectVia {
+            port,
+            pid,
+            src: src.clone(),
+            dst: dst.clone(),
+            via: vec!["WIDE1-1".parse()?],
+        },
+        Packet::IncomingConnect {
+            port,
+            pid,
+            src: src.clone(),
+            dst: dst.clone(),
+            text: String::new(),
+        },
+        Packet::ConnectionEstablished {
+            port,
+            pid,
+            src: src.clone(),
+            dst: dst.clone(),
+            text: format!("*** CONNECTED With Station {src}\r\0"),
+        },
+        Packet::Disconnect {
+            port,
+            pid,
+            src: src.clone(),
+            dst: dst.clone(),
+            text: String::new(),
+        },
+        Packet::Disconnect {
+            port,
+            pid,
+            src: src.clone(),
+            dst: dst.clone(),
+            text: format!("*** DISCONNECTED From Station {src}\r\0"),
+        },
+        Packet::Unproto {
+            port,
+            pid,
+            src: src.clone(),
+            dst: dst.clone(),
+            data: b"hello".to_vec(),
+        },
+        Packet::UnprotoVia {
+            port,
+            pid,
+            src: src.clone(),
+            dst: dst.clone(),
+            via: vec!["WIDE1-1".parse()?, "WIDE2-1".parse()?],
+            data: b"hello".to_vec(),
+        },
+        Packet::ToggleMonitor,
+        Packet::ToggleRaw,
+        Packet::RawFrame {
+            port,
+            data: vec![
+                0, 0x82, 0xa0, 0xb4, 0x60, 0x60, 0x62, 0x60, 0x9a, 0x60, 0xa2, 0xa2, 0xa2, 0x40,
+                0x71, 0x03, 0xf0, b'h', b'i',
+            ],
+        },
+        Packet::Monitor {
+            kind: MonitorKind::Unproto,
+            port,
+            pid,
+            src: src.clone(),
+            dst: dst.clone(),
+            data: format!(
+                " 1:Fm {src} To {dst} Via WIDE1-1* <UI pid=F0 Len=5 >[12:00:00]\rhello\r\0"
+            )
+            .into_bytes(),
+        },
+        Packet::Data {
+            port,
+            pid,
+            src,
+            dst,
+            data: b"hello".to_vec(),
+        },
+        Packet::Unknown {
+            header: raw,
+            data: vec![0, 0xc0, 0x42],
+        },
+    ]
+    .iter()
+    .map(Packet::serialize)
+    .collect())
+}
+
+fn mutate(rng: &mut Rng, frame: &mut Vec<u8>) {
+    for _ in 0..=rng.below(4) {
+        match rng.below(6) {
+            0 if !frame.is_empty() => {
+                let pos = rng.below(frame.len());
+                frame[pos] = rng.byte();
+            }
+            1 if !frame.is_empty() => {
+                let len = rng.below(frame.len());
+                frame.truncate(len);
+            }
+            2 => {
+                for _ in 0..rng.below(32) {
+                    frame.push(rng.byte());
+                }
+            }
+            3 if frame.len() >= HEADER_LEN => {
+                // Data kind.
+                frame[4] = rng.byte();
+            }
+            4 if frame.len() >= HEADER_LEN => {
+                // Data length.
+                let len = u32::try_from(rng.below(64)).expect("can't happen: below 64");
+                frame[28..32].copy_from_slice(&len.to_le_bytes());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parse frame, and check that it round trips.
+///
+/// The packet and the `Frame` must give back the exact same bytes, and the
+/// typed packet of the `Frame` must parse back to itself after serializing.
+fn parse(frame: &[u8]) {
+    let Some(header) = frame.first_chunk::<HEADER_LEN>() else {
+        return;
+    };
+    let Ok(header) = agw::parse_header(header) else {
+        return;
+    };
+    let payload = &frame[HEADER_LEN..];
+    let len = payload
+        .len()
+        .min(usize::try_from(header.data_len).unwrap_or(usize::MAX));
+    // The v1 client parses replies with its own parser.
+    let _ = agw::fuzz_parse_reply(&header, &payload[..len]);
+    if usize::try_from(header.data_len) != Ok(len) {
+        // Not a whole frame, so only check that it doesn't panic.
+        let _ = Packet::parse(&header, &payload[..len]);
+        return;
+    }
+    let Ok(Some((packet, n))) = Packet::parse_frame(frame, u32::MAX) else {
+        return;
+    };
+    assert_eq!(n, HEADER_LEN + len);
+    assert_eq!(
+        packet.try_serialize().ok().as_deref(),
+        Some(&frame[..n]),
+        "{packet:?} did not round trip"
+    );
+    let (parsed, _) = Frame::parse_buf(frame, u32::MAX)
+        .expect("can't happen: parsed as packet")
+        .expect("can't happen: whole frame");
+    assert_eq!(
+        parsed.to_bytes(),
+        &frame[..n],
+        "{parsed:?} did not round trip"
+    );
+    let packet = parsed.packet();
+    let bytes = packet.serialize();
+    let (again, _) = Frame::parse_buf(&bytes, u32::MAX)
+        .unwrap_or_else(|e| panic!("{packet:?} serialized to unparsable {bytes:02x?}: {e}"))
+        .expect("can't happen: whole frame");
+    assert_eq!(again.packet(), packet, "{packet:?} did not round trip");
+}
+
+#[test]
+fn corpus_round_trips() -> Result<()> {
+    for frame in corpus()? {
+        parse(&frame);
+    }
+    Ok(())
+}
+
+#[test]
+fn mutated_frames_dont_panic() -> Result<()> {
+    let corpus = corpus()?;
+    let mut rng = Rng(SEED);
+    for n in 0..ITERATIONS {
+        let mut frame = corpus[rng.below(corpus.len())].clone();
+        mutate(&mut rng, &mut frame);
+        assert!(
+            std::panic::catch_unwind(|| parse(&frame)).is_ok(),
+            "iteration {n} panicked on input {frame:02x?}"
+        );
+    }
+    Ok(())
+}